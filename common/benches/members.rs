@@ -0,0 +1,94 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use ed25519_dalek::SigningKey;
+use freenet_scaffold::ComposableState;
+use rand::rngs::OsRng;
+use river_common::room_state::configuration::{AuthorizedConfigurationV1, Configuration};
+use river_common::room_state::member::{AuthorizedMember, Member, MemberId, MembersDelta, MembersV1};
+use river_common::room_state::ChatRoomParametersV1;
+use river_common::ChatRoomStateV1;
+
+const MAX_MEMBERS: usize = 1000;
+const MAX_INVITE_DEPTH: usize = 8;
+const ADDED: usize = 100;
+
+/// A room at `MAX_MEMBERS` members whose invite tree is a set of chains, each as deep as
+/// `MAX_INVITE_DEPTH` allows, so every member but the first of a chain has a full chain
+/// of inviters to check
+fn create_room() -> (ChatRoomStateV1, ChatRoomParametersV1) {
+    let owner_key = SigningKey::generate(&mut OsRng);
+    let owner_id = MemberId::new(&owner_key.verifying_key());
+    let parameters = ChatRoomParametersV1 {
+        owner: owner_key.verifying_key(),
+        admins: None,
+        recovery: None,
+    };
+
+    let mut state = ChatRoomStateV1 {
+        configuration: AuthorizedConfigurationV1::new(
+            Configuration {
+                owner_member_id: owner_id,
+                max_members: MAX_MEMBERS,
+                max_invites_per_member: MAX_MEMBERS,
+                max_invite_depth: MAX_INVITE_DEPTH,
+                ..Configuration::default()
+            },
+            &owner_key,
+        ),
+        ..ChatRoomStateV1::default()
+    };
+
+    let mut inviter_key = owner_key.clone();
+    for i in 0..MAX_MEMBERS {
+        if i % MAX_INVITE_DEPTH == 0 {
+            inviter_key = owner_key.clone();
+        }
+        let member_key = SigningKey::generate(&mut OsRng);
+        state.members.members.push(AuthorizedMember::new(
+            Member {
+                owner_member_id: owner_id,
+                invited_by: MemberId::new(&inviter_key.verifying_key()),
+                member_vk: member_key.verifying_key(),
+            },
+            &inviter_key,
+        ));
+        inviter_key = member_key;
+    }
+
+    state
+        .members
+        .verify(&state, &parameters)
+        .expect("Benchmark room should be valid");
+    (state, parameters)
+}
+
+fn bench_members(c: &mut Criterion) {
+    let (state, parameters) = create_room();
+
+    c.bench_function("verify members at max_members", |b| {
+        b.iter(|| state.members.verify(&state, &parameters).unwrap())
+    });
+
+    // The last `ADDED` members join a room holding everyone else, in a single delta
+    let split = MAX_MEMBERS - ADDED;
+    let existing = MembersV1 {
+        members: state.members.members[..split].to_vec(),
+        departed: vec![],
+        redeemed: vec![],
+    };
+    let delta = MembersDelta::new(state.members.members[split..].to_vec(), vec![]);
+
+    c.bench_function("apply members delta at max_members", |b| {
+        b.iter_batched(
+            || existing.clone(),
+            |mut members| members.apply_delta(&state, &parameters, &delta).unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(20);
+    targets = bench_members
+}
+criterion_main!(benches);
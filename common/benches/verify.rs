@@ -0,0 +1,129 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use ed25519_dalek::SigningKey;
+use freenet_scaffold::ComposableState;
+use rand::rngs::OsRng;
+use river_common::room_state::configuration::{AuthorizedConfigurationV1, Configuration};
+use river_common::room_state::member::{AuthorizedMember, Member, MemberId};
+use river_common::room_state::member_info::{AuthorizedMemberInfo, MemberInfo};
+use river_common::room_state::message::{AuthorizedMessageV1, MessageV1};
+use river_common::room_state::ChatRoomParametersV1;
+use river_common::util::{verify_struct, SignatureBatch};
+use river_common::ChatRoomStateV1;
+use std::time::{Duration, SystemTime};
+
+const MEMBERS: usize = 200;
+const MESSAGES: usize = 100;
+
+/// A room with `MEMBERS` members, each invited by the owner, with member info for each
+/// and `MESSAGES` messages
+fn create_room() -> (ChatRoomStateV1, ChatRoomParametersV1) {
+    let owner_key = SigningKey::generate(&mut OsRng);
+    let owner_id = MemberId::new(&owner_key.verifying_key());
+    let parameters = ChatRoomParametersV1 {
+        owner: owner_key.verifying_key(),
+        admins: None,
+        recovery: None,
+    };
+
+    let mut state = ChatRoomStateV1 {
+        configuration: AuthorizedConfigurationV1::new(
+            Configuration {
+                owner_member_id: owner_id,
+                max_members: MEMBERS,
+                max_invites_per_member: MEMBERS,
+                max_recent_messages: MESSAGES,
+                ..Configuration::default()
+            },
+            &owner_key,
+        ),
+        ..ChatRoomStateV1::default()
+    };
+
+    let member_keys: Vec<SigningKey> = (0..MEMBERS)
+        .map(|_| SigningKey::generate(&mut OsRng))
+        .collect();
+    for (i, member_key) in member_keys.iter().enumerate() {
+        let member_id = MemberId::new(&member_key.verifying_key());
+        state.members.members.push(AuthorizedMember::new(
+            Member {
+                owner_member_id: owner_id,
+                invited_by: owner_id,
+                member_vk: member_key.verifying_key(),
+            },
+            &owner_key,
+        ));
+        state
+            .member_info
+            .member_info
+            .push(AuthorizedMemberInfo::new_with_member_key(
+                MemberInfo {
+                    member_id,
+                    version: 1,
+                    preferred_nickname: format!("Member {}", i),
+                    avatar: None,
+                    bio: None,
+                    status: None,
+                    encryption_key: None,
+                },
+                member_key,
+            ));
+    }
+    for i in 0..MESSAGES {
+        let author_key = &member_keys[i % MEMBERS];
+        state.recent_messages.messages.push(AuthorizedMessageV1::new(
+            MessageV1 {
+                room_owner: owner_id,
+                author: MemberId::new(&author_key.verifying_key()),
+                time: SystemTime::UNIX_EPOCH + Duration::from_secs(i as u64),
+                content: format!("Message {}", i),
+                encrypted_content: None,
+            },
+            author_key,
+        ));
+    }
+
+    state
+        .verify(&state, &parameters)
+        .expect("Benchmark room should be valid");
+    (state, parameters)
+}
+
+fn bench_verify(c: &mut Criterion) {
+    let (state, parameters) = create_room();
+
+    c.bench_function("verify room state", |b| {
+        b.iter(|| state.verify(&state, &parameters).unwrap())
+    });
+
+    let messages = &state.recent_messages.messages;
+    let members_by_id = state.members.participants_by_member_id(&parameters);
+    let signed: Vec<_> = messages
+        .iter()
+        .map(|m| (m, *members_by_id[&m.message.author].verifying_key()))
+        .collect();
+
+    c.bench_function("verify messages individually", |b| {
+        b.iter(|| {
+            for (message, key) in &signed {
+                verify_struct(&message.message, &message.signature, key).unwrap();
+            }
+        })
+    });
+
+    c.bench_function("verify messages in a batch", |b| {
+        b.iter_batched(
+            || {
+                let mut batch = SignatureBatch::new();
+                for (message, key) in &signed {
+                    batch.add(&message.message, &message.signature, key, String::new);
+                }
+                batch
+            },
+            |batch| batch.verify().unwrap(),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(benches, bench_verify);
+criterion_main!(benches);
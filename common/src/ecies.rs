@@ -0,0 +1,339 @@
+//! Sealed boxes, data encrypted to a member's key so that only they can read it.
+//!
+//! A [`SealedBox`] is ECIES over the member's Ed25519 key converted to X25519: a fresh
+//! ephemeral key is agreed with the recipient's key, HKDF-SHA256 derives an AES-256-GCM key
+//! and nonce from the shared secret, and the derivation is bound to both public keys and to
+//! what the box is for, so a box can't be passed off as sealed to someone else or for
+//! another purpose. The format is versioned so it can change without breaking boxes already
+//! stored in rooms.
+
+use aes_gcm::{
+    aead::{Aead, KeyInit},
+    Aes256Gcm, Nonce,
+};
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+use std::fmt;
+use x25519_dalek::{PublicKey as X25519PublicKey, StaticSecret as X25519StaticSecret};
+
+/// Size of the authentication tag AES-GCM appends to the ciphertext
+pub const AES_GCM_TAG_SIZE: usize = 16;
+
+/// The version of the [`SealedBox`] format new boxes are sealed with
+pub const SEALED_BOX_VERSION: u8 = 1;
+
+/// Prefix of the HKDF info for version 1 boxes, followed by the box's context
+const SEALED_BOX_V1_INFO: &[u8] = b"river-sealed-box-v1:";
+
+/// Data sealed to a member's key, along with what the member needs to open it
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct SealedBox {
+    pub version: u8,
+    pub ephemeral_key: [u8; 32],
+    pub ciphertext: Vec<u8>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SealedBoxError {
+    /// The box was sealed with a version of the format this code doesn't know
+    UnsupportedVersion(u8),
+    /// The recipient's key is a weak key that anyone could open boxes sealed to
+    InvalidRecipientKey,
+    /// The plaintext is too long to be encrypted with a single key
+    PlaintextTooLong,
+    /// The box wasn't sealed to this key for this context, or it has been tampered with
+    OpenFailed,
+}
+
+impl fmt::Display for SealedBoxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SealedBoxError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported sealed box version {}", version)
+            }
+            SealedBoxError::InvalidRecipientKey => write!(f, "Invalid recipient key"),
+            SealedBoxError::PlaintextTooLong => write!(f, "Plaintext is too long to seal"),
+            SealedBoxError::OpenFailed => write!(f, "Failed to open sealed box"),
+        }
+    }
+}
+
+impl std::error::Error for SealedBoxError {}
+
+impl From<SealedBoxError> for String {
+    fn from(error: SealedBoxError) -> Self {
+        error.to_string()
+    }
+}
+
+impl SealedBox {
+    /// Seals `plaintext` to the holder of `recipient_key`. `context` says what the box is
+    /// for, it's needed to open the box again and isn't stored in it.
+    pub fn seal(
+        recipient_key: &VerifyingKey,
+        context: &[u8],
+        plaintext: &[u8],
+    ) -> Result<Self, SealedBoxError> {
+        Self::seal_with_ephemeral_key(
+            X25519StaticSecret::random_from_rng(OsRng),
+            recipient_key,
+            context,
+            plaintext,
+        )
+    }
+
+    fn seal_with_ephemeral_key(
+        ephemeral_secret: X25519StaticSecret,
+        recipient_key: &VerifyingKey,
+        context: &[u8],
+        plaintext: &[u8],
+    ) -> Result<Self, SealedBoxError> {
+        let ephemeral_key = X25519PublicKey::from(&ephemeral_secret);
+        let recipient_key = ed25519_to_x25519_public_key(recipient_key)?;
+        let shared_secret = ephemeral_secret.diffie_hellman(&recipient_key);
+        if !shared_secret.was_contributory() {
+            return Err(SealedBoxError::InvalidRecipientKey);
+        }
+        let (cipher, nonce) = derive_cipher(
+            shared_secret.as_bytes(),
+            &ephemeral_key,
+            &recipient_key,
+            context,
+        );
+        let ciphertext = cipher
+            .encrypt(&Nonce::from(nonce), plaintext)
+            .map_err(|_| SealedBoxError::PlaintextTooLong)?;
+        Ok(Self {
+            version: SEALED_BOX_VERSION,
+            ephemeral_key: ephemeral_key.to_bytes(),
+            ciphertext,
+        })
+    }
+
+    /// Opens a box sealed to the holder of `recipient_key` for `context`
+    pub fn open(
+        &self,
+        recipient_key: &SigningKey,
+        context: &[u8],
+    ) -> Result<Vec<u8>, SealedBoxError> {
+        if self.version != SEALED_BOX_VERSION {
+            return Err(SealedBoxError::UnsupportedVersion(self.version));
+        }
+        let recipient_secret = ed25519_to_x25519_private_key(recipient_key);
+        let ephemeral_key = X25519PublicKey::from(self.ephemeral_key);
+        let shared_secret = recipient_secret.diffie_hellman(&ephemeral_key);
+        if !shared_secret.was_contributory() {
+            return Err(SealedBoxError::OpenFailed);
+        }
+        let (cipher, nonce) = derive_cipher(
+            shared_secret.as_bytes(),
+            &ephemeral_key,
+            &X25519PublicKey::from(&recipient_secret),
+            context,
+        );
+        cipher
+            .decrypt(&Nonce::from(nonce), self.ciphertext.as_ref())
+            .map_err(|_| SealedBoxError::OpenFailed)
+    }
+
+    /// Size of the data that was sealed, known without opening the box
+    pub fn plaintext_size(&self) -> usize {
+        self.ciphertext.len().saturating_sub(AES_GCM_TAG_SIZE)
+    }
+}
+
+/// Derives the key and nonce of a version 1 box. Every box has its own ephemeral key, so a
+/// nonce derived along with the key is never reused.
+fn derive_cipher(
+    shared_secret: &[u8; 32],
+    ephemeral_key: &X25519PublicKey,
+    recipient_key: &X25519PublicKey,
+    context: &[u8],
+) -> (Aes256Gcm, [u8; 12]) {
+    let mut salt = [0u8; 64];
+    salt[..32].copy_from_slice(ephemeral_key.as_bytes());
+    salt[32..].copy_from_slice(recipient_key.as_bytes());
+    let info = [SEALED_BOX_V1_INFO, context].concat();
+
+    let mut okm = [0u8; 44];
+    Hkdf::<Sha256>::new(Some(&salt), shared_secret)
+        .expand(&info, &mut okm)
+        .expect("44 bytes is a valid HKDF-SHA256 output length");
+    let cipher = Aes256Gcm::new_from_slice(&okm[..32]).expect("AES-256 keys are 32 bytes");
+    let mut nonce = [0u8; 12];
+    nonce.copy_from_slice(&okm[32..]);
+    (cipher, nonce)
+}
+
+fn ed25519_to_x25519_public_key(
+    ed25519_pk: &VerifyingKey,
+) -> Result<X25519PublicKey, SealedBoxError> {
+    let ed_y = CompressedEdwardsY(ed25519_pk.to_bytes())
+        .decompress()
+        .ok_or(SealedBoxError::InvalidRecipientKey)?;
+    Ok(X25519PublicKey::from(ed_y.to_montgomery().to_bytes()))
+}
+
+/// The X25519 public key corresponding to a member's Ed25519 signing key, suitable for
+/// publishing in their profile so others can encrypt data for them.
+pub fn x25519_public_key(signing_key: &SigningKey) -> X25519PublicKey {
+    X25519PublicKey::from(&ed25519_to_x25519_private_key(signing_key))
+}
+
+fn ed25519_to_x25519_private_key(ed25519_sk: &SigningKey) -> X25519StaticSecret {
+    let h = Sha512::digest(ed25519_sk.to_bytes());
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&h[..32]);
+    key[0] &= 248;
+    key[31] &= 127;
+    key[31] |= 64;
+    X25519StaticSecret::from(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data_encoding::HEXLOWER;
+
+    /// Version 1 boxes that other implementations, e.g. in the delegate, must open and seal
+    /// byte for byte: (recipient signing key, ephemeral secret, context, plaintext,
+    /// ephemeral public key, ciphertext). The second uses the first Ed25519 key of RFC 8032
+    /// and Alice's X25519 key of RFC 7748.
+    ///
+    /// The third, with the second Ed25519 key of RFC 8032 and Bob's X25519 key of RFC 7748,
+    /// was computed independently of this implementation with pyca/cryptography:
+    ///
+    /// ```python
+    /// from hashlib import sha512
+    /// from cryptography.hazmat.primitives.asymmetric.x25519 import X25519PrivateKey
+    /// from cryptography.hazmat.primitives.ciphers.aead import AESGCM
+    /// from cryptography.hazmat.primitives.hashes import SHA256
+    /// from cryptography.hazmat.primitives.kdf.hkdf import HKDF
+    /// from cryptography.hazmat.primitives.serialization import Encoding, PublicFormat
+    ///
+    /// raw = lambda k: k.public_key().public_bytes(Encoding.Raw, PublicFormat.Raw)
+    /// seed = bytes.fromhex("4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb")
+    /// recipient = X25519PrivateKey.from_private_bytes(sha512(seed).digest()[:32])
+    /// ephemeral = X25519PrivateKey.from_private_bytes(bytes.fromhex(
+    ///     "5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb"))
+    /// shared = ephemeral.exchange(recipient.public_key())
+    /// salt = raw(ephemeral) + raw(recipient)
+    /// okm = HKDF(SHA256(), 44, salt, b"river-sealed-box-v1:room_secret").derive(shared)
+    /// print(raw(ephemeral).hex())
+    /// print(AESGCM(okm[:32]).encrypt(okm[32:], b"Checked against pyca/cryptography", None).hex())
+    /// ```
+    const TEST_VECTORS: [(&str, &str, &str, &str, &str, &str); 3] = [
+        (
+            "0101010101010101010101010101010101010101010101010101010101010101",
+            "0202020202020202020202020202020202020202020202020202020202020202",
+            "",
+            "",
+            "ce8d3ad1ccb633ec7b70c17814a5c76ecd029685050d344745ba05870e587d59",
+            "9fa8145ebb8fa1296d95efd86e9bf476",
+        ),
+        (
+            "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+            "77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a",
+            "direct_message",
+            "Secret message",
+            "8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a",
+            "4c69a7fda3fcdfe6a58fda0f19be652d70ed059912da5c6c7cd54dd89acc",
+        ),
+        (
+            "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+            "5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb",
+            "room_secret",
+            "Checked against pyca/cryptography",
+            "de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f",
+            "768ff3b3835b1804d553946540714a7cc675304a65a52cb87590b1f357768c09379eaa15e70f008383830f0e72c1574b32",
+        ),
+    ];
+
+    fn decode<const N: usize>(hex: &str) -> [u8; N] {
+        HEXLOWER.decode(hex.as_bytes()).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn test_vectors() {
+        for (recipient, ephemeral, context, plaintext, ephemeral_key, ciphertext) in TEST_VECTORS {
+            let recipient = SigningKey::from_bytes(&decode(recipient));
+            let sealed = SealedBox::seal_with_ephemeral_key(
+                X25519StaticSecret::from(decode::<32>(ephemeral)),
+                &recipient.verifying_key(),
+                context.as_bytes(),
+                plaintext.as_bytes(),
+            )
+            .unwrap();
+            assert_eq!(sealed.version, 1);
+            assert_eq!(HEXLOWER.encode(&sealed.ephemeral_key), ephemeral_key);
+            assert_eq!(HEXLOWER.encode(&sealed.ciphertext), ciphertext);
+            assert_eq!(
+                sealed.open(&recipient, context.as_bytes()),
+                Ok(plaintext.as_bytes().to_vec())
+            );
+        }
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let recipient = SigningKey::generate(&mut OsRng);
+        let sealed =
+            SealedBox::seal(&recipient.verifying_key(), b"test", b"Secret message").unwrap();
+        assert_eq!(sealed.plaintext_size(), 14);
+        assert_eq!(
+            sealed.open(&recipient, b"test"),
+            Ok(b"Secret message".to_vec())
+        );
+
+        // Only the recipient can open the box, and only for the context it was sealed for
+        let other = SigningKey::generate(&mut OsRng);
+        assert_eq!(
+            sealed.open(&other, b"test"),
+            Err(SealedBoxError::OpenFailed)
+        );
+        assert_eq!(
+            sealed.open(&recipient, b"other"),
+            Err(SealedBoxError::OpenFailed)
+        );
+
+        let mut tampered = sealed.clone();
+        tampered.ciphertext[0] ^= 1;
+        assert_eq!(
+            tampered.open(&recipient, b"test"),
+            Err(SealedBoxError::OpenFailed)
+        );
+
+        let mut future = sealed.clone();
+        future.version = 2;
+        assert_eq!(
+            future.open(&recipient, b"test"),
+            Err(SealedBoxError::UnsupportedVersion(2))
+        );
+    }
+
+    #[test]
+    fn test_weak_recipient_key() {
+        // The identity point, whose shared secret with any key is all zeroes
+        let mut identity = [0u8; 32];
+        identity[0] = 1;
+        let weak_key = VerifyingKey::from_bytes(&identity).unwrap();
+        assert_eq!(
+            SealedBox::seal(&weak_key, b"test", b"Secret message"),
+            Err(SealedBoxError::InvalidRecipientKey)
+        );
+    }
+
+    #[test]
+    fn test_serialization() {
+        let recipient = SigningKey::generate(&mut OsRng);
+        let sealed = SealedBox::seal(&recipient.verifying_key(), b"test", b"Hello").unwrap();
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(&sealed, &mut bytes).unwrap();
+        let decoded: SealedBox = ciborium::de::from_reader(bytes.as_slice()).unwrap();
+        assert_eq!(decoded, sealed);
+    }
+}
@@ -1,3 +1,4 @@
+pub mod ecies;
 pub mod room_state;
 pub mod util;
 
@@ -1,25 +1,33 @@
+pub mod authority;
 pub mod ban;
 pub mod configuration;
+pub mod direct_message;
 pub mod member;
 pub mod member_info;
 pub mod message;
+pub mod secret;
 pub mod upgrade;
 
-use crate::room_state::ban::BansV1;
-use crate::room_state::configuration::AuthorizedConfigurationV1;
-use crate::room_state::member::{MemberId, MembersV1};
-use crate::room_state::member_info::MemberInfoV1;
+use crate::room_state::authority::{AdminPolicy, KeyPolicy};
+use crate::room_state::ban::{AuthorizedUserBan, BansV1, UserBan};
+use crate::room_state::configuration::{AuthorizedConfigurationV1, Configuration};
+use crate::room_state::direct_message::DirectMessagesV1;
+use crate::room_state::member::{AuthorizedMember, Member, MemberId, MembersV1};
+use crate::room_state::member_info::{AuthorizedMemberInfo, MemberInfo, MemberInfoV1};
 use crate::room_state::message::MessagesV1;
-use crate::room_state::upgrade::OptionalUpgradeV1;
-use ed25519_dalek::VerifyingKey;
+use crate::room_state::secret::RoomSecretsV1;
+use crate::room_state::upgrade::{AuthorizedUpgradeV1, OptionalUpgradeV1, UpgradeV1};
+use ed25519_dalek::{SigningKey, VerifyingKey};
 use freenet_scaffold_macro::composable;
 use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
 
-#[composable]
+#[composable(post_apply_delta = "ChatRoomStateV1::post_apply_delta")]
 #[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
 pub struct ChatRoomStateV1 {
     // WARNING: The order of these fields is important for the purposes of the #[composable] macro.
-    // `configuration` must be first, followed by `bans`, `members`, `member_info`, and then `recent_messages`.
+    // `configuration` must be first, followed by `bans`, `members`, `member_info`, `room_secrets`, and then `recent_messages`
+    // and `direct_messages`.
     // This is due to interdependencies between the fields and the order in which they must be applied in
     // the `apply_delta` function. DO NOT reorder fields without fully understanding the implications.
     /// Configures things like maximum message length, can be updated by the owner.
@@ -35,23 +43,331 @@ pub struct ChatRoomStateV1 {
     /// Metadata about members like their nickname, can be updated by members themselves.
     pub member_info: MemberInfoV1,
 
+    /// The room secret of a private room sealed to each member, set by the owner
+    #[serde(default)]
+    pub room_secrets: RoomSecretsV1,
+
     /// The most recent messages in the chat room, the number is limited by the room configuration.
     pub recent_messages: MessagesV1,
 
+    /// Private messages between participants, each sealed to its recipient, the number in
+    /// each inbox is limited by the room configuration.
+    #[serde(default)]
+    pub direct_messages: DirectMessagesV1,
+
     /// If this contract has been replaced by a new contract this will contain the new contract address.
     /// This can only be set by the owner.
     pub upgrade: OptionalUpgradeV1,
 }
 
+impl ChatRoomStateV1 {
+    /// Called after all field deltas have been applied. Removes members affected by bans in
+    /// this delta, trims every field to the limits of the current configuration, then
+    /// removes member info and room secrets belonging to anyone who is no longer a
+    /// participant, for example because they left the room, were banned or were evicted.
+    /// Messages and direct messages of members who left stay, unless dated after they left.
+    fn post_apply_delta(
+        &mut self,
+        _parent_state: &ChatRoomStateV1,
+        parameters: &ChatRoomParametersV1,
+    ) -> Result<(), String> {
+        // Members are applied against the bans from before the delta, so new bans take
+        // effect here
+        self.members.remove_banned_members(&self.bans, parameters);
+
+        // Fields are applied against the configuration from before the delta, so lowered
+        // limits take effect here. Trimming only depends on the state, so all peers converge.
+        let configuration = self.configuration.configuration.clone();
+        self.bans.enforce_limits(&configuration);
+        let mut members = std::mem::take(&mut self.members);
+        members.enforce_limits(self, parameters);
+        self.members = members;
+        self.member_info.enforce_limits(&configuration);
+        self.recent_messages.enforce_limits(&configuration);
+        self.direct_messages.enforce_limits(&configuration);
+
+        let participants_by_id = self.members.participants_by_member_id(parameters);
+        let authors_by_id = self.members.authors_by_member_id(&self.bans, parameters);
+        self.member_info
+            .member_info
+            .retain(|info| participants_by_id.contains_key(&info.member_info.member_id));
+        self.room_secrets
+            .secrets
+            .retain(|secret| participants_by_id.contains_key(&secret.sealed_secret.member_id));
+        // Messages are applied against the upgrade from before the delta, so messages
+        // written after a new upgrade's cutoff are dropped here
+        let upgrade = &self.upgrade;
+        self.recent_messages.messages.retain(|message| {
+            authors_by_id
+                .get(&message.message.author)
+                .is_some_and(|a| a.could_write_at(message.message.time))
+                && !upgrade.is_past_cutoff(message.message.time)
+        });
+        self.direct_messages.messages.retain(|message| {
+            message.sender(&authors_by_id).is_some()
+                && message.recipient(&authors_by_id).is_some()
+                && !upgrade.is_past_cutoff(message.message.time)
+        });
+        self.room_secrets
+            .retain_epochs_in_use(&self.recent_messages);
+        Ok(())
+    }
+
+    /// Signs, with `signing_keys`, an upgrade that transfers this room to `new_owner`,
+    /// pointing it at the successor's address for a room contract whose code hashes to
+    /// `new_contract_code_hash`, see [`ChatRoomParametersV1::for_new_owner`]. Messages
+    /// written after `cutoff` belong in the successor.
+    pub fn prepare_ownership_transfer(
+        &self,
+        parameters: &ChatRoomParametersV1,
+        new_owner: VerifyingKey,
+        new_contract_code_hash: &blake3::Hash,
+        cutoff: SystemTime,
+        signing_keys: &[&SigningKey],
+    ) -> Result<AuthorizedUpgradeV1, String> {
+        let new_parameters = parameters.for_new_owner(new_owner);
+        new_parameters.validate()?;
+        Ok(AuthorizedUpgradeV1::new_with_keys(
+            UpgradeV1 {
+                owner_member_id: parameters.owner_id(),
+                version: self.upgrade.next_version()?,
+                new_chatroom_address: new_parameters.contract_address(new_contract_code_hash),
+                new_owner: Some(new_owner),
+                cutoff: Some(cutoff),
+            },
+            signing_keys,
+        ))
+    }
+
+    /// Builds the state of the room that succeeds this one after `upgrade` transferred
+    /// ownership to `new_owner_key`, along with the successor's parameters. The upgrade
+    /// must point at the successor's address for a room contract whose code hashes to
+    /// `contract_code_hash`.
+    ///
+    /// Members, bans and the configuration are bound to the owner, so the new owner
+    /// re-signs them. Members are carried over as invited by the new owner directly because
+    /// only their inviters could re-sign the original invites, which flattens the invite
+    /// tree. Member info is carried over as is, the old owner's becomes the new owner's
+    /// unless they have their own. The old owner is left out, as are messages and direct
+    /// messages, which only their senders could re-sign and which stay readable in this
+    /// room, and the room secrets of a private room, which the new owner seals anew.
+    pub fn successor_for_new_owner(
+        &self,
+        parameters: &ChatRoomParametersV1,
+        upgrade: &AuthorizedUpgradeV1,
+        new_owner_key: &SigningKey,
+        contract_code_hash: &blake3::Hash,
+    ) -> Result<(ChatRoomStateV1, ChatRoomParametersV1), String> {
+        upgrade.verify_signature(parameters)?;
+        parameters.check_room_binding(upgrade.upgrade.owner_member_id)?;
+        if upgrade.upgrade.new_owner != Some(new_owner_key.verifying_key()) {
+            return Err("The upgrade doesn't transfer the room to this key".to_string());
+        }
+
+        let old_owner_id = parameters.owner_id();
+        let new_parameters = parameters.for_new_owner(new_owner_key.verifying_key());
+        if upgrade.upgrade.new_chatroom_address
+            != new_parameters.contract_address(contract_code_hash)
+        {
+            return Err("The upgrade doesn't point at the new owner's room".to_string());
+        }
+        let new_owner_id = new_parameters.owner_id();
+
+        let configuration = AuthorizedConfigurationV1::new(
+            Configuration {
+                owner_member_id: new_owner_id,
+                ..self.configuration.configuration.clone()
+            },
+            new_owner_key,
+        );
+
+        let bans = BansV1(
+            self.bans
+                .0
+                .iter()
+                .filter(|ban| ban.ban.banned_user != new_owner_id)
+                .map(|ban| {
+                    let ban = UserBan {
+                        owner_member_id: new_owner_id,
+                        ..ban.ban.clone()
+                    };
+                    AuthorizedUserBan::new(ban, new_owner_id, new_owner_key)
+                })
+                .collect(),
+        );
+
+        let members = MembersV1 {
+            members: self
+                .members
+                .members
+                .iter()
+                .filter(|m| m.member.id() != new_owner_id)
+                .map(|m| {
+                    let member = Member {
+                        owner_member_id: new_owner_id,
+                        invited_by: new_owner_id,
+                        member_vk: m.member.member_vk,
+                    };
+                    AuthorizedMember::new(member, new_owner_key)
+                })
+                .collect(),
+            departed: vec![],
+            redeemed: vec![],
+        };
+
+        let new_owner_has_info = self
+            .member_info
+            .member_info
+            .iter()
+            .any(|info| info.member_info.member_id == new_owner_id);
+        let member_info = MemberInfoV1 {
+            member_info: self
+                .member_info
+                .member_info
+                .iter()
+                .filter_map(|info| {
+                    if info.member_info.member_id != old_owner_id {
+                        Some(info.clone())
+                    } else if !new_owner_has_info {
+                        let member_info = MemberInfo {
+                            member_id: new_owner_id,
+                            ..info.member_info.clone()
+                        };
+                        Some(AuthorizedMemberInfo::new_with_member_key(
+                            member_info,
+                            new_owner_key,
+                        ))
+                    } else {
+                        None
+                    }
+                })
+                .collect(),
+        };
+
+        let mut successor = ChatRoomStateV1 {
+            configuration,
+            bans,
+            members,
+            member_info,
+            room_secrets: RoomSecretsV1::default(),
+            recent_messages: MessagesV1::default(),
+            direct_messages: DirectMessagesV1::default(),
+            upgrade: OptionalUpgradeV1(None),
+        };
+        // Drops the info of anyone who didn't make it into the successor, e.g. the banned
+        successor.post_apply_delta(self, &new_parameters)?;
+        successor.verify(&successor, &new_parameters)?;
+        Ok((successor, new_parameters))
+    }
+
+    /// Builds the state a new version of the room contract, whose code hashes to
+    /// `new_contract_code_hash`, starts from, along with the upgrade pointing this room at
+    /// it. The room keeps its parameters, so everything signed for it stays valid in the
+    /// successor and only the upgrade pointer is left behind. Messages written after
+    /// `cutoff` belong in the successor.
+    ///
+    /// The upgrade is signed by `signing_keys`, admins that must co-sign can add their
+    /// signatures with [`AuthorizedUpgradeV1::add_signature`] before it's applied.
+    pub fn prepare_upgrade(
+        &self,
+        parameters: &ChatRoomParametersV1,
+        new_contract_code_hash: &blake3::Hash,
+        cutoff: SystemTime,
+        signing_keys: &[&SigningKey],
+    ) -> Result<(ChatRoomStateV1, AuthorizedUpgradeV1), String> {
+        let upgrade = AuthorizedUpgradeV1::new_with_keys(
+            UpgradeV1 {
+                owner_member_id: parameters.owner_id(),
+                version: self.upgrade.next_version()?,
+                new_chatroom_address: parameters.contract_address(new_contract_code_hash),
+                new_owner: None,
+                cutoff: Some(cutoff),
+            },
+            signing_keys,
+        );
+        let successor = self.successor_state();
+        successor.verify(&successor, parameters)?;
+        Ok((successor, upgrade))
+    }
+
+    /// The state a new version of the room contract with the same parameters starts from
+    pub fn successor_state(&self) -> ChatRoomStateV1 {
+        ChatRoomStateV1 {
+            upgrade: OptionalUpgradeV1(None),
+            ..self.clone()
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Default, PartialEq, Debug)]
 pub struct ChatRoomParametersV1 {
     pub owner: VerifyingKey,
+    /// Co-owner keys that can authorize the configuration and upgrades together, so that a
+    /// team can run a room without sharing the owner's private key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub admins: Option<AdminPolicy>,
+    /// Keys that can authorize the configuration and upgrades in place of a lost or
+    /// compromised owner key, see [`authority::Authority`] for which takes precedence
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recovery: Option<KeyPolicy>,
 }
 
 impl ChatRoomParametersV1 {
     pub fn owner_id(&self) -> MemberId {
         MemberId::new(&self.owner)
     }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(admins) = &self.admins {
+            admins.validate()?;
+            if admins.keys.contains(&self.owner) {
+                return Err("The owner key can't also be an admin key".to_string());
+            }
+        }
+        if let Some(recovery) = &self.recovery {
+            recovery.validate()?;
+            if recovery.keys.contains(&self.owner) {
+                return Err("The owner key can't also be a recovery key".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// The parameters of the room that succeeds this one once it's transferred to
+    /// `new_owner`. The admins were chosen by the old owner, so the new owner appoints
+    /// their own, while the recovery keys are kept.
+    pub fn for_new_owner(&self, new_owner: VerifyingKey) -> ChatRoomParametersV1 {
+        ChatRoomParametersV1 {
+            owner: new_owner,
+            admins: None,
+            recovery: self.recovery.clone(),
+        }
+    }
+
+    /// Address of the room contract whose code hashes to `contract_code_hash` with these
+    /// parameters, derived the way Freenet derives contract instance IDs
+    pub fn contract_address(&self, contract_code_hash: &blake3::Hash) -> blake3::Hash {
+        let mut parameters_bytes = Vec::new();
+        ciborium::ser::into_writer(self, &mut parameters_bytes)
+            .expect("Serialization should not fail");
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(contract_code_hash.as_bytes());
+        hasher.update(&parameters_bytes);
+        hasher.finalize()
+    }
+
+    /// Checks that the owner ID a signed item was bound to is this room's owner, so that
+    /// items signed for one room can't be replayed into another
+    pub fn check_room_binding(&self, owner_member_id: MemberId) -> Result<(), String> {
+        if owner_member_id != self.owner_id() {
+            return Err(format!(
+                "Signed for a different room: owner {} does not match room owner {}",
+                owner_member_id,
+                self.owner_id()
+            ));
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -127,7 +443,13 @@ mod tests {
         let owner_verifying_key = owner_signing_key.verifying_key();
         let owner_id = MemberId::new(&owner_verifying_key);
 
-        let config = AuthorizedConfigurationV1::new(Configuration::default(), &owner_signing_key);
+        let config = AuthorizedConfigurationV1::new(
+            Configuration {
+                owner_member_id: owner_id,
+                ..Configuration::default()
+            },
+            &owner_signing_key,
+        );
 
         (
             ChatRoomStateV1 {
@@ -135,11 +457,15 @@ mod tests {
                 bans: BansV1::default(),
                 members: MembersV1::default(),
                 member_info: MemberInfoV1::default(),
+                room_secrets: RoomSecretsV1::default(),
                 recent_messages: MessagesV1::default(),
+                direct_messages: DirectMessagesV1::default(),
                 upgrade: OptionalUpgradeV1(None),
             },
             ChatRoomParametersV1 {
                 owner: owner_verifying_key,
+                admins: None,
+                recovery: None,
             },
             owner_signing_key,
         )
@@ -201,4 +527,1326 @@ mod tests {
             "State should be partially modified"
         );
     }
+
+    #[test]
+    fn test_leave_removes_member_info_but_keeps_messages() {
+        use crate::room_state::ban::{AuthorizedUserBan, UserBan};
+        use crate::room_state::direct_message::{AuthorizedDirectMessage, DirectMessage};
+        use crate::room_state::member::{
+            AuthorizedMember, AuthorizedMemberLeave, Member, MemberLeave, MembersDelta,
+        };
+        use crate::room_state::member_info::{AuthorizedMemberInfo, MemberInfo};
+        use crate::room_state::message::{AuthorizedMessageV1, MessageV1};
+        use std::time::{Duration, SystemTime};
+
+        let (mut state, parameters, owner_signing_key) = create_empty_chat_room_state();
+        let owner_id = parameters.owner_id();
+
+        let member_signing_key = SigningKey::generate(&mut rand::thread_rng());
+        let member = AuthorizedMember::new(
+            Member {
+                owner_member_id: owner_id,
+                invited_by: owner_id,
+                member_vk: member_signing_key.verifying_key(),
+            },
+            &owner_signing_key,
+        );
+        let member_id = member.member.id();
+        state.members.members.push(member.clone());
+        state
+            .member_info
+            .member_info
+            .push(AuthorizedMemberInfo::new_with_member_key(
+                MemberInfo {
+                    member_id,
+                    version: 1,
+                    preferred_nickname: "Leaver".to_string(),
+                    avatar: None,
+                    bio: None,
+                    status: None,
+                    encryption_key: None,
+                },
+                &member_signing_key,
+            ));
+        let left_at = SystemTime::now();
+        let message = |content: &str, time: SystemTime| {
+            AuthorizedMessageV1::new(
+                MessageV1 {
+                    room_owner: owner_id,
+                    author: member_id,
+                    time,
+                    content: content.to_string(),
+                    encrypted_content: None,
+                },
+                &member_signing_key,
+            )
+        };
+        let direct_message = |content: &str, time: SystemTime| {
+            AuthorizedDirectMessage::new(
+                DirectMessage::new(
+                    owner_id,
+                    &member_signing_key.verifying_key(),
+                    &parameters.owner,
+                    time,
+                    content,
+                )
+                .unwrap(),
+                &member_signing_key,
+            )
+        };
+        let before = left_at - Duration::from_secs(60);
+        let after = left_at + Duration::from_secs(60);
+        state.recent_messages.messages.push(message("Goodbye", before));
+        state
+            .direct_messages
+            .messages
+            .push(direct_message("Goodbye", before));
+        assert!(state.verify(&state, &parameters).is_ok());
+
+        let empty_delta = ChatRoomStateV1Delta {
+            configuration: None,
+            bans: None,
+            members: None,
+            member_info: None,
+            room_secrets: None,
+            recent_messages: None,
+            direct_messages: None,
+            upgrade: None,
+        };
+        let leave_delta = ChatRoomStateV1Delta {
+            members: Some(MembersDelta::new(
+                vec![],
+                vec![AuthorizedMemberLeave::new(
+                    MemberLeave {
+                        owner_member_id: owner_id,
+                        member,
+                        left_at,
+                    },
+                    &member_signing_key,
+                )],
+            )),
+            ..empty_delta.clone()
+        };
+        let delayed = ChatRoomStateV1Delta {
+            recent_messages: Some(vec![message("Written before leaving", before)]),
+            direct_messages: Some(vec![direct_message("Written before leaving", before)]),
+            ..empty_delta.clone()
+        };
+        let late = ChatRoomStateV1Delta {
+            recent_messages: Some(vec![message("After leaving", after)]),
+            direct_messages: Some(vec![direct_message("After leaving", after)]),
+            ..empty_delta.clone()
+        };
+
+        // The member and their info are gone, but whichever order the leave and their
+        // messages arrive in, the messages written before leaving stay
+        let mut left_first = state.clone();
+        for delta in [&leave_delta, &delayed, &late] {
+            left_first
+                .apply_delta(&left_first.clone(), &parameters, delta)
+                .unwrap();
+        }
+        let mut left_last = state.clone();
+        for delta in [&late, &delayed, &leave_delta] {
+            left_last
+                .apply_delta(&left_last.clone(), &parameters, delta)
+                .unwrap();
+        }
+        assert_eq!(left_first, left_last);
+        assert!(left_first.members.members.is_empty());
+        assert!(left_first.member_info.member_info.is_empty());
+        assert_eq!(left_first.recent_messages.messages.len(), 2);
+        assert_eq!(left_first.direct_messages.messages.len(), 2);
+        assert!(left_first.verify(&left_first, &parameters).is_ok());
+
+        // A state holding messages dated after the member left is invalid
+        let mut invalid = left_first.clone();
+        invalid
+            .recent_messages
+            .messages
+            .push(message("After leaving", after));
+        assert!(invalid.verify(&invalid, &parameters).is_err());
+
+        // Banning the member after they left clears their messages
+        let ban_delta = ChatRoomStateV1Delta {
+            bans: Some(vec![AuthorizedUserBan::new(
+                UserBan {
+                    owner_member_id: owner_id,
+                    banned_at: after,
+                    banned_user: member_id,
+                },
+                owner_id,
+                &owner_signing_key,
+            )]),
+            ..empty_delta
+        };
+        left_first
+            .apply_delta(&left_first.clone(), &parameters, &ban_delta)
+            .unwrap();
+        assert!(left_first.recent_messages.messages.is_empty());
+        assert!(left_first.direct_messages.messages.is_empty());
+        assert!(left_first.verify(&left_first, &parameters).is_ok());
+    }
+
+    #[test]
+    fn test_ban_survives_removal_of_banned_member() {
+        use crate::room_state::ban::{AuthorizedUserBan, UserBan};
+        use crate::room_state::member::{
+            AuthorizedMember, AuthorizedMemberLeave, Member, MemberLeave, MembersDelta,
+        };
+        use std::time::SystemTime;
+
+        let (mut state, parameters, owner_signing_key) = create_empty_chat_room_state();
+        let owner_id = parameters.owner_id();
+        let invite = |invited_by: &SigningKey, member_key: &SigningKey| {
+            AuthorizedMember::new(
+                Member {
+                    owner_member_id: owner_id,
+                    invited_by: MemberId::new(&invited_by.verifying_key()),
+                    member_vk: member_key.verifying_key(),
+                },
+                invited_by,
+            )
+        };
+
+        // owner -> alice -> bob -> carol
+        let alice_key = SigningKey::generate(&mut rand::thread_rng());
+        let bob_key = SigningKey::generate(&mut rand::thread_rng());
+        let carol_key = SigningKey::generate(&mut rand::thread_rng());
+        let alice_id = MemberId::new(&alice_key.verifying_key());
+        let bob_id = MemberId::new(&bob_key.verifying_key());
+        let carol_id = MemberId::new(&carol_key.verifying_key());
+        state.members.members = vec![
+            invite(&owner_signing_key, &alice_key),
+            invite(&alice_key, &bob_key),
+            invite(&bob_key, &carol_key),
+        ];
+        assert!(state.verify(&state, &parameters).is_ok());
+
+        let empty_delta = ChatRoomStateV1Delta {
+            configuration: None,
+            bans: None,
+            members: None,
+            member_info: None,
+            room_secrets: None,
+            recent_messages: None,
+            direct_messages: None,
+            upgrade: None,
+        };
+        let apply = |state: &ChatRoomStateV1, delta: ChatRoomStateV1Delta| {
+            let mut new_state = state.clone();
+            new_state.apply_delta(state, &parameters, &delta).unwrap();
+            new_state.verify(&new_state, &parameters).unwrap();
+            new_state
+        };
+
+        // Alice bans bob, which also removes carol
+        let ban = AuthorizedUserBan::new_with_invite_chain(
+            UserBan {
+                owner_member_id: owner_id,
+                banned_at: SystemTime::now(),
+                banned_user: bob_id,
+            },
+            alice_id,
+            &alice_key,
+            state.members.invite_path(bob_id, &parameters).unwrap(),
+        );
+
+        // Bob bans carol concurrently. Whichever order the two bans arrive in, bob's is
+        // dropped because he's banned himself.
+        let bob_ban = AuthorizedUserBan::new_with_invite_chain(
+            UserBan {
+                owner_member_id: owner_id,
+                banned_at: SystemTime::now(),
+                banned_user: carol_id,
+            },
+            bob_id,
+            &bob_key,
+            state.members.invite_path(carol_id, &parameters).unwrap(),
+        );
+        for bans in [
+            vec![ban.clone(), bob_ban.clone()],
+            vec![bob_ban.clone(), ban.clone()],
+        ] {
+            let mut merged = state.clone();
+            for ban in bans {
+                merged = apply(
+                    &merged,
+                    ChatRoomStateV1Delta {
+                        bans: Some(vec![ban]),
+                        ..empty_delta.clone()
+                    },
+                );
+            }
+            assert_eq!(merged.bans.0, vec![ban.clone()]);
+        }
+        let mut invalid = state.clone();
+        invalid.bans.0 = vec![ban.clone(), bob_ban.clone()];
+        assert!(invalid.verify(&invalid, &parameters).is_err());
+
+        // Had alice left first, her ban would be rejected
+        let leave = AuthorizedMemberLeave::new(
+            MemberLeave {
+                owner_member_id: owner_id,
+                member: state.members.members[0].clone(),
+                left_at: SystemTime::now(),
+            },
+            &alice_key,
+        );
+        let mut departed_state = state.clone();
+        departed_state.members.members.remove(0);
+        departed_state.members.departed.push(leave);
+        let report = departed_state
+            .bans
+            .apply_delta(&departed_state.clone(), &parameters, &vec![ban.clone()])
+            .unwrap();
+        assert!(report.rejected[0].1.contains("has left the room"));
+
+        let state = apply(
+            &state,
+            ChatRoomStateV1Delta {
+                bans: Some(vec![ban.clone()]),
+                ..empty_delta.clone()
+            },
+        );
+        let member_ids = |state: &ChatRoomStateV1| -> Vec<MemberId> {
+            state.members.members.iter().map(|m| m.member.id()).collect()
+        };
+        assert_eq!(member_ids(&state), vec![alice_id]);
+        assert_eq!(state.bans.0, vec![ban.clone()]);
+
+        // The owner re-invites both, the ban keeps bob out but carol wasn't banned
+        let state = apply(
+            &state,
+            ChatRoomStateV1Delta {
+                members: Some(MembersDelta::new(
+                    vec![
+                        invite(&owner_signing_key, &bob_key),
+                        invite(&owner_signing_key, &carol_key),
+                    ],
+                    vec![],
+                )),
+                ..empty_delta.clone()
+            },
+        );
+        assert_eq!(member_ids(&state), vec![alice_id, carol_id]);
+        assert_eq!(state.bans.0, vec![ban.clone()]);
+
+        // Bob's evidence over carol predates his ban, but a banned member can't ban anyone,
+        // even someone who rejoined through a different inviter
+        let report = state
+            .bans
+            .clone()
+            .apply_delta(&state, &parameters, &vec![bob_ban])
+            .unwrap();
+        assert!(report.rejected[0].1.contains("has been banned"));
+    }
+
+    #[test]
+    fn test_delta_is_idempotent_and_partially_applied() {
+        use crate::room_state::member::{AuthorizedMember, Member, MembersDelta};
+        use crate::room_state::message::{AuthorizedMessageV1, MessageV1};
+        use std::time::SystemTime;
+
+        let (state, parameters, owner_signing_key) = create_empty_chat_room_state();
+        let owner_id = parameters.owner_id();
+
+        let member_key = SigningKey::generate(&mut rand::thread_rng());
+        let member = AuthorizedMember::new(
+            Member {
+                owner_member_id: owner_id,
+                invited_by: owner_id,
+                member_vk: member_key.verifying_key(),
+            },
+            &owner_signing_key,
+        );
+        let message = |content: &str, signing_key: &SigningKey| {
+            AuthorizedMessageV1::new(
+                MessageV1 {
+                    room_owner: owner_id,
+                    author: owner_id,
+                    time: SystemTime::now(),
+                    content: content.to_string(),
+                    encrypted_content: None,
+                },
+                signing_key,
+            )
+        };
+        let valid_message = message("Hello", &owner_signing_key);
+        let forged_message = message("Forged", &member_key);
+        let delta = ChatRoomStateV1Delta {
+            configuration: None,
+            bans: None,
+            members: Some(MembersDelta::new(vec![member.clone()], vec![])),
+            member_info: None,
+            room_secrets: None,
+            recent_messages: Some(vec![forged_message.clone(), valid_message.clone()]),
+            direct_messages: None,
+            upgrade: None,
+        };
+
+        // The forged message is dropped without affecting the rest of the delta
+        let mut new_state = state.clone();
+        let report = new_state.apply_delta(&state, &parameters, &delta).unwrap();
+        assert_eq!(
+            report.applied,
+            vec![
+                format!("members: member {:?}", member.member.id()),
+                format!("recent_messages: message {}", valid_message.id()),
+            ]
+        );
+        assert_eq!(report.rejected.len(), 1);
+        assert_eq!(
+            report.rejected[0].0,
+            format!("recent_messages: message {}", forged_message.id())
+        );
+        assert_eq!(new_state.members.members, vec![member]);
+        assert_eq!(new_state.recent_messages.messages, vec![valid_message]);
+        assert!(new_state.verify(&new_state, &parameters).is_ok());
+
+        // Redelivering the delta changes nothing
+        let parent_state = new_state.clone();
+        let report = new_state.apply_delta(&parent_state, &parameters, &delta).unwrap();
+        assert!(report.applied.is_empty());
+        assert_eq!(new_state, parent_state);
+    }
+
+    #[test]
+    fn test_lowered_limits_trim_existing_state() {
+        use crate::room_state::ban::{AuthorizedUserBan, UserBan};
+        use crate::room_state::member::{AuthorizedMember, Member};
+        use crate::room_state::message::{AuthorizedMessageV1, MessageV1};
+        use std::time::{Duration, SystemTime};
+
+        let (mut state, parameters, owner_signing_key) = create_empty_chat_room_state();
+        let owner_id = parameters.owner_id();
+        let start = SystemTime::now();
+
+        for _ in 0..4 {
+            let member_key = SigningKey::generate(&mut rand::thread_rng());
+            state.members.members.push(AuthorizedMember::new(
+                Member {
+                    owner_member_id: owner_id,
+                    invited_by: owner_id,
+                    member_vk: member_key.verifying_key(),
+                },
+                &owner_signing_key,
+            ));
+        }
+        for i in 0..4 {
+            let banned_key = SigningKey::generate(&mut rand::thread_rng());
+            state.bans.0.push(AuthorizedUserBan::new(
+                UserBan {
+                    owner_member_id: owner_id,
+                    banned_at: start + Duration::from_secs(i),
+                    banned_user: MemberId::new(&banned_key.verifying_key()),
+                },
+                owner_id,
+                &owner_signing_key,
+            ));
+        }
+        for (i, content) in ["one", "two", "a message that is too long now", "four"]
+            .iter()
+            .enumerate()
+        {
+            state.recent_messages.messages.push(AuthorizedMessageV1::new(
+                MessageV1 {
+                    room_owner: owner_id,
+                    author: owner_id,
+                    time: start + Duration::from_secs(i as u64),
+                    content: content.to_string(),
+                    encrypted_content: None,
+                },
+                &owner_signing_key,
+            ));
+        }
+        assert!(state.verify(&state, &parameters).is_ok());
+
+        let mut configuration = state.configuration.configuration.clone();
+        configuration.configuration_version += 1;
+        configuration.max_members = 2;
+        configuration.max_user_bans = 2;
+        configuration.max_recent_messages = 2;
+        configuration.max_message_size = 10;
+        let delta = ChatRoomStateV1Delta {
+            configuration: Some(AuthorizedConfigurationV1::new(configuration, &owner_signing_key)),
+            bans: None,
+            members: None,
+            member_info: None,
+            room_secrets: None,
+            recent_messages: None,
+            direct_messages: None,
+            upgrade: None,
+        };
+
+        let mut new_state = state.clone();
+        let report = new_state.apply_delta(&state, &parameters, &delta).unwrap();
+        assert!(report.is_fully_applied());
+        assert!(new_state.verify(&new_state, &parameters).is_ok());
+        assert_eq!(new_state.members.members.len(), 2);
+        assert_eq!(new_state.bans.0, state.bans.0[2..].to_vec());
+        let contents: Vec<&str> = new_state
+            .recent_messages
+            .messages
+            .iter()
+            .map(|m| m.message.content.as_str())
+            .collect();
+        assert_eq!(contents, vec!["two", "four"]);
+
+        // A peer holding the same items in a different order ends up with the same items
+        let mut shuffled_state = state.clone();
+        shuffled_state.members.members.reverse();
+        shuffled_state.bans.0.reverse();
+        shuffled_state.recent_messages.messages.reverse();
+        shuffled_state
+            .apply_delta(&state, &parameters, &delta)
+            .unwrap();
+        shuffled_state.members.members.reverse();
+        shuffled_state.bans.0.reverse();
+        assert_eq!(shuffled_state, new_state);
+    }
+
+    #[test]
+    fn test_ownership_transfer() {
+        use crate::room_state::ban::{AuthorizedUserBan, UserBan};
+        use crate::room_state::member::{AuthorizedMember, Member};
+        use crate::room_state::member_info::{AuthorizedMemberInfo, MemberInfo};
+        use crate::room_state::message::{AuthorizedMessageV1, MessageV1};
+        use std::time::SystemTime;
+
+        let (mut state, parameters, owner_signing_key) = create_empty_chat_room_state();
+        let owner_id = parameters.owner_id();
+        let new_key = || SigningKey::generate(&mut rand::thread_rng());
+        let (alice_key, bob_key, carol_key) = (new_key(), new_key(), new_key());
+        let id = |key: &SigningKey| MemberId::new(&key.verifying_key());
+        let invite = |inviter: &SigningKey, member: &SigningKey| {
+            AuthorizedMember::new(
+                Member {
+                    owner_member_id: owner_id,
+                    invited_by: id(inviter),
+                    member_vk: member.verifying_key(),
+                },
+                inviter,
+            )
+        };
+        let info = |key: &SigningKey, nickname: &str| {
+            AuthorizedMemberInfo::new_with_member_key(
+                MemberInfo {
+                    member_id: id(key),
+                    version: 1,
+                    preferred_nickname: nickname.to_string(),
+                    avatar: None,
+                    bio: None,
+                    status: None,
+                    encryption_key: None,
+                },
+                key,
+            )
+        };
+
+        // owner -> alice -> bob, carol is banned
+        state.members.members = vec![
+            invite(&owner_signing_key, &alice_key),
+            invite(&alice_key, &bob_key),
+        ];
+        state.member_info.member_info = vec![
+            info(&owner_signing_key, "Owner"),
+            info(&alice_key, "Alice"),
+            info(&bob_key, "Bob"),
+        ];
+        state.bans.0.push(AuthorizedUserBan::new(
+            UserBan {
+                owner_member_id: owner_id,
+                banned_at: SystemTime::now(),
+                banned_user: id(&carol_key),
+            },
+            owner_id,
+            &owner_signing_key,
+        ));
+        state.recent_messages.messages.push(AuthorizedMessageV1::new(
+            MessageV1 {
+                room_owner: owner_id,
+                author: owner_id,
+                time: SystemTime::now(),
+                content: "Moving soon".to_string(),
+                encrypted_content: None,
+            },
+            &owner_signing_key,
+        ));
+        assert!(state.verify(&state, &parameters).is_ok());
+
+        let code_hash = blake3::hash(b"room contract v2");
+        let transfer_to = |new_owner: &SigningKey| {
+            state
+                .prepare_ownership_transfer(
+                    &parameters,
+                    new_owner.verifying_key(),
+                    &code_hash,
+                    SystemTime::now(),
+                    &[&owner_signing_key],
+                )
+                .unwrap()
+        };
+        let member_ids = |state: &ChatRoomStateV1| -> Vec<MemberId> {
+            state.members.members.iter().map(|m| m.member.id()).collect()
+        };
+        let nicknames = |state: &ChatRoomStateV1| -> Vec<(MemberId, String)> {
+            state
+                .member_info
+                .member_info
+                .iter()
+                .map(|i| (i.member_info.member_id, i.member_info.preferred_nickname.clone()))
+                .collect()
+        };
+
+        // The owner rotates to a new key, which takes over the owner's profile
+        let rotated_key = new_key();
+        let upgrade = transfer_to(&rotated_key);
+        let (successor, successor_parameters) = state
+            .successor_for_new_owner(&parameters, &upgrade, &rotated_key, &code_hash)
+            .unwrap();
+        assert_eq!(successor_parameters.owner, rotated_key.verifying_key());
+        assert_eq!(
+            upgrade.upgrade.new_chatroom_address,
+            successor_parameters.contract_address(&code_hash)
+        );
+        assert!(successor.verify(&successor, &successor_parameters).is_ok());
+        assert_eq!(member_ids(&successor), vec![id(&alice_key), id(&bob_key)]);
+        assert!(successor
+            .members
+            .members
+            .iter()
+            .all(|m| m.member.invited_by == id(&rotated_key)));
+        assert_eq!(
+            nicknames(&successor),
+            vec![
+                (id(&rotated_key), "Owner".to_string()),
+                (id(&alice_key), "Alice".to_string()),
+                (id(&bob_key), "Bob".to_string()),
+            ]
+        );
+        assert_eq!(successor.bans.0.len(), 1);
+        assert_eq!(successor.bans.0[0].ban.banned_user, id(&carol_key));
+        assert!(successor.recent_messages.messages.is_empty());
+
+        // The old room points to its successor
+        let mut old_room = state.clone();
+        let report = old_room
+            .apply_delta(
+                &state,
+                &parameters,
+                &ChatRoomStateV1Delta {
+                    configuration: None,
+                    bans: None,
+                    members: None,
+                    member_info: None,
+                    room_secrets: None,
+                    recent_messages: None,
+                    direct_messages: None,
+                    upgrade: Some(upgrade.clone()),
+                },
+            )
+            .unwrap();
+        assert!(report.is_fully_applied());
+        assert_eq!(old_room.upgrade.0, Some(upgrade.clone()));
+
+        // Handing the room to a member makes them the owner, with their own profile
+        let upgrade = transfer_to(&alice_key);
+        let (successor, successor_parameters) = state
+            .successor_for_new_owner(&parameters, &upgrade, &alice_key, &code_hash)
+            .unwrap();
+        assert!(successor.verify(&successor, &successor_parameters).is_ok());
+        assert_eq!(member_ids(&successor), vec![id(&bob_key)]);
+        assert_eq!(
+            nicknames(&successor),
+            vec![
+                (id(&alice_key), "Alice".to_string()),
+                (id(&bob_key), "Bob".to_string()),
+            ]
+        );
+
+        // Only the key the upgrade names can take over the room
+        assert!(state
+            .successor_for_new_owner(&parameters, &upgrade, &bob_key, &code_hash)
+            .is_err());
+        let forged = AuthorizedUpgradeV1::new(upgrade.upgrade.clone(), &bob_key);
+        assert!(state
+            .successor_for_new_owner(&parameters, &forged, &alice_key, &code_hash)
+            .is_err());
+
+        // The upgrade must point at the successor for the given contract code
+        assert!(state
+            .successor_for_new_owner(&parameters, &upgrade, &alice_key, &blake3::hash(b"other"))
+            .is_err());
+        let misdirected = AuthorizedUpgradeV1::new(
+            UpgradeV1 {
+                new_chatroom_address: blake3::hash(alice_key.verifying_key().as_bytes()),
+                ..upgrade.upgrade.clone()
+            },
+            &owner_signing_key,
+        );
+        assert!(state
+            .successor_for_new_owner(&parameters, &misdirected, &alice_key, &code_hash)
+            .is_err());
+
+        // The recovery keys carry over, so they're part of the successor's address
+        let recovery_key = new_key();
+        let parameters = ChatRoomParametersV1 {
+            recovery: Some(KeyPolicy::single(recovery_key.verifying_key())),
+            ..parameters
+        };
+        let upgrade = state
+            .prepare_ownership_transfer(
+                &parameters,
+                alice_key.verifying_key(),
+                &code_hash,
+                SystemTime::now(),
+                &[&owner_signing_key],
+            )
+            .unwrap();
+        let (_, successor_parameters) = state
+            .successor_for_new_owner(&parameters, &upgrade, &alice_key, &code_hash)
+            .unwrap();
+        assert_eq!(successor_parameters.recovery, parameters.recovery);
+        assert_eq!(
+            upgrade.upgrade.new_chatroom_address,
+            successor_parameters.contract_address(&code_hash)
+        );
+        assert_ne!(
+            upgrade.upgrade.new_chatroom_address,
+            ChatRoomParametersV1 {
+                owner: alice_key.verifying_key(),
+                admins: None,
+                recovery: None,
+            }
+            .contract_address(&code_hash)
+        );
+    }
+
+    #[test]
+    fn test_owner_can_set_nickname_and_post_messages() {
+        use crate::room_state::member_info::{AuthorizedMemberInfo, MemberInfo};
+        use crate::room_state::message::{AuthorizedMessageV1, MessageV1};
+        use std::time::SystemTime;
+
+        let (state, parameters, owner_signing_key) = create_empty_chat_room_state();
+        let owner_id = parameters.owner_id();
+
+        let mut modified_state = state.clone();
+        modified_state
+            .member_info
+            .member_info
+            .push(AuthorizedMemberInfo::new(
+                MemberInfo {
+                    member_id: owner_id,
+                    version: 1,
+                    preferred_nickname: "Owner".to_string(),
+                    avatar: None,
+                    bio: None,
+                    status: None,
+                    encryption_key: None,
+                },
+                &owner_signing_key,
+            ));
+        modified_state
+            .recent_messages
+            .messages
+            .push(AuthorizedMessageV1::new(
+                MessageV1 {
+                    room_owner: owner_id,
+                    author: owner_id,
+                    time: SystemTime::now(),
+                    content: "Welcome".to_string(),
+                    encrypted_content: None,
+                },
+                &owner_signing_key,
+            ));
+
+        // The owner is a participant without being in the members list
+        assert!(modified_state.members.members.is_empty());
+        test_apply_delta(state, modified_state, &parameters);
+    }
+
+    #[test]
+    fn test_cross_room_replay_rejected() {
+        use crate::room_state::ban::{AuthorizedUserBan, UserBan};
+        use crate::room_state::member::{
+            AuthorizedMember, AuthorizedMemberLeave, Member, MemberLeave, MembersDelta,
+        };
+        use crate::room_state::message::{AuthorizedMessageV1, MessageV1};
+        use crate::room_state::upgrade::{AuthorizedUpgradeV1, UpgradeV1};
+        use std::time::SystemTime;
+
+        let (room_a, parameters_a, owner_a_key) = create_empty_chat_room_state();
+        let (room_b, parameters_b, owner_b_key) = create_empty_chat_room_state();
+        let (owner_a_id, owner_b_id) = (parameters_a.owner_id(), parameters_b.owner_id());
+
+        // The same member is in both rooms and has invited someone into each
+        let member_key = SigningKey::generate(&mut rand::thread_rng());
+        let invitee_key = SigningKey::generate(&mut rand::thread_rng());
+        let member_in = |owner_id, owner_key: &SigningKey| {
+            AuthorizedMember::new(
+                Member {
+                    owner_member_id: owner_id,
+                    invited_by: owner_id,
+                    member_vk: member_key.verifying_key(),
+                },
+                owner_key,
+            )
+        };
+        let invitee_in = |owner_id, inviter: &AuthorizedMember| {
+            AuthorizedMember::new(
+                Member {
+                    owner_member_id: owner_id,
+                    invited_by: inviter.member.id(),
+                    member_vk: invitee_key.verifying_key(),
+                },
+                &member_key,
+            )
+        };
+        let member_a = member_in(owner_a_id, &owner_a_key);
+        let member_b = member_in(owner_b_id, &owner_b_key);
+        let mut room_b = room_b;
+        room_b.members.members.push(member_b.clone());
+        room_b
+            .members
+            .members
+            .push(invitee_in(owner_b_id, &member_b));
+        assert!(room_b.verify(&room_b, &parameters_b).is_ok());
+
+        let empty_delta = ChatRoomStateV1Delta {
+            configuration: None,
+            bans: None,
+            members: None,
+            member_info: None,
+            room_secrets: None,
+            recent_messages: None,
+            direct_messages: None,
+            upgrade: None,
+        };
+        let replay = |delta: ChatRoomStateV1Delta| {
+            let mut state = room_b.clone();
+            let report = state.apply_delta(&room_b, &parameters_b, &delta).unwrap();
+            (report, state)
+        };
+
+        // A message the member wrote in room A
+        let message = AuthorizedMessageV1::new(
+            MessageV1 {
+                room_owner: owner_a_id,
+                author: member_b.member.id(),
+                time: SystemTime::now(),
+                content: "Meant for room A".to_string(),
+                encrypted_content: None,
+            },
+            &member_key,
+        );
+        let (_, state) = replay(ChatRoomStateV1Delta {
+            recent_messages: Some(vec![message.clone()]),
+            ..empty_delta.clone()
+        });
+        assert!(state.recent_messages.messages.is_empty());
+        let mut tampered = room_b.clone();
+        tampered.recent_messages.messages.push(message);
+        assert!(tampered.verify(&tampered, &parameters_b).is_err());
+
+        // A ban the member issued in room A
+        let ban = AuthorizedUserBan::new(
+            UserBan {
+                owner_member_id: owner_a_id,
+                banned_at: SystemTime::now(),
+                banned_user: MemberId::new(&invitee_key.verifying_key()),
+            },
+            member_b.member.id(),
+            &member_key,
+        );
+        let (report, state) = replay(ChatRoomStateV1Delta {
+            bans: Some(vec![ban]),
+            ..empty_delta.clone()
+        });
+        assert!(!report.is_fully_applied(), "Ban from another room should be rejected");
+        assert_eq!(state, room_b);
+
+        // The member leaving room A
+        let leave = AuthorizedMemberLeave::new(
+            MemberLeave {
+                owner_member_id: owner_a_id,
+                member: member_a.clone(),
+                left_at: SystemTime::now(),
+            },
+            &member_key,
+        );
+        let (report, state) = replay(ChatRoomStateV1Delta {
+            members: Some(MembersDelta::new(vec![], vec![leave])),
+            ..empty_delta.clone()
+        });
+        assert!(!report.is_fully_applied(), "Leave from another room should be rejected");
+        assert_eq!(state, room_b);
+
+        // The invitee's membership of room A
+        let mut room_b_without_invitee = room_b.clone();
+        room_b_without_invitee.members.members.truncate(1);
+        let mut state = room_b_without_invitee.clone();
+        let report = state
+            .apply_delta(
+                &room_b_without_invitee,
+                &parameters_b,
+                &ChatRoomStateV1Delta {
+                    members: Some(MembersDelta::new(
+                        vec![invitee_in(owner_a_id, &member_b)],
+                        vec![],
+                    )),
+                    ..empty_delta.clone()
+                },
+            )
+            .unwrap();
+        assert!(!report.is_fully_applied(), "Membership from another room should be rejected");
+        assert_eq!(state, room_b_without_invitee);
+
+        // Configuration and upgrades signed by room B's owner but bound to room A
+        let mut configuration = room_b.configuration.configuration.clone();
+        configuration.configuration_version += 1;
+        configuration.owner_member_id = owner_a_id;
+        let mut tampered = room_b.clone();
+        tampered.configuration = AuthorizedConfigurationV1::new(configuration, &owner_b_key);
+        assert!(tampered.verify(&tampered, &parameters_b).is_err());
+
+        let upgrade = AuthorizedUpgradeV1::new(
+            UpgradeV1 {
+                owner_member_id: owner_a_id,
+                version: 1,
+                new_chatroom_address: blake3::hash(b"new room"),
+                new_owner: None,
+                cutoff: None,
+            },
+            &owner_b_key,
+        );
+        let (report, state) = replay(ChatRoomStateV1Delta {
+            upgrade: Some(upgrade),
+            ..empty_delta.clone()
+        });
+        assert!(!report.is_fully_applied(), "Upgrade bound to another room should be rejected");
+        assert_eq!(state, room_b);
+
+        // Room A itself is unaffected by any of this
+        assert!(room_a.verify(&room_a, &parameters_a).is_ok());
+    }
+
+    #[test]
+    fn test_contract_upgrade() {
+        use crate::room_state::direct_message::{AuthorizedDirectMessage, DirectMessage};
+        use crate::room_state::message::{AuthorizedMessageV1, MessageV1};
+        use std::time::{Duration, SystemTime};
+
+        let (mut state, parameters, owner_signing_key) = create_empty_chat_room_state();
+        let owner_vk = owner_signing_key.verifying_key();
+        let cutoff = SystemTime::now();
+        let message = |content: &str, time: SystemTime| {
+            AuthorizedMessageV1::new(
+                MessageV1 {
+                    room_owner: parameters.owner_id(),
+                    author: parameters.owner_id(),
+                    time,
+                    content: content.to_string(),
+                    encrypted_content: None,
+                },
+                &owner_signing_key,
+            )
+        };
+        let direct_message = |content: &str, time: SystemTime| {
+            AuthorizedDirectMessage::new(
+                DirectMessage::new(parameters.owner_id(), &owner_vk, &owner_vk, time, content)
+                    .unwrap(),
+                &owner_signing_key,
+            )
+        };
+        let before = cutoff - Duration::from_secs(60);
+        let after = cutoff + Duration::from_secs(60);
+        state
+            .recent_messages
+            .messages
+            .push(message("Before the upgrade", before));
+
+        let code_hash = blake3::hash(b"room contract v2");
+        let (successor, upgrade) = state
+            .prepare_upgrade(&parameters, &code_hash, cutoff, &[&owner_signing_key])
+            .unwrap();
+        assert_eq!(upgrade.upgrade.version, 1);
+        assert_eq!(upgrade.upgrade.cutoff, Some(cutoff));
+        assert_eq!(
+            upgrade.upgrade.new_chatroom_address,
+            parameters.contract_address(&code_hash)
+        );
+        assert_ne!(
+            upgrade.upgrade.new_chatroom_address,
+            parameters.contract_address(&blake3::hash(b"room contract v1")),
+            "The address depends on the contract code"
+        );
+        assert_eq!(successor.recent_messages, state.recent_messages);
+        assert!(successor.verify(&successor, &parameters).is_ok());
+
+        let empty_delta = ChatRoomStateV1Delta {
+            configuration: None,
+            bans: None,
+            members: None,
+            member_info: None,
+            room_secrets: None,
+            recent_messages: None,
+            direct_messages: None,
+            upgrade: None,
+        };
+        let upgrade_delta = ChatRoomStateV1Delta {
+            upgrade: Some(upgrade),
+            ..empty_delta.clone()
+        };
+        let delayed = ChatRoomStateV1Delta {
+            recent_messages: Some(vec![message("Written before the cutoff", before)]),
+            direct_messages: Some(vec![direct_message("Written before the cutoff", before)]),
+            ..empty_delta.clone()
+        };
+        let late = ChatRoomStateV1Delta {
+            recent_messages: Some(vec![message("After the upgrade", after)]),
+            direct_messages: Some(vec![direct_message("After the upgrade", after)]),
+            ..empty_delta
+        };
+
+        // Whichever order the upgrade and the messages arrive in, the old room keeps the
+        // messages written up to the cutoff and drops later ones
+        let mut upgraded_first = state.clone();
+        for delta in [&upgrade_delta, &delayed, &late] {
+            upgraded_first
+                .apply_delta(&upgraded_first.clone(), &parameters, delta)
+                .unwrap();
+        }
+        let mut upgraded_last = state.clone();
+        for delta in [&late, &delayed, &upgrade_delta] {
+            let report = upgraded_last
+                .apply_delta(&upgraded_last.clone(), &parameters, delta)
+                .unwrap();
+            assert!(report.is_fully_applied());
+        }
+        assert_eq!(upgraded_first, upgraded_last);
+        assert_eq!(upgraded_first.recent_messages.messages.len(), 2);
+        assert_eq!(upgraded_first.direct_messages.messages.len(), 1);
+        assert!(upgraded_first.verify(&upgraded_first, &parameters).is_ok());
+
+        // The old room rejects messages past the cutoff, the successor takes them
+        let report = upgraded_first
+            .apply_delta(&upgraded_first.clone(), &parameters, &late)
+            .unwrap();
+        assert!(!report.is_fully_applied());
+        let mut successor = successor;
+        let report = successor
+            .apply_delta(&successor.clone(), &parameters, &late)
+            .unwrap();
+        assert!(report.is_fully_applied());
+        assert_eq!(successor.recent_messages.messages.len(), 2);
+        assert_eq!(successor.direct_messages.messages.len(), 1);
+
+        // A state holding messages past the cutoff is invalid
+        let mut invalid = upgraded_first.clone();
+        invalid
+            .recent_messages
+            .messages
+            .push(message("After the upgrade", after));
+        assert!(invalid.verify(&invalid, &parameters).is_err());
+        let mut invalid = upgraded_first.clone();
+        invalid
+            .direct_messages
+            .messages
+            .push(direct_message("After the upgrade", after));
+        assert!(invalid.verify(&invalid, &parameters).is_err());
+
+        // Further upgrades take the next version
+        let (_, upgrade) = upgraded_first
+            .prepare_upgrade(&parameters, &code_hash, cutoff, &[&owner_signing_key])
+            .unwrap();
+        assert_eq!(upgrade.upgrade.version, 2);
+    }
+
+    #[test]
+    fn test_private_room() {
+        use crate::room_state::configuration::RoomPrivacy;
+        use crate::room_state::message::{AuthorizedMessageV1, MessageV1};
+        use crate::room_state::secret::RoomSecret;
+        use std::time::SystemTime;
+
+        let (mut state, parameters, owner_signing_key) = create_empty_chat_room_state();
+        let owner_id = parameters.owner_id();
+        let mut configuration = state.configuration.configuration.clone();
+        configuration.configuration_version += 1;
+        configuration.privacy = RoomPrivacy::Private;
+
+        // The owner makes the room private and seals its secret to themselves
+        let secret = RoomSecret::generate(1, SystemTime::now());
+        let empty_delta = ChatRoomStateV1Delta {
+            configuration: None,
+            bans: None,
+            members: None,
+            member_info: None,
+            room_secrets: None,
+            recent_messages: None,
+            direct_messages: None,
+            upgrade: None,
+        };
+        let delta = ChatRoomStateV1Delta {
+            configuration: Some(AuthorizedConfigurationV1::new(
+                configuration,
+                &owner_signing_key,
+            )),
+            room_secrets: state
+                .room_secrets
+                .share(&state, &parameters, &secret, &owner_signing_key)
+                .unwrap(),
+            ..empty_delta.clone()
+        };
+        let report = state
+            .apply_delta(&state.clone(), &parameters, &delta)
+            .unwrap();
+        assert!(report.is_fully_applied());
+
+        let message = |content: &str, encrypted: bool| {
+            AuthorizedMessageV1::new(
+                MessageV1 {
+                    room_owner: owner_id,
+                    author: owner_id,
+                    time: SystemTime::now(),
+                    content: if encrypted {
+                        String::new()
+                    } else {
+                        content.to_string()
+                    },
+                    encrypted_content: encrypted.then(|| secret.encrypt(content)),
+                },
+                &owner_signing_key,
+            )
+        };
+        let delta = ChatRoomStateV1Delta {
+            recent_messages: Some(vec![message("Secret", true), message("Leaked", false)]),
+            ..empty_delta
+        };
+        let report = state
+            .apply_delta(&state.clone(), &parameters, &delta)
+            .unwrap();
+        assert_eq!(report.applied.len(), 1);
+        assert_eq!(
+            report.rejected[0].1,
+            "Messages in a private room must be encrypted"
+        );
+        assert!(state.verify(&state, &parameters).is_ok());
+
+        // Only holders of the room secret can read the message
+        let opened = state
+            .room_secrets
+            .open(&owner_signing_key)
+            .unwrap()
+            .unwrap();
+        let stored = &state.recent_messages.messages[0].message;
+        assert_eq!(
+            opened.decrypt(stored.encrypted_content.as_ref().unwrap()),
+            Ok("Secret".to_string())
+        );
+    }
+
+    #[test]
+    fn test_room_secret_rotation() {
+        use crate::room_state::configuration::RoomPrivacy;
+        use crate::room_state::member::MembersDelta;
+        use crate::room_state::message::{AuthorizedMessageV1, MessageV1};
+        use crate::room_state::secret::RoomSecret;
+        use std::time::{Duration, SystemTime};
+
+        let (mut state, parameters, owner_signing_key) = create_empty_chat_room_state();
+        let owner_id = parameters.owner_id();
+        state.configuration = AuthorizedConfigurationV1::new(
+            Configuration {
+                privacy: RoomPrivacy::Private,
+                ..state.configuration.configuration.clone()
+            },
+            &owner_signing_key,
+        );
+
+        let new_key = || SigningKey::generate(&mut rand::thread_rng());
+        let (member_signing_key, carol_signing_key) = (new_key(), new_key());
+        let invite = |key: &SigningKey| {
+            AuthorizedMember::new(
+                Member {
+                    owner_member_id: owner_id,
+                    invited_by: owner_id,
+                    member_vk: key.verifying_key(),
+                },
+                &owner_signing_key,
+            )
+        };
+        let ban = |key: &SigningKey, banned_at: SystemTime| {
+            AuthorizedUserBan::new(
+                UserBan {
+                    owner_member_id: owner_id,
+                    banned_at,
+                    banned_user: MemberId::new(&key.verifying_key()),
+                },
+                owner_id,
+                &owner_signing_key,
+            )
+        };
+        let message = |secret: &RoomSecret, content: &str| {
+            AuthorizedMessageV1::new(
+                MessageV1 {
+                    room_owner: owner_id,
+                    author: owner_id,
+                    time: SystemTime::now(),
+                    content: String::new(),
+                    encrypted_content: Some(secret.encrypt(content)),
+                },
+                &owner_signing_key,
+            )
+        };
+        let empty_delta = ChatRoomStateV1Delta {
+            configuration: None,
+            bans: None,
+            members: None,
+            member_info: None,
+            room_secrets: None,
+            recent_messages: None,
+            direct_messages: None,
+            upgrade: None,
+        };
+        let apply = |state: &mut ChatRoomStateV1, delta: ChatRoomStateV1Delta| {
+            let report = state
+                .apply_delta(&state.clone(), &parameters, &delta)
+                .unwrap();
+            assert!(report.is_fully_applied(), "{:?}", report.rejected);
+        };
+        state.members.members.push(invite(&member_signing_key));
+
+        // The first epoch is sealed to the owner and the member and records them both
+        let epoch_start = SystemTime::now();
+        let first = RoomSecret::generate(1, epoch_start);
+        let shared = state
+            .room_secrets
+            .share(&state, &parameters, &first, &owner_signing_key)
+            .unwrap();
+        apply(
+            &mut state,
+            ChatRoomStateV1Delta {
+                room_secrets: shared,
+                recent_messages: Some(vec![message(&first, "Before the ban")]),
+                ..empty_delta.clone()
+            },
+        );
+        assert_eq!(state.room_secrets.current_epoch(), Some((1, epoch_start)));
+        assert_eq!(
+            state.room_secrets.epoch(1).unwrap().epoch.sealed_to.len(),
+            2
+        );
+        assert!(!state.room_secrets.needs_rotation(&state, &parameters));
+
+        // Banning the member ends the epoch, even if the ban is dated before it started
+        apply(
+            &mut state,
+            ChatRoomStateV1Delta {
+                bans: Some(vec![ban(
+                    &member_signing_key,
+                    epoch_start - Duration::from_secs(60),
+                )]),
+                ..empty_delta.clone()
+            },
+        );
+        assert!(state.room_secrets.needs_rotation(&state, &parameters));
+
+        // The owner starts a new epoch sealed only to those still in the room, which chains
+        // the first epoch's secret
+        let (second, rotated) = state
+            .room_secrets
+            .rotate(&state, &parameters, &owner_signing_key, SystemTime::now())
+            .unwrap();
+        assert_eq!(second.version, 2);
+        assert_eq!(rotated.secrets.len(), 1);
+        apply(
+            &mut state,
+            ChatRoomStateV1Delta {
+                room_secrets: Some(rotated),
+                recent_messages: Some(vec![message(&second, "After the ban")]),
+                ..empty_delta.clone()
+            },
+        );
+        assert!(!state.room_secrets.needs_rotation(&state, &parameters));
+        assert!(state.room_secrets.open(&member_signing_key).is_none());
+        assert!(state.verify(&state, &parameters).is_ok());
+
+        // Someone who joins later is given the current secret and reads the history through
+        // the chain, the banned member can't read the new epoch
+        apply(
+            &mut state,
+            ChatRoomStateV1Delta {
+                members: Some(MembersDelta::new(vec![invite(&carol_signing_key)], vec![])),
+                ..empty_delta.clone()
+            },
+        );
+        let shared = state
+            .room_secrets
+            .share(&state, &parameters, &second, &owner_signing_key)
+            .unwrap();
+        apply(
+            &mut state,
+            ChatRoomStateV1Delta {
+                room_secrets: shared,
+                ..empty_delta.clone()
+            },
+        );
+        let opened = state
+            .room_secrets
+            .open(&carol_signing_key)
+            .unwrap()
+            .unwrap();
+        assert_eq!(opened, second);
+        assert_eq!(
+            state.room_secrets.earlier_secrets(&opened),
+            vec![first.clone()]
+        );
+        let read = |secret: &RoomSecret| -> Vec<Result<String, String>> {
+            state
+                .recent_messages
+                .messages
+                .iter()
+                .map(|m| secret.decrypt(m.message.encrypted_content.as_ref().unwrap()))
+                .collect()
+        };
+        assert_eq!(
+            read(&first),
+            vec![
+                Ok("Before the ban".to_string()),
+                Err("Encrypted with room secret version 2, not 1".to_string())
+            ]
+        );
+        assert_eq!(read(&second)[1], Ok("After the ban".to_string()));
+
+        // Sharing recorded the newcomer, so their leaving ends the epoch too
+        assert!(!state.room_secrets.needs_rotation(&state, &parameters));
+        apply(
+            &mut state,
+            ChatRoomStateV1Delta {
+                bans: Some(vec![ban(&carol_signing_key, SystemTime::now())]),
+                ..empty_delta.clone()
+            },
+        );
+        assert!(state.room_secrets.needs_rotation(&state, &parameters));
+
+        // The first epoch's record goes once no message uses it any more
+        let mut configuration = state.configuration.configuration.clone();
+        configuration.configuration_version += 1;
+        configuration.max_recent_messages = 1;
+        apply(
+            &mut state,
+            ChatRoomStateV1Delta {
+                configuration: Some(AuthorizedConfigurationV1::new(
+                    configuration,
+                    &owner_signing_key,
+                )),
+                ..empty_delta
+            },
+        );
+        assert!(state.room_secrets.epoch(1).is_none());
+        assert!(state.room_secrets.epoch(2).is_some());
+    }
 }
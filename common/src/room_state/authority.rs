@@ -0,0 +1,333 @@
+use crate::room_state::ChatRoomParametersV1;
+use crate::util::{sign_struct, truncated_base64, verify_struct, Signable};
+use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+
+/// A set of keys of which at least `threshold` must sign
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct KeyPolicy {
+    pub keys: Vec<VerifyingKey>,
+    pub threshold: usize,
+}
+
+impl KeyPolicy {
+    /// A single key that can sign on its own
+    pub fn single(key: VerifyingKey) -> Self {
+        Self {
+            keys: vec![key],
+            threshold: 1,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.threshold == 0 || self.threshold > self.keys.len() {
+            return Err(format!(
+                "Key policy threshold must be between 1 and {}, got {}",
+                self.keys.len(),
+                self.threshold
+            ));
+        }
+        if self.keys.iter().collect::<HashSet<_>>().len() != self.keys.len() {
+            return Err("Key policy lists a key more than once".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Co-owner keys that administer the room alongside the owner, with the number of them
+/// that must sign each kind of room-wide item
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct AdminPolicy {
+    pub keys: Vec<VerifyingKey>,
+    pub configuration_threshold: usize,
+    pub upgrade_threshold: usize,
+}
+
+impl AdminPolicy {
+    pub fn threshold(&self, action: RoomAction) -> usize {
+        match action {
+            RoomAction::Configure => self.configuration_threshold,
+            RoomAction::Upgrade => self.upgrade_threshold,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for action in [RoomAction::Configure, RoomAction::Upgrade] {
+            KeyPolicy {
+                keys: self.keys.clone(),
+                threshold: self.threshold(action),
+            }
+            .validate()
+            .map_err(|e| format!("Invalid admin policy for {:?}: {}", action, e))?;
+        }
+        Ok(())
+    }
+}
+
+/// The kind of room-wide item being signed, which decides how many admins must sign it
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RoomAction {
+    Configure,
+    Upgrade,
+}
+
+/// Who authorized a room-wide item such as the configuration or an upgrade.
+///
+/// Items authorized by the recovery keys take precedence over those signed by the owner,
+/// whatever their versions: the recovery keys can replace any owner-signed item and the
+/// owner can't replace an item the recovery keys signed. This lets the recovery keys take
+/// the room back from a lost or compromised owner key.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Authority {
+    /// The owner, or enough of the room's admins
+    Owner,
+    Recovery,
+}
+
+/// Signatures over a room-wide item, by the owner, enough of the room's admins or enough of
+/// its recovery keys. Co-signers add their signatures to the same item one at a time.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct RoomSignatures(pub Vec<(VerifyingKey, Signature)>);
+
+impl RoomSignatures {
+    pub fn sign<T: Signable>(item: &T, signing_keys: &[&SigningKey]) -> Self {
+        Self(
+            signing_keys
+                .iter()
+                .map(|key| (key.verifying_key(), sign_struct(item, key)))
+                .collect(),
+        )
+    }
+
+    /// Adds a signature by `signing_key`, replacing any earlier one by the same key
+    pub fn add<T: Signable>(&mut self, item: &T, signing_key: &SigningKey) {
+        let key = signing_key.verifying_key();
+        self.0.retain(|(signer, _)| *signer != key);
+        self.0.push((key, sign_struct(item, signing_key)));
+    }
+
+    /// Checks every signature and returns the authority the signers carry in this room
+    pub fn verify<T: Signable>(
+        &self,
+        item: &T,
+        action: RoomAction,
+        parameters: &ChatRoomParametersV1,
+    ) -> Result<Authority, String> {
+        let mut signers = HashSet::new();
+        for (key, signature) in &self.0 {
+            if !signers.insert(*key) {
+                return Err("Signed more than once by the same key".to_string());
+            }
+            verify_struct(item, signature, key).map_err(|e| format!("Invalid signature: {}", e))?;
+        }
+
+        let admin_keys = admin_keys(parameters);
+        let recovery_keys = recovery_keys(parameters);
+        if signers.iter().any(|key| {
+            *key != parameters.owner && !admin_keys.contains(key) && !recovery_keys.contains(key)
+        }) {
+            return Err("Signed by a key with no authority over the room".to_string());
+        }
+
+        match self.claimed_authority(parameters) {
+            Authority::Owner => {
+                let admin_signers = signers
+                    .iter()
+                    .filter(|key| admin_keys.contains(key))
+                    .count();
+                let enough_admins = parameters.admins.as_ref().is_some_and(|admins| {
+                    admin_signers > 0 && admin_signers >= admins.threshold(action)
+                });
+                if signers.contains(&parameters.owner) || enough_admins {
+                    return Ok(Authority::Owner);
+                }
+            }
+            Authority::Recovery => {
+                let enough_recovery_keys = parameters.recovery.as_ref().is_some_and(|recovery| {
+                    !signers.is_empty() && signers.len() >= recovery.threshold
+                });
+                if enough_recovery_keys {
+                    return Ok(Authority::Recovery);
+                }
+            }
+        }
+        Err(
+            "Not signed by the room owner, enough of its admins or enough of its recovery keys"
+                .to_string(),
+        )
+    }
+
+    /// The authority the signers would carry if their signatures are valid: items signed
+    /// by the owner or any admin are owner items, others are recovery items. Used to order
+    /// items whose signatures have already been verified.
+    pub fn claimed_authority(&self, parameters: &ChatRoomParametersV1) -> Authority {
+        let admin_keys = admin_keys(parameters);
+        if self
+            .0
+            .iter()
+            .any(|(key, _)| *key == parameters.owner || admin_keys.contains(key))
+        {
+            Authority::Owner
+        } else {
+            Authority::Recovery
+        }
+    }
+
+    /// Identifies the signed item, signatures being unique to it
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.0
+            .iter()
+            .flat_map(|(_, signature)| signature.to_bytes())
+            .collect()
+    }
+}
+
+fn admin_keys(parameters: &ChatRoomParametersV1) -> &[VerifyingKey] {
+    parameters
+        .admins
+        .as_ref()
+        .map_or(&[], |admins| &admins.keys)
+}
+
+fn recovery_keys(parameters: &ChatRoomParametersV1) -> &[VerifyingKey] {
+    parameters
+        .recovery
+        .as_ref()
+        .map_or(&[], |recovery| &recovery.keys)
+}
+
+impl fmt::Debug for RoomSignatures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut list = f.debug_list();
+        for (_, signature) in &self.0 {
+            list.entry(&format_args!("{}", truncated_base64(signature.to_bytes())));
+        }
+        list.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::room_state::configuration::Configuration;
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn test_room_signatures_authority() {
+        let owner_key = SigningKey::generate(&mut OsRng);
+        let recovery_keys: Vec<SigningKey> =
+            (0..3).map(|_| SigningKey::generate(&mut OsRng)).collect();
+        let parameters = ChatRoomParametersV1 {
+            owner: owner_key.verifying_key(),
+            admins: None,
+            recovery: Some(KeyPolicy {
+                keys: recovery_keys.iter().map(|k| k.verifying_key()).collect(),
+                threshold: 2,
+            }),
+        };
+        let item = Configuration::default();
+        let verify = |keys: &[&SigningKey]| {
+            RoomSignatures::sign(&item, keys).verify(&item, RoomAction::Configure, &parameters)
+        };
+
+        assert_eq!(verify(&[&owner_key]), Ok(Authority::Owner));
+        assert_eq!(
+            verify(&[&recovery_keys[0], &recovery_keys[2]]),
+            Ok(Authority::Recovery)
+        );
+        assert!(verify(&[&recovery_keys[0]]).is_err(), "Below the threshold");
+        assert!(
+            verify(&[&recovery_keys[0], &recovery_keys[0]]).is_err(),
+            "The same key can't count twice"
+        );
+        let stranger = SigningKey::generate(&mut OsRng);
+        assert!(verify(&[&recovery_keys[0], &stranger]).is_err());
+        assert!(verify(&[]).is_err());
+
+        // Signatures over another item don't count
+        let signatures = RoomSignatures::sign(&item, &[&owner_key]);
+        let other_item = Configuration {
+            name: "Other".to_string(),
+            ..Configuration::default()
+        };
+        assert!(signatures
+            .verify(&other_item, RoomAction::Configure, &parameters)
+            .is_err());
+
+        // Without a recovery policy only the owner has authority
+        let parameters = ChatRoomParametersV1 {
+            owner: owner_key.verifying_key(),
+            admins: None,
+            recovery: None,
+        };
+        assert!(
+            RoomSignatures::sign(&item, &[&recovery_keys[0], &recovery_keys[1]])
+                .verify(&item, RoomAction::Configure, &parameters)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_admin_thresholds() {
+        let owner_key = SigningKey::generate(&mut OsRng);
+        let admin_keys: Vec<SigningKey> =
+            (0..3).map(|_| SigningKey::generate(&mut OsRng)).collect();
+        let parameters = ChatRoomParametersV1 {
+            owner: owner_key.verifying_key(),
+            admins: Some(AdminPolicy {
+                keys: admin_keys.iter().map(|k| k.verifying_key()).collect(),
+                configuration_threshold: 1,
+                upgrade_threshold: 2,
+            }),
+            recovery: None,
+        };
+        assert!(parameters.validate().is_ok());
+        let item = Configuration::default();
+        let verify = |keys: &[&SigningKey], action| {
+            RoomSignatures::sign(&item, keys).verify(&item, action, &parameters)
+        };
+
+        // One admin can configure the room but an upgrade needs two
+        assert_eq!(
+            verify(&[&admin_keys[1]], RoomAction::Configure),
+            Ok(Authority::Owner)
+        );
+        assert!(verify(&[&admin_keys[1]], RoomAction::Upgrade).is_err());
+        assert_eq!(
+            verify(&[&admin_keys[0], &admin_keys[2]], RoomAction::Upgrade),
+            Ok(Authority::Owner)
+        );
+        assert_eq!(
+            verify(&[&owner_key], RoomAction::Upgrade),
+            Ok(Authority::Owner)
+        );
+
+        // Co-signatures are gathered on the same item
+        let mut signatures = RoomSignatures::sign(&item, &[&admin_keys[0]]);
+        signatures.add(&item, &admin_keys[0]);
+        assert_eq!(
+            signatures.0.len(),
+            1,
+            "Signing again replaces the signature"
+        );
+        signatures.add(&item, &admin_keys[1]);
+        assert_eq!(
+            signatures.verify(&item, RoomAction::Upgrade, &parameters),
+            Ok(Authority::Owner)
+        );
+
+        let mut invalid = parameters.clone();
+        invalid.admins.as_mut().unwrap().upgrade_threshold = 4;
+        assert!(invalid.validate().is_err());
+        let mut invalid = parameters.clone();
+        invalid
+            .admins
+            .as_mut()
+            .unwrap()
+            .keys
+            .push(owner_key.verifying_key());
+        assert!(invalid.validate().is_err(), "The owner can't be an admin");
+    }
+}
@@ -1,12 +1,13 @@
-use crate::room_state::member::MemberId;
+use crate::room_state::configuration::Configuration;
+use crate::room_state::member::{AuthorizedMember, MemberId};
 use crate::room_state::ChatRoomParametersV1;
-use crate::util::{sign_struct, verify_struct};
+use crate::util::{sign_struct, verify_struct, Signable, SignatureBatch};
 use crate::ChatRoomStateV1;
 use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
 use freenet_scaffold::util::{fast_hash, FastHash};
-use freenet_scaffold::ComposableState;
+use freenet_scaffold::{ComposableState, DeltaReport};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
 use std::time::SystemTime;
 
@@ -14,82 +15,75 @@ use std::time::SystemTime;
 pub struct BansV1(pub Vec<AuthorizedUserBan>);
 
 impl BansV1 {
+    /// Drops the oldest bans beyond `max_user_bans`, ties broken by id so that every peer
+    /// keeps the same bans after the owner has lowered the limit
+    pub fn enforce_limits(&mut self, configuration: &Configuration) {
+        if self.0.len() <= configuration.max_user_bans {
+            return;
+        }
+        let mut by_age: Vec<(SystemTime, BanId)> =
+            self.0.iter().map(|ban| (ban.ban.banned_at, ban.id())).collect();
+        by_age.sort();
+        let dropped: HashSet<BanId> = by_age[..self.0.len() - configuration.max_user_bans]
+            .iter()
+            .map(|(_, id)| id.clone())
+            .collect();
+        self.0.retain(|ban| !dropped.contains(&ban.id()));
+    }
+
+    /// The bans issued by members who are banned themselves. Bans are taken from the banner
+    /// nearest the owner down, ties broken by id, and a ban only counts while its banner
+    /// isn't banned by one that counts. Whatever a ban is dated, a banned member can't ban
+    /// anyone, and every peer agrees on which bans count.
+    fn bans_by_banned_members(&self, parameters: &ChatRoomParametersV1) -> HashSet<BanId> {
+        let mut ordered: Vec<&AuthorizedUserBan> = self.0.iter().collect();
+        ordered.sort_by_cached_key(|ban| (ban.banner_depth(parameters), ban.id()));
+        let mut banned_ids = HashSet::new();
+        let mut dropped = HashSet::new();
+        for ban in ordered {
+            if banned_ids.contains(&ban.banned_by) {
+                dropped.insert(ban.id());
+            } else {
+                banned_ids.insert(ban.ban.banned_user);
+            }
+        }
+        dropped
+    }
+
+    /// Drops the bans issued by members who have since been banned themselves, see
+    /// `bans_by_banned_members`
+    pub fn remove_bans_by_banned_members(&mut self, parameters: &ChatRoomParametersV1) {
+        let dropped = self.bans_by_banned_members(parameters);
+        if !dropped.is_empty() {
+            self.0.retain(|ban| !dropped.contains(&ban.id()));
+        }
+    }
+
     fn get_invalid_bans(
         &self,
         parent_state: &ChatRoomStateV1,
         parameters: &ChatRoomParametersV1,
     ) -> HashMap<BanId, String> {
-        let member_map = parent_state.members.members_by_member_id();
         let mut invalid_bans = HashMap::new();
 
+        // Bans are checked against the evidence they carry rather than the current member
+        // list, so they stay valid after the banned member has been removed
         for ban in &self.0 {
-            let banning_member = match member_map.get(&ban.banned_by) {
-                Some(member) => member,
-                None => {
-                    invalid_bans.insert(
-                        ban.id(),
-                        "Banning member not found in member list".to_string(),
-                    );
-                    continue;
-                }
-            };
-
-            let banned_member = match member_map.get(&ban.ban.banned_user) {
-                Some(member) => member,
-                None => {
-                    invalid_bans.insert(
-                        ban.id(),
-                        "Banned member not found in member list".to_string(),
-                    );
-                    continue;
-                }
-            };
+            if let Err(e) = parameters.check_room_binding(ban.ban.owner_member_id) {
+                invalid_bans.insert(ban.id(), e);
+                continue;
+            }
 
             if ban.banned_by != parameters.owner_id() {
-                // No need to check invite chain if banner is owner
-                let mut current_member = banned_member;
-                let mut chain = Vec::new();
-                let mut is_valid = false;
-
-                while current_member.member.id() != parameters.owner_id() {
-                    chain.push(current_member);
-                    if current_member.member.id() == banning_member.member.id() {
-                        is_valid = true;
-                        break;
-                    }
-                    current_member = match member_map.get(&current_member.member.invited_by) {
-                        Some(m) => m,
-                        None => {
-                            invalid_bans.insert(
-                                ban.id(),
-                                format!(
-                                    "Inviting member not found for {:?}",
-                                    current_member.member.id()
-                                ),
-                            );
-                            break;
-                        }
-                    };
-                    if chain.contains(&current_member) {
-                        invalid_bans.insert(
-                            ban.id(),
-                            format!(
-                                "Self-invitation detected for member {:?}",
-                                current_member.member.id()
-                            ),
-                        );
-                        break;
-                    }
-                }
-
-                if !is_valid {
-                    invalid_bans.insert(
-                        ban.id(),
-                        "Banner is not in the invite chain of the banned member".to_string(),
-                    );
+                // The owner may ban anyone, other members only those downstream of them
+                if let Err(e) = ban.check_invite_chain(parameters) {
+                    invalid_bans.insert(ban.id(), e);
                 }
             }
         }
+        for ban_id in self.bans_by_banned_members(parameters) {
+            invalid_bans.insert(ban_id, "The banning member has been banned".to_string());
+        }
 
         let extra_bans =
             self.0.len() as isize - parent_state.configuration.configuration.max_user_bans as isize;
@@ -138,19 +132,12 @@ impl ComposableState for BansV1 {
             ));
         }
 
-        // Create a local variable to extend the lifetime of the members_by_member_id result
-        let members_by_id = parent_state.members.members_by_member_id();
-
-        // Verify signatures for all bans
+        // Verify signatures for all bans and their invite chains
+        let mut batch = SignatureBatch::new();
         for ban in &self.0 {
-            let banning_member = members_by_id
-                .get(&ban.banned_by)
-                .ok_or_else(|| "Banning member not found".to_string())?;
-            ban.verify_signature(&banning_member.member.member_vk)
-                .map_err(|e| format!("Invalid ban signature: {}", e))?;
+            ban.add_signatures_to_batch(parameters, &mut batch)?;
         }
-
-        Ok(())
+        batch.verify()
     }
 
     fn summarize(
@@ -186,28 +173,52 @@ impl ComposableState for BansV1 {
         parent_state: &Self::ParentState,
         parameters: &Self::Parameters,
         delta: &Self::Delta,
-    ) -> Result<(), String> {
-        // Check for duplicate bans
-        let existing_ban_ids: std::collections::HashSet<_> =
-            self.0.iter().map(|ban| ban.id()).collect();
-        for new_ban in delta {
-            if existing_ban_ids.contains(&new_ban.id()) {
-                return Err(format!("Duplicate ban detected: {:?}", new_ban.id()));
+    ) -> Result<DeltaReport, String> {
+        let max_user_bans = parent_state.configuration.configuration.max_user_bans;
+        let mut report = DeltaReport::default();
+        let mut ban_ids: HashSet<BanId> = self.0.iter().map(|ban| ban.id()).collect();
+        let departed_ids = parent_state.members.departed_ids();
+        let members_by_id = parent_state.members.members_by_member_id();
+        let mut banned_ids: HashSet<MemberId> =
+            self.0.iter().map(|ban| ban.ban.banned_user).collect();
+        for ban in delta {
+            if !ban_ids.insert(ban.id()) {
+                // Already held, e.g. redelivered by gossip
+                continue;
+            }
+            let item = format!("ban {:?}", ban.id());
+            if self.0.len() >= max_user_bans {
+                report.rejected(item, "Exceeded maximum number of user bans");
+                continue;
+            }
+            // Bans are checked against their evidence, but only someone still in the room
+            // may issue a new one
+            if ban.banned_by != parameters.owner_id() {
+                if departed_ids.contains(&ban.banned_by) {
+                    report.rejected(item, "The banning member has left the room");
+                    continue;
+                }
+                if banned_ids.contains(&ban.banned_by) {
+                    report.rejected(item, "The banning member has been banned");
+                    continue;
+                }
+                if !members_by_id.contains_key(&ban.banned_by) {
+                    report.rejected(item, "The banning member is not in the room");
+                    continue;
+                }
+            }
+            match ban.validate(parameters) {
+                Ok(()) => {
+                    banned_ids.insert(ban.ban.banned_user);
+                    self.0.push(ban.clone());
+                    report.applied(item);
+                }
+                Err(e) => report.rejected(item, e),
             }
         }
-
-        // Create a temporary BansV1 with the new bans
-        let mut temp_bans = self.clone();
-        temp_bans.0.extend(delta.iter().cloned());
-
-        // Verify the temporary room_state
-        if let Err(e) = temp_bans.verify(parent_state, parameters) {
-            return Err(format!("Invalid delta: {}", e));
-        }
-
-        // If verification passes, update the actual room_state
-        self.0 = temp_bans.0;
-        Ok(())
+        // A ban in this delta may be against someone whose bans are already held
+        self.remove_bans_by_banned_members(parameters);
+        Ok(report)
     }
 }
 
@@ -216,6 +227,11 @@ pub struct AuthorizedUserBan {
     pub ban: UserBan,
     pub banned_by: MemberId,
     pub signature: Signature,
+    /// For bans by anyone but the owner, the banned member followed by their inviters up to
+    /// one invited by the owner. It proves the banner's authority over the banned member at
+    /// the time of the ban, without relying on either still being in the room.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub invite_chain: Vec<AuthorizedMember>,
 }
 
 impl Eq for AuthorizedUserBan {}
@@ -239,7 +255,118 @@ impl AuthorizedUserBan {
             ban,
             banned_by,
             signature,
+            invite_chain: Vec::new(),
+        }
+    }
+
+    /// Creates a ban by a member other than the owner, `invite_chain` is the banned member's
+    /// invite chain as returned by
+    /// [`MembersV1::invite_path`](crate::room_state::member::MembersV1::invite_path)
+    pub fn new_with_invite_chain(
+        ban: UserBan,
+        banned_by: MemberId,
+        banner_signing_key: &SigningKey,
+        invite_chain: Vec<AuthorizedMember>,
+    ) -> Self {
+        Self {
+            invite_chain,
+            ..Self::new(ban, banned_by, banner_signing_key)
+        }
+    }
+
+    /// Checks that the invite chain leads from the banned member to the owner and passes
+    /// through the banner. Signatures are checked by `add_signatures_to_batch`.
+    fn check_invite_chain(&self, parameters: &ChatRoomParametersV1) -> Result<(), String> {
+        let banned_member = self.invite_chain.first().ok_or_else(|| {
+            "A ban by a member must include the banned member's invite chain".to_string()
+        })?;
+        if banned_member.member.id() != self.ban.banned_user {
+            return Err("Invite chain does not start with the banned member".to_string());
+        }
+        let mut visited = HashSet::new();
+        for (i, link) in self.invite_chain.iter().enumerate() {
+            parameters.check_room_binding(link.member.owner_member_id)?;
+            if !visited.insert(link.member.id()) {
+                return Err(format!(
+                    "Circular invite chain detected for member {:?}",
+                    link.member.id()
+                ));
+            }
+            let inviter_id = self
+                .invite_chain
+                .get(i + 1)
+                .map_or(parameters.owner_id(), |inviter| inviter.member.id());
+            if link.member.invited_by != inviter_id {
+                return Err(format!(
+                    "Invite chain is broken at member {:?}",
+                    link.member.id()
+                ));
+            }
+        }
+        if !self.invite_chain[1..]
+            .iter()
+            .any(|m| m.member.id() == self.banned_by)
+        {
+            return Err("Banner is not in the invite chain of the banned member".to_string());
+        }
+        Ok(())
+    }
+
+    /// How many invites separate the banner from the owner according to the ban's invite
+    /// chain, 0 for the owner
+    fn banner_depth(&self, parameters: &ChatRoomParametersV1) -> usize {
+        if self.banned_by == parameters.owner_id() {
+            return 0;
+        }
+        self.invite_chain
+            .iter()
+            .position(|m| m.member.id() == self.banned_by)
+            .map_or(usize::MAX, |i| self.invite_chain.len() - i)
+    }
+
+    /// The key the ban must be signed with, the owner's or the banner's from the invite chain
+    pub fn banner_vk<'a>(
+        &'a self,
+        parameters: &'a ChatRoomParametersV1,
+    ) -> Result<&'a VerifyingKey, String> {
+        if self.banned_by == parameters.owner_id() {
+            return Ok(&parameters.owner);
+        }
+        self.invite_chain
+            .iter()
+            .find(|m| m.member.id() == self.banned_by)
+            .map(|m| &m.member.member_vk)
+            .ok_or_else(|| "Banning member not found in the invite chain".to_string())
+    }
+
+    /// Checks a single ban, as `BansV1::verify` does for all of them
+    pub fn validate(&self, parameters: &ChatRoomParametersV1) -> Result<(), String> {
+        parameters.check_room_binding(self.ban.owner_member_id)?;
+        if self.banned_by != parameters.owner_id() {
+            self.check_invite_chain(parameters)?;
         }
+        let mut batch = SignatureBatch::new();
+        self.add_signatures_to_batch(parameters, &mut batch)?;
+        batch.verify()
+    }
+
+    /// Adds the signature on the ban, and on each link of its invite chain, to a batch
+    fn add_signatures_to_batch<'a>(
+        &'a self,
+        parameters: &ChatRoomParametersV1,
+        batch: &mut SignatureBatch<'a>,
+    ) -> Result<(), String> {
+        batch.add(&self.ban, &self.signature, self.banner_vk(parameters)?, || {
+            format!("ban {:?}", self.id())
+        });
+        for (i, link) in self.invite_chain.iter().enumerate() {
+            let inviter_vk = self
+                .invite_chain
+                .get(i + 1)
+                .map_or(&parameters.owner, |inviter| &inviter.member.member_vk);
+            link.add_signatures_to_batch(inviter_vk, batch)?;
+        }
+        Ok(())
     }
 
     pub fn verify_signature(&self, banner_verifying_key: &VerifyingKey) -> Result<(), String> {
@@ -259,7 +386,11 @@ pub struct UserBan {
     pub banned_user: MemberId,
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Hash, Debug)]
+impl Signable for UserBan {
+    const DOMAIN: &'static str = "user-ban";
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Hash, Debug, Ord, PartialOrd)]
 pub struct BanId(pub FastHash);
 
 #[cfg(test)]
@@ -276,7 +407,9 @@ mod tests {
             configuration: AuthorizedConfigurationV1::default(),
             members: MembersV1::default(),
             member_info: Default::default(),
+            room_secrets: Default::default(),
             recent_messages: Default::default(),
+            direct_messages: Default::default(),
             upgrade: Default::default(),
             bans: Default::default(),
         }
@@ -287,16 +420,22 @@ mod tests {
         let owner_key = SigningKey::generate(&mut rand::thread_rng());
         ChatRoomParametersV1 {
             owner: owner_key.verifying_key(),
+            admins: None,
+            recovery: None,
         }
     }
 
     #[test]
     fn test_bans_verify() {
         let mut state = create_test_chat_room_state();
-        let params = create_test_parameters();
 
         // Create some test members
         let owner_key = SigningKey::generate(&mut rand::thread_rng());
+        let params = ChatRoomParametersV1 {
+            owner: owner_key.verifying_key(),
+            admins: None,
+            recovery: None,
+        };
         let owner_id = MemberId::new(&owner_key.verifying_key());
         let member1_key = SigningKey::generate(&mut rand::thread_rng());
         let member1_id = MemberId::new(&member1_key.verifying_key());
@@ -389,21 +528,46 @@ mod tests {
         );
 
         // Test 4: Valid ban by non-owner member
-        let ban_by_member = AuthorizedUserBan::new(
-            UserBan {
-                owner_member_id: owner_id.clone(),
-                banned_at: SystemTime::now(),
-                banned_user: member2_id.clone(),
-            },
+        let ban = UserBan {
+            owner_member_id: owner_id.clone(),
+            banned_at: SystemTime::now(),
+            banned_user: member2_id.clone(),
+        };
+        let invite_chain = state.members.invite_path(member2_id, &params).unwrap();
+        let ban_by_member = AuthorizedUserBan::new_with_invite_chain(
+            ban.clone(),
             member1_id.clone(),
             &member1_key,
+            invite_chain.clone(),
         );
 
-        let member_bans = BansV1(vec![ban_by_member]);
+        let member_bans = BansV1(vec![ban_by_member.clone()]);
         assert!(
             member_bans.verify(&state, &params).is_ok(),
-            "Valid ban by non-owner member should pass verification"
+            "Valid ban by non-owner member should pass verification: {:?}",
+            member_bans.verify(&state, &params).err()
+        );
+
+        // Test 5: The ban stays valid once the banned member has been removed
+        state.members.members.retain(|m| m.member.id() != member2_id);
+        assert!(member_bans.verify(&state, &params).is_ok());
+
+        // Test 6: A member's ban needs the invite chain, which must pass through the banner
+        let without_chain = AuthorizedUserBan::new(ban.clone(), member1_id, &member1_key);
+        assert!(BansV1(vec![without_chain]).verify(&state, &params).is_err());
+        let by_stranger = AuthorizedUserBan::new_with_invite_chain(
+            ban.clone(),
+            invalid_id,
+            &invalid_key,
+            invite_chain.clone(),
         );
+        assert!(BansV1(vec![by_stranger]).verify(&state, &params).is_err());
+        let mut broken_chain = ban_by_member.clone();
+        broken_chain.invite_chain.remove(1);
+        assert!(BansV1(vec![broken_chain]).verify(&state, &params).is_err());
+        let mut tampered_chain = ban_by_member;
+        tampered_chain.invite_chain[0].signature = Signature::from_bytes(&[0; 64]);
+        assert!(BansV1(vec![tampered_chain]).verify(&state, &params).is_err());
     }
 
     #[test]
@@ -491,9 +655,13 @@ mod tests {
     #[test]
     fn test_bans_apply_delta() {
         let mut state = create_test_chat_room_state();
-        let params = create_test_parameters();
 
         let owner_key = SigningKey::generate(&mut rand::thread_rng());
+        let params = ChatRoomParametersV1 {
+            owner: owner_key.verifying_key(),
+            admins: None,
+            recovery: None,
+        };
         let owner_id = MemberId::new(&owner_key.verifying_key());
         let member_key = SigningKey::generate(&mut rand::thread_rng());
         let member_id = MemberId::new(&member_key.verifying_key());
@@ -558,55 +726,52 @@ mod tests {
                 &owner_key,
             ));
         }
-        let delta_exceeding_max = many_bans;
-        assert!(
-            bans.apply_delta(&state, &params, &delta_exceeding_max)
-                .is_err(),
-            "Delta exceeding max_user_bans should fail: {:?}",
-            bans.apply_delta(&state, &params, &delta_exceeding_max).ok()
-        );
+        let report = bans.apply_delta(&state, &params, &many_bans).unwrap();
         assert_eq!(
-            bans.0.len(),
-            1,
-            "Bans should not change after failed delta application"
-        );
-
-        // Test 3: Apply invalid delta (duplicate ban)
-        let invalid_delta = vec![new_ban.clone()];
-        assert!(
-            bans.apply_delta(&state, &params, &invalid_delta).is_err(),
-            "Applying duplicate ban should fail: {:?}",
-            bans.apply_delta(&state, &params, &invalid_delta).ok()
+            report.applied.len(),
+            4,
+            "Bans up to max_user_bans should be applied: {:?}",
+            report
         );
+        assert_eq!(report.rejected.len(), 1);
+        assert_eq!(report.rejected[0].1, "Exceeded maximum number of user bans");
         assert_eq!(
             bans.0.len(),
-            1,
-            "State should not change after applying duplicate ban"
+            5,
+            "State should have max number of bans after applying the delta"
         );
+        assert!(bans.verify(&state, &params).is_ok());
 
-        // Test 4: Apply delta with remaining capacity
-        let mut remaining_bans = Vec::new();
-        for _ in 0..4 {
-            remaining_bans.push(AuthorizedUserBan::new(
-                UserBan {
-                    owner_member_id: owner_id.clone(),
-                    banned_at: SystemTime::now(),
-                    banned_user: member_id.clone(),
-                },
-                owner_id.clone(),
-                &owner_key,
-            ));
-        }
-        assert!(
-            bans.apply_delta(&state, &params, &remaining_bans).is_ok(),
-            "Applying remaining bans should succeed: {:?}",
-            bans.apply_delta(&state, &params, &remaining_bans).err()
+        // Test 3: Redelivered bans are skipped without being reported
+        let redelivered = vec![new_ban.clone(), many_bans[0].clone()];
+        assert_eq!(
+            bans.apply_delta(&state, &params, &redelivered),
+            Ok(DeltaReport::default())
         );
         assert_eq!(
             bans.0.len(),
             5,
-            "State should have max number of bans after applying remaining bans"
+            "State should not change after applying duplicate bans"
+        );
+
+        // Test 4: Invalid bans are rejected while valid ones in the same delta are applied
+        let mut bans = BansV1::default();
+        let stranger_key = SigningKey::generate(&mut rand::thread_rng());
+        let invalid_ban = AuthorizedUserBan::new(
+            UserBan {
+                owner_member_id: owner_id,
+                banned_at: SystemTime::now(),
+                banned_user: member_id,
+            },
+            MemberId::new(&stranger_key.verifying_key()),
+            &stranger_key,
         );
+        let report = bans
+            .apply_delta(&state, &params, &vec![invalid_ban, new_ban.clone()])
+            .unwrap();
+        assert_eq!(report.applied, vec![format!("ban {:?}", new_ban.id())]);
+        assert_eq!(report.rejected.len(), 1);
+        assert_eq!(bans.0, vec![new_ban]);
     }
 
     #[test]
@@ -1,22 +1,44 @@
 use crate::room_state::member::MemberId;
 use crate::room_state::ChatRoomParametersV1;
-use crate::util::truncated_base64;
+use crate::room_state::authority::{Authority, RoomAction, RoomSignatures};
+use crate::util::Signable;
 use crate::ChatRoomStateV1;
-use ed25519_dalek::{Signature, SignatureError, Signer, SigningKey, Verifier, VerifyingKey};
+use ed25519_dalek::SigningKey;
 use freenet_scaffold::util::{fast_hash, FastHash};
-use freenet_scaffold::ComposableState;
+use freenet_scaffold::{ComposableState, DeltaReport};
 use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
 use std::fmt;
 
+// Upper bounds for the configurable limits, so that no configuration can make the room's
+// state grow without bound
+pub const MAX_RECENT_MESSAGES_LIMIT: usize = 10_000;
+pub const MAX_USER_BANS_LIMIT: usize = 10_000;
+pub const MAX_MESSAGE_SIZE_LIMIT: usize = 64 * 1024;
+pub const MAX_NICKNAME_SIZE_LIMIT: usize = 256;
+pub const MAX_MEMBERS_LIMIT: usize = 10_000;
+pub const MAX_INVITE_DEPTH_LIMIT: usize = 64;
+pub const MAX_AVATAR_SIZE_LIMIT: usize = 64 * 1024;
+pub const MAX_BIO_SIZE_LIMIT: usize = 10_000;
+pub const MAX_STATUS_SIZE_LIMIT: usize = 1_000;
+pub const MAX_DESCRIPTION_SIZE_LIMIT: usize = 10_000;
+pub const MAX_TOPIC_SIZE_LIMIT: usize = 1_000;
+pub const MAX_RULES_SIZE_LIMIT: usize = 50_000;
+pub const MAX_DIRECT_MESSAGES_PER_RECIPIENT_LIMIT: usize = 1_000;
+/// The room name has no configurable limit
+pub const MAX_NAME_SIZE: usize = 256;
+
 #[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct AuthorizedConfigurationV1 {
     pub configuration: Configuration,
-    pub signature: Signature,
+    pub signatures: RoomSignatures,
 }
 
 impl ComposableState for AuthorizedConfigurationV1 {
     type ParentState = ChatRoomStateV1;
-    type Summary = u32;
+    /// Configurations are ordered by who authorized them, then by version, then by lowest
+    /// signature
+    type Summary = (Authority, u32, Reverse<Vec<u8>>);
     type Delta = AuthorizedConfigurationV1;
     type Parameters = ChatRoomParametersV1;
 
@@ -25,25 +47,27 @@ impl ComposableState for AuthorizedConfigurationV1 {
         _parent_state: &Self::ParentState,
         parameters: &Self::Parameters,
     ) -> Result<(), String> {
-        self.verify_signature(&parameters.owner)
-            .map_err(|e| format!("Invalid signature: {}", e))
+        parameters.validate()?;
+        self.verify_signature(parameters)?;
+        parameters.check_room_binding(self.configuration.owner_member_id)?;
+        self.configuration.validate()
     }
 
     fn summarize(
         &self,
         _parent_state: &Self::ParentState,
-        _parameters: &Self::Parameters,
+        parameters: &Self::Parameters,
     ) -> Self::Summary {
-        self.configuration.configuration_version
+        self.precedence(parameters)
     }
 
     fn delta(
         &self,
         _parent_state: &Self::ParentState,
-        _parameters: &Self::Parameters,
-        old_version: &Self::Summary,
+        parameters: &Self::Parameters,
+        old_summary: &Self::Summary,
     ) -> Option<Self::Delta> {
-        if self.configuration.configuration_version > *old_version {
+        if self.precedence(parameters) > *old_summary {
             Some(self.clone())
         } else {
             None
@@ -55,67 +79,161 @@ impl ComposableState for AuthorizedConfigurationV1 {
         _parent_state: &Self::ParentState,
         parameters: &Self::Parameters,
         delta: &Self::Delta,
-    ) -> Result<(), String> {
-        // Verify the delta's signature
-        delta
-            .verify_signature(&parameters.owner)
-            .map_err(|e| format!("Invalid signature: {}", e))?;
-
-        // Check if the new version is greater than the current version
-        if delta.configuration.configuration_version <= self.configuration.configuration_version {
-            return Err(
-                "New configuration version must be greater than the current version".to_string(),
-            );
-        }
+    ) -> Result<DeltaReport, String> {
+        let mut report = DeltaReport::default();
 
-        // Verify that the owner_member_id hasn't changed
-        if delta.configuration.owner_member_id != self.configuration.owner_member_id {
-            return Err("Cannot change the owner_member_id".to_string());
+        // The configuration we hold, or one that ours supersedes, is being redelivered
+        if delta.precedence(parameters) < self.precedence(parameters) || delta == self {
+            return Ok(report);
         }
 
-        // Verify that the new configuration is valid
-        if delta.configuration.max_recent_messages == 0
-            || delta.configuration.max_user_bans == 0
-            || delta.configuration.max_message_size == 0
-            || delta.configuration.max_nickname_size == 0
-            || delta.configuration.max_members == 0
-        {
-            return Err("Invalid configuration values".to_string());
+        let item = format!(
+            "configuration version {}",
+            delta.configuration.configuration_version
+        );
+        match self.check_update(delta, parameters) {
+            Ok(()) => {
+                *self = delta.clone();
+                report.applied(item);
+            }
+            Err(e) => report.rejected(item, e),
         }
-
-        // If all checks pass, apply the delta
-        self.configuration = delta.configuration.clone();
-        self.signature = delta.signature.clone();
-
-        Ok(())
+        Ok(report)
     }
 }
 
 impl AuthorizedConfigurationV1 {
     pub fn new(configuration: Configuration, owner_signing_key: &SigningKey) -> Self {
-        let mut serialized_config = Vec::new();
-        ciborium::ser::into_writer(&configuration, &mut serialized_config)
-            .expect("Serialization should not fail");
-        let signature = owner_signing_key.sign(&serialized_config);
+        Self::new_with_keys(configuration, &[owner_signing_key])
+    }
+
+    /// Signs the configuration with several keys, e.g. enough of the room's recovery keys
+    pub fn new_with_keys(configuration: Configuration, signing_keys: &[&SigningKey]) -> Self {
+        let signatures = RoomSignatures::sign(&configuration, signing_keys);
 
         Self {
             configuration,
-            signature,
+            signatures,
         }
     }
 
-    pub fn verify_signature(
-        &self,
-        owner_verifying_key: &VerifyingKey,
-    ) -> Result<(), SignatureError> {
-        let mut serialized_config = Vec::new();
-        ciborium::ser::into_writer(&self.configuration, &mut serialized_config)
-            .expect("Serialization should not fail");
-        owner_verifying_key.verify(&serialized_config, &self.signature)
+    /// Checks the signatures and returns the authority they carry
+    pub fn verify_signature(&self, parameters: &ChatRoomParametersV1) -> Result<Authority, String> {
+        self.signatures
+            .verify(&self.configuration, RoomAction::Configure, parameters)
+    }
+
+    /// Adds a co-signature, for rooms whose admins must sign the configuration together
+    pub fn add_signature(&mut self, signing_key: &SigningKey) {
+        self.signatures.add(&self.configuration, signing_key);
     }
 
     pub fn id(&self) -> FastHash {
-        fast_hash(&self.signature.to_bytes())
+        fast_hash(&self.signatures.to_bytes())
+    }
+
+    /// Which of two configurations wins, assuming both have valid signatures. Different
+    /// configurations with the same version and authority are told apart by their
+    /// signatures, the lowest winning, so that all peers keep the same one whichever
+    /// arrives first.
+    fn precedence(&self, parameters: &ChatRoomParametersV1) -> (Authority, u32, Reverse<Vec<u8>>) {
+        self.precedence_with(self.signatures.claimed_authority(parameters))
+    }
+
+    fn precedence_with(&self, authority: Authority) -> (Authority, u32, Reverse<Vec<u8>>) {
+        (
+            authority,
+            self.configuration.configuration_version,
+            Reverse(self.signatures.to_bytes()),
+        )
+    }
+
+    /// Checks that a new configuration may replace this one
+    fn check_update(
+        &self,
+        delta: &AuthorizedConfigurationV1,
+        parameters: &ChatRoomParametersV1,
+    ) -> Result<(), String> {
+        // Verify the delta's signatures
+        let authority = delta.verify_signature(parameters)?;
+
+        // Check if the new version is greater than the current version, the recovery keys
+        // can replace an owner-signed configuration whatever its version
+        if delta.precedence_with(authority) <= self.precedence(parameters) {
+            return Err(
+                "New configuration version must be greater than the current version".to_string(),
+            );
+        }
+
+        // Verify that the owner_member_id hasn't changed
+        if delta.configuration.owner_member_id != self.configuration.owner_member_id {
+            return Err("Cannot change the owner_member_id".to_string());
+        }
+        parameters.check_room_binding(delta.configuration.owner_member_id)?;
+
+        delta.configuration.validate()
+    }
+}
+
+impl Configuration {
+    /// Checks that every limit is within its bounds and the room's details within their
+    /// limits. Used both for configuration updates and when verifying a full state.
+    pub fn validate(&self) -> Result<(), String> {
+        let limits = [
+            ("max_recent_messages", self.max_recent_messages, 1, MAX_RECENT_MESSAGES_LIMIT),
+            ("max_user_bans", self.max_user_bans, 1, MAX_USER_BANS_LIMIT),
+            ("max_message_size", self.max_message_size, 1, MAX_MESSAGE_SIZE_LIMIT),
+            ("max_nickname_size", self.max_nickname_size, 1, MAX_NICKNAME_SIZE_LIMIT),
+            ("max_members", self.max_members, 1, MAX_MEMBERS_LIMIT),
+            ("max_invites_per_member", self.max_invites_per_member, 0, MAX_MEMBERS_LIMIT),
+            ("max_invite_depth", self.max_invite_depth, 1, MAX_INVITE_DEPTH_LIMIT),
+            ("max_avatar_size", self.max_avatar_size, 0, MAX_AVATAR_SIZE_LIMIT),
+            ("max_bio_size", self.max_bio_size, 0, MAX_BIO_SIZE_LIMIT),
+            ("max_status_size", self.max_status_size, 0, MAX_STATUS_SIZE_LIMIT),
+            ("max_description_size", self.max_description_size, 0, MAX_DESCRIPTION_SIZE_LIMIT),
+            ("max_topic_size", self.max_topic_size, 0, MAX_TOPIC_SIZE_LIMIT),
+            ("max_rules_size", self.max_rules_size, 0, MAX_RULES_SIZE_LIMIT),
+            (
+                "max_direct_messages_per_recipient",
+                self.max_direct_messages_per_recipient,
+                0,
+                MAX_DIRECT_MESSAGES_PER_RECIPIENT_LIMIT,
+            ),
+        ];
+        for (field, value, min, max) in limits {
+            if !(min..=max).contains(&value) {
+                return Err(format!(
+                    "Invalid configuration value: {} must be between {} and {}, got {}",
+                    field, min, max, value
+                ));
+            }
+        }
+
+        if self.name.len() > MAX_NAME_SIZE {
+            return Err(format!(
+                "Room name is too long: {} > {} bytes",
+                self.name.len(),
+                MAX_NAME_SIZE
+            ));
+        }
+        for (field, text, max_size) in [
+            ("description", &self.description, self.max_description_size),
+            ("topic", &self.topic, self.max_topic_size),
+            ("rules", &self.rules, self.max_rules_size),
+        ] {
+            if let Some(text) = text {
+                if text.len() > max_size {
+                    return Err(format!(
+                        "Room {} is too long: {} > {} bytes",
+                        field,
+                        text.len(),
+                        max_size
+                    ));
+                }
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -138,6 +256,22 @@ impl Default for Configuration {
             max_message_size: 1000,
             max_nickname_size: 50,
             max_members: 200,
+            max_invites_per_member: DEFAULT_MAX_INVITES_PER_MEMBER,
+            max_invite_depth: DEFAULT_MAX_INVITE_DEPTH,
+            eviction_policy: EvictionPolicy::default(),
+            max_avatar_size: DEFAULT_MAX_AVATAR_SIZE,
+            max_bio_size: DEFAULT_MAX_BIO_SIZE,
+            max_status_size: DEFAULT_MAX_STATUS_SIZE,
+            description: None,
+            topic: None,
+            rules: None,
+            icon_hash: None,
+            visibility: RoomVisibility::default(),
+            privacy: RoomPrivacy::default(),
+            max_description_size: DEFAULT_MAX_DESCRIPTION_SIZE,
+            max_topic_size: DEFAULT_MAX_TOPIC_SIZE,
+            max_rules_size: DEFAULT_MAX_RULES_SIZE,
+            max_direct_messages_per_recipient: DEFAULT_MAX_DIRECT_MESSAGES_PER_RECIPIENT,
         }
     }
 }
@@ -146,10 +280,7 @@ impl fmt::Debug for AuthorizedConfigurationV1 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("AuthorizedConfiguration")
             .field("configuration", &self.configuration)
-            .field(
-                "signature",
-                &format_args!("{}", truncated_base64(self.signature.to_bytes())),
-            )
+            .field("signatures", &self.signatures)
             .finish()
     }
 }
@@ -164,31 +295,163 @@ pub struct Configuration {
     pub max_message_size: usize,
     pub max_nickname_size: usize,
     pub max_members: usize,
+    /// How many members a single (non-owner) member may have invited at once
+    #[serde(
+        default = "default_size::<DEFAULT_MAX_INVITES_PER_MEMBER>",
+        skip_serializing_if = "is_size::<DEFAULT_MAX_INVITES_PER_MEMBER>"
+    )]
+    pub max_invites_per_member: usize,
+    /// The maximum length of an invite chain, members invited by the owner have depth 1
+    #[serde(
+        default = "default_size::<DEFAULT_MAX_INVITE_DEPTH>",
+        skip_serializing_if = "is_size::<DEFAULT_MAX_INVITE_DEPTH>"
+    )]
+    pub max_invite_depth: usize,
+    /// Which members are removed first if the room has more than `max_members`
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub eviction_policy: EvictionPolicy,
+    /// Maximum size in bytes of an inline avatar image in a member's profile
+    #[serde(
+        default = "default_size::<DEFAULT_MAX_AVATAR_SIZE>",
+        skip_serializing_if = "is_size::<DEFAULT_MAX_AVATAR_SIZE>"
+    )]
+    pub max_avatar_size: usize,
+    #[serde(
+        default = "default_size::<DEFAULT_MAX_BIO_SIZE>",
+        skip_serializing_if = "is_size::<DEFAULT_MAX_BIO_SIZE>"
+    )]
+    pub max_bio_size: usize,
+    #[serde(
+        default = "default_size::<DEFAULT_MAX_STATUS_SIZE>",
+        skip_serializing_if = "is_size::<DEFAULT_MAX_STATUS_SIZE>"
+    )]
+    pub max_status_size: usize,
+    /// What the room is about, shown under its name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// The current topic of conversation, changed more often than the description
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    /// Rules or code of conduct members are expected to follow
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rules: Option<String>,
+    /// SHA-256 hash of the room's icon image, which is distributed separately
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub icon_hash: Option<[u8; 32]>,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub visibility: RoomVisibility,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub privacy: RoomPrivacy,
+    #[serde(
+        default = "default_size::<DEFAULT_MAX_DESCRIPTION_SIZE>",
+        skip_serializing_if = "is_size::<DEFAULT_MAX_DESCRIPTION_SIZE>"
+    )]
+    pub max_description_size: usize,
+    #[serde(
+        default = "default_size::<DEFAULT_MAX_TOPIC_SIZE>",
+        skip_serializing_if = "is_size::<DEFAULT_MAX_TOPIC_SIZE>"
+    )]
+    pub max_topic_size: usize,
+    #[serde(
+        default = "default_size::<DEFAULT_MAX_RULES_SIZE>",
+        skip_serializing_if = "is_size::<DEFAULT_MAX_RULES_SIZE>"
+    )]
+    pub max_rules_size: usize,
+    /// How many direct messages each participant's inbox keeps, 0 disables direct messages
+    #[serde(
+        default = "default_size::<DEFAULT_MAX_DIRECT_MESSAGES_PER_RECIPIENT>",
+        skip_serializing_if = "is_size::<DEFAULT_MAX_DIRECT_MESSAGES_PER_RECIPIENT>"
+    )]
+    pub max_direct_messages_per_recipient: usize,
+}
+
+impl Signable for Configuration {
+    const DOMAIN: &'static str = "configuration";
+}
+
+// Fields added after the first release are left out of the serialized configuration while
+// they have their default value, so that a configuration signed before they existed still
+// deserializes and serializes back to the bytes that were signed
+const DEFAULT_MAX_INVITES_PER_MEMBER: usize = 20;
+const DEFAULT_MAX_INVITE_DEPTH: usize = 8;
+const DEFAULT_MAX_AVATAR_SIZE: usize = 8192;
+const DEFAULT_MAX_BIO_SIZE: usize = 500;
+const DEFAULT_MAX_STATUS_SIZE: usize = 100;
+const DEFAULT_MAX_DIRECT_MESSAGES_PER_RECIPIENT: usize = 20;
+const DEFAULT_MAX_DESCRIPTION_SIZE: usize = 1000;
+const DEFAULT_MAX_TOPIC_SIZE: usize = 200;
+const DEFAULT_MAX_RULES_SIZE: usize = 5000;
+
+fn default_size<const SIZE: usize>() -> usize {
+    SIZE
+}
+
+fn is_size<const SIZE: usize>(size: &usize) -> bool {
+    *size == SIZE
+}
+
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum EvictionPolicy {
+    /// Remove the member with the longest invite chain
+    #[default]
+    LongestInviteChain,
+    /// Remove the member whose most recent message in `recent_messages` is oldest, members
+    /// with no recent messages first. Ties are broken by invite chain length, then member ID.
+    LeastRecentlyActive,
+}
+
+/// Whether a room may be listed in room directories. Unlisted rooms can only be found by
+/// those who know the owner's key.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum RoomVisibility {
+    #[default]
+    Public,
+    Unlisted,
+}
+
+/// Whether message content is readable by anyone or encrypted with a room secret that the
+/// owner seals to each member
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum RoomPrivacy {
+    #[default]
+    Public,
+    Private,
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::room_state::authority::KeyPolicy;
+    use ed25519_dalek::VerifyingKey;
     use rand::rngs::OsRng;
 
     #[test]
     fn test_verify() {
         let owner_signing_key = SigningKey::generate(&mut OsRng);
         let owner_verifying_key = VerifyingKey::from(&owner_signing_key);
-        let configuration = Configuration::default();
+        let configuration = Configuration {
+            owner_member_id: MemberId::new(&owner_verifying_key),
+            ..Configuration::default()
+        };
         let authorized_configuration =
             AuthorizedConfigurationV1::new(configuration.clone(), &owner_signing_key);
 
-        assert!(authorized_configuration
-            .verify_signature(&owner_verifying_key)
-            .is_ok());
-
         let mut parent_state = ChatRoomStateV1::default();
         parent_state.configuration = authorized_configuration.clone();
         let parameters = ChatRoomParametersV1 {
             owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
         };
 
+        assert!(authorized_configuration
+            .verify_signature(&parameters)
+            .is_ok());
+
         assert!(authorized_configuration
             .verify(&parent_state, &parameters)
             .is_ok());
@@ -198,33 +461,88 @@ mod tests {
     fn test_verify_fail() {
         let owner_signing_key = SigningKey::generate(&mut OsRng);
         let owner_verifying_key = VerifyingKey::from(&owner_signing_key);
-        let configuration = Configuration::default();
+        let configuration = Configuration {
+            owner_member_id: MemberId::new(&owner_verifying_key),
+            ..Configuration::default()
+        };
         let authorized_configuration =
             AuthorizedConfigurationV1::new(configuration.clone(), &owner_signing_key);
 
         let mut wrong_owner_signing_key = SigningKey::generate(&mut OsRng);
         let wrong_owner_verifying_key = VerifyingKey::from(&wrong_owner_signing_key);
 
-        assert!(authorized_configuration
-            .verify_signature(&wrong_owner_verifying_key)
-            .is_err());
-
         let mut parent_state = ChatRoomStateV1::default();
         parent_state.configuration = authorized_configuration.clone();
         let parameters = ChatRoomParametersV1 {
             owner: wrong_owner_verifying_key,
+            admins: None,
+            recovery: None,
         };
 
+        assert!(authorized_configuration
+            .verify_signature(&parameters)
+            .is_err());
+
         assert!(authorized_configuration
             .verify(&parent_state, &parameters)
             .is_err());
     }
 
+    #[test]
+    fn test_verify_invalid_values() {
+        let owner_signing_key = SigningKey::generate(&mut OsRng);
+        let owner_verifying_key = VerifyingKey::from(&owner_signing_key);
+        let parameters = ChatRoomParametersV1 {
+            owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
+        };
+        let parent_state = ChatRoomStateV1::default();
+        let verify = |configuration: Configuration| {
+            AuthorizedConfigurationV1::new(configuration, &owner_signing_key)
+                .verify(&parent_state, &parameters)
+        };
+        let configuration = Configuration {
+            owner_member_id: MemberId::new(&owner_verifying_key),
+            ..Configuration::default()
+        };
+        assert!(verify(configuration.clone()).is_ok());
+
+        // A correctly signed state can still carry a configuration that no update would
+        // have been allowed to set
+        assert_eq!(
+            verify(Configuration {
+                max_members: 0,
+                ..configuration.clone()
+            }),
+            Err("Invalid configuration value: max_members must be between 1 and 10000, got 0"
+                .to_string())
+        );
+        assert!(verify(Configuration {
+            max_recent_messages: MAX_RECENT_MESSAGES_LIMIT + 1,
+            ..configuration.clone()
+        })
+        .is_err());
+        assert!(verify(Configuration {
+            name: "x".repeat(MAX_NAME_SIZE + 1),
+            ..configuration.clone()
+        })
+        .is_err());
+        assert!(verify(Configuration {
+            owner_member_id: MemberId(FastHash(1)),
+            ..configuration
+        })
+        .is_err());
+    }
+
     #[test]
     fn test_summarize() {
         let owner_signing_key = SigningKey::generate(&mut OsRng);
         let owner_verifying_key = VerifyingKey::from(&owner_signing_key);
-        let configuration = Configuration::default();
+        let configuration = Configuration {
+            owner_member_id: MemberId::new(&owner_verifying_key),
+            ..Configuration::default()
+        };
         let authorized_configuration =
             AuthorizedConfigurationV1::new(configuration.clone(), &owner_signing_key);
 
@@ -232,11 +550,17 @@ mod tests {
         parent_state.configuration = authorized_configuration.clone();
         let parameters = ChatRoomParametersV1 {
             owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
         };
 
         assert_eq!(
             authorized_configuration.summarize(&parent_state, &parameters),
-            configuration.configuration_version
+            (
+                Authority::Owner,
+                configuration.configuration_version,
+                Reverse(authorized_configuration.signatures.to_bytes())
+            )
         );
     }
 
@@ -244,7 +568,10 @@ mod tests {
     fn test_delta_new_version() {
         let owner_signing_key = SigningKey::generate(&mut OsRng);
         let owner_verifying_key = VerifyingKey::from(&owner_signing_key);
-        let configuration = Configuration::default();
+        let configuration = Configuration {
+            owner_member_id: MemberId::new(&owner_verifying_key),
+            ..Configuration::default()
+        };
         let authorized_configuration =
             AuthorizedConfigurationV1::new(configuration.clone(), &owner_signing_key);
 
@@ -252,6 +579,8 @@ mod tests {
         parent_state.configuration = authorized_configuration.clone();
         let parameters = ChatRoomParametersV1 {
             owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
         };
 
         let new_configuration = Configuration {
@@ -261,8 +590,9 @@ mod tests {
         let new_authorized_configuration =
             AuthorizedConfigurationV1::new(new_configuration.clone(), &owner_signing_key);
 
+        let old_summary = authorized_configuration.summarize(&parent_state, &parameters);
         assert_eq!(
-            new_authorized_configuration.delta(&parent_state, &parameters, &1),
+            new_authorized_configuration.delta(&parent_state, &parameters, &old_summary),
             Some(new_authorized_configuration)
         );
     }
@@ -271,7 +601,10 @@ mod tests {
     fn test_delta_older_version() {
         let owner_signing_key = SigningKey::generate(&mut OsRng);
         let owner_verifying_key = VerifyingKey::from(&owner_signing_key);
-        let configuration = Configuration::default();
+        let configuration = Configuration {
+            owner_member_id: MemberId::new(&owner_verifying_key),
+            ..Configuration::default()
+        };
         let authorized_configuration =
             AuthorizedConfigurationV1::new(configuration.clone(), &owner_signing_key);
 
@@ -279,6 +612,8 @@ mod tests {
         parent_state.configuration = authorized_configuration.clone();
         let parameters = ChatRoomParametersV1 {
             owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
         };
 
         let new_configuration = Configuration {
@@ -288,8 +623,9 @@ mod tests {
         let new_authorized_configuration =
             AuthorizedConfigurationV1::new(new_configuration.clone(), &owner_signing_key);
 
+        let old_summary = authorized_configuration.summarize(&parent_state, &parameters);
         assert_eq!(
-            authorized_configuration.delta(&parent_state, &parameters, &1),
+            authorized_configuration.delta(&parent_state, &parameters, &old_summary),
             None
         );
     }
@@ -298,7 +634,10 @@ mod tests {
     fn test_apply_delta_should_apply() {
         let owner_signing_key = SigningKey::generate(&mut OsRng);
         let owner_verifying_key = VerifyingKey::from(&owner_signing_key);
-        let configuration = Configuration::default();
+        let configuration = Configuration {
+            owner_member_id: MemberId::new(&owner_verifying_key),
+            ..Configuration::default()
+        };
         let mut authorized_configuration =
             AuthorizedConfigurationV1::new(configuration.clone(), &owner_signing_key);
 
@@ -306,6 +645,8 @@ mod tests {
         parent_state.configuration = authorized_configuration.clone();
         let parameters = ChatRoomParametersV1 {
             owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
         };
 
         let new_configuration = Configuration {
@@ -330,7 +671,10 @@ mod tests {
     fn test_apply_delta_old_version() {
         let owner_signing_key = SigningKey::generate(&mut OsRng);
         let owner_verifying_key = VerifyingKey::from(&owner_signing_key);
-        let configuration = Configuration::default();
+        let configuration = Configuration {
+            owner_member_id: MemberId::new(&owner_verifying_key),
+            ..Configuration::default()
+        };
         let mut authorized_configuration =
             AuthorizedConfigurationV1::new(configuration.clone(), &owner_signing_key);
 
@@ -340,6 +684,8 @@ mod tests {
         parent_state.configuration = authorized_configuration.clone();
         let parameters = ChatRoomParametersV1 {
             owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
         };
 
         let new_configuration = Configuration {
@@ -355,14 +701,59 @@ mod tests {
             &new_authorized_configuration,
         );
 
-        assert!(result.is_err());
-        assert_eq!(
-            result.unwrap_err(),
-            "New configuration version must be greater than the current version"
-        );
+        // An older configuration is a redelivery that has since been replaced
+        assert_eq!(result, Ok(DeltaReport::default()));
         assert_eq!(authorized_configuration, orig_authorized_configuration);
     }
 
+    #[test]
+    fn test_concurrent_configurations_converge() {
+        let owner_signing_key = SigningKey::generate(&mut OsRng);
+        let owner_verifying_key = VerifyingKey::from(&owner_signing_key);
+        let parameters = ChatRoomParametersV1 {
+            owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
+        };
+        let configuration = |name: &str| {
+            AuthorizedConfigurationV1::new(
+                Configuration {
+                    owner_member_id: MemberId::new(&owner_verifying_key),
+                    configuration_version: 2,
+                    name: name.to_string(),
+                    ..Configuration::default()
+                },
+                &owner_signing_key,
+            )
+        };
+        let original = AuthorizedConfigurationV1::new(
+            Configuration {
+                owner_member_id: MemberId::new(&owner_verifying_key),
+                ..Configuration::default()
+            },
+            &owner_signing_key,
+        );
+        let (first, second) = (configuration("First"), configuration("Second"));
+        let winner = if first.signatures.to_bytes() < second.signatures.to_bytes() {
+            &first
+        } else {
+            &second
+        };
+        let parent_state = ChatRoomStateV1::default();
+
+        // Two configurations of the same version end up the same whichever arrives first
+        for (a, b) in [(&first, &second), (&second, &first)] {
+            let mut state = original.clone();
+            for delta in [a, b] {
+                let report = state
+                    .apply_delta(&parent_state, &parameters, delta)
+                    .unwrap();
+                assert!(report.rejected.is_empty());
+            }
+            assert_eq!(&state, winner);
+        }
+    }
+
     #[test]
     fn test_apply_delta_change_owner() {
         let owner_signing_key = SigningKey::generate(&mut OsRng);
@@ -376,6 +767,8 @@ mod tests {
         parent_state.configuration = authorized_configuration.clone();
         let parameters = ChatRoomParametersV1 {
             owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
         };
 
         let mut new_configuration = configuration.clone();
@@ -390,15 +783,17 @@ mod tests {
             &new_authorized_configuration,
         );
 
-        assert!(result.is_err());
-        assert_eq!(result.unwrap_err(), "Cannot change the owner_member_id");
+        assert_eq!(result.unwrap().rejected[0].1, "Cannot change the owner_member_id");
     }
 
     #[test]
     fn test_apply_delta_invalid_values() {
         let owner_signing_key = SigningKey::generate(&mut OsRng);
         let owner_verifying_key = VerifyingKey::from(&owner_signing_key);
-        let configuration = Configuration::default();
+        let configuration = Configuration {
+            owner_member_id: MemberId::new(&owner_verifying_key),
+            ..Configuration::default()
+        };
         let mut authorized_configuration =
             AuthorizedConfigurationV1::new(configuration.clone(), &owner_signing_key);
 
@@ -406,6 +801,8 @@ mod tests {
         parent_state.configuration = authorized_configuration.clone();
         let parameters = ChatRoomParametersV1 {
             owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
         };
 
         let mut new_configuration = configuration.clone();
@@ -420,7 +817,213 @@ mod tests {
             &new_authorized_configuration,
         );
 
-        assert!(result.is_err());
-        assert_eq!(result.unwrap_err(), "Invalid configuration values");
+        assert_eq!(
+            result.unwrap().rejected[0].1,
+            "Invalid configuration value: max_recent_messages must be between 1 and 10000, got 0"
+        );
+    }
+
+    #[test]
+    fn test_recovery_keys_take_precedence() {
+        let owner_signing_key = SigningKey::generate(&mut OsRng);
+        let recovery_keys: Vec<SigningKey> =
+            (0..3).map(|_| SigningKey::generate(&mut OsRng)).collect();
+        let parameters = ChatRoomParametersV1 {
+            owner: owner_signing_key.verifying_key(),
+            admins: None,
+            recovery: Some(KeyPolicy {
+                keys: recovery_keys.iter().map(|k| k.verifying_key()).collect(),
+                threshold: 2,
+            }),
+        };
+        let configuration = Configuration {
+            owner_member_id: parameters.owner_id(),
+            ..Configuration::default()
+        };
+        let mut authorized_configuration =
+            AuthorizedConfigurationV1::new(configuration.clone(), &owner_signing_key);
+        let parent_state = ChatRoomStateV1::default();
+        let version = |version: u32| Configuration {
+            configuration_version: version,
+            ..configuration.clone()
+        };
+        let mut apply = |delta: AuthorizedConfigurationV1| {
+            let report = authorized_configuration
+                .apply_delta(&parent_state, &parameters, &delta)
+                .unwrap();
+            (report, authorized_configuration.clone())
+        };
+
+        let owner_config = AuthorizedConfigurationV1::new(version(5), &owner_signing_key);
+        assert_eq!(apply(owner_config.clone()).1, owner_config);
+
+        // Enough recovery keys replace the owner's configuration whatever its version
+        let recovery_config = AuthorizedConfigurationV1::new_with_keys(
+            version(2),
+            &[&recovery_keys[0], &recovery_keys[2]],
+        );
+        assert_eq!(apply(recovery_config.clone()).1, recovery_config);
+        assert_eq!(recovery_config.verify(&parent_state, &parameters), Ok(()));
+
+        // The owner can no longer replace it, a single recovery key never could
+        let owner_config = AuthorizedConfigurationV1::new(version(10), &owner_signing_key);
+        assert_eq!(apply(owner_config).1, recovery_config);
+        let (report, held) = apply(AuthorizedConfigurationV1::new_with_keys(
+            version(3),
+            &[&recovery_keys[1]],
+        ));
+        assert!(!report.is_fully_applied());
+        assert_eq!(held, recovery_config);
+
+        // The recovery keys can keep updating it
+        let recovery_config = AuthorizedConfigurationV1::new_with_keys(
+            version(3),
+            &[&recovery_keys[1], &recovery_keys[2]],
+        );
+        assert_eq!(apply(recovery_config.clone()).1, recovery_config);
+    }
+
+    #[test]
+    fn test_apply_delta_room_details() {
+        let owner_signing_key = SigningKey::generate(&mut OsRng);
+        let owner_verifying_key = VerifyingKey::from(&owner_signing_key);
+        let configuration = Configuration {
+            owner_member_id: MemberId::new(&owner_verifying_key),
+            max_topic_size: 10,
+            ..Configuration::default()
+        };
+        let mut authorized_configuration =
+            AuthorizedConfigurationV1::new(configuration.clone(), &owner_signing_key);
+
+        let parent_state = ChatRoomStateV1::default();
+        let parameters = ChatRoomParametersV1 {
+            owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
+        };
+
+        // A topic over the size limit is rejected
+        let mut new_configuration = configuration.clone();
+        new_configuration.configuration_version += 1;
+        new_configuration.topic = Some("Release planning".to_string());
+        let report = authorized_configuration
+            .apply_delta(
+                &parent_state,
+                &parameters,
+                &AuthorizedConfigurationV1::new(new_configuration, &owner_signing_key),
+            )
+            .unwrap();
+        assert_eq!(report.rejected[0].1, "Room topic is too long: 16 > 10 bytes");
+
+        // Details within their limits are applied
+        let mut new_configuration = configuration;
+        new_configuration.configuration_version += 1;
+        new_configuration.description = Some("Developers of Freenet".to_string());
+        new_configuration.topic = Some("Releases".to_string());
+        new_configuration.rules = Some("Be kind".to_string());
+        new_configuration.icon_hash = Some([7; 32]);
+        new_configuration.visibility = RoomVisibility::Unlisted;
+        let new_authorized_configuration =
+            AuthorizedConfigurationV1::new(new_configuration, &owner_signing_key);
+        let report = authorized_configuration
+            .apply_delta(&parent_state, &parameters, &new_authorized_configuration)
+            .unwrap();
+        assert!(report.is_fully_applied());
+        assert_eq!(authorized_configuration, new_authorized_configuration);
+    }
+
+    fn round_trip(configuration: &Configuration) -> (Vec<String>, Configuration) {
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(configuration, &mut bytes).unwrap();
+        let value: ciborium::value::Value = ciborium::de::from_reader(bytes.as_slice()).unwrap();
+        let keys = value
+            .as_map()
+            .unwrap()
+            .iter()
+            .map(|(key, _)| key.as_text().unwrap().to_string())
+            .collect();
+        (keys, ciborium::de::from_reader(bytes.as_slice()).unwrap())
+    }
+
+    #[test]
+    fn test_fields_added_later_are_optional() {
+        // Left out at their defaults, so configurations signed before they existed still
+        // serialize to the bytes that were signed
+        let configuration = Configuration::default();
+        let (keys, decoded) = round_trip(&configuration);
+        for key in [
+            "description",
+            "topic",
+            "rules",
+            "icon_hash",
+            "visibility",
+            "max_description_size",
+            "max_topic_size",
+            "max_rules_size",
+            "max_direct_messages_per_recipient",
+            "privacy",
+            "max_avatar_size",
+            "max_bio_size",
+            "max_status_size",
+            "eviction_policy",
+            "max_invites_per_member",
+            "max_invite_depth",
+        ] {
+            assert!(!keys.iter().any(|k| k == key), "{} is serialized", key);
+        }
+        assert_eq!(decoded, configuration);
+
+        let configuration = Configuration {
+            topic: Some("Releases".to_string()),
+            visibility: RoomVisibility::Unlisted,
+            max_topic_size: 10,
+            ..Configuration::default()
+        };
+        assert_eq!(round_trip(&configuration).1, configuration);
+    }
+
+    #[test]
+    fn test_apply_delta_zero_invite_depth() {
+        let owner_signing_key = SigningKey::generate(&mut OsRng);
+        let owner_verifying_key = VerifyingKey::from(&owner_signing_key);
+        let configuration = Configuration {
+            owner_member_id: MemberId::new(&owner_verifying_key),
+            ..Configuration::default()
+        };
+        let mut authorized_configuration =
+            AuthorizedConfigurationV1::new(configuration.clone(), &owner_signing_key);
+
+        let parent_state = ChatRoomStateV1::default();
+        let parameters = ChatRoomParametersV1 {
+            owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
+        };
+
+        let mut new_configuration = configuration.clone();
+        new_configuration.configuration_version += 1;
+        new_configuration.max_invite_depth = 0;
+        let new_authorized_configuration =
+            AuthorizedConfigurationV1::new(new_configuration, &owner_signing_key);
+
+        let result = authorized_configuration.apply_delta(
+            &parent_state,
+            &parameters,
+            &new_authorized_configuration,
+        );
+        assert_eq!(
+            result.unwrap().rejected[0].1,
+            "Invalid configuration value: max_invite_depth must be between 1 and 64, got 0"
+        );
+
+        // Zero invites per member is allowed, it means only the owner can invite
+        let mut new_configuration = configuration;
+        new_configuration.configuration_version += 1;
+        new_configuration.max_invites_per_member = 0;
+        let new_authorized_configuration =
+            AuthorizedConfigurationV1::new(new_configuration, &owner_signing_key);
+        assert!(authorized_configuration
+            .apply_delta(&parent_state, &parameters, &new_authorized_configuration)
+            .is_ok());
     }
 }
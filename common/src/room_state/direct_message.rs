@@ -0,0 +1,552 @@
+use crate::ecies::SealedBox;
+use crate::room_state::configuration::Configuration;
+use crate::room_state::member::{Author, MemberId};
+use crate::room_state::ChatRoomParametersV1;
+use crate::util::{sign_struct, truncated_base64, verify_struct, Signable, SignatureBatch};
+use crate::ChatRoomStateV1;
+use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
+use freenet_scaffold::util::{fast_hash, FastHash};
+use freenet_scaffold::{ComposableState, DeltaReport};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::time::SystemTime;
+
+/// Private messages between participants of the room, each sealed to its recipient. The
+/// contract can't read them, it only checks who sent them and keeps the most recent ones
+/// in each recipient's inbox.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct DirectMessagesV1 {
+    pub messages: Vec<AuthorizedDirectMessage>,
+}
+
+impl ComposableState for DirectMessagesV1 {
+    type ParentState = ChatRoomStateV1;
+    type Summary = Vec<DirectMessageId>;
+    type Delta = Vec<AuthorizedDirectMessage>;
+    type Parameters = ChatRoomParametersV1;
+
+    fn verify(
+        &self,
+        parent_state: &Self::ParentState,
+        parameters: &Self::Parameters,
+    ) -> Result<(), String> {
+        let configuration = &parent_state.configuration.configuration;
+        let authors_by_id = parent_state
+            .members
+            .authors_by_member_id(&parent_state.bans, parameters);
+
+        let mut inbox_sizes: HashMap<MemberId, usize> = HashMap::new();
+        let mut batch = SignatureBatch::new();
+        for message in &self.messages {
+            parameters.check_room_binding(message.message.room_owner)?;
+            if parent_state.upgrade.is_past_cutoff(message.message.time) {
+                return Err(format!(
+                    "Direct message {} was written after the room was upgraded",
+                    message.id()
+                ));
+            }
+            if message.message.content_size() > configuration.max_message_size {
+                return Err(format!(
+                    "Direct message {} is over the size limit",
+                    message.id()
+                ));
+            }
+            let inbox_size = inbox_sizes.entry(message.message.recipient).or_default();
+            *inbox_size += 1;
+            if *inbox_size > configuration.max_direct_messages_per_recipient {
+                return Err(format!(
+                    "Too many direct messages for {:?}",
+                    message.message.recipient
+                ));
+            }
+            let Some(sender) = message.sender(&authors_by_id) else {
+                return Err(format!(
+                    "Direct message sender not found: {:?}",
+                    message.message.sender
+                ));
+            };
+            if message.recipient(&authors_by_id).is_none() {
+                return Err(format!(
+                    "Direct message recipient not found: {:?}",
+                    message.message.recipient
+                ));
+            }
+            batch.add(
+                &message.message,
+                &message.signature,
+                sender.verifying_key(),
+                || format!("direct message id:{}", message.id()),
+            );
+        }
+        batch.verify()?;
+
+        Ok(())
+    }
+
+    fn summarize(
+        &self,
+        _parent_state: &Self::ParentState,
+        _parameters: &Self::Parameters,
+    ) -> Self::Summary {
+        self.messages.iter().map(|m| m.id()).collect()
+    }
+
+    fn delta(
+        &self,
+        _parent_state: &Self::ParentState,
+        _parameters: &Self::Parameters,
+        old_state_summary: &Self::Summary,
+    ) -> Option<Self::Delta> {
+        let delta: Vec<AuthorizedDirectMessage> = self
+            .messages
+            .iter()
+            .filter(|m| !old_state_summary.contains(&m.id()))
+            .cloned()
+            .collect();
+        if delta.is_empty() {
+            None
+        } else {
+            Some(delta)
+        }
+    }
+
+    fn apply_delta(
+        &mut self,
+        parent_state: &Self::ParentState,
+        parameters: &Self::Parameters,
+        delta: &Self::Delta,
+    ) -> Result<DeltaReport, String> {
+        let max_message_size = parent_state.configuration.configuration.max_message_size;
+        let authors_by_id = parent_state
+            .members
+            .authors_by_member_id(&parent_state.bans, parameters);
+        let mut report = DeltaReport::default();
+
+        let mut message_ids: HashSet<DirectMessageId> =
+            self.messages.iter().map(|m| m.id()).collect();
+        for message in delta {
+            if !message_ids.insert(message.id()) {
+                continue;
+            }
+            let item = format!("direct message {}", message.id());
+            if !parent_state
+                .upgrade
+                .accepts_message_at(message.message.time)
+            {
+                report.rejected(
+                    item,
+                    "The room has been upgraded, send messages to its successor",
+                );
+                continue;
+            }
+            if message.message.content_size() > max_message_size {
+                report.rejected(item, "Direct message is over the size limit");
+                continue;
+            }
+            if let Err(e) = parameters.check_room_binding(message.message.room_owner) {
+                report.rejected(item, e);
+                continue;
+            }
+            let Some(sender) = message.sender(&authors_by_id) else {
+                report.rejected(
+                    item,
+                    format!(
+                        "Direct message sender not found: {:?}",
+                        message.message.sender
+                    ),
+                );
+                continue;
+            };
+            if message.recipient(&authors_by_id).is_none() {
+                report.rejected(
+                    item,
+                    format!(
+                        "Direct message recipient not found: {:?}",
+                        message.message.recipient
+                    ),
+                );
+                continue;
+            }
+            if let Err(e) = message.validate(sender.verifying_key()) {
+                report.rejected(item, format!("Invalid direct message signature: {}", e));
+                continue;
+            }
+            self.messages.push(message.clone());
+            report.applied(item);
+        }
+
+        self.enforce_limits(&parent_state.configuration.configuration);
+
+        Ok(report)
+    }
+}
+
+impl DirectMessagesV1 {
+    /// Drops any messages over the size limit, then trims each recipient's inbox to
+    /// `max_direct_messages_per_recipient`, and sorts the rest by time. Senders share an
+    /// inbox in turns, newest first, so one sender can't push out everyone else's
+    /// messages by sending many, or by dating them in the future. Ties are broken by id
+    /// so that every peer keeps the same messages.
+    pub fn enforce_limits(&mut self, configuration: &Configuration) {
+        self.messages
+            .retain(|m| m.message.content_size() <= configuration.max_message_size);
+        self.messages
+            .sort_by_cached_key(|m| Reverse((m.message.time, m.id())));
+        // How many newer messages each sender already has in the same inbox
+        let mut pair_sizes: HashMap<(MemberId, MemberId), usize> = HashMap::new();
+        let turns: HashMap<DirectMessageId, usize> = self
+            .messages
+            .iter()
+            .map(|m| {
+                let pair_size = pair_sizes
+                    .entry((m.message.sender, m.message.recipient))
+                    .or_default();
+                *pair_size += 1;
+                (m.id(), *pair_size)
+            })
+            .collect();
+        self.messages.sort_by_key(|m| turns[&m.id()]);
+        let mut inbox_sizes: HashMap<MemberId, usize> = HashMap::new();
+        self.messages.retain(|m| {
+            let inbox_size = inbox_sizes.entry(m.message.recipient).or_default();
+            *inbox_size += 1;
+            *inbox_size <= configuration.max_direct_messages_per_recipient
+        });
+        self.messages
+            .sort_by_cached_key(|m| (m.message.time, m.id()));
+    }
+
+    /// The messages two participants sent each other, oldest first
+    pub fn conversation(
+        &self,
+        member: MemberId,
+        other: MemberId,
+    ) -> impl Iterator<Item = &AuthorizedDirectMessage> {
+        self.messages.iter().filter(move |m| {
+            (m.message.sender == member && m.message.recipient == other)
+                || (m.message.sender == other && m.message.recipient == member)
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct DirectMessage {
+    pub room_owner: MemberId,
+    pub sender: MemberId,
+    pub recipient: MemberId,
+    pub time: SystemTime,
+    /// The text, sealed to the recipient
+    pub content: SealedBox,
+    /// The text sealed to the sender, so that they can read what they sent
+    pub sender_copy: SealedBox,
+}
+
+impl Signable for DirectMessage {
+    const DOMAIN: &'static str = "direct_message";
+}
+
+impl DirectMessage {
+    pub fn new(
+        room_owner: MemberId,
+        sender_vk: &VerifyingKey,
+        recipient_vk: &VerifyingKey,
+        time: SystemTime,
+        text: &str,
+    ) -> Result<Self, String> {
+        let seal = |member_vk| {
+            SealedBox::seal(member_vk, Self::DOMAIN.as_bytes(), text.as_bytes())
+                .map_err(|e| format!("Can't seal direct message: {}", e))
+        };
+        Ok(Self {
+            room_owner,
+            sender: MemberId::new(sender_vk),
+            recipient: MemberId::new(recipient_vk),
+            time,
+            content: seal(recipient_vk)?,
+            sender_copy: seal(sender_vk)?,
+        })
+    }
+
+    /// Size of the message text, which both copies must stay within
+    pub fn content_size(&self) -> usize {
+        self.content
+            .plaintext_size()
+            .max(self.sender_copy.plaintext_size())
+    }
+
+    /// The message text, for its sender or recipient
+    pub fn open(&self, signing_key: &SigningKey) -> Result<String, String> {
+        let member_id = MemberId::new(&signing_key.verifying_key());
+        let sealed = if member_id == self.recipient {
+            &self.content
+        } else if member_id == self.sender {
+            &self.sender_copy
+        } else {
+            return Err("Not the sender or recipient of this direct message".to_string());
+        };
+        String::from_utf8(sealed.open(signing_key, Self::DOMAIN.as_bytes())?)
+            .map_err(|_| "Direct message isn't valid UTF-8".to_string())
+    }
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuthorizedDirectMessage {
+    pub message: DirectMessage,
+    pub signature: Signature,
+}
+
+impl fmt::Debug for AuthorizedDirectMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthorizedDirectMessage")
+            .field("sender", &self.message.sender)
+            .field("recipient", &self.message.recipient)
+            .field("time", &self.message.time)
+            .field(
+                "signature",
+                &format_args!("{}", truncated_base64(self.signature.to_bytes())),
+            )
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Eq, PartialEq, Hash, Serialize, Deserialize, Clone, Debug, Ord, PartialOrd)]
+pub struct DirectMessageId(pub FastHash);
+
+impl fmt::Display for DirectMessageId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self.0)
+    }
+}
+
+impl AuthorizedDirectMessage {
+    pub fn new(message: DirectMessage, sender_signing_key: &SigningKey) -> Self {
+        Self {
+            signature: sign_struct(&message, sender_signing_key),
+            message,
+        }
+    }
+
+    pub fn validate(&self, sender_vk: &VerifyingKey) -> Result<(), ed25519_dalek::SignatureError> {
+        verify_struct(&self.message, &self.signature, sender_vk)
+    }
+
+    pub fn id(&self) -> DirectMessageId {
+        DirectMessageId(fast_hash(&self.signature.to_bytes()))
+    }
+
+    /// The sender, if they were in the room when the message was written, see
+    /// [`MembersV1::authors_by_member_id`](crate::room_state::member::MembersV1::authors_by_member_id)
+    pub fn sender<'a>(
+        &self,
+        authors_by_id: &'a HashMap<MemberId, Author<'a>>,
+    ) -> Option<&'a Author<'a>> {
+        authors_by_id
+            .get(&self.message.sender)
+            .filter(|a| a.could_write_at(self.message.time))
+    }
+
+    /// The recipient, if they were in the room when the message was written
+    pub fn recipient<'a>(
+        &self,
+        authors_by_id: &'a HashMap<MemberId, Author<'a>>,
+    ) -> Option<&'a Author<'a>> {
+        authors_by_id
+            .get(&self.message.recipient)
+            .filter(|a| a.could_write_at(self.message.time))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::room_state::configuration::AuthorizedConfigurationV1;
+    use crate::room_state::member::{AuthorizedMember, Member};
+    use rand::rngs::OsRng;
+    use std::time::Duration;
+
+    fn create_room() -> (
+        ChatRoomStateV1,
+        ChatRoomParametersV1,
+        SigningKey,
+        SigningKey,
+    ) {
+        let owner_key = SigningKey::generate(&mut OsRng);
+        let member_key = SigningKey::generate(&mut OsRng);
+        let parameters = ChatRoomParametersV1 {
+            owner: owner_key.verifying_key(),
+            admins: None,
+            recovery: None,
+        };
+        let mut state = ChatRoomStateV1 {
+            configuration: AuthorizedConfigurationV1::new(
+                Configuration {
+                    owner_member_id: parameters.owner_id(),
+                    max_direct_messages_per_recipient: 2,
+                    ..Configuration::default()
+                },
+                &owner_key,
+            ),
+            ..ChatRoomStateV1::default()
+        };
+        state.members.members.push(AuthorizedMember::new(
+            Member {
+                owner_member_id: parameters.owner_id(),
+                invited_by: parameters.owner_id(),
+                member_vk: member_key.verifying_key(),
+            },
+            &owner_key,
+        ));
+        (state, parameters, owner_key, member_key)
+    }
+
+    fn send(
+        parameters: &ChatRoomParametersV1,
+        sender_key: &SigningKey,
+        recipient_vk: &VerifyingKey,
+        time: SystemTime,
+        text: &str,
+    ) -> AuthorizedDirectMessage {
+        AuthorizedDirectMessage::new(
+            DirectMessage::new(
+                parameters.owner_id(),
+                &sender_key.verifying_key(),
+                recipient_vk,
+                time,
+                text,
+            )
+            .unwrap(),
+            sender_key,
+        )
+    }
+
+    #[test]
+    fn test_open() {
+        let (_, parameters, owner_key, member_key) = create_room();
+        let stranger_key = SigningKey::generate(&mut OsRng);
+        let message = send(
+            &parameters,
+            &member_key,
+            &owner_key.verifying_key(),
+            SystemTime::now(),
+            "Hello",
+        );
+        assert_eq!(message.message.content_size(), 5);
+        assert_eq!(message.message.open(&owner_key), Ok("Hello".to_string()));
+        assert_eq!(message.message.open(&member_key), Ok("Hello".to_string()));
+        assert!(message.message.open(&stranger_key).is_err());
+        assert!(message.validate(&member_key.verifying_key()).is_ok());
+        assert!(message.validate(&owner_key.verifying_key()).is_err());
+    }
+
+    #[test]
+    fn test_apply_delta() {
+        let (state, parameters, owner_key, member_key) = create_room();
+        let owner_vk = owner_key.verifying_key();
+        let member_vk = member_key.verifying_key();
+        let stranger_key = SigningKey::generate(&mut OsRng);
+        let start = SystemTime::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut messages = DirectMessagesV1::default();
+
+        let mut forged = send(&parameters, &member_key, &owner_vk, at(0), "Forged");
+        forged.message.sender = parameters.owner_id();
+        let report = messages
+            .apply_delta(
+                &state,
+                &parameters,
+                &vec![
+                    send(&parameters, &member_key, &owner_vk, at(1), "One"),
+                    send(&parameters, &owner_key, &member_vk, at(2), "Two"),
+                    send(&parameters, &stranger_key, &owner_vk, at(3), "Stranger"),
+                    send(
+                        &parameters,
+                        &member_key,
+                        &stranger_key.verifying_key(),
+                        at(4),
+                        "Outside",
+                    ),
+                    forged,
+                ],
+            )
+            .unwrap();
+        assert_eq!(report.applied.len(), 2);
+        assert_eq!(
+            report.rejected.len(),
+            3,
+            "A non-member sender, a non-member recipient and a forged sender"
+        );
+        assert!(messages.verify(&state, &parameters).is_ok());
+
+        // The owner's inbox only keeps the most recent messages
+        let report = messages
+            .apply_delta(
+                &state,
+                &parameters,
+                &vec![
+                    send(&parameters, &member_key, &owner_vk, at(5), "Three"),
+                    send(&parameters, &member_key, &owner_vk, at(6), "Four"),
+                ],
+            )
+            .unwrap();
+        assert!(report.is_fully_applied());
+        assert!(messages.verify(&state, &parameters).is_ok());
+        let texts: Vec<String> = messages
+            .conversation(parameters.owner_id(), MemberId::new(&member_vk))
+            .map(|m| m.message.open(&owner_key).unwrap())
+            .collect();
+        assert_eq!(texts, vec!["Two", "Three", "Four"]);
+
+        // Over-full inboxes don't verify
+        let mut overfull = messages.clone();
+        overfull
+            .messages
+            .push(send(&parameters, &member_key, &owner_vk, at(7), "Five"));
+        assert!(overfull.verify(&state, &parameters).is_err());
+    }
+
+    #[test]
+    fn test_inbox_shared_between_senders() {
+        let (mut state, parameters, owner_key, member_key) = create_room();
+        let owner_vk = owner_key.verifying_key();
+        let other_key = SigningKey::generate(&mut OsRng);
+        state.members.members.push(AuthorizedMember::new(
+            Member {
+                owner_member_id: parameters.owner_id(),
+                invited_by: parameters.owner_id(),
+                member_vk: other_key.verifying_key(),
+            },
+            &owner_key,
+        ));
+        let start = SystemTime::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut messages = DirectMessagesV1::default();
+
+        let report = messages
+            .apply_delta(
+                &state,
+                &parameters,
+                &vec![send(&parameters, &other_key, &owner_vk, at(1), "Hello")],
+            )
+            .unwrap();
+        assert!(report.is_fully_applied());
+
+        // Flooding the owner's inbox with messages dated far in the future only replaces
+        // the flooder's own messages
+        let flood: Vec<AuthorizedDirectMessage> = (0..5)
+            .map(|i| send(&parameters, &member_key, &owner_vk, at(86_400 + i), "Spam"))
+            .collect();
+        messages.apply_delta(&state, &parameters, &flood).unwrap();
+        assert!(messages.verify(&state, &parameters).is_ok());
+        let senders: Vec<MemberId> = messages.messages.iter().map(|m| m.message.sender).collect();
+        assert_eq!(
+            senders,
+            vec![
+                MemberId::new(&other_key.verifying_key()),
+                MemberId::new(&member_key.verifying_key())
+            ]
+        );
+        assert_eq!(messages.messages[1].message.time, at(86_404));
+    }
+}
@@ -1,31 +1,50 @@
+pub mod index;
+pub mod invitation;
+
 use crate::room_state::ban::BansV1;
+use crate::room_state::configuration::{Configuration, EvictionPolicy};
+use crate::room_state::member::index::{InviteTree, MemberIndex};
+use crate::room_state::member::invitation::{AuthorizedInvitation, InvitationId, RedeemedInvitation};
 use crate::room_state::ChatRoomParametersV1;
-use crate::util::{sign_struct, truncated_base32, verify_struct};
+use crate::util::{sign_struct, truncated_base32, verify_struct, Signable, SignatureBatch};
 use crate::ChatRoomStateV1;
 use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
 use freenet_scaffold::util::{fast_hash, FastHash};
-use freenet_scaffold::ComposableState;
+use freenet_scaffold::{ComposableState, DeltaReport};
 use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
 use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::hash::{Hash, Hasher};
+use std::time::SystemTime;
 
 #[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
 pub struct MembersV1 {
     pub members: Vec<AuthorizedMember>,
+    /// Signed records of members who voluntarily left the room. These are retained so that
+    /// the departure propagates through merges, and so that the invite chains of anyone the
+    /// departed member invited can still be verified.
+    #[serde(default)]
+    pub departed: Vec<AuthorizedMemberLeave>,
+    /// A record of every invitation redeemed so far, one per invitation. These outlive the
+    /// member who redeemed it, so that a removed member can't rejoin with the same token.
+    #[serde(default)]
+    pub redeemed: Vec<RedeemedInvitation>,
 }
 
 impl Default for MembersV1 {
     fn default() -> Self {
         MembersV1 {
             members: Vec::new(),
+            departed: Vec::new(),
+            redeemed: Vec::new(),
         }
     }
 }
 
 impl ComposableState for MembersV1 {
     type ParentState = ChatRoomStateV1;
-    type Summary = HashSet<MemberId>;
+    type Summary = MembersSummary;
     type Delta = MembersDelta;
     type Parameters = ChatRoomParametersV1;
 
@@ -34,9 +53,39 @@ impl ComposableState for MembersV1 {
         parent_state: &Self::ParentState,
         parameters: &Self::Parameters,
     ) -> Result<(), String> {
-        if self.members.is_empty() {
-            return Ok(());
+        let departed_ids = self.departed_ids();
+        let index = MemberIndex::new(self, parameters);
+
+        // Every link of an invite chain is itself a member or departure, so checking each
+        // one's own invite signature and the shape of the invite tree covers all chains
+        let mut batch = SignatureBatch::new();
+        for leave in &self.departed {
+            leave.verify_room_binding(parameters)?;
+            batch.add(
+                &leave.leave,
+                &leave.signature,
+                &leave.leave.member.member.member_vk,
+                || format!("leave of member {:?}", leave.member_id()),
+            );
+            Self::add_invite_signatures_to_batch(&leave.leave.member, &index, &mut batch)?;
+        }
+        for member in &self.members {
+            Self::add_invite_signatures_to_batch(member, &index, &mut batch)?;
+        }
+        let mut redeemed_by = HashMap::with_capacity(self.redeemed.len());
+        for record in &self.redeemed {
+            parameters.check_room_binding(record.member.owner_member_id)?;
+            if redeemed_by.insert(record.id(), record.member.id()).is_some() {
+                return Err(format!(
+                    "Invitation {:?} has more than one redemption record",
+                    record.id()
+                ));
+            }
+            batch.add(&record.member, &record.signature, &record.invitation_vk, || {
+                format!("redemption of invitation {:?}", record.id())
+            });
         }
+        batch.verify()?;
 
         if self.members.len() > parent_state.configuration.configuration.max_members {
             return Err(format!(
@@ -47,14 +96,36 @@ impl ComposableState for MembersV1 {
         }
 
         let owner_id = parameters.owner_id();
+        let mut invitation_ids = HashSet::new();
         for member in &self.members {
+            if let Some(invitation_id) = member.invitation_id() {
+                if !invitation_ids.insert(invitation_id)
+                    || redeemed_by.get(&invitation_id).is_some_and(|id| *id != member.member.id())
+                {
+                    return Err(format!(
+                        "Invitation {:?} has been redeemed more than once",
+                        invitation_id
+                    ));
+                }
+            }
             if member.member.id() == owner_id {
                 return Err("Owner should not be included in the members list".to_string());
             }
+            parameters.check_room_binding(member.member.owner_member_id)?;
             if member.member.member_vk == parameters.owner {
                 return Err("Member cannot have the same verifying key as the room owner".to_string());
             }
-            self.get_invite_chain(member, parameters)?;
+            if departed_ids.contains(&member.member.id()) {
+                return Err(format!(
+                    "Member {:?} has left the room but is still in the members list",
+                    member.member.id()
+                ));
+            }
+        }
+        let configuration = &parent_state.configuration.configuration;
+        let violations = Self::invite_limit_violations(&index, configuration)?;
+        if let Some((_, e)) = violations.into_iter().next() {
+            return Err(e);
         }
         Ok(())
     }
@@ -63,7 +134,11 @@ impl ComposableState for MembersV1 {
         _parent_state: &Self::ParentState,
         _parameters: &Self::Parameters,
     ) -> Self::Summary {
-        self.members.iter().map(|m| m.member.id()).collect()
+        MembersSummary {
+            members: self.members.iter().map(|m| m.member.id()).collect(),
+            departed: self.departed_ids(),
+            redeemed: self.redeemed.iter().map(|r| (r.id(), r.member.id())).collect(),
+        }
     }
 
     fn delta(
@@ -75,13 +150,29 @@ impl ComposableState for MembersV1 {
         let added = self
             .members
             .iter()
-            .filter(|m| !old_state_summary.contains(&m.member.id()))
+            .filter(|m| !old_state_summary.members.contains(&m.member.id()))
+            .cloned()
+            .collect::<Vec<_>>();
+        let departed = self
+            .departed
+            .iter()
+            .filter(|l| !old_state_summary.departed.contains(&l.member_id()))
+            .cloned()
+            .collect::<Vec<_>>();
+        let redeemed = self
+            .redeemed
+            .iter()
+            .filter(|r| !old_state_summary.redeemed.contains(&(r.id(), r.member.id())))
             .cloned()
             .collect::<Vec<_>>();
-        if added.is_empty() {
+        if added.is_empty() && departed.is_empty() && redeemed.is_empty() {
             None
         } else {
-            Some(MembersDelta { added })
+            Some(MembersDelta {
+                added,
+                departed,
+                redeemed,
+            })
         }
     }
 
@@ -90,51 +181,285 @@ impl ComposableState for MembersV1 {
         parent_state: &Self::ParentState,
         parameters: &Self::Parameters,
         delta: &Self::Delta,
-    ) -> Result<(), String> {
+    ) -> Result<DeltaReport, String> {
         let max_members = parent_state.configuration.configuration.max_members;
+        let mut index = MemberIndex::new(self, parameters);
+        let mut report = DeltaReport::default();
 
-        // Verify that all new members have valid invites
-        for member in &delta.added {
-            self.verify_member_invite(member, parent_state, parameters)?;
+        // Apply departures first so that a departed member can't be re-added by the same delta
+        for leave in &delta.departed {
+            if index.is_departed(&leave.member_id()) {
+                continue;
+            }
+            let item = format!("leave of member {:?}", leave.member_id());
+            match self.apply_leave(leave, &mut index, parameters) {
+                Ok(()) => report.applied(item),
+                Err(e) => report.rejected(item, e),
+            }
+        }
+
+        // Concurrent redemptions of one invitation are resolved in favour of the lowest member
+        // ID, so that every peer ends up with the same record whatever order they arrive in
+        for record in &delta.redeemed {
+            let invitation_id = record.id();
+            let existing = self.redeemed.iter().position(|r| r.id() == invitation_id);
+            if existing.is_some_and(|i| self.redeemed[i].member.id() <= record.member.id()) {
+                continue;
+            }
+            let item = format!("redemption of invitation {:?}", invitation_id);
+            let result = parameters
+                .check_room_binding(record.member.owner_member_id)
+                .and_then(|()| record.verify_signature());
+            match result {
+                Ok(()) => {
+                    if let Some(i) = existing {
+                        self.redeemed.remove(i);
+                    }
+                    self.redeemed.push(record.clone());
+                    report.applied(item);
+                }
+                Err(e) => report.rejected(item, e),
+            }
         }
 
-        // Add new members, but don't exceed max_members
+        // Add new members, but don't exceed max_members. Each invite is verified against an
+        // index that includes the members added so far, so invite limits apply across the delta.
+        let banned_ids: HashSet<MemberId> =
+            parent_state.bans.0.iter().map(|b| b.ban.banned_user).collect();
+        // The redemption records take precedence over members who are yet to be removed
+        // because someone else's redemption of their invitation won
+        let mut redeemed: HashMap<InvitationId, MemberId> = self
+            .members
+            .iter()
+            .filter_map(|m| Some((m.invitation_id()?, m.member.id())))
+            .chain(self.redeemed.iter().map(|r| (r.id(), r.member.id())))
+            .collect();
         for member in &delta.added {
-            if self.members.len() < max_members {
-                self.members.push(member.clone());
-            } else {
-                break;
+            let member_id = member.member.id();
+            if index.is_member(&member_id) || index.is_departed(&member_id) {
+                // Already a member, or left for good so stale copies of the member are ignored
+                continue;
+            }
+            let item = format!("member {:?}", member_id);
+            if let Some(invitation_id) = member.invitation_id() {
+                if redeemed.get(&invitation_id).is_some_and(|id| *id != member_id) {
+                    report.rejected(
+                        item,
+                        format!("Invitation {:?} has already been redeemed", invitation_id),
+                    );
+                    continue;
+                }
+            }
+            if let Err(e) = Self::verify_member_invite(member, &index, parent_state, parameters) {
+                report.rejected(item, e);
+                continue;
+            }
+            if index.chain_contains(&member.member, &banned_ids) {
+                report.rejected(item, "Member or one of their inviters is banned");
+                continue;
+            }
+            if self.members.len() >= max_members {
+                report.rejected(item, "The room is full");
+                continue;
+            }
+            self.members.push(member.clone());
+            index.insert(&member.member, false);
+            if let Some(record) = RedeemedInvitation::from_member(member) {
+                redeemed.insert(record.id(), member_id);
+                if !self.redeemed.iter().any(|r| r.id() == record.id()) {
+                    self.redeemed.push(record);
+                }
             }
+            report.applied(item);
         }
+        self.remove_superseded_redemptions(parameters);
 
         // Remove banned members
         self.remove_banned_members(&parent_state.bans, parameters);
 
-        // Remove excess members (this should not be necessary, but we'll keep it as a safeguard)
-        self.remove_excess_members(parameters, max_members);
+        self.enforce_limits(parent_state, parameters);
 
-        Ok(())
+        Ok(report)
     }
 }
 
 impl MembersV1 {
+    /// Verifies a new member's invite against an index of the members already verified
     fn verify_member_invite(
-        &self,
         member: &AuthorizedMember,
-        _parent_state: &ChatRoomStateV1,
+        index: &MemberIndex,
+        parent_state: &ChatRoomStateV1,
         parameters: &ChatRoomParametersV1,
     ) -> Result<(), String> {
+        let configuration = &parent_state.configuration.configuration;
+        parameters.check_room_binding(member.member.owner_member_id)?;
         if member.member.invited_by == parameters.owner_id() {
             // Member was invited by the owner, verify signature against owner's key
             member
                 .verify_signature(&parameters.owner)
                 .map_err(|e| format!("Invalid signature for member invited by owner: {}", e))?;
         } else {
-            // Member was invited by another member, verify the invite chain
-            self.get_invite_chain(member, parameters)?;
+            // Member was invited by another member, whose own invite chain is already known
+            // to be valid, so only this member's signature and place in the tree are checked.
+            // Members who have left remain links of existing chains but can't invite anyone new.
+            if index.is_departed(&member.member.invited_by) {
+                return Err(format!(
+                    "Inviter {:?} has left the room and can't invite new members",
+                    member.member.invited_by
+                ));
+            }
+            let inviter_vk = index.inviter_vk(&member.member).ok_or_else(|| {
+                format!(
+                    "Inviter {:?} not found for member {:?}",
+                    member.member.invited_by,
+                    member.member.id()
+                )
+            })?;
+            member.verify_signature(inviter_vk).map_err(|e| {
+                format!("Invalid signature for member {:?}: {}", member.member.id(), e)
+            })?;
+            let chain_len = index.chain_len(&member.member)?;
+
+            if chain_len + 1 > configuration.max_invite_depth {
+                return Err(format!(
+                    "Invite chain for member {:?} exceeds the maximum depth of {}",
+                    member.member.id(),
+                    configuration.max_invite_depth
+                ));
+            }
+
+            let invites = index.invite_count(&member.member.invited_by, &member.member.id());
+            if invites >= configuration.max_invites_per_member {
+                return Err(format!(
+                    "Member {:?} has already invited the maximum of {} members",
+                    member.member.invited_by, configuration.max_invites_per_member
+                ));
+            }
         }
         Ok(())
     }
+
+    /// Verifies a new leave record, then removes the member and remembers the departure
+    fn apply_leave(
+        &mut self,
+        leave: &AuthorizedMemberLeave,
+        index: &mut MemberIndex,
+        parameters: &ChatRoomParametersV1,
+    ) -> Result<(), String> {
+        let member_id = leave.member_id();
+        leave.verify_room_binding(parameters)?;
+        leave.verify_signature()?;
+        let inviter_vk = index.inviter_vk(&leave.leave.member.member).ok_or_else(|| {
+            format!(
+                "Inviter {:?} not found for departing member {:?}",
+                leave.leave.member.member.invited_by, member_id
+            )
+        })?;
+        leave.leave.member.verify_signature(inviter_vk)?;
+        index.chain_len(&leave.leave.member.member)?;
+
+        self.members.retain(|m| m.member.id() != member_id);
+        self.departed.push(leave.clone());
+        index.insert(&leave.leave.member.member, true);
+        index.mark_departed(member_id);
+        Ok(())
+    }
+
+    /// Removes members whose invitation is recorded as redeemed by someone else, along with
+    /// everyone downstream of them
+    fn remove_superseded_redemptions(&mut self, parameters: &ChatRoomParametersV1) {
+        let redeemed_by: HashMap<InvitationId, MemberId> =
+            self.redeemed.iter().map(|r| (r.id(), r.member.id())).collect();
+        let superseded: Vec<MemberId> = self
+            .members
+            .iter()
+            .filter(|m| {
+                m.invitation_id()
+                    .and_then(|id| redeemed_by.get(&id))
+                    .is_some_and(|id| *id != m.member.id())
+            })
+            .map(|m| m.member.id())
+            .collect();
+        if superseded.is_empty() {
+            return;
+        }
+        let index = MemberIndex::new(self, parameters);
+        let mut removed = HashSet::new();
+        for member_id in superseded {
+            removed.insert(member_id);
+            removed.extend(index.downstream(member_id));
+        }
+        self.members.retain(|m| !removed.contains(&m.member.id()));
+    }
+
+    /// Evicts members beyond `max_members` according to the room's eviction policy. This
+    /// also brings the members back within a `max_members` the owner has since lowered.
+    /// Departure records are never dropped, otherwise a stale copy of a departed member could
+    /// be merged back into the room.
+    pub fn enforce_limits(&mut self, parent_state: &ChatRoomStateV1, parameters: &ChatRoomParametersV1) {
+        let max_members = parent_state.configuration.configuration.max_members;
+        self.remove_invite_limit_violations(&parent_state.configuration.configuration, parameters);
+        self.remove_excess_members(parent_state, parameters, max_members);
+    }
+
+    /// The current members whose invite breaks `max_invite_depth` or
+    /// `max_invites_per_member`, with the reason. New invites are checked as they're applied,
+    /// so this only finds anyone after the owner lowers the limits. Of an inviter's invitees,
+    /// those with the lowest IDs are the ones within the limit.
+    fn invite_limit_violations(
+        index: &MemberIndex,
+        configuration: &Configuration,
+    ) -> Result<Vec<(MemberId, String)>, String> {
+        let chain_lengths = index.chain_lengths()?;
+        let mut violations = Vec::new();
+        for (member_id, chain_len) in &chain_lengths {
+            if index.is_member(member_id) && chain_len + 1 > configuration.max_invite_depth {
+                violations.push((
+                    *member_id,
+                    format!(
+                        "Invite chain for member {:?} exceeds the maximum depth of {}",
+                        member_id, configuration.max_invite_depth
+                    ),
+                ));
+            }
+        }
+        for inviter in index.inviters() {
+            let mut invitees = index.invitees(inviter);
+            invitees.sort();
+            for invitee in invitees.into_iter().skip(configuration.max_invites_per_member) {
+                violations.push((
+                    invitee,
+                    format!(
+                        "Member {:?} has invited more than the maximum of {} members",
+                        inviter, configuration.max_invites_per_member
+                    ),
+                ));
+            }
+        }
+        Ok(violations)
+    }
+
+    /// Removes the members found by `invite_limit_violations`, along with everyone downstream
+    /// of them whose invite chain would otherwise be broken
+    fn remove_invite_limit_violations(
+        &mut self,
+        configuration: &Configuration,
+        parameters: &ChatRoomParametersV1,
+    ) {
+        let index = MemberIndex::new(self, parameters);
+        let Ok(violations) = Self::invite_limit_violations(&index, configuration) else {
+            // Invalid chains are reported by verify
+            return;
+        };
+        let mut removed = HashSet::new();
+        for (member_id, _) in violations {
+            removed.insert(member_id);
+            removed.extend(index.downstream(member_id));
+        }
+        if !removed.is_empty() {
+            self.members.retain(|m| !removed.contains(&m.member.id()));
+        }
+    }
 }
 
 impl MembersV1 {
@@ -142,49 +467,225 @@ impl MembersV1 {
         self.members.iter().map(|m| (m.member.id(), m)).collect()
     }
 
+    /// Everyone who may take part in the room, the owner followed by the members
+    pub fn participants<'a>(
+        &'a self,
+        parameters: &'a ChatRoomParametersV1,
+    ) -> impl Iterator<Item = Participant<'a>> {
+        std::iter::once(Participant::Owner(&parameters.owner))
+            .chain(self.members.iter().map(Participant::Member))
+    }
+
+    /// Everyone who may take part in the room, including the owner, by member ID
+    pub fn participants_by_member_id<'a>(
+        &'a self,
+        parameters: &'a ChatRoomParametersV1,
+    ) -> HashMap<MemberId, Participant<'a>> {
+        self.participants(parameters).map(|p| (p.id(), p)).collect()
+    }
+
+    /// Everyone whose messages may be in the room, by member ID. These are the participants,
+    /// and members who have left for the messages they wrote before leaving. Members who are
+    /// banned after leaving aren't included, so that the ban still clears their messages.
+    pub fn authors_by_member_id<'a>(
+        &'a self,
+        bans: &BansV1,
+        parameters: &'a ChatRoomParametersV1,
+    ) -> HashMap<MemberId, Author<'a>> {
+        let banned_ids: HashSet<MemberId> = bans.0.iter().map(|b| b.ban.banned_user).collect();
+        self.departed
+            .iter()
+            .filter(|l| !banned_ids.contains(&l.member_id()))
+            .map(|l| (l.member_id(), Author::Departed(l)))
+            .chain(
+                self.participants(parameters)
+                    .map(|p| (p.id(), Author::Participant(p))),
+            )
+            .collect()
+    }
+
+    /// The IDs of members who have voluntarily left the room
+    pub fn departed_ids(&self) -> HashSet<MemberId> {
+        self.departed.iter().map(|l| l.member_id()).collect()
+    }
+
+    /// The room's invite tree, rooted at the owner. Members who have left the room are
+    /// included, marked as departed, so that the members they invited stay in place.
+    pub fn invite_tree(&self, parameters: &ChatRoomParametersV1) -> InviteTree {
+        MemberIndex::new(self, parameters).tree(parameters.owner_id())
+    }
+
+    /// The current members directly invited by a participant
+    pub fn invitees(&self, member_id: MemberId, parameters: &ChatRoomParametersV1) -> Vec<MemberId> {
+        MemberIndex::new(self, parameters).invitees(&member_id)
+    }
+
+    /// The current members invited by a participant, directly or through others. These are
+    /// the members that banning the participant would also remove.
+    pub fn descendants(
+        &self,
+        member_id: MemberId,
+        parameters: &ChatRoomParametersV1,
+    ) -> HashSet<MemberId> {
+        MemberIndex::new(self, parameters).downstream(member_id)
+    }
+
+    /// The number of current members below a participant in the invite tree
+    pub fn subtree_size(&self, member_id: MemberId, parameters: &ChatRoomParametersV1) -> usize {
+        self.descendants(member_id, parameters).len()
+    }
+
+    /// How many invites separate a participant from the owner, counted as for
+    /// `max_invite_depth`: the owner is at depth 0 and members who have left aren't counted.
+    /// `None` if the participant isn't in the room or their invite chain is broken.
+    pub fn invite_depth(
+        &self,
+        member_id: MemberId,
+        parameters: &ChatRoomParametersV1,
+    ) -> Option<usize> {
+        let index = MemberIndex::new(self, parameters);
+        let ancestors = index.ancestors(member_id)?;
+        Some(
+            ancestors
+                .iter()
+                .filter(|id| index.is_member(id))
+                .count(),
+        )
+    }
+
+    /// The lowest participant with both members in their part of the invite tree, which may
+    /// be one of the two members themselves. `None` if either isn't in the room.
+    pub fn lowest_common_inviter(
+        &self,
+        a: MemberId,
+        b: MemberId,
+        parameters: &ChatRoomParametersV1,
+    ) -> Option<MemberId> {
+        let index = MemberIndex::new(self, parameters);
+        let a_ancestors = index.ancestors(a)?;
+        let b_ancestors: HashSet<MemberId> = index.ancestors(b)?.into_iter().collect();
+        a_ancestors.into_iter().find(|id| {
+            b_ancestors.contains(id) && (*id == parameters.owner_id() || index.is_member(id))
+        })
+    }
+
+    /// A current member followed by their inviters up to one invited by the owner, including
+    /// inviters who have since left the room. Attached to a ban, it proves the banner's
+    /// authority over the banned member. `None` if the member isn't in the room or their
+    /// invite chain is broken.
+    pub fn invite_path(
+        &self,
+        member_id: MemberId,
+        parameters: &ChatRoomParametersV1,
+    ) -> Option<Vec<AuthorizedMember>> {
+        let known_members: HashMap<MemberId, &AuthorizedMember> = self
+            .departed
+            .iter()
+            .map(|l| &l.leave.member)
+            .chain(&self.members)
+            .map(|m| (m.member.id(), m))
+            .collect();
+        let ancestors = MemberIndex::new(self, parameters).ancestors(member_id)?;
+        ancestors
+            .iter()
+            .filter(|id| **id != parameters.owner_id())
+            .map(|id| known_members.get(id).map(|m| (*m).clone()))
+            .collect()
+    }
+
+    /// Looks up the inviter of a member among the current members. Members who have left
+    /// aren't returned, as they may no longer invite or ban anyone.
+    pub fn get_inviter(&self, member: &Member) -> Option<&AuthorizedMember> {
+        self.members
+            .iter()
+            .find(|m| m.member.id() == member.invited_by)
+    }
+
+    /// Looks up the next link up a member's invite chain, which may be a member who has left
+    fn get_chain_link(&self, member: &Member) -> Option<&AuthorizedMember> {
+        self.get_inviter(member).or_else(|| {
+            self.departed
+                .iter()
+                .map(|l| &l.leave.member)
+                .find(|m| m.member.id() == member.invited_by)
+        })
+    }
+
     /// Checks if there are any banned members or members downstream of banned members in the invite chain
     pub fn has_banned_members(&self, bans_v1: &BansV1, parameters: &ChatRoomParametersV1) -> bool {
         self.check_banned_members(bans_v1, parameters).is_some()
     }
 
     /// Removes banned members or members downstream of banned members in the invite chain
-    fn remove_banned_members(&mut self, bans_v1: &BansV1, _parameters: &ChatRoomParametersV1) {
+    pub fn remove_banned_members(&mut self, bans_v1: &BansV1, parameters: &ChatRoomParametersV1) {
+        let index = MemberIndex::new(self, parameters);
         let mut banned_ids = HashSet::new();
         for ban in &bans_v1.0 {
             banned_ids.insert(ban.ban.banned_user);
-            banned_ids.extend(self.get_downstream_members(ban.ban.banned_user));
+            banned_ids.extend(index.downstream(ban.ban.banned_user));
         }
         self.members
             .retain(|m| !banned_ids.contains(&m.member.id()));
     }
 
-    /// Helper function to get all downstream members of a given member
-    fn get_downstream_members(&self, member_id: MemberId) -> HashSet<MemberId> {
-        let mut downstream = HashSet::new();
-        let mut to_check = vec![member_id];
-        while let Some(current) = to_check.pop() {
-            for member in &self.members {
-                if member.member.invited_by == current {
-                    downstream.insert(member.member.id());
-                    to_check.push(member.member.id());
-                }
-            }
+    /// If the number of members exceeds the specified limit, remove members according to the
+    /// room's eviction policy until the limit is satisfied. Only members with nobody still in
+    /// the room below them in the invite tree are candidates, including anyone invited through
+    /// members who have left, so that no remaining invite chain is broken. Evicted members
+    /// aren't banned and can be invited again.
+    fn remove_excess_members(
+        &mut self,
+        parent_state: &ChatRoomStateV1,
+        parameters: &ChatRoomParametersV1,
+        max_members: usize,
+    ) {
+        if self.members.len() <= max_members {
+            return;
+        }
+        let eviction_policy = parent_state.configuration.configuration.eviction_policy;
+        let mut last_active: HashMap<MemberId, SystemTime> = HashMap::new();
+        for message in &parent_state.recent_messages.messages {
+            let time = last_active
+                .entry(message.message.author)
+                .or_insert(message.message.time);
+            *time = (*time).max(message.message.time);
         }
-        downstream
-    }
 
-    /// If the number of members exceeds the specified limit, remove the members with the longest invite chains
-    /// until the limit is satisfied
-    fn remove_excess_members(&mut self, parameters: &ChatRoomParametersV1, max_members: usize) {
+        // Evicting a leaf doesn't change anyone else's chain, so lengths are computed once
+        let mut index = MemberIndex::new(self, parameters);
+        let chain_lengths: HashMap<MemberId, usize> = self
+            .members
+            .iter()
+            .map(|m| (m.member.id(), index.chain_len(&m.member).unwrap_or(usize::MAX)))
+            .collect();
         while self.members.len() > max_members {
-            let member_to_remove = self
+            let upstream = index.upstream_of_members();
+            let candidates = self
                 .members
                 .iter()
-                .max_by_key(|m| self.get_invite_chain(m, parameters).unwrap().len())
-                .unwrap()
-                .member
-                .id();
+                .filter(|m| !upstream.contains(&m.member.id()));
+            let chain_len = |m: &AuthorizedMember| chain_lengths[&m.member.id()];
+            let member_to_remove = match eviction_policy {
+                EvictionPolicy::LongestInviteChain => candidates
+                    .max_by_key(|m| (chain_len(m), m.member.id()))
+                    .unwrap()
+                    .member
+                    .id(),
+                EvictionPolicy::LeastRecentlyActive => candidates
+                    .min_by_key(|m| {
+                        (
+                            last_active.get(&m.member.id()).copied(),
+                            Reverse(chain_len(m)),
+                            m.member.id(),
+                        )
+                    })
+                    .unwrap()
+                    .member
+                    .id(),
+            };
             self.members.retain(|m| m.member.id() != member_to_remove);
+            // Kept as a link, so that whoever invited the evicted member may be evicted next
+            index.mark_departed(member_to_remove);
         }
     }
 
@@ -194,16 +695,10 @@ impl MembersV1 {
         bans_v1: &BansV1,
         parameters: &ChatRoomParametersV1,
     ) -> Option<HashSet<MemberId>> {
+        let index = MemberIndex::new(self, parameters);
         let mut banned_ids = HashSet::new();
-        for m in &self.members {
-            if let Ok(invite_chain) = self.get_invite_chain(m, parameters) {
-                if invite_chain
-                    .iter()
-                    .any(|m| bans_v1.0.iter().any(|b| b.ban.banned_user == m.member.id()))
-                {
-                    banned_ids.insert(m.member.id());
-                }
-            }
+        for ban in &bans_v1.0 {
+            banned_ids.extend(index.downstream(ban.ban.banned_user));
         }
         if banned_ids.is_empty() {
             None
@@ -212,11 +707,15 @@ impl MembersV1 {
         }
     }
 
+    /// Walks the invite chain of a member back to the owner, verifying each signature along the
+    /// way. Members who have left the room remain verifiable links in the chain but are not
+    /// included in the result, so their invitees are effectively re-parented to their inviter.
     pub fn get_invite_chain(
         &self,
         member: &AuthorizedMember,
         parameters: &ChatRoomParametersV1,
     ) -> Result<Vec<AuthorizedMember>, String> {
+        let departed_ids = self.departed_ids();
         let mut invite_chain = Vec::new();
         let mut current_member = member;
         let owner_id = parameters.owner_id();
@@ -251,9 +750,7 @@ impl MembersV1 {
                 break;
             } else {
                 let inviter = self
-                    .members
-                    .iter()
-                    .find(|m| m.member.id() == current_member.member.invited_by)
+                    .get_chain_link(&current_member.member)
                     .ok_or_else(|| {
                         format!(
                             "Inviter {:?} not found for member {:?}",
@@ -272,24 +769,65 @@ impl MembersV1 {
                         )
                     })?;
 
-                invite_chain.push(inviter.clone());
+                if !departed_ids.contains(&inviter.member.id()) {
+                    invite_chain.push(inviter.clone());
+                }
                 current_member = inviter;
             }
         }
 
         Ok(invite_chain)
     }
+
+    /// Adds the signature(s) on a member's invite to a batch, checked against the inviter's key
+    fn add_invite_signatures_to_batch<'a>(
+        member: &'a AuthorizedMember,
+        index: &MemberIndex,
+        batch: &mut SignatureBatch<'a>,
+    ) -> Result<(), String> {
+        match index.inviter_vk(&member.member) {
+            Some(inviter_vk) => member.add_signatures_to_batch(inviter_vk, batch),
+            // Either a member who left before their inviter was removed, whose own leave
+            // signature still stands, or a broken chain reported by the invite chain check
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
+pub struct MembersSummary {
+    pub members: HashSet<MemberId>,
+    pub departed: HashSet<MemberId>,
+    pub redeemed: HashSet<(InvitationId, MemberId)>,
 }
 
 #[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
 pub struct MembersDelta {
     added: Vec<AuthorizedMember>,
+    departed: Vec<AuthorizedMemberLeave>,
+    #[serde(default)]
+    redeemed: Vec<RedeemedInvitation>,
+}
+
+impl MembersDelta {
+    pub fn new(added: Vec<AuthorizedMember>, departed: Vec<AuthorizedMemberLeave>) -> Self {
+        Self {
+            added,
+            departed,
+            redeemed: Vec::new(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
 pub struct AuthorizedMember {
     pub member: Member,
+    /// The inviter's signature over `member`, or if `invitation` is set, the signature of the
+    /// invitation's single-use key
     pub signature: Signature,
+    /// Set when the member joined by redeeming an invitation token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub invitation: Option<AuthorizedInvitation>,
 }
 
 impl AuthorizedMember {
@@ -302,12 +840,66 @@ impl AuthorizedMember {
         Self {
             member: member.clone(),
             signature: sign_struct(&member, inviter_signing_key),
+            invitation: None,
         }
     }
 
     pub fn verify_signature(&self, inviter_vk: &VerifyingKey) -> Result<(), String> {
-        verify_struct(&self.member, &self.signature, inviter_vk)
-            .map_err(|e| format!("Invalid signature: {}", e))
+        match &self.invitation {
+            None => verify_struct(&self.member, &self.signature, inviter_vk)
+                .map_err(|e| format!("Invalid signature: {}", e)),
+            Some(invitation) => {
+                invitation.verify_signature(inviter_vk)?;
+                self.check_invitation_matches(invitation)?;
+                verify_struct(
+                    &self.member,
+                    &self.signature,
+                    &invitation.invitation.invitation_vk,
+                )
+                .map_err(|e| format!("Invalid signature: {}", e))
+            }
+        }
+    }
+
+    /// Adds the signatures `verify_signature` checks to a batch instead of checking them
+    pub fn add_signatures_to_batch<'a>(
+        &'a self,
+        inviter_vk: &VerifyingKey,
+        batch: &mut SignatureBatch<'a>,
+    ) -> Result<(), String> {
+        let describe = || format!("member {:?}", self.member.id());
+        match &self.invitation {
+            None => batch.add(&self.member, &self.signature, inviter_vk, describe),
+            Some(invitation) => {
+                self.check_invitation_matches(invitation)?;
+                batch.add(&invitation.invitation, &invitation.signature, inviter_vk, || {
+                    format!("invitation of member {:?}", self.member.id())
+                });
+                batch.add(
+                    &self.member,
+                    &self.signature,
+                    &invitation.invitation.invitation_vk,
+                    describe,
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn check_invitation_matches(&self, invitation: &AuthorizedInvitation) -> Result<(), String> {
+        if invitation.invitation.invited_by != self.member.invited_by
+            || invitation.invitation.owner_member_id != self.member.owner_member_id
+        {
+            return Err(format!(
+                "Invitation for member {:?} does not match the member",
+                self.member.id()
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn invitation_id(&self) -> Option<InvitationId> {
+        self.invitation.as_ref().map(|i| i.id())
     }
 }
 
@@ -324,6 +916,10 @@ pub struct Member {
     pub member_vk: VerifyingKey,
 }
 
+impl Signable for Member {
+    const DOMAIN: &'static str = "member";
+}
+
 impl fmt::Debug for Member {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Member")
@@ -350,6 +946,113 @@ impl Member {
     }
 }
 
+/// Someone who may take part in a room. The owner isn't stored in [`MembersV1`] because
+/// their key is part of the room parameters, but can set member info and post messages
+/// just like a member.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Participant<'a> {
+    Owner(&'a VerifyingKey),
+    Member(&'a AuthorizedMember),
+}
+
+impl Participant<'_> {
+    pub fn id(&self) -> MemberId {
+        MemberId::new(self.verifying_key())
+    }
+
+    pub fn verifying_key(&self) -> &VerifyingKey {
+        match self {
+            Participant::Owner(owner) => owner,
+            Participant::Member(member) => &member.member.member_vk,
+        }
+    }
+
+    pub fn is_owner(&self) -> bool {
+        matches!(self, Participant::Owner(_))
+    }
+
+    /// The member record, `None` for the owner
+    pub fn member(&self) -> Option<&AuthorizedMember> {
+        match self {
+            Participant::Owner(_) => None,
+            Participant::Member(member) => Some(member),
+        }
+    }
+}
+
+/// Someone whose messages may be in the room, see [`MembersV1::authors_by_member_id`]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Author<'a> {
+    Participant(Participant<'a>),
+    Departed(&'a AuthorizedMemberLeave),
+}
+
+impl Author<'_> {
+    pub fn verifying_key(&self) -> &VerifyingKey {
+        match self {
+            Author::Participant(participant) => participant.verifying_key(),
+            Author::Departed(leave) => &leave.leave.member.member.member_vk,
+        }
+    }
+
+    /// Whether a message dated `time` may be theirs, members who have left can't write
+    /// anything dated after they left
+    pub fn could_write_at(&self, time: SystemTime) -> bool {
+        match self {
+            Author::Participant(_) => true,
+            Author::Departed(leave) => time <= leave.leave.left_at,
+        }
+    }
+}
+
+/// A member's signed statement that they are leaving the room. It carries the member's own
+/// `AuthorizedMember` so that the invite chains of anyone they invited remain verifiable.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
+pub struct MemberLeave {
+    pub owner_member_id: MemberId,
+    pub member: AuthorizedMember,
+    pub left_at: SystemTime,
+}
+
+impl Signable for MemberLeave {
+    const DOMAIN: &'static str = "member-leave";
+}
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
+pub struct AuthorizedMemberLeave {
+    pub leave: MemberLeave,
+    pub signature: Signature,
+}
+
+impl AuthorizedMemberLeave {
+    pub fn new(leave: MemberLeave, member_signing_key: &SigningKey) -> Self {
+        assert_eq!(
+            leave.member.member.member_vk,
+            member_signing_key.verifying_key(),
+            "Only the member themselves can sign a leave"
+        );
+        Self {
+            signature: sign_struct(&leave, member_signing_key),
+            leave,
+        }
+    }
+
+    pub fn verify_signature(&self) -> Result<(), String> {
+        verify_struct(&self.leave, &self.signature, &self.leave.member.member.member_vk)
+            .map_err(|e| format!("Invalid leave signature: {}", e))
+    }
+
+    /// Checks that both the leave and the member record it carries belong to this room
+    pub fn verify_room_binding(&self, parameters: &ChatRoomParametersV1) -> Result<(), String> {
+        parameters.check_room_binding(self.leave.owner_member_id)?;
+        parameters.check_room_binding(self.leave.member.member.owner_member_id)
+    }
+
+    pub fn member_id(&self) -> MemberId {
+        self.leave.member.member.id()
+    }
+}
+
 impl fmt::Display for MemberId {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", truncated_base32(&self.0 .0.to_le_bytes()))
@@ -389,6 +1092,7 @@ mod tests {
 
         let members = MembersV1 {
             members: vec![authorized_member1.clone(), authorized_member2.clone()],
+            ..Default::default()
         };
 
         println!("Member1 ID: {:?}", member1.id());
@@ -399,6 +1103,8 @@ mod tests {
         parent_state.configuration.configuration.max_members = 3;
         let parameters = ChatRoomParametersV1 {
             owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
         };
 
         let result = members.verify(&parent_state, &parameters);
@@ -414,6 +1120,7 @@ mod tests {
         let authorized_owner = AuthorizedMember::new(owner_member, &owner_signing_key);
         let members_with_owner = MembersV1 {
             members: vec![authorized_owner, authorized_member1, authorized_member2],
+            ..Default::default()
         };
         let result_with_owner = members_with_owner.verify(&parent_state, &parameters);
         println!("Verification result with owner: {:?}", result_with_owner);
@@ -438,17 +1145,21 @@ mod tests {
 
         let members = MembersV1 {
             members: vec![authorized_member1, authorized_member2],
+            ..Default::default()
         };
 
         let parent_state = ChatRoomStateV1::default();
         let parameters = ChatRoomParametersV1 {
             owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
         };
 
         let summary = members.summarize(&parent_state, &parameters);
-        assert_eq!(summary.len(), 2);
-        assert!(summary.contains(&member1.id()));
-        assert!(summary.contains(&member2.id()));
+        assert_eq!(summary.members.len(), 2);
+        assert!(summary.members.contains(&member1.id()));
+        assert!(summary.members.contains(&member2.id()));
+        assert!(summary.departed.is_empty());
     }
 
     #[test]
@@ -467,15 +1178,19 @@ mod tests {
 
         let old_members = MembersV1 {
             members: vec![authorized_member1.clone(), authorized_member2.clone()],
+            ..Default::default()
         };
 
         let new_members = MembersV1 {
             members: vec![authorized_member1.clone(), authorized_member3.clone()],
+            ..Default::default()
         };
 
         let parent_state = ChatRoomStateV1::default();
         let parameters = ChatRoomParametersV1 {
             owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
         };
 
         let old_summary = old_members.summarize(&parent_state, &parameters);
@@ -503,10 +1218,13 @@ mod tests {
 
         let original_members = MembersV1 {
             members: vec![authorized_member1.clone(), authorized_member2.clone()],
+            ..Default::default()
         };
 
         let delta = MembersDelta {
             added: vec![authorized_member3.clone()],
+            departed: vec![],
+            redeemed: vec![],
         };
 
         let mut parent_state = ChatRoomStateV1::default();
@@ -514,6 +1232,8 @@ mod tests {
 
         let parameters = ChatRoomParametersV1 {
             owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
         };
 
         let mut modified_members = original_members.clone();
@@ -560,6 +1280,7 @@ mod tests {
         let invalid_member2 = AuthorizedMember {
             member: member2.clone(),
             signature: Signature::from_bytes(&[0; 64]),
+            invitation: None,
         };
         assert!(invalid_member2
             .verify_signature(&member1.member_vk)
@@ -588,11 +1309,14 @@ mod tests {
 
         let members = MembersV1 {
             members: vec![authorized_member],
+            ..Default::default()
         };
 
         let parent_state = ChatRoomStateV1::default();
         let parameters = ChatRoomParametersV1 {
             owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
         };
 
         let result = members.verify(&parent_state, &parameters);
@@ -617,11 +1341,14 @@ mod tests {
 
         let members = MembersV1 {
             members: vec![authorized_member1, authorized_member2, authorized_member3],
+            ..Default::default()
         };
 
         let parent_state = ChatRoomStateV1::default();
         let parameters = ChatRoomParametersV1 {
             owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
         };
 
         let result = members.verify(&parent_state, &parameters);
@@ -648,10 +1375,13 @@ mod tests {
 
         let members = MembersV1 {
             members: vec![authorized_member1, authorized_member2.clone()],
+            ..Default::default()
         };
 
         let parameters = ChatRoomParametersV1 {
             owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
         };
 
         let result = members.get_invite_chain(&authorized_member3, &parameters);
@@ -675,6 +1405,7 @@ mod tests {
                 circular_authorized_member1.clone(),
                 circular_authorized_member2,
             ],
+            ..Default::default()
         };
 
         let result = circular_members.get_invite_chain(&circular_authorized_member1, &parameters);
@@ -690,6 +1421,7 @@ mod tests {
         let orphan_authorized_member = AuthorizedMember {
             member: orphan_member,
             signature: Signature::from_bytes(&[0; 64]), // Use a dummy signature
+            invitation: None,
         };
 
         let result = members.get_invite_chain(&orphan_authorized_member, &parameters);
@@ -703,6 +1435,7 @@ mod tests {
         let invalid_authorized_member = AuthorizedMember {
             member: invalid_member,
             signature: Signature::from_bytes(&[0; 64]),
+            invitation: None,
         };
 
         let result = members.get_invite_chain(&invalid_authorized_member, &parameters);
@@ -726,10 +1459,13 @@ mod tests {
 
         let members = MembersV1 {
             members: vec![authorized_member1, authorized_member2, authorized_member3],
+            ..Default::default()
         };
 
         let parameters = ChatRoomParametersV1 {
             owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
         };
 
         // Test case 1: No banned members
@@ -770,10 +1506,13 @@ mod tests {
                 authorized_member3.clone(),
                 authorized_member4.clone(),
             ],
+            ..Default::default()
         };
 
         let parameters = ChatRoomParametersV1 {
             owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
         };
 
         // Test case 1: No banned members
@@ -816,6 +1555,7 @@ mod tests {
                 authorized_member3,
                 authorized_member4,
             ],
+            ..Default::default()
         };
         let banned_member = UserBan {
             owner_member_id: owner_id,
@@ -860,18 +1600,21 @@ mod tests {
 
         let mut members = MembersV1 {
             members: vec![authorized_member1, authorized_member2, authorized_member3],
+            ..Default::default()
         };
 
         let parameters = ChatRoomParametersV1 {
             owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
         };
 
         // Test case 1: No excess members
-        members.remove_excess_members(&parameters, 3);
+        members.remove_excess_members(&ChatRoomStateV1::default(), &parameters, 3);
         assert_eq!(members.members.len(), 3);
 
         // Test case 2: One excess member
-        members.remove_excess_members(&parameters, 2);
+        members.remove_excess_members(&ChatRoomStateV1::default(), &parameters, 2);
         assert_eq!(members.members.len(), 2);
         assert!(members
             .members
@@ -901,6 +1644,7 @@ mod tests {
 
         let members = MembersV1 {
             members: vec![authorized_member1.clone(), authorized_member2.clone()],
+            ..Default::default()
         };
 
         let members_map = members.members_by_member_id();
@@ -916,6 +1660,39 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_participants_by_member_id() {
+        let owner_signing_key = SigningKey::generate(&mut OsRng);
+        let owner_verifying_key = owner_signing_key.verifying_key();
+        let owner_id = MemberId::new(&owner_verifying_key);
+        let parameters = ChatRoomParametersV1 {
+            owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
+        };
+
+        let (member, _) = create_test_member(owner_id, owner_id);
+        let authorized_member = AuthorizedMember::new(member.clone(), &owner_signing_key);
+        let members = MembersV1 {
+            members: vec![authorized_member.clone()],
+            departed: vec![],
+            redeemed: vec![],
+        };
+
+        let participants = members.participants_by_member_id(&parameters);
+        assert_eq!(participants.len(), 2);
+
+        let owner = participants[&owner_id];
+        assert!(owner.is_owner());
+        assert_eq!(owner.verifying_key(), &owner_verifying_key);
+        assert_eq!(owner.member(), None);
+
+        let participant = participants[&member.id()];
+        assert!(!participant.is_owner());
+        assert_eq!(participant.verifying_key(), &member.member_vk);
+        assert_eq!(participant.member(), Some(&authorized_member));
+    }
+
     #[test]
     fn test_members_apply_delta_complex() {
         let owner_signing_key = SigningKey::generate(&mut OsRng);
@@ -934,6 +1711,7 @@ mod tests {
 
         let mut members = MembersV1 {
             members: vec![authorized_member1.clone(), authorized_member2.clone()],
+            ..Default::default()
         };
 
         let mut parent_state = ChatRoomStateV1::default();
@@ -941,11 +1719,15 @@ mod tests {
 
         let parameters = ChatRoomParametersV1 {
             owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
         };
 
         // Test applying delta that would exceed max_members
         let delta = MembersDelta {
             added: vec![authorized_member3.clone(), authorized_member4.clone()],
+            departed: vec![],
+            redeemed: vec![],
         };
 
         let result = members.apply_delta(&parent_state, &parameters, &delta);
@@ -971,6 +1753,8 @@ mod tests {
         // Test applying delta with already existing member
         let delta = MembersDelta {
             added: vec![authorized_member2.clone()],
+            departed: vec![],
+            redeemed: vec![],
         };
 
         let result = members.apply_delta(&parent_state, &parameters, &delta);
@@ -992,21 +1776,24 @@ mod tests {
 
         let mut members = MembersV1 {
             members: vec![authorized_member1.clone(), authorized_member2.clone()],
+            ..Default::default()
         };
 
         let parameters = ChatRoomParametersV1 {
             owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
         };
 
         // Test with max_members set to 0
-        members.remove_excess_members(&parameters, 0);
+        members.remove_excess_members(&ChatRoomStateV1::default(), &parameters, 0);
         assert_eq!(members.members.len(), 0);
 
         // Reset members
         members.members = vec![authorized_member1.clone(), authorized_member2.clone()];
 
         // Test with max_members greater than current number of members
-        members.remove_excess_members(&parameters, 3);
+        members.remove_excess_members(&ChatRoomStateV1::default(), &parameters, 3);
         assert_eq!(members.members.len(), 2);
     }
 
@@ -1034,10 +1821,12 @@ mod tests {
 
         let parameters = ChatRoomParametersV1 {
             owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
         };
 
         // Test with empty member list
-        let empty_members = MembersV1 { members: vec![] };
+        let empty_members = MembersV1::default();
         assert!(empty_members.verify(&parent_state, &parameters).is_ok());
 
         // Test with maximum allowed number of members
@@ -1049,6 +1838,7 @@ mod tests {
 
         let max_members = MembersV1 {
             members: vec![authorized_member1, authorized_member2],
+            ..Default::default()
         };
         assert!(max_members.verify(&parent_state, &parameters).is_ok());
 
@@ -1062,6 +1852,7 @@ mod tests {
 
         let invalid_members = MembersV1 {
             members: vec![invalid_authorized_member],
+            ..Default::default()
         };
         assert!(invalid_members.verify(&parent_state, &parameters).is_err());
     }
@@ -1082,6 +1873,7 @@ mod tests {
 
         let members = MembersV1 {
             members: vec![authorized_owner_member],
+            ..Default::default()
         };
 
         let mut parent_state = ChatRoomStateV1::default();
@@ -1089,10 +1881,674 @@ mod tests {
 
         let parameters = ChatRoomParametersV1 {
             owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
         };
 
         let result = members.verify(&parent_state, &parameters);
         assert!(result.is_err(), "Room owner should not be allowed in the members list");
         assert!(result.unwrap_err().contains("Owner should not be included in the members list"));
     }
+
+    fn create_test_leave(member: &AuthorizedMember, signing_key: &SigningKey) -> AuthorizedMemberLeave {
+        AuthorizedMemberLeave::new(
+            MemberLeave {
+                owner_member_id: member.member.owner_member_id,
+                member: member.clone(),
+                left_at: SystemTime::now(),
+            },
+            signing_key,
+        )
+    }
+
+    #[test]
+    fn test_member_leave() {
+        let owner_signing_key = SigningKey::generate(&mut OsRng);
+        let owner_verifying_key = VerifyingKey::from(&owner_signing_key);
+        let owner_id = MemberId::new(&owner_verifying_key);
+
+        let (member1, member1_signing_key) = create_test_member(owner_id, owner_id);
+        let (member2, _) = create_test_member(owner_id, member1.id());
+
+        let authorized_member1 = AuthorizedMember::new(member1.clone(), &owner_signing_key);
+        let authorized_member2 = AuthorizedMember::new(member2.clone(), &member1_signing_key);
+
+        let mut members = MembersV1 {
+            members: vec![authorized_member1.clone(), authorized_member2.clone()],
+            ..Default::default()
+        };
+
+        let mut parent_state = ChatRoomStateV1::default();
+        parent_state.configuration.configuration.max_members = 3;
+        let parameters = ChatRoomParametersV1 {
+            owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
+        };
+
+        let leave = create_test_leave(&authorized_member1, &member1_signing_key);
+        let delta = MembersDelta::new(vec![], vec![leave.clone()]);
+        assert!(members
+            .apply_delta(&parent_state, &parameters, &delta)
+            .is_ok());
+
+        // member1 is gone but the member they invited stays, re-parented to the owner
+        assert_eq!(members.members.len(), 1);
+        assert_eq!(members.members[0].member.id(), member2.id());
+        assert_eq!(members.departed, vec![leave.clone()]);
+        assert!(members.verify(&parent_state, &parameters).is_ok());
+        assert!(members
+            .get_invite_chain(&authorized_member2, &parameters)
+            .unwrap()
+            .is_empty());
+
+        // Applying the same leave again is a no-op
+        assert!(members
+            .apply_delta(&parent_state, &parameters, &delta)
+            .is_ok());
+        assert_eq!(members.departed.len(), 1);
+
+        // A stale copy of the departed member can't be re-added
+        let delta = MembersDelta::new(vec![authorized_member1.clone()], vec![]);
+        assert!(members
+            .apply_delta(&parent_state, &parameters, &delta)
+            .is_ok());
+        assert_eq!(members.members.len(), 1);
+
+        // The departed member remains a link of member2's chain, but can't invite anyone new
+        assert!(members.get_inviter(&member2).is_none());
+        let (member3, _) = create_test_member(owner_id, member1.id());
+        let authorized_member3 = AuthorizedMember::new(member3, &member1_signing_key);
+        let delta = MembersDelta::new(vec![authorized_member3], vec![]);
+        let report = members.apply_delta(&parent_state, &parameters, &delta).unwrap();
+        assert!(report.rejected[0].1.contains("has left the room"));
+        assert_eq!(members.members.len(), 1);
+
+        // The departure is remembered however low max_members goes
+        parent_state.configuration.configuration.max_members = 0;
+        members.enforce_limits(&parent_state, &parameters);
+        assert_eq!(members.departed, vec![leave.clone()]);
+        parent_state.configuration.configuration.max_members = 3;
+
+        // Listing a departed member as a member fails verification
+        let mut invalid_members = members.clone();
+        invalid_members.members.push(authorized_member1);
+        assert!(invalid_members.verify(&parent_state, &parameters).is_err());
+    }
+
+    #[test]
+    fn test_member_leave_invalid_signature() {
+        let owner_signing_key = SigningKey::generate(&mut OsRng);
+        let owner_verifying_key = VerifyingKey::from(&owner_signing_key);
+        let owner_id = MemberId::new(&owner_verifying_key);
+
+        let (member1, _) = create_test_member(owner_id, owner_id);
+        let authorized_member1 = AuthorizedMember::new(member1, &owner_signing_key);
+
+        let mut members = MembersV1 {
+            members: vec![authorized_member1.clone()],
+            ..Default::default()
+        };
+
+        let parent_state = ChatRoomStateV1::default();
+        let parameters = ChatRoomParametersV1 {
+            owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
+        };
+
+        // Someone other than the member can't make them leave
+        let forged_leave = AuthorizedMemberLeave {
+            leave: MemberLeave {
+                owner_member_id: owner_id,
+                member: authorized_member1.clone(),
+                left_at: SystemTime::now(),
+            },
+            signature: Signature::from_bytes(&[0; 64]),
+        };
+        let delta = MembersDelta::new(vec![], vec![forged_leave]);
+        let report = members.apply_delta(&parent_state, &parameters, &delta).unwrap();
+        assert!(report.rejected[0].1.contains("Invalid leave signature"));
+        assert_eq!(members.members.len(), 1);
+    }
+
+    #[test]
+    fn test_member_leave_propagates_through_merge() {
+        let owner_signing_key = SigningKey::generate(&mut OsRng);
+        let owner_verifying_key = VerifyingKey::from(&owner_signing_key);
+        let owner_id = MemberId::new(&owner_verifying_key);
+
+        let (member1, member1_signing_key) = create_test_member(owner_id, owner_id);
+        let authorized_member1 = AuthorizedMember::new(member1, &owner_signing_key);
+
+        let stale_members = MembersV1 {
+            members: vec![authorized_member1.clone()],
+            ..Default::default()
+        };
+        let mut left_members = MembersV1 {
+            departed: vec![create_test_leave(&authorized_member1, &member1_signing_key)],
+            ..Default::default()
+        };
+
+        let parent_state = ChatRoomStateV1::default();
+        let parameters = ChatRoomParametersV1 {
+            owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
+        };
+
+        // Merging the stale state into the one where member1 left doesn't bring them back
+        left_members
+            .merge(&parent_state, &parameters, &stale_members)
+            .unwrap();
+        assert!(left_members.members.is_empty());
+
+        // Merging the departure into the stale state removes them
+        let mut merged = stale_members.clone();
+        merged
+            .merge(&parent_state, &parameters, &left_members)
+            .unwrap();
+        assert!(merged.members.is_empty());
+        assert_eq!(merged, left_members);
+    }
+
+    #[test]
+    fn test_max_invites_per_member() {
+        let owner_signing_key = SigningKey::generate(&mut OsRng);
+        let owner_verifying_key = VerifyingKey::from(&owner_signing_key);
+        let owner_id = MemberId::new(&owner_verifying_key);
+
+        let (member1, member1_signing_key) = create_test_member(owner_id, owner_id);
+        let authorized_member1 = AuthorizedMember::new(member1.clone(), &owner_signing_key);
+        let invitees = (0..3)
+            .map(|_| {
+                let (invitee, _) = create_test_member(owner_id, member1.id());
+                AuthorizedMember::new(invitee, &member1_signing_key)
+            })
+            .collect::<Vec<_>>();
+
+        let mut parent_state = ChatRoomStateV1::default();
+        parent_state.configuration.configuration.max_invites_per_member = 2;
+        let parameters = ChatRoomParametersV1 {
+            owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
+        };
+
+        // The limit applies across members added by the same delta
+        let mut members = MembersV1 {
+            members: vec![authorized_member1.clone()],
+            ..Default::default()
+        };
+        let delta = MembersDelta::new(invitees.clone(), vec![]);
+        let report = members.apply_delta(&parent_state, &parameters, &delta).unwrap();
+        assert_eq!(report.applied.len(), 2);
+        assert!(report.rejected[0].1.contains("maximum of 2 members"));
+        assert_eq!(members.members.len(), 3, "Only invites within the limit are applied");
+
+        // Redelivering the applied invites changes nothing
+        let delta = MembersDelta::new(invitees[..2].to_vec(), vec![]);
+        assert_eq!(
+            members.apply_delta(&parent_state, &parameters, &delta),
+            Ok(DeltaReport::default())
+        );
+        assert_eq!(members.members.len(), 3);
+
+        let delta = MembersDelta::new(vec![invitees[2].clone()], vec![]);
+        let report = members.apply_delta(&parent_state, &parameters, &delta).unwrap();
+        assert!(!report.is_fully_applied());
+        assert_eq!(members.members.len(), 3);
+
+        // The owner isn't subject to the limit
+        let owner_invitees = (0..3)
+            .map(|_| {
+                let (invitee, _) = create_test_member(owner_id, owner_id);
+                AuthorizedMember::new(invitee, &owner_signing_key)
+            })
+            .collect::<Vec<_>>();
+        let delta = MembersDelta::new(owner_invitees, vec![]);
+        assert!(members
+            .apply_delta(&parent_state, &parameters, &delta)
+            .is_ok());
+        assert_eq!(members.members.len(), 6);
+        assert!(members.verify(&parent_state, &parameters).is_ok());
+
+        // Lowering the limit fails verification until the excess invitee is removed
+        parent_state.configuration.configuration.max_invites_per_member = 1;
+        let err = members.verify(&parent_state, &parameters).unwrap_err();
+        assert!(err.contains("more than the maximum of 1 members"), "{}", err);
+        members.enforce_limits(&parent_state, &parameters);
+        assert_eq!(members.members.len(), 5);
+        assert!(members.verify(&parent_state, &parameters).is_ok());
+    }
+
+    #[test]
+    fn test_max_invite_depth() {
+        let owner_signing_key = SigningKey::generate(&mut OsRng);
+        let owner_verifying_key = VerifyingKey::from(&owner_signing_key);
+        let owner_id = MemberId::new(&owner_verifying_key);
+
+        let (member1, member1_signing_key) = create_test_member(owner_id, owner_id);
+        let (member2, member2_signing_key) = create_test_member(owner_id, member1.id());
+        let (member3, _) = create_test_member(owner_id, member2.id());
+
+        let authorized_member1 = AuthorizedMember::new(member1, &owner_signing_key);
+        let authorized_member2 = AuthorizedMember::new(member2, &member1_signing_key);
+        let authorized_member3 = AuthorizedMember::new(member3, &member2_signing_key);
+
+        let mut parent_state = ChatRoomStateV1::default();
+        parent_state.configuration.configuration.max_invite_depth = 2;
+        let parameters = ChatRoomParametersV1 {
+            owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
+        };
+
+        let mut members = MembersV1 {
+            members: vec![authorized_member1],
+            ..Default::default()
+        };
+
+        // Depth 2 is allowed
+        let delta = MembersDelta::new(vec![authorized_member2], vec![]);
+        assert!(members
+            .apply_delta(&parent_state, &parameters, &delta)
+            .is_ok());
+
+        // Depth 3 is not
+        let delta = MembersDelta::new(vec![authorized_member3], vec![]);
+        let report = members.apply_delta(&parent_state, &parameters, &delta).unwrap();
+        assert!(report.rejected[0].1.contains("exceeds the maximum depth of 2"));
+        assert_eq!(members.members.len(), 2);
+        assert!(members.verify(&parent_state, &parameters).is_ok());
+
+        // Lowering the limit fails verification until the deeper member is removed
+        parent_state.configuration.configuration.max_invite_depth = 1;
+        let err = members.verify(&parent_state, &parameters).unwrap_err();
+        assert!(err.contains("exceeds the maximum depth of 1"), "{}", err);
+        members.enforce_limits(&parent_state, &parameters);
+        assert_eq!(members.members.len(), 1);
+        assert!(members.verify(&parent_state, &parameters).is_ok());
+    }
+
+    #[test]
+    fn test_redeemed_invitation_is_single_use() {
+        use crate::room_state::member::invitation::InvitationToken;
+
+        let owner_signing_key = SigningKey::generate(&mut OsRng);
+        let owner_verifying_key = VerifyingKey::from(&owner_signing_key);
+        let owner_id = MemberId::new(&owner_verifying_key);
+
+        let (member1, member1_signing_key) = create_test_member(owner_id, owner_id);
+        let authorized_member1 = AuthorizedMember::new(member1, &owner_signing_key);
+
+        let mut members = MembersV1 {
+            members: vec![authorized_member1],
+            ..Default::default()
+        };
+
+        let parent_state = ChatRoomStateV1::default();
+        let parameters = ChatRoomParametersV1 {
+            owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
+        };
+
+        // member1 hands out a token without knowing who will redeem it
+        let token = InvitationToken::new(owner_id, &member1_signing_key);
+        let invitee_key = SigningKey::generate(&mut OsRng);
+        let redeemed = token.redeem(invitee_key.verifying_key());
+
+        let delta = MembersDelta::new(vec![redeemed.clone()], vec![]);
+        assert!(members
+            .apply_delta(&parent_state, &parameters, &delta)
+            .is_ok());
+        assert_eq!(members.members.len(), 2);
+        assert_eq!(
+            members
+                .get_invite_chain(&redeemed, &parameters)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(members.verify(&parent_state, &parameters).is_ok());
+
+        // Redeeming the same token for a second key is rejected
+        let second_redemption = token.redeem(SigningKey::generate(&mut OsRng).verifying_key());
+        let delta = MembersDelta::new(vec![second_redemption.clone()], vec![]);
+        let report = members.apply_delta(&parent_state, &parameters, &delta).unwrap();
+        assert!(report.rejected[0].1.contains("already been redeemed"));
+
+        let mut invalid_members = members.clone();
+        invalid_members.members.push(second_redemption.clone());
+        assert!(invalid_members.verify(&parent_state, &parameters).is_err());
+
+        // The redemption is remembered after the invitee is evicted, and reaches peers that
+        // never saw the invitee
+        let mut small_room = parent_state.clone();
+        small_room.configuration.configuration.max_members = 1;
+        members.enforce_limits(&small_room, &parameters);
+        assert_eq!(members.members.len(), 1);
+        let mut peer = MembersV1 {
+            members: members.members.clone(),
+            ..Default::default()
+        };
+        let summary = peer.summarize(&parent_state, &parameters);
+        let delta = members.delta(&parent_state, &parameters, &summary).unwrap();
+        peer.apply_delta(&parent_state, &parameters, &delta).unwrap();
+        assert_eq!(peer, members);
+        for members in [&mut members, &mut peer] {
+            let delta = MembersDelta::new(vec![second_redemption.clone()], vec![]);
+            let report = members.apply_delta(&parent_state, &parameters, &delta).unwrap();
+            assert!(report.rejected[0].1.contains("already been redeemed"));
+            assert!(members.verify(&parent_state, &parameters).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_concurrent_redemptions_converge() {
+        use crate::room_state::member::invitation::InvitationToken;
+
+        let owner_signing_key = SigningKey::generate(&mut OsRng);
+        let owner_id = MemberId::new(&owner_signing_key.verifying_key());
+        let parent_state = ChatRoomStateV1::default();
+        let parameters = ChatRoomParametersV1 {
+            owner: owner_signing_key.verifying_key(),
+            admins: None,
+            recovery: None,
+        };
+
+        // Two peers each accept a different redemption of the same token
+        let token = InvitationToken::new(owner_id, &owner_signing_key);
+        let redemptions = [
+            token.redeem(SigningKey::generate(&mut OsRng).verifying_key()),
+            token.redeem(SigningKey::generate(&mut OsRng).verifying_key()),
+        ];
+        let peers = redemptions.clone().map(|redemption| {
+            let mut members = MembersV1::default();
+            let delta = MembersDelta::new(vec![redemption], vec![]);
+            members.apply_delta(&parent_state, &parameters, &delta).unwrap();
+            members
+        });
+
+        // Whichever way they merge, the lowest member ID keeps the invitation
+        let merge = |into: &MembersV1, from: &MembersV1| {
+            let mut merged = into.clone();
+            let summary = into.summarize(&parent_state, &parameters);
+            if let Some(delta) = from.delta(&parent_state, &parameters, &summary) {
+                merged.apply_delta(&parent_state, &parameters, &delta).unwrap();
+            }
+            merged
+        };
+        let a = merge(&peers[0], &peers[1]);
+        let b = merge(&peers[1], &peers[0]);
+        let winner = redemptions.iter().map(|m| m.member.id()).min().unwrap();
+        for merged in [&a, &b] {
+            assert_eq!(merged.members.len(), 1);
+            assert_eq!(merged.members[0].member.id(), winner);
+            assert_eq!(merged.redeemed.len(), 1);
+            assert!(merged.verify(&parent_state, &parameters).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_remove_excess_members_least_recently_active() {
+        use crate::room_state::message::{AuthorizedMessageV1, MessageV1};
+        use std::time::Duration;
+
+        let owner_signing_key = SigningKey::generate(&mut OsRng);
+        let owner_verifying_key = VerifyingKey::from(&owner_signing_key);
+        let owner_id = MemberId::new(&owner_verifying_key);
+
+        let (member1, member1_signing_key) = create_test_member(owner_id, owner_id);
+        let (member2, _) = create_test_member(owner_id, owner_id);
+        let (member3, member3_signing_key) = create_test_member(owner_id, owner_id);
+        let (member4, _) = create_test_member(owner_id, member1.id());
+
+        let authorized_member1 = AuthorizedMember::new(member1.clone(), &owner_signing_key);
+        let authorized_member2 = AuthorizedMember::new(member2.clone(), &owner_signing_key);
+        let authorized_member3 = AuthorizedMember::new(member3.clone(), &owner_signing_key);
+        let authorized_member4 = AuthorizedMember::new(member4.clone(), &member1_signing_key);
+
+        let mut parent_state = ChatRoomStateV1::default();
+        parent_state.configuration.configuration.eviction_policy =
+            EvictionPolicy::LeastRecentlyActive;
+        let now = SystemTime::now();
+        let message = |author: MemberId, time: SystemTime, signing_key: &SigningKey| {
+            AuthorizedMessageV1::new(
+                MessageV1 {
+                    room_owner: owner_id,
+                    author,
+                    time,
+                    content: "Hello".to_string(),
+                    encrypted_content: None,
+                },
+                signing_key,
+            )
+        };
+        parent_state.recent_messages.messages = vec![
+            message(member1.id(), now - Duration::from_secs(100), &member1_signing_key),
+            message(member3.id(), now - Duration::from_secs(200), &member3_signing_key),
+            message(member3.id(), now - Duration::from_secs(10), &member3_signing_key),
+        ];
+
+        let parameters = ChatRoomParametersV1 {
+            owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
+        };
+
+        let mut members = MembersV1 {
+            members: vec![
+                authorized_member1,
+                authorized_member2,
+                authorized_member3,
+                authorized_member4,
+            ],
+            ..Default::default()
+        };
+
+        // member2 and member4 have never posted, member4 has the longer invite chain
+        members.remove_excess_members(&parent_state, &parameters, 3);
+        let remaining = members.members_by_member_id();
+        assert!(!remaining.contains_key(&member4.id()));
+
+        members.remove_excess_members(&parent_state, &parameters, 2);
+        let remaining = members.members_by_member_id();
+        assert!(!remaining.contains_key(&member2.id()));
+
+        // member1 posted longer ago than member3's latest message
+        members.remove_excess_members(&parent_state, &parameters, 1);
+        let remaining = members.members_by_member_id();
+        assert!(remaining.contains_key(&member3.id()));
+        assert_eq!(remaining.len(), 1);
+    }
+
+    #[test]
+    fn test_remove_excess_members_keeps_inviters() {
+        let owner_signing_key = SigningKey::generate(&mut OsRng);
+        let owner_verifying_key = VerifyingKey::from(&owner_signing_key);
+        let owner_id = MemberId::new(&owner_verifying_key);
+
+        let (member1, member1_signing_key) = create_test_member(owner_id, owner_id);
+        let (member2, _) = create_test_member(owner_id, member1.id());
+
+        let authorized_member1 = AuthorizedMember::new(member1.clone(), &owner_signing_key);
+        let authorized_member2 = AuthorizedMember::new(member2.clone(), &member1_signing_key);
+
+        let mut parent_state = ChatRoomStateV1::default();
+        parent_state.configuration.configuration.eviction_policy =
+            EvictionPolicy::LeastRecentlyActive;
+        let parameters = ChatRoomParametersV1 {
+            owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
+        };
+
+        let mut members = MembersV1 {
+            members: vec![authorized_member1.clone(), authorized_member2.clone()],
+            ..Default::default()
+        };
+
+        // Neither has posted, but member1 can't be evicted while member2 depends on them
+        members.remove_excess_members(&parent_state, &parameters, 1);
+        assert_eq!(members.members, vec![authorized_member1]);
+        assert!(members.verify(&parent_state, &parameters).is_ok());
+
+        // An evicted member isn't banned and can be invited again
+        let delta = MembersDelta::new(vec![authorized_member2], vec![]);
+        assert!(members
+            .apply_delta(&parent_state, &parameters, &delta)
+            .is_ok());
+        assert_eq!(members.members.len(), 2);
+    }
+
+    #[test]
+    fn test_removing_the_inviter_of_a_departed_member() {
+        use crate::room_state::message::{AuthorizedMessageV1, MessageV1};
+
+        let owner_signing_key = SigningKey::generate(&mut OsRng);
+        let owner_verifying_key = VerifyingKey::from(&owner_signing_key);
+        let owner_id = MemberId::new(&owner_verifying_key);
+        let parameters = ChatRoomParametersV1 {
+            owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
+        };
+
+        // owner -> x -> a -> b, where b has left
+        let (x, x_signing_key) = create_test_member(owner_id, owner_id);
+        let (a, a_signing_key) = create_test_member(owner_id, x.id());
+        let (b, b_signing_key) = create_test_member(owner_id, a.id());
+        let authorized_x = AuthorizedMember::new(x.clone(), &owner_signing_key);
+        let authorized_a = AuthorizedMember::new(a.clone(), &x_signing_key);
+        let authorized_b = AuthorizedMember::new(b.clone(), &a_signing_key);
+        let members = MembersV1 {
+            members: vec![authorized_x.clone(), authorized_a.clone()],
+            departed: vec![create_test_leave(&authorized_b, &b_signing_key)],
+            ..Default::default()
+        };
+        let no_change = MembersDelta::new(vec![], vec![]);
+
+        // Banning a
+        let parent_state = ChatRoomStateV1 {
+            bans: BansV1(vec![AuthorizedUserBan::new(
+                UserBan {
+                    owner_member_id: owner_id,
+                    banned_at: SystemTime::now(),
+                    banned_user: a.id(),
+                },
+                owner_id,
+                &owner_signing_key,
+            )]),
+            ..Default::default()
+        };
+        let mut banned = members.clone();
+        banned.apply_delta(&parent_state, &parameters, &no_change).unwrap();
+        assert_eq!(banned.members, vec![authorized_x.clone()]);
+        assert_eq!(banned.departed.len(), 1);
+        assert!(banned.verify(&parent_state, &parameters).is_ok());
+
+        // Evicting x and a
+        let mut parent_state = ChatRoomStateV1::default();
+        parent_state.configuration.configuration.max_members = 0;
+        let mut evicted = members.clone();
+        evicted.apply_delta(&parent_state, &parameters, &no_change).unwrap();
+        assert!(evicted.members.is_empty());
+        assert!(evicted.verify(&parent_state, &parameters).is_ok());
+
+        // Trimming a after the owner lowers the invite depth
+        let mut parent_state = ChatRoomStateV1::default();
+        parent_state.configuration.configuration.max_invite_depth = 1;
+        let mut trimmed = members.clone();
+        trimmed.apply_delta(&parent_state, &parameters, &no_change).unwrap();
+        assert_eq!(trimmed.members, vec![authorized_x.clone()]);
+        assert!(trimmed.verify(&parent_state, &parameters).is_ok());
+
+        // A member invited through b keeps a from being evicted, even though a is the least
+        // recently active
+        let (c, c_signing_key) = create_test_member(owner_id, b.id());
+        let authorized_c = AuthorizedMember::new(c.clone(), &b_signing_key);
+        let mut members = members;
+        members.members.push(authorized_c.clone());
+        let mut parent_state = ChatRoomStateV1::default();
+        parent_state.configuration.configuration.max_members = 2;
+        parent_state.configuration.configuration.eviction_policy =
+            EvictionPolicy::LeastRecentlyActive;
+        parent_state.recent_messages.messages = vec![AuthorizedMessageV1::new(
+            MessageV1 {
+                room_owner: owner_id,
+                author: c.id(),
+                time: SystemTime::now(),
+                content: "Hello".to_string(),
+                encrypted_content: None,
+            },
+            &c_signing_key,
+        )];
+        members.apply_delta(&parent_state, &parameters, &no_change).unwrap();
+        assert_eq!(members.members, vec![authorized_x, authorized_a]);
+        assert!(members.verify(&parent_state, &parameters).is_ok());
+
+        // But a current member whose inviter is gone still breaks the chain
+        members.members.push(authorized_c);
+        members.members.remove(1);
+        let err = members.verify(&parent_state, &parameters).unwrap_err();
+        assert!(err.contains("not found"), "{}", err);
+    }
+
+    #[test]
+    fn test_invite_tree_queries() {
+        let owner_signing_key = SigningKey::generate(&mut OsRng);
+        let owner_id = MemberId::new(&owner_signing_key.verifying_key());
+        let parameters = ChatRoomParametersV1 {
+            owner: owner_signing_key.verifying_key(),
+            admins: None,
+            recovery: None,
+        };
+
+        // owner -> a -> b (left) -> c, a -> d, and owner -> e
+        let (a, a_key) = create_test_member(owner_id, owner_id);
+        let (b, b_key) = create_test_member(owner_id, a.id());
+        let (c, _) = create_test_member(owner_id, b.id());
+        let (d, _) = create_test_member(owner_id, a.id());
+        let (e, _) = create_test_member(owner_id, owner_id);
+        let authorized_b = AuthorizedMember::new(b.clone(), &a_key);
+        let members = MembersV1 {
+            members: vec![
+                AuthorizedMember::new(a.clone(), &owner_signing_key),
+                AuthorizedMember::new(c.clone(), &b_key),
+                AuthorizedMember::new(d.clone(), &a_key),
+                AuthorizedMember::new(e.clone(), &owner_signing_key),
+            ],
+            departed: vec![create_test_leave(&authorized_b, &b_key)],
+            redeemed: vec![],
+        };
+
+        let tree = members.invite_tree(&parameters);
+        assert_eq!(tree.member_id, owner_id);
+        assert_eq!(tree.size(), 4);
+        let a_tree = tree.invitees.iter().find(|t| t.member_id == a.id()).unwrap();
+        let b_tree = a_tree.invitees.iter().find(|t| t.member_id == b.id()).unwrap();
+        assert!(b_tree.departed);
+        assert_eq!(b_tree.invitees[0].member_id, c.id());
+        assert_eq!(a_tree.size(), 2);
+
+        assert_eq!(members.invitees(a.id(), &parameters), vec![d.id()]);
+        assert_eq!(
+            members.descendants(a.id(), &parameters),
+            HashSet::from([c.id(), d.id()])
+        );
+        assert_eq!(members.subtree_size(owner_id, &parameters), 4);
+        assert_eq!(members.subtree_size(e.id(), &parameters), 0);
+
+        assert_eq!(members.invite_depth(owner_id, &parameters), Some(0));
+        assert_eq!(members.invite_depth(a.id(), &parameters), Some(1));
+        assert_eq!(members.invite_depth(c.id(), &parameters), Some(2));
+        assert_eq!(members.invite_depth(b.id(), &parameters), None);
+
+        assert_eq!(members.lowest_common_inviter(c.id(), d.id(), &parameters), Some(a.id()));
+        assert_eq!(members.lowest_common_inviter(a.id(), c.id(), &parameters), Some(a.id()));
+        assert_eq!(members.lowest_common_inviter(c.id(), e.id(), &parameters), Some(owner_id));
+        assert_eq!(members.lowest_common_inviter(b.id(), e.id(), &parameters), None);
+    }
 }
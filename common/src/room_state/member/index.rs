@@ -0,0 +1,439 @@
+use crate::room_state::member::{Member, MemberId, MembersV1};
+use crate::room_state::ChatRoomParametersV1;
+use ed25519_dalek::VerifyingKey;
+use std::collections::{HashMap, HashSet};
+
+/// A member and everyone they invited, recursively
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InviteTree {
+    pub member_id: MemberId,
+    /// Whether the member has left the room, their invitees remain in the tree
+    pub departed: bool,
+    pub invitees: Vec<InviteTree>,
+}
+
+impl InviteTree {
+    /// The number of current members below this one in the tree
+    pub fn size(&self) -> usize {
+        self.invitees
+            .iter()
+            .map(|invitee| invitee.size() + usize::from(!invitee.departed))
+            .sum()
+    }
+}
+
+/// An index over the invite tree of a room, covering current members and members who have
+/// left. It's built once per verify or apply_delta pass so that lookups, invite counts and
+/// invite chain checks don't rescan the member list.
+#[derive(Clone, Debug)]
+pub struct MemberIndex {
+    owner_id: MemberId,
+    owner_vk: VerifyingKey,
+    nodes: HashMap<MemberId, IndexedMember>,
+    invitees: HashMap<MemberId, Vec<MemberId>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct IndexedMember {
+    invited_by: MemberId,
+    member_vk: VerifyingKey,
+    departed: bool,
+}
+
+impl MemberIndex {
+    pub fn new(members: &MembersV1, parameters: &ChatRoomParametersV1) -> Self {
+        let mut index = Self {
+            owner_id: parameters.owner_id(),
+            owner_vk: parameters.owner,
+            nodes: HashMap::with_capacity(members.members.len() + members.departed.len()),
+            invitees: HashMap::new(),
+        };
+        for leave in &members.departed {
+            index.insert(&leave.leave.member.member, true);
+        }
+        for member in &members.members {
+            index.insert(&member.member, false);
+        }
+        index
+    }
+
+    /// Adds a member, or updates whether they have departed if already present
+    pub fn insert(&mut self, member: &Member, departed: bool) {
+        let id = member.id();
+        let node = IndexedMember {
+            invited_by: member.invited_by,
+            member_vk: member.member_vk,
+            departed,
+        };
+        if self.nodes.insert(id, node).is_none() {
+            self.invitees.entry(member.invited_by).or_default().push(id);
+        }
+    }
+
+    pub fn mark_departed(&mut self, member_id: MemberId) {
+        if let Some(node) = self.nodes.get_mut(&member_id) {
+            node.departed = true;
+        }
+    }
+
+    /// Whether the member is currently in the room, as opposed to unknown or departed
+    pub fn is_member(&self, member_id: &MemberId) -> bool {
+        self.nodes.get(member_id).is_some_and(|node| !node.departed)
+    }
+
+    pub fn is_departed(&self, member_id: &MemberId) -> bool {
+        self.nodes.get(member_id).is_some_and(|node| node.departed)
+    }
+
+    /// The ID of whoever invited a member, `None` if the member isn't indexed
+    pub fn invited_by(&self, member_id: &MemberId) -> Option<MemberId> {
+        self.nodes.get(member_id).map(|node| node.invited_by)
+    }
+
+    /// The key a member's invite must be signed with, `None` if the inviter is unknown. The
+    /// inviter may have left the room, which is fine for a link of an existing chain, but new
+    /// invites must also check that the inviter [`is_member`](Self::is_member).
+    pub fn inviter_vk(&self, member: &Member) -> Option<&VerifyingKey> {
+        if member.invited_by == self.owner_id {
+            Some(&self.owner_vk)
+        } else {
+            self.nodes.get(&member.invited_by).map(|node| &node.member_vk)
+        }
+    }
+
+    /// Every member, departed or not, who has invited someone other than the owner
+    pub fn inviters(&self) -> impl Iterator<Item = &MemberId> {
+        self.invitees.keys().filter(move |id| **id != self.owner_id)
+    }
+
+    /// The current members directly invited by a member
+    pub fn invitees(&self, member_id: &MemberId) -> Vec<MemberId> {
+        self.invitees
+            .get(member_id)
+            .into_iter()
+            .flatten()
+            .filter(|id| self.is_member(id))
+            .copied()
+            .collect()
+    }
+
+    /// A member followed by their inviters up to and including the owner, `None` if the
+    /// member isn't in the room or their invite chain is broken
+    pub fn ancestors(&self, member_id: MemberId) -> Option<Vec<MemberId>> {
+        if member_id != self.owner_id && !self.is_member(&member_id) {
+            return None;
+        }
+        let mut ancestors = vec![member_id];
+        let mut visited = HashSet::from([member_id]);
+        let mut current = member_id;
+        while current != self.owner_id {
+            current = self.invited_by(&current)?;
+            if !visited.insert(current) {
+                return None;
+            }
+            ancestors.push(current);
+        }
+        Some(ancestors)
+    }
+
+    /// The invite tree below a member, including members who have left so that the members
+    /// they invited stay in place
+    pub fn tree(&self, member_id: MemberId) -> InviteTree {
+        let invitees = self
+            .invitees
+            .get(&member_id)
+            .into_iter()
+            .flatten()
+            .map(|id| self.tree(*id))
+            .collect();
+        InviteTree {
+            member_id,
+            departed: self.is_departed(&member_id),
+            invitees,
+        }
+    }
+
+    /// The number of current members invited by `inviter`, not counting `except`
+    pub fn invite_count(&self, inviter: &MemberId, except: &MemberId) -> usize {
+        self.invitees.get(inviter).map_or(0, |invitees| {
+            invitees
+                .iter()
+                .filter(|id| *id != except && self.is_member(id))
+                .count()
+        })
+    }
+
+    /// Whether a member, or anyone up their invite chain, is one of `ids`
+    pub fn chain_contains(&self, member: &Member, ids: &HashSet<MemberId>) -> bool {
+        if ids.contains(&member.id()) {
+            return true;
+        }
+        let mut visited = HashSet::new();
+        let mut current = member.invited_by;
+        while current != self.owner_id && visited.insert(current) {
+            if ids.contains(&current) {
+                return true;
+            }
+            match self.invited_by(&current) {
+                Some(inviter) => current = inviter,
+                None => break,
+            }
+        }
+        false
+    }
+
+    /// All current members downstream of a member in the invite tree, following the tree
+    /// through members who have left the room
+    pub fn downstream(&self, member_id: MemberId) -> HashSet<MemberId> {
+        let mut downstream = HashSet::new();
+        let mut visited = HashSet::new();
+        let mut to_check = vec![member_id];
+        while let Some(current) = to_check.pop() {
+            for invitee in self.invitees.get(&current).into_iter().flatten() {
+                if visited.insert(*invitee) {
+                    if self.is_member(invitee) {
+                        downstream.insert(*invitee);
+                    }
+                    to_check.push(*invitee);
+                }
+            }
+        }
+        downstream
+    }
+
+    /// Everyone with a current member below them in the invite tree, following the tree
+    /// through members who have left the room
+    pub fn upstream_of_members(&self) -> HashSet<MemberId> {
+        let mut upstream = HashSet::new();
+        for (member_id, node) in &self.nodes {
+            if node.departed {
+                continue;
+            }
+            let mut current = *member_id;
+            while let Some(inviter) = self.invited_by(&current) {
+                if inviter == self.owner_id || !upstream.insert(inviter) {
+                    break;
+                }
+                current = inviter;
+            }
+        }
+        upstream
+    }
+
+    /// The number of current members between a member and the owner in the invite tree,
+    /// which is the length of [`MembersV1::get_invite_chain`]. The member itself doesn't
+    /// need to be in the index, but all of its inviters do.
+    pub fn chain_len(&self, member: &Member) -> Result<usize, String> {
+        let member_id = member.id();
+        if member.invited_by == member_id {
+            return Err(format!("Self-invitation detected for member {:?}", member_id));
+        }
+        let mut chain_len = 0;
+        let mut visited = HashSet::from([member_id]);
+        let mut current = (member_id, member.invited_by);
+        while current.1 != self.owner_id {
+            let (current_id, inviter_id) = current;
+            let inviter = self.nodes.get(&inviter_id).ok_or_else(|| {
+                format!(
+                    "Inviter {:?} not found for member {:?}",
+                    inviter_id, current_id
+                )
+            })?;
+            if !visited.insert(inviter_id) {
+                return Err(format!("Circular invite chain detected for member {:?}", inviter_id));
+            }
+            if inviter.invited_by == inviter_id {
+                return Err(format!("Self-invitation detected for member {:?}", inviter_id));
+            }
+            if !inviter.departed {
+                chain_len += 1;
+            }
+            current = (inviter_id, inviter.invited_by);
+        }
+        Ok(chain_len)
+    }
+
+    /// Validates the invite chain of every indexed member in a single pass, so that each
+    /// link is followed once rather than once per member below it. Returns the chain length
+    /// of each member, as [`MemberIndex::chain_len`] would.
+    ///
+    /// Members who have left keep their record after their inviter is banned or evicted, so
+    /// a departed member's chain may end at an inviter who is gone. Such members are left out
+    /// of the result, and only a current member below them makes the chain invalid.
+    pub fn chain_lengths(&self) -> Result<HashMap<MemberId, usize>, String> {
+        let mut chain_lengths: HashMap<MemberId, usize> = HashMap::with_capacity(self.nodes.len());
+        let mut orphaned: HashSet<MemberId> = HashSet::new();
+        for start in self.nodes.keys() {
+            // Walk up until reaching a member invited by the owner or one whose chain length
+            // is already known, then fill in the chain lengths on the way back down
+            let mut path = Vec::new();
+            let mut on_path = HashSet::new();
+            let mut current = *start;
+            let next_len = loop {
+                if let Some(len) = chain_lengths.get(&current) {
+                    break Some(len + usize::from(!self.nodes[&current].departed));
+                }
+                if orphaned.contains(&current) {
+                    break None;
+                }
+                let node = &self.nodes[&current];
+                if node.invited_by == current {
+                    return Err(format!("Self-invitation detected for member {:?}", current));
+                }
+                if !on_path.insert(current) {
+                    return Err(format!("Circular invite chain detected for member {:?}", current));
+                }
+                path.push(current);
+                if node.invited_by == self.owner_id {
+                    break Some(0);
+                }
+                if !self.nodes.contains_key(&node.invited_by) {
+                    if node.departed {
+                        break None;
+                    }
+                    return Err(format!(
+                        "Inviter {:?} not found for member {:?}",
+                        node.invited_by, current
+                    ));
+                }
+                current = node.invited_by;
+            };
+            let Some(mut next_len) = next_len else {
+                if let Some(id) = path.iter().find(|id| !self.nodes[*id].departed) {
+                    return Err(format!(
+                        "Inviter {:?} not found for member {:?}",
+                        self.nodes[id].invited_by, id
+                    ));
+                }
+                orphaned.extend(path);
+                continue;
+            };
+            while let Some(id) = path.pop() {
+                chain_lengths.insert(id, next_len);
+                next_len += usize::from(!self.nodes[&id].departed);
+            }
+        }
+        Ok(chain_lengths)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::room_state::member::{AuthorizedMember, AuthorizedMemberLeave, MemberLeave};
+    use ed25519_dalek::{Signature, SigningKey};
+    use rand::rngs::OsRng;
+    use std::time::SystemTime;
+
+    fn member(owner_id: MemberId, invited_by: MemberId) -> (AuthorizedMember, SigningKey) {
+        let signing_key = SigningKey::generate(&mut OsRng);
+        let member = AuthorizedMember {
+            member: Member {
+                owner_member_id: owner_id,
+                invited_by,
+                member_vk: signing_key.verifying_key(),
+            },
+            signature: Signature::from_bytes(&[0; 64]),
+            invitation: None,
+        };
+        (member, signing_key)
+    }
+
+    #[test]
+    fn test_chain_lengths_match_invite_chains() {
+        let owner_key = SigningKey::generate(&mut OsRng);
+        let parameters = ChatRoomParametersV1 {
+            owner: owner_key.verifying_key(),
+            admins: None,
+            recovery: None,
+        };
+        let owner_id = parameters.owner_id();
+
+        // owner -> a -> b (departed) -> c -> d, and owner -> e
+        let (a, _) = member(owner_id, owner_id);
+        let (b, b_key) = member(owner_id, a.member.id());
+        let (c, _) = member(owner_id, b.member.id());
+        let (d, _) = member(owner_id, c.member.id());
+        let (e, _) = member(owner_id, owner_id);
+        let leave = AuthorizedMemberLeave::new(
+            MemberLeave {
+                owner_member_id: owner_id,
+                member: b.clone(),
+                left_at: SystemTime::now(),
+            },
+            &b_key,
+        );
+        let members = MembersV1 {
+            members: vec![d.clone(), c.clone(), a.clone(), e.clone()],
+            departed: vec![leave],
+            redeemed: vec![],
+        };
+
+        let index = MemberIndex::new(&members, &parameters);
+        let chain_lengths = index.chain_lengths().unwrap();
+        for m in [&a, &b, &c, &d, &e] {
+            assert_eq!(chain_lengths[&m.member.id()], index.chain_len(&m.member).unwrap());
+        }
+        assert_eq!(chain_lengths[&a.member.id()], 0);
+        assert_eq!(chain_lengths[&b.member.id()], 1);
+        assert_eq!(chain_lengths[&c.member.id()], 1);
+        assert_eq!(chain_lengths[&d.member.id()], 2);
+        assert_eq!(chain_lengths[&e.member.id()], 0);
+
+        assert!(index.is_member(&a.member.id()));
+        assert!(index.is_departed(&b.member.id()));
+        assert_eq!(index.invite_count(&owner_id, &e.member.id()), 1);
+        assert_eq!(
+            index.downstream(a.member.id()),
+            HashSet::from([c.member.id(), d.member.id()])
+        );
+        assert_eq!(index.inviter_vk(&a.member), Some(&parameters.owner));
+        assert_eq!(index.inviter_vk(&c.member), Some(&b.member.member_vk));
+    }
+
+    #[test]
+    fn test_chain_lengths_detect_invalid_chains() {
+        let owner_key = SigningKey::generate(&mut OsRng);
+        let parameters = ChatRoomParametersV1 {
+            owner: owner_key.verifying_key(),
+            admins: None,
+            recovery: None,
+        };
+        let owner_id = parameters.owner_id();
+
+        let (mut a, _) = member(owner_id, owner_id);
+        let (b, _) = member(owner_id, a.member.id());
+        a.member.invited_by = b.member.id();
+        let members = MembersV1 {
+            members: vec![a, b],
+            departed: vec![],
+            redeemed: vec![],
+        };
+        let err = MemberIndex::new(&members, &parameters)
+            .chain_lengths()
+            .unwrap_err();
+        assert!(err.contains("Circular invite chain detected"), "{}", err);
+
+        let (mut selfish, _) = member(owner_id, owner_id);
+        selfish.member.invited_by = selfish.member.id();
+        let members = MembersV1 {
+            members: vec![selfish.clone()],
+            departed: vec![],
+            redeemed: vec![],
+        };
+        let index = MemberIndex::new(&members, &parameters);
+        assert!(index.chain_lengths().unwrap_err().contains("Self-invitation detected"));
+        assert!(index.chain_len(&selfish.member).unwrap_err().contains("Self-invitation detected"));
+
+        let (stranger, _) = member(owner_id, owner_id);
+        let (orphan, _) = member(owner_id, stranger.member.id());
+        let members = MembersV1 {
+            members: vec![orphan.clone()],
+            departed: vec![],
+            redeemed: vec![],
+        };
+        let index = MemberIndex::new(&members, &parameters);
+        assert!(index.chain_lengths().unwrap_err().contains("not found"));
+        assert!(index.chain_len(&orphan.member).unwrap_err().contains("not found"));
+    }
+}
@@ -0,0 +1,171 @@
+use crate::room_state::member::{AuthorizedMember, Member, MemberId};
+use crate::util::{sign_struct, verify_struct, Signable};
+use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
+use freenet_scaffold::util::{fast_hash, FastHash};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+
+/// An invitation to join a room, signed by the inviter. Rather than naming the invitee it names
+/// a single-use `invitation_vk`, so the inviter doesn't need to know the invitee's key up front.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
+pub struct Invitation {
+    pub owner_member_id: MemberId,
+    pub invited_by: MemberId,
+    pub invitation_vk: VerifyingKey,
+}
+
+impl Signable for Invitation {
+    const DOMAIN: &'static str = "invitation";
+}
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
+pub struct AuthorizedInvitation {
+    pub invitation: Invitation,
+    pub signature: Signature,
+}
+
+impl AuthorizedInvitation {
+    pub fn new(invitation: Invitation, inviter_signing_key: &SigningKey) -> Self {
+        assert_eq!(
+            invitation.invited_by,
+            MemberId::new(&inviter_signing_key.verifying_key()),
+            "The invitation's invited_by must match the inviter's signing key"
+        );
+        Self {
+            signature: sign_struct(&invitation, inviter_signing_key),
+            invitation,
+        }
+    }
+
+    pub fn verify_signature(&self, inviter_vk: &VerifyingKey) -> Result<(), String> {
+        verify_struct(&self.invitation, &self.signature, inviter_vk)
+            .map_err(|e| format!("Invalid invitation signature: {}", e))
+    }
+
+    pub fn id(&self) -> InvitationId {
+        InvitationId(fast_hash(&self.invitation.invitation_vk.to_bytes()))
+    }
+}
+
+#[derive(Eq, PartialEq, Hash, Serialize, Deserialize, Clone, Debug, Ord, PartialOrd, Copy)]
+pub struct InvitationId(pub FastHash);
+
+/// Proof that an invitation was redeemed by a member, kept after the member is removed so
+/// that the invitation can't be redeemed again. It's checked against the invitation's
+/// single-use key alone, as the inviter may have left the room since.
+#[derive(Serialize, Deserialize, Eq, PartialEq, Clone, Debug)]
+pub struct RedeemedInvitation {
+    pub invitation_vk: VerifyingKey,
+    pub member: Member,
+    pub signature: Signature,
+}
+
+impl RedeemedInvitation {
+    /// The redemption record of a member who joined with an invitation
+    pub fn from_member(member: &AuthorizedMember) -> Option<Self> {
+        let invitation = member.invitation.as_ref()?;
+        Some(Self {
+            invitation_vk: invitation.invitation.invitation_vk,
+            member: member.member.clone(),
+            signature: member.signature,
+        })
+    }
+
+    pub fn verify_signature(&self) -> Result<(), String> {
+        verify_struct(&self.member, &self.signature, &self.invitation_vk)
+            .map_err(|e| format!("Invalid redemption signature: {}", e))
+    }
+
+    pub fn id(&self) -> InvitationId {
+        InvitationId(fast_hash(&self.invitation_vk.to_bytes()))
+    }
+}
+
+/// What the inviter hands to the invitee out-of-band: the signed invitation together with the
+/// private half of its `invitation_vk`. The invitee redeems it by using that key to sign a
+/// `Member` naming their own verifying key.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct InvitationToken {
+    pub invitation: AuthorizedInvitation,
+    pub invitation_signing_key: SigningKey,
+}
+
+impl InvitationToken {
+    pub fn new(owner_member_id: MemberId, inviter_signing_key: &SigningKey) -> Self {
+        let invitation_signing_key = SigningKey::generate(&mut OsRng);
+        let invitation = Invitation {
+            owner_member_id,
+            invited_by: MemberId::new(&inviter_signing_key.verifying_key()),
+            invitation_vk: invitation_signing_key.verifying_key(),
+        };
+        Self {
+            invitation: AuthorizedInvitation::new(invitation, inviter_signing_key),
+            invitation_signing_key,
+        }
+    }
+
+    /// Binds the invitee's real key to this invitation, producing a member that the room
+    /// contract will accept as invited by the inviter
+    pub fn redeem(&self, member_vk: VerifyingKey) -> AuthorizedMember {
+        let member = Member {
+            owner_member_id: self.invitation.invitation.owner_member_id,
+            invited_by: self.invitation.invitation.invited_by,
+            member_vk,
+        };
+        AuthorizedMember {
+            signature: sign_struct(&member, &self.invitation_signing_key),
+            member,
+            invitation: Some(self.invitation.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redeem_invitation() {
+        let owner_signing_key = SigningKey::generate(&mut OsRng);
+        let owner_id = MemberId::new(&owner_signing_key.verifying_key());
+        let invitee_signing_key = SigningKey::generate(&mut OsRng);
+
+        let token = InvitationToken::new(owner_id, &owner_signing_key);
+        let member = token.redeem(invitee_signing_key.verifying_key());
+
+        assert_eq!(member.member.invited_by, owner_id);
+        assert_eq!(member.member.member_vk, invitee_signing_key.verifying_key());
+        assert!(member
+            .verify_signature(&owner_signing_key.verifying_key())
+            .is_ok());
+
+        // The invitation must have been signed by the inviter
+        let wrong_key = SigningKey::generate(&mut OsRng).verifying_key();
+        assert!(member.verify_signature(&wrong_key).is_err());
+
+        // The redemption can't be moved to a different member key
+        let mut tampered = member.clone();
+        tampered.member.member_vk = SigningKey::generate(&mut OsRng).verifying_key();
+        assert!(tampered
+            .verify_signature(&owner_signing_key.verifying_key())
+            .is_err());
+    }
+
+    #[test]
+    fn test_redeem_invitation_mismatched_inviter() {
+        let owner_signing_key = SigningKey::generate(&mut OsRng);
+        let owner_id = MemberId::new(&owner_signing_key.verifying_key());
+        let other_signing_key = SigningKey::generate(&mut OsRng);
+
+        let token = InvitationToken::new(owner_id, &owner_signing_key);
+        let mut member = token.redeem(SigningKey::generate(&mut OsRng).verifying_key());
+
+        // Claiming a different inviter than the one that signed the invitation is rejected,
+        // even if the redemption is re-signed with the invitation key
+        member.member.invited_by = MemberId::new(&other_signing_key.verifying_key());
+        member.signature = sign_struct(&member.member, &token.invitation_signing_key);
+        let result = member.verify_signature(&owner_signing_key.verifying_key());
+        assert!(result.is_err());
+        assert!(result.unwrap_err().contains("does not match"));
+    }
+}
@@ -1,11 +1,14 @@
+use crate::room_state::configuration::Configuration;
 use crate::room_state::member::MemberId;
 use crate::room_state::ChatRoomParametersV1;
 use crate::room_state::ChatRoomStateV1;
-use crate::util::{sign_struct, verify_struct};
+use crate::util::{sign_struct, verify_struct, Signable, SignatureBatch};
 use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
-use freenet_scaffold::ComposableState;
+use freenet_scaffold::{ComposableState, DeltaReport};
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use unicode_security::confusable_detection::skeleton;
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct MemberInfoV1 {
@@ -31,10 +34,15 @@ impl ComposableState for MemberInfoV1 {
         parent_state: &Self::ParentState,
         parameters: &Self::Parameters,
     ) -> Result<(), String> {
-        let members_by_id = parent_state.members.members_by_member_id();
+        let participants_by_id = parent_state.members.participants_by_member_id(parameters);
+        let mut batch = SignatureBatch::new();
         for member_info in &self.member_info {
-            // Check if the member exists in the parent room_state
-            let member = members_by_id
+            member_info
+                .member_info
+                .validate(&parent_state.configuration.configuration)?;
+
+            // Check if the member or owner exists in the parent room_state
+            let participant = participants_by_id
                 .get(&member_info.member_info.member_id)
                 .ok_or_else(|| {
                     format!(
@@ -43,13 +51,29 @@ impl ComposableState for MemberInfoV1 {
                     )
                 })?;
 
-            // Verify the signature
-            if member.member.member_vk == parameters.owner {
-                // If the member is the room owner, verify against the room owner's key
-                member_info.verify_signature(parameters)?;
-            } else {
-                // Otherwise, verify against the member's key
-                member_info.verify_signature_with_key(&member.member.member_vk)?;
+            batch.add(
+                &member_info.member_info,
+                &member_info.signature,
+                participant.verifying_key(),
+                || format!("member info of {:?}", member_info.member_info.member_id),
+            );
+        }
+        batch.verify()?;
+
+        let mut by_skeleton: HashMap<String, &MemberInfo> =
+            HashMap::with_capacity(self.member_info.len());
+        for member_info in &self.member_info {
+            let member_info = &member_info.member_info;
+            let nickname = nickname_skeleton(&member_info.preferred_nickname);
+            if let Some(other) = by_skeleton.insert(nickname, member_info) {
+                let (kept, dropped) = if Self::nickname_precedence(other, parameters)
+                    < Self::nickname_precedence(member_info, parameters)
+                {
+                    (other, member_info)
+                } else {
+                    (member_info, other)
+                };
+                return Err(Self::similar_nickname_error(dropped, kept, parameters));
             }
         }
         Ok(())
@@ -91,37 +115,150 @@ impl ComposableState for MemberInfoV1 {
         parent_state: &Self::ParentState,
         parameters: &Self::Parameters,
         delta: &Self::Delta,
-    ) -> Result<(), String> {
+    ) -> Result<DeltaReport, String> {
+        let participants_by_id = parent_state.members.participants_by_member_id(parameters);
+        let mut report = DeltaReport::default();
         for member_info in delta {
             let member_id = &member_info.member_info.member_id;
-            if let Some(member) = parent_state.members.members_by_member_id().get(member_id) {
-                // Verify the signature
-                if member.member.member_vk == parameters.owner {
-                    // If the member is the room owner, verify against the room owner's key
-                    member_info.verify_signature(parameters)?;
-                } else {
-                    // Otherwise, verify against the member's key
-                    member_info.verify_signature_with_key(&member.member.member_vk)?;
+            let existing = self
+                .member_info
+                .iter()
+                .position(|info| info.member_info.member_id == *member_id);
+            if let Some(i) = existing {
+                if member_info.member_info.version <= self.member_info[i].member_info.version {
+                    // The info we hold, or one we have since replaced, is being redelivered
+                    continue;
                 }
-                
-                // Update or add the member info
-                if let Some(existing_info) = self
-                    .member_info
-                    .iter_mut()
-                    .find(|info| info.member_info.member_id == *member_id)
-                {
-                    if member_info.member_info.version > existing_info.member_info.version {
-                        *existing_info = member_info.clone();
-                    }
-                } else {
-                    self.member_info.push(member_info.clone());
-                }
-            } else {
-                return Err(format!("Member {} not found in parent room_state", member_id));
             }
+
+            let item = format!(
+                "member info of {} version {}",
+                member_id, member_info.member_info.version
+            );
+            let validation = participants_by_id
+                .get(member_id)
+                .ok_or_else(|| format!("Member {} not found in parent room_state", member_id))
+                .and_then(|participant| {
+                    member_info
+                        .member_info
+                        .validate(&parent_state.configuration.configuration)?;
+                    member_info.verify_signature_with_key(participant.verifying_key())?;
+                    self.check_nickname_available(&member_info.member_info, parameters)
+                });
+            if let Err(e) = validation {
+                report.rejected(item, e);
+                continue;
+            }
+
+            // Update or add the member info
+            match existing {
+                Some(i) => self.member_info[i] = member_info.clone(),
+                None => self.member_info.push(member_info.clone()),
+            }
+            // Members who had a nickname like this one, but lower precedence, lose their
+            // info and can publish new info
+            let nickname = nickname_skeleton(&member_info.member_info.preferred_nickname);
+            self.member_info.retain(|info| {
+                info.member_info.member_id == *member_id
+                    || nickname_skeleton(&info.member_info.preferred_nickname) != nickname
+            });
+            report.applied(item);
         }
-        Ok(())
+        Ok(report)
+    }
+}
+
+impl MemberInfoV1 {
+    /// Drops member info that no longer fits the room configuration, e.g. a nickname over a
+    /// `max_nickname_size` the owner has since lowered. Those members can publish new info.
+    pub fn enforce_limits(&mut self, configuration: &Configuration) {
+        self.member_info
+            .retain(|info| info.member_info.validate(configuration).is_ok());
+    }
+
+    /// Which of two members with confusable nicknames keeps theirs, lowest first: the owner,
+    /// then the member with the lowest ID. It doesn't depend on which nickname came first, so
+    /// every peer keeps the same one whatever order updates arrive in.
+    fn nickname_precedence(
+        member_info: &MemberInfo,
+        parameters: &ChatRoomParametersV1,
+    ) -> (bool, MemberId) {
+        (
+            member_info.member_id != parameters.owner_id(),
+            member_info.member_id,
+        )
+    }
+
+    /// Checks that no other member with higher precedence has a nickname that can be
+    /// confused with this one
+    fn check_nickname_available(
+        &self,
+        member_info: &MemberInfo,
+        parameters: &ChatRoomParametersV1,
+    ) -> Result<(), String> {
+        let nickname = nickname_skeleton(&member_info.preferred_nickname);
+        let precedence = Self::nickname_precedence(member_info, parameters);
+        let taken_by = self
+            .member_info
+            .iter()
+            .map(|info| &info.member_info)
+            .find(|info| {
+                info.member_id != member_info.member_id
+                    && Self::nickname_precedence(info, parameters) < precedence
+                    && nickname_skeleton(&info.preferred_nickname) == nickname
+            });
+        match taken_by {
+            Some(other) => Err(Self::similar_nickname_error(member_info, other, parameters)),
+            None => Ok(()),
+        }
+    }
+
+    fn similar_nickname_error(
+        member_info: &MemberInfo,
+        other: &MemberInfo,
+        parameters: &ChatRoomParametersV1,
+    ) -> String {
+        let whose = if other.member_id == parameters.owner_id() {
+            "the room owner's nickname"
+        } else {
+            "another member's nickname"
+        };
+        format!(
+            "Nickname \"{}\" is too similar to {}",
+            member_info.preferred_nickname, whose
+        )
+    }
+}
+
+/// Checks a nickname against the room's nickname policy: it must be non-empty,
+/// trimmed, free of control characters and within the size limit
+pub fn validate_nickname(nickname: &str, max_nickname_size: usize) -> Result<(), String> {
+    if nickname.trim().is_empty() {
+        return Err("Nickname can't be empty".to_string());
+    }
+    if nickname.trim() != nickname {
+        return Err("Nickname can't start or end with whitespace".to_string());
+    }
+    if nickname.chars().any(char::is_control) {
+        return Err("Nickname can't contain control characters".to_string());
     }
+    if nickname.len() > max_nickname_size {
+        return Err(format!(
+            "Nickname is too long: {} > {} bytes",
+            nickname.len(),
+            max_nickname_size
+        ));
+    }
+    Ok(())
+}
+
+/// Reduces a nickname to a form in which visually confusable nicknames
+/// (e.g. "Alice" and "Аlice" with a Cyrillic "А") compare equal
+pub fn nickname_skeleton(nickname: &str) -> String {
+    skeleton(&nickname.to_lowercase())
+        .flat_map(char::to_lowercase)
+        .filter(|c| !c.is_whitespace())
+        .collect()
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -169,6 +306,75 @@ pub struct MemberInfo {
     pub member_id: MemberId,
     pub version: u32,
     pub preferred_nickname: String,
+    // The profile fields are left out while unset, so that member info signed before they
+    // existed serializes back to the bytes that were signed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar: Option<Avatar>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bio: Option<String>,
+    /// A short line describing what the member is up to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+    /// An X25519 public key others can use to encrypt data for this member
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption_key: Option<[u8; 32]>,
+}
+
+impl Signable for MemberInfo {
+    const DOMAIN: &'static str = "member-info";
+}
+
+impl MemberInfo {
+    /// Checks the nickname policy and the profile fields against the size limits
+    /// in the room configuration
+    pub fn validate(&self, configuration: &Configuration) -> Result<(), String> {
+        validate_nickname(&self.preferred_nickname, configuration.max_nickname_size)?;
+        if let Some(Avatar::Inline(image)) = &self.avatar {
+            if image.len() > configuration.max_avatar_size {
+                return Err(format!(
+                    "Avatar is too large: {} > {} bytes",
+                    image.len(),
+                    configuration.max_avatar_size
+                ));
+            }
+        }
+        if let Some(bio) = &self.bio {
+            if bio.len() > configuration.max_bio_size {
+                return Err(format!(
+                    "Bio is too long: {} > {} bytes",
+                    bio.len(),
+                    configuration.max_bio_size
+                ));
+            }
+        }
+        if let Some(status) = &self.status {
+            if status.len() > configuration.max_status_size {
+                return Err(format!(
+                    "Status is too long: {} > {} bytes",
+                    status.len(),
+                    configuration.max_status_size
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub enum Avatar {
+    /// A small image stored directly in the room state
+    Inline(Vec<u8>),
+    /// The hash of an image stored elsewhere
+    Hash(blake3::Hash),
+}
+
+impl fmt::Debug for Avatar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Avatar::Inline(image) => write!(f, "Inline({} bytes)", image.len()),
+            Avatar::Hash(hash) => write!(f, "Hash({})", hash),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -183,6 +389,10 @@ mod tests {
             member_id,
             version: 1,
             preferred_nickname: "TestUser".to_string(),
+            avatar: None,
+            bio: None,
+            status: None,
+            encryption_key: None,
         }
     }
 
@@ -219,6 +429,8 @@ mod tests {
 
         let parameters = ChatRoomParametersV1 {
             owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
         };
 
         let result = member_info_v1.verify(&parent_state, &parameters);
@@ -279,6 +491,8 @@ mod tests {
         let parent_state = ChatRoomStateV1::default();
         let parameters = ChatRoomParametersV1 {
             owner: owner_signing_key.verifying_key(),
+            admins: None,
+            recovery: None,
         };
 
         let summary = member_info_v1.summarize(&parent_state, &parameters);
@@ -305,6 +519,8 @@ mod tests {
         let parent_state = ChatRoomStateV1::default();
         let parameters = ChatRoomParametersV1 {
             owner: owner_signing_key.verifying_key(),
+            admins: None,
+            recovery: None,
         };
 
         let old_summary = vec![member_id1];
@@ -343,10 +559,13 @@ mod tests {
                 .sign("TestUser".as_bytes())
                 .to_bytes()
                 .into(),
+            invitation: None,
         });
 
         let parameters = ChatRoomParametersV1 {
             owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
         };
 
         // Test applying delta with a new member
@@ -380,7 +599,8 @@ mod tests {
 
         let result = member_info_v1.apply_delta(&parent_state, &parameters, &non_existent_delta);
         println!("Result: {:?}", result);
-        assert!(result.is_err());
+        assert!(result.unwrap().rejected[0].1.contains("not found"));
+        assert_eq!(member_info_v1.member_info.len(), 1);
 
         // Test applying delta with an older version (should not update)
         println!("Applying delta with an older version");
@@ -413,6 +633,7 @@ mod tests {
                 .sign("NewTestUser".as_bytes())
                 .to_bytes()
                 .into(),
+            invitation: None,
         });
 
         let multi_delta = vec![updated_authorized_member_info.clone(), new_authorized_member_info.clone()];
@@ -436,13 +657,15 @@ mod tests {
 
         let parameters = ChatRoomParametersV1 {
             owner: owner_signing_key.verifying_key(),
+            admins: None,
+            recovery: None,
         };
 
         assert!(authorized_member_info.verify_signature(&parameters).is_ok());
 
         // Test with wrong key
         let wrong_key = SigningKey::generate(&mut OsRng).verifying_key();
-        let wrong_parameters = ChatRoomParametersV1 { owner: wrong_key };
+        let wrong_parameters = ChatRoomParametersV1 { owner: wrong_key, admins: None, recovery: None };
         assert!(authorized_member_info
             .verify_signature(&wrong_parameters)
             .is_err());
@@ -457,6 +680,8 @@ mod tests {
         let parent_state = ChatRoomStateV1::default();
         let parameters = ChatRoomParametersV1 {
             owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
         };
 
         // Generate 5 member infos
@@ -510,13 +735,286 @@ mod tests {
                 member_vk: owner_verifying_key,
             },
             signature: owner_signing_key.sign("TestOwner".as_bytes()).to_bytes().into(),
+            invitation: None,
         });
 
         let parameters = ChatRoomParametersV1 {
             owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
         };
 
         let result = member_info_v1.verify(&parent_state, &parameters);
         assert!(result.is_ok(), "Room owner should be allowed to have member info: {:?}", result);
     }
+
+    #[test]
+    fn test_member_info_profile_limits() {
+        let owner_signing_key = SigningKey::generate(&mut OsRng);
+        let owner_verifying_key = owner_signing_key.verifying_key();
+        let owner_id = MemberId::new(&owner_verifying_key);
+
+        let member_signing_key = SigningKey::generate(&mut OsRng);
+        let member_verifying_key = member_signing_key.verifying_key();
+        let member_id = MemberId::new(&member_verifying_key);
+
+        let mut parent_state = ChatRoomStateV1::default();
+        parent_state.configuration.configuration.max_avatar_size = 16;
+        parent_state.configuration.configuration.max_bio_size = 10;
+        parent_state.configuration.configuration.max_status_size = 5;
+        parent_state.members.members.push(AuthorizedMember::new(
+            Member {
+                owner_member_id: owner_id,
+                invited_by: owner_id,
+                member_vk: member_verifying_key,
+            },
+            &owner_signing_key,
+        ));
+        let parameters = ChatRoomParametersV1 {
+            owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
+        };
+
+        let profile = MemberInfo {
+            avatar: Some(Avatar::Inline(vec![0; 16])),
+            bio: Some("Hi there".to_string()),
+            status: Some("Away".to_string()),
+            encryption_key: Some([1; 32]),
+            ..create_test_member_info(member_id)
+        };
+        let mut member_info_v1 = MemberInfoV1::default();
+        let delta = vec![AuthorizedMemberInfo::new_with_member_key(
+            profile.clone(),
+            &member_signing_key,
+        )];
+        assert!(member_info_v1
+            .apply_delta(&parent_state, &parameters, &delta)
+            .is_ok());
+        assert!(member_info_v1.verify(&parent_state, &parameters).is_ok());
+
+        let oversized = [
+            MemberInfo {
+                avatar: Some(Avatar::Inline(vec![0; 17])),
+                ..profile.clone()
+            },
+            MemberInfo {
+                bio: Some("A very long bio".to_string()),
+                ..profile.clone()
+            },
+            MemberInfo {
+                status: Some("Very busy".to_string()),
+                ..profile.clone()
+            },
+        ];
+        for (version, mut info) in (2..).zip(oversized) {
+            info.version = version;
+            let authorized_info =
+                AuthorizedMemberInfo::new_with_member_key(info, &member_signing_key);
+            let result = member_info_v1.apply_delta(
+                &parent_state,
+                &parameters,
+                &vec![authorized_info.clone()],
+            );
+            assert!(
+                !result.unwrap().is_fully_applied(),
+                "Oversized profile should be rejected"
+            );
+
+            let invalid = MemberInfoV1 {
+                member_info: vec![authorized_info],
+            };
+            assert!(invalid.verify(&parent_state, &parameters).is_err());
+        }
+        assert_eq!(member_info_v1.member_info[0].member_info, profile);
+
+        // An avatar stored elsewhere is only referenced by hash
+        assert!(MemberInfo {
+            avatar: Some(Avatar::Hash(blake3::hash(&[0; 1024]))),
+            ..profile
+        }
+        .validate(&parent_state.configuration.configuration)
+        .is_ok());
+    }
+
+    #[test]
+    fn test_member_info_signed_before_profile_fields() {
+        #[derive(Serialize)]
+        struct LegacyMemberInfo {
+            member_id: MemberId,
+            version: u32,
+            preferred_nickname: String,
+        }
+        impl Signable for LegacyMemberInfo {
+            const DOMAIN: &'static str = MemberInfo::DOMAIN;
+        }
+
+        let signing_key = SigningKey::generate(&mut OsRng);
+        let member_info = create_test_member_info(MemberId::new(&signing_key.verifying_key()));
+        let legacy = LegacyMemberInfo {
+            member_id: member_info.member_id,
+            version: member_info.version,
+            preferred_nickname: member_info.preferred_nickname.clone(),
+        };
+        let signature = sign_struct(&legacy, &signing_key);
+        assert!(verify_struct(&member_info, &signature, &signing_key.verifying_key()).is_ok());
+
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(&legacy, &mut bytes).unwrap();
+        let decoded: MemberInfo = ciborium::de::from_reader(bytes.as_slice()).unwrap();
+        assert_eq!(decoded, member_info);
+    }
+
+    #[test]
+    fn test_validate_nickname() {
+        assert!(validate_nickname("Alice", 50).is_ok());
+        assert!(validate_nickname("Zoë 🦀", 50).is_ok());
+
+        let rejected = [
+            ("", "empty"),
+            ("   ", "empty"),
+            (" Alice", "whitespace"),
+            ("Alice\n", "whitespace"),
+            ("Al\u{7}ice", "control characters"),
+            ("Alice the Magnificent", "too long"),
+        ];
+        for (nickname, reason) in rejected {
+            let err = validate_nickname(nickname, 10).expect_err(nickname);
+            assert!(err.contains(reason), "Unexpected error for {:?}: {}", nickname, err);
+        }
+    }
+
+    #[test]
+    fn test_nickname_skeleton() {
+        assert_eq!(nickname_skeleton("Alice"), nickname_skeleton("alice"));
+        // Cyrillic "А"
+        assert_eq!(nickname_skeleton("Alice"), nickname_skeleton("\u{410}lice"));
+        assert_eq!(nickname_skeleton("paypal"), nickname_skeleton("paypa1"));
+        assert_eq!(nickname_skeleton("Room Owner"), nickname_skeleton("roomowner"));
+        assert_ne!(nickname_skeleton("Alice"), nickname_skeleton("Alicia"));
+    }
+
+    #[test]
+    fn test_nickname_policy() {
+        let owner_signing_key = SigningKey::generate(&mut OsRng);
+        let owner_verifying_key = owner_signing_key.verifying_key();
+        let owner_id = MemberId::new(&owner_verifying_key);
+        let parameters = ChatRoomParametersV1 {
+            owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
+        };
+
+        let mut parent_state = ChatRoomStateV1::default();
+        let member_keys: Vec<SigningKey> = (0..2).map(|_| SigningKey::generate(&mut OsRng)).collect();
+        for key in &member_keys {
+            parent_state.members.members.push(AuthorizedMember::new(
+                Member {
+                    owner_member_id: owner_id,
+                    invited_by: owner_id,
+                    member_vk: key.verifying_key(),
+                },
+                &owner_signing_key,
+            ));
+        }
+        let nickname_info = |key: &SigningKey, version: u32, nickname: &str| {
+            AuthorizedMemberInfo::new_with_member_key(
+                MemberInfo {
+                    version,
+                    preferred_nickname: nickname.to_string(),
+                    ..create_test_member_info(MemberId::new(&key.verifying_key()))
+                },
+                key,
+            )
+        };
+
+        let mut member_info_v1 = MemberInfoV1::default();
+        member_info_v1
+            .apply_delta(&parent_state, &parameters, &vec![nickname_info(&member_keys[0], 1, "Alice")])
+            .unwrap();
+
+        // Too long and untrimmed nicknames are rejected
+        parent_state.configuration.configuration.max_nickname_size = 8;
+        for nickname in ["Alexandria", "Bob "] {
+            let delta = vec![nickname_info(&member_keys[1], 1, nickname)];
+            assert!(
+                !member_info_v1
+                    .apply_delta(&parent_state, &parameters, &delta)
+                    .unwrap()
+                    .is_fully_applied(),
+                "Nickname {:?} should be rejected",
+                nickname
+            );
+        }
+
+        // Of two members with homoglyph nicknames, the one with the lowest ID keeps theirs
+        // whichever arrives first
+        let member_ids: Vec<MemberId> = member_keys
+            .iter()
+            .map(|key| MemberId::new(&key.verifying_key()))
+            .collect();
+        let alice = nickname_info(&member_keys[0], 1, "Alice");
+        let homoglyph = nickname_info(&member_keys[1], 1, "\u{410}lice");
+        let mut merged = Vec::new();
+        for infos in [[&alice, &homoglyph], [&homoglyph, &alice]] {
+            let mut member_info_v1 = MemberInfoV1::default();
+            for info in infos {
+                member_info_v1
+                    .apply_delta(&parent_state, &parameters, &vec![info.clone()])
+                    .unwrap();
+            }
+            assert!(member_info_v1.verify(&parent_state, &parameters).is_ok());
+            assert_eq!(member_info_v1.member_info.len(), 1);
+            merged.push(member_info_v1);
+        }
+        assert_eq!(merged[0], merged[1]);
+        assert_eq!(
+            merged[0].member_info[0].member_info.member_id,
+            *member_ids.iter().min().unwrap()
+        );
+        let both = MemberInfoV1 {
+            member_info: vec![alice.clone(), homoglyph.clone()],
+        };
+        let err = both.verify(&parent_state, &parameters).unwrap_err();
+        assert!(err.contains("another member's nickname"), "Unexpected error: {}", err);
+
+        // A member may keep a nickname similar to their own previous one
+        let mut member_info_v1 = MemberInfoV1 {
+            member_info: vec![alice],
+        };
+        let report = member_info_v1
+            .apply_delta(&parent_state, &parameters, &vec![nickname_info(&member_keys[0], 2, "ALICE")])
+            .unwrap();
+        assert!(report.is_fully_applied());
+        member_info_v1
+            .apply_delta(&parent_state, &parameters, &vec![nickname_info(&member_keys[1], 2, "Bob")])
+            .unwrap();
+        assert!(member_info_v1.verify(&parent_state, &parameters).is_ok());
+        assert_eq!(member_info_v1.member_info.len(), 2);
+
+        // Only the owner may use a nickname like the owner's
+        member_info_v1
+            .apply_delta(&parent_state, &parameters, &vec![nickname_info(&owner_signing_key, 1, "Carol")])
+            .unwrap();
+        let delta = vec![nickname_info(&member_keys[1], 3, "CAROL")];
+        let report = member_info_v1
+            .apply_delta(&parent_state, &parameters, &delta)
+            .unwrap();
+        let err = &report.rejected[0].1;
+        assert!(err.contains("room owner's nickname"), "Unexpected error: {}", err);
+        let mut invalid = member_info_v1.clone();
+        invalid.member_info[1] = delta[0].clone();
+        assert!(invalid.verify(&parent_state, &parameters).is_err());
+
+        // When the owner takes a member's nickname, the member's info is dropped
+        member_info_v1
+            .apply_delta(&parent_state, &parameters, &vec![nickname_info(&owner_signing_key, 2, "Bob")])
+            .unwrap();
+        assert!(member_info_v1.verify(&parent_state, &parameters).is_ok());
+        assert_eq!(member_info_v1.member_info.len(), 2);
+        assert!(member_info_v1
+            .member_info
+            .iter()
+            .all(|info| info.member_info.member_id != member_ids[1]));
+    }
 }
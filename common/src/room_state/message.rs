@@ -1,12 +1,15 @@
+use crate::room_state::configuration::{Configuration, RoomPrivacy};
 use crate::room_state::member::MemberId;
+use crate::room_state::secret::EncryptedContent;
 use crate::room_state::ChatRoomParametersV1;
 use crate::util::sign_struct;
-use crate::util::{truncated_base64, verify_struct};
+use crate::util::{truncated_base64, verify_struct, Signable, SignatureBatch};
 use crate::ChatRoomStateV1;
 use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
 use freenet_scaffold::util::{fast_hash, FastHash};
-use freenet_scaffold::ComposableState;
+use freenet_scaffold::{ComposableState, DeltaReport};
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::fmt;
 use std::time::SystemTime;
 
@@ -24,19 +27,31 @@ impl ComposableState for MessagesV1 {
     fn verify(
         &self,
         parent_state: &Self::ParentState,
-        _parameters: &Self::Parameters,
+        parameters: &Self::Parameters,
     ) -> Result<(), String> {
-        let members_by_id = parent_state.members.members_by_member_id();
+        let authors_by_id = parent_state
+            .members
+            .authors_by_member_id(&parent_state.bans, parameters);
 
+        let mut batch = SignatureBatch::new();
         for message in &self.messages {
-            if let Some(member) = members_by_id.get(&message.message.author) {
-                if message.validate(&member.member.member_vk).is_err() {
-                    return Err(format!(
-                        "Invalid message signature: id:{:?} content:{:?}",
-                        message.id(),
-                        message.message.content
-                    ));
-                }
+            parameters.check_room_binding(message.message.room_owner)?;
+            if parent_state.upgrade.is_past_cutoff(message.message.time) {
+                return Err(format!(
+                    "Message {:?} was written after the room was upgraded",
+                    message.id()
+                ));
+            }
+            if let Some(author) = authors_by_id
+                .get(&message.message.author)
+                .filter(|a| a.could_write_at(message.message.time))
+            {
+                batch.add(
+                    &message.message,
+                    &message.signature,
+                    author.verifying_key(),
+                    || format!("message id:{:?} content:{:?}", message.id(), message.message.content),
+                );
             } else {
                 return Err(format!(
                     "Message author not found: {:?}",
@@ -44,6 +59,7 @@ impl ComposableState for MessagesV1 {
                 ));
             }
         }
+        batch.verify()?;
 
         Ok(())
     }
@@ -78,35 +94,82 @@ impl ComposableState for MessagesV1 {
     fn apply_delta(
         &mut self,
         parent_state: &Self::ParentState,
-        _parameters: &Self::Parameters,
+        parameters: &Self::Parameters,
         delta: &Self::Delta,
-    ) -> Result<(), String> {
-        let max_recent_messages = parent_state.configuration.configuration.max_recent_messages;
+    ) -> Result<DeltaReport, String> {
         let max_message_size = parent_state.configuration.configuration.max_message_size;
+        let privacy = parent_state.configuration.configuration.privacy;
+        let authors_by_id = parent_state
+            .members
+            .authors_by_member_id(&parent_state.bans, parameters);
+        let mut report = DeltaReport::default();
+
+        // Add new messages from delta, skipping any we already hold
+        let mut message_ids: HashSet<MessageId> = self.messages.iter().map(|m| m.id()).collect();
+        for message in delta {
+            if !message_ids.insert(message.id()) {
+                continue;
+            }
+            let item = format!("message {}", message.id());
+            // Once the room points to its successor new messages belong there
+            if !parent_state.upgrade.accepts_message_at(message.message.time) {
+                report.rejected(
+                    item,
+                    "The room has been upgraded, send messages to its successor",
+                );
+                continue;
+            }
+            if message.message.content_size() > max_message_size {
+                report.rejected(item, "Message is over the size limit");
+                continue;
+            }
+            if let Err(e) = message.message.check_privacy(privacy) {
+                report.rejected(item, e);
+                continue;
+            }
+            if let Err(e) = parameters.check_room_binding(message.message.room_owner) {
+                report.rejected(item, e);
+                continue;
+            }
+            // Messages must be authored by the owner or a member, including members who have
+            // since left for messages they wrote before leaving
+            let Some(author) = authors_by_id
+                .get(&message.message.author)
+                .filter(|a| a.could_write_at(message.message.time))
+            else {
+                report.rejected(
+                    item,
+                    format!("Message author not found: {:?}", message.message.author),
+                );
+                continue;
+            };
+            if let Err(e) = message.validate(author.verifying_key()) {
+                report.rejected(item, format!("Invalid message signature: {}", e));
+                continue;
+            }
+            self.messages.push(message.clone());
+            report.applied(item);
+        }
 
-        // Add new messages from delta
-        self.messages.extend(delta.iter().cloned());
+        self.enforce_limits(&parent_state.configuration.configuration);
 
-        // Ensure there are no messages over the size limit
-        self.messages
-            .retain(|m| m.message.content.len() <= max_message_size);
+        Ok(report)
+    }
+}
 
-        // Ensure all messages are signed by a valid member, remove if not
-        let members_by_id = parent_state.members.members_by_member_id();
+impl MessagesV1 {
+    /// Sorts messages by time and drops any over the size limit, then the oldest beyond
+    /// `max_recent_messages`. Ties are broken by id so that every peer keeps the same
+    /// messages, also after the owner has lowered either limit.
+    pub fn enforce_limits(&mut self, configuration: &Configuration) {
         self.messages
-            .retain(|m| members_by_id.contains_key(&m.message.author));
-
-        // Sort messages by time
+            .retain(|m| m.message.content_size() <= configuration.max_message_size);
         self.messages
-            .sort_by(|a, b| a.message.time.cmp(&b.message.time));
-
-        // Remove oldest messages if there are too many
-        if self.messages.len() > max_recent_messages {
+            .sort_by_cached_key(|m| (m.message.time, m.id()));
+        if self.messages.len() > configuration.max_recent_messages {
             self.messages
-                .drain(0..self.messages.len() - max_recent_messages);
+                .drain(0..self.messages.len() - configuration.max_recent_messages);
         }
-
-        Ok(())
     }
 }
 
@@ -124,6 +187,41 @@ pub struct MessageV1 {
     pub author: MemberId,
     pub time: SystemTime,
     pub content: String,
+    /// Set instead of `content` in private rooms
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encrypted_content: Option<EncryptedContent>,
+}
+
+impl Signable for MessageV1 {
+    const DOMAIN: &'static str = "message";
+}
+
+impl MessageV1 {
+    /// Size of the message text, whether it's in cleartext or encrypted
+    pub fn content_size(&self) -> usize {
+        self.content.len()
+            + self
+                .encrypted_content
+                .as_ref()
+                .map_or(0, EncryptedContent::plaintext_size)
+    }
+
+    /// Private rooms only take encrypted messages and public rooms only cleartext ones.
+    /// Messages already in the room are kept when the owner changes its privacy.
+    pub fn check_privacy(&self, privacy: RoomPrivacy) -> Result<(), String> {
+        match (privacy, &self.encrypted_content) {
+            (RoomPrivacy::Private, None) => {
+                Err("Messages in a private room must be encrypted".to_string())
+            }
+            (RoomPrivacy::Private, Some(_)) if !self.content.is_empty() => {
+                Err("Encrypted messages can't have cleartext content".to_string())
+            }
+            (RoomPrivacy::Public, Some(_)) => {
+                Err("Messages in a public room can't be encrypted".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
 }
 
 #[derive(Clone, PartialEq, Serialize, Deserialize)]
@@ -187,6 +285,7 @@ mod tests {
             author: author_id,
             time: SystemTime::now(),
             content: "Test message".to_string(),
+            encrypted_content: None,
         }
     }
 
@@ -289,6 +388,8 @@ mod tests {
         // Set up parameters for verification
         let parameters = ChatRoomParametersV1 {
             owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
         };
 
         // Verify that a valid message passes verification
@@ -340,6 +441,8 @@ mod tests {
         let parent_state = ChatRoomStateV1::default();
         let parameters = ChatRoomParametersV1 {
             owner: signing_key.verifying_key(),
+            admins: None,
+            recovery: None,
         };
 
         let summary = messages.summarize(&parent_state, &parameters);
@@ -378,6 +481,8 @@ mod tests {
         let parent_state = ChatRoomStateV1::default();
         let parameters = ChatRoomParametersV1 {
             owner: signing_key.verifying_key(),
+            admins: None,
+            recovery: None,
         };
 
         // Test with partial old summary
@@ -427,10 +532,13 @@ mod tests {
                 member_vk: author_verifying_key,
             },
             signature: owner_signing_key.try_sign(&[0; 32]).unwrap(),
+            invitation: None,
         }];
 
         let parameters = ChatRoomParametersV1 {
             owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
         };
 
         // Create messages
@@ -440,6 +548,7 @@ mod tests {
                 author: author_id,
                 time,
                 content: "Test message".to_string(),
+                encrypted_content: None,
             };
             AuthorizedMessageV1::new(message, &author_signing_key)
         };
@@ -0,0 +1,910 @@
+use crate::ecies::{SealedBox, AES_GCM_TAG_SIZE};
+use crate::room_state::member::MemberId;
+use crate::room_state::message::MessagesV1;
+use crate::room_state::ChatRoomParametersV1;
+use crate::room_state::ChatRoomStateV1;
+use crate::util::{sign_struct, truncated_base64, verify_struct, Signable};
+use aes_gcm::{
+    aead::{Aead, KeyInit},
+    Aes256Gcm, Nonce,
+};
+use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
+use freenet_scaffold::util::{fast_hash, FastHash};
+use freenet_scaffold::{ComposableState, DeltaReport};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::time::SystemTime;
+
+/// The key message content is encrypted with in a private room. The owner seals it to each
+/// member so that only members can read the room, while the contract, which only sees the
+/// sealed copies and ciphertext, still checks who wrote what.
+///
+/// Each version of the secret is an epoch. The owner starts a new epoch whenever someone the
+/// secret was sealed to is no longer in the room, so that they can't read what is written
+/// after they're gone. Rotation is up to the owner's client, so until it next runs, the
+/// departed can still read new messages. Each epoch carries the previous epoch's secret
+/// encrypted with its own, so that members can read the messages written before.
+#[derive(Clone, PartialEq, Eq)]
+pub struct RoomSecret {
+    pub version: u32,
+    pub started_at: SystemTime,
+    pub key: [u8; 32],
+}
+
+impl fmt::Debug for RoomSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RoomSecret")
+            .field("version", &self.version)
+            .field("started_at", &self.started_at)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RoomSecret {
+    pub fn generate(version: u32, started_at: SystemTime) -> Self {
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        Self {
+            version,
+            started_at,
+            key,
+        }
+    }
+
+    pub fn encrypt(&self, plaintext: &str) -> EncryptedContent {
+        self.encrypt_bytes(plaintext.as_bytes())
+    }
+
+    pub fn decrypt(&self, content: &EncryptedContent) -> Result<String, String> {
+        let plaintext = self.decrypt_bytes(content)?;
+        String::from_utf8(plaintext).map_err(|e| format!("Invalid message content: {}", e))
+    }
+
+    /// Encrypts `previous`, the secret of an earlier epoch, with this secret
+    pub fn chain(&self, previous: &RoomSecret) -> ChainedSecret {
+        ChainedSecret {
+            secret_version: previous.version,
+            started_at: previous.started_at,
+            encrypted_key: self.encrypt_bytes(&previous.key),
+        }
+    }
+
+    /// Decrypts the secret of an earlier epoch that was chained under this one
+    pub fn unchain(&self, chained: &ChainedSecret) -> Result<RoomSecret, String> {
+        let key = self.decrypt_bytes(&chained.encrypted_key)?;
+        Ok(RoomSecret {
+            version: chained.secret_version,
+            started_at: chained.started_at,
+            key: key
+                .try_into()
+                .map_err(|_| "Room secret has the wrong length".to_string())?,
+        })
+    }
+
+    fn encrypt_bytes(&self, plaintext: &[u8]) -> EncryptedContent {
+        let nonce = rand::random::<[u8; 12]>();
+        let ciphertext = self
+            .cipher()
+            .encrypt(&Nonce::from(nonce), plaintext)
+            .expect("Encryption should not fail");
+        EncryptedContent {
+            secret_version: self.version,
+            nonce,
+            ciphertext,
+        }
+    }
+
+    fn decrypt_bytes(&self, content: &EncryptedContent) -> Result<Vec<u8>, String> {
+        if content.secret_version != self.version {
+            return Err(format!(
+                "Encrypted with room secret version {}, not {}",
+                content.secret_version, self.version
+            ));
+        }
+        self.cipher()
+            .decrypt(&Nonce::from(content.nonce), content.ciphertext.as_ref())
+            .map_err(|_| "Decryption failed".to_string())
+    }
+
+    /// Seals the secret to `member_vk`, signed by the room owner
+    pub fn seal(
+        &self,
+        member_vk: &VerifyingKey,
+        owner_signing_key: &SigningKey,
+    ) -> Result<AuthorizedSealedSecret, String> {
+        let sealed_key = SealedBox::seal(member_vk, SealedSecret::DOMAIN.as_bytes(), &self.key)
+            .map_err(|e| format!("Can't seal room secret: {}", e))?;
+        Ok(AuthorizedSealedSecret::new(
+            SealedSecret {
+                owner_member_id: MemberId::new(&owner_signing_key.verifying_key()),
+                member_id: MemberId::new(member_vk),
+                secret_version: self.version,
+                epoch_started_at: self.started_at,
+                sealed_key,
+            },
+            owner_signing_key,
+        ))
+    }
+
+    /// Seals the secret to `recipients` and signs the record of its epoch, sealed to them
+    /// and the members in `sealed_to`
+    fn seal_epoch(
+        &self,
+        recipients: &[VerifyingKey],
+        sealed_to: Vec<MemberId>,
+        previous_secret: Option<ChainedSecret>,
+        owner_signing_key: &SigningKey,
+    ) -> Result<RoomSecretsDelta, String> {
+        let secrets = recipients
+            .iter()
+            .map(|member_vk| self.seal(member_vk, owner_signing_key))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut sealed_to: Vec<MemberId> = sealed_to
+            .into_iter()
+            .chain(recipients.iter().map(MemberId::new))
+            .collect();
+        sealed_to.sort();
+        sealed_to.dedup();
+        let epoch = SecretEpoch {
+            owner_member_id: MemberId::new(&owner_signing_key.verifying_key()),
+            secret_version: self.version,
+            sealed_to,
+            previous_secret,
+        };
+        Ok(RoomSecretsDelta {
+            secrets,
+            epochs: vec![AuthorizedSecretEpoch::new(epoch, owner_signing_key)],
+        })
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new_from_slice(&self.key).expect("Room secrets are valid AES-256 keys")
+    }
+}
+
+/// The secret of an earlier epoch, encrypted with the secret of a later one
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct ChainedSecret {
+    pub secret_version: u32,
+    pub started_at: SystemTime,
+    pub encrypted_key: EncryptedContent,
+}
+
+/// Message content encrypted with the room secret of the epoch it was written in
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct EncryptedContent {
+    pub secret_version: u32,
+    pub nonce: [u8; 12],
+    pub ciphertext: Vec<u8>,
+}
+
+impl EncryptedContent {
+    /// Size of the text before encryption, the ciphertext also holds the AES-GCM tag
+    pub fn plaintext_size(&self) -> usize {
+        self.ciphertext.len().saturating_sub(AES_GCM_TAG_SIZE)
+    }
+}
+
+/// The room secrets sealed to each member, the latest version per member, and the records
+/// of the epochs messages in the room are still encrypted with
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct RoomSecretsV1 {
+    pub secrets: Vec<AuthorizedSealedSecret>,
+    #[serde(default)]
+    pub epochs: Vec<AuthorizedSecretEpoch>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct RoomSecretsSummary {
+    pub secrets: Vec<SealedSecretId>,
+    pub epochs: Vec<SecretEpochId>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct RoomSecretsDelta {
+    pub secrets: Vec<AuthorizedSealedSecret>,
+    #[serde(default)]
+    pub epochs: Vec<AuthorizedSecretEpoch>,
+}
+
+impl ComposableState for RoomSecretsV1 {
+    type ParentState = ChatRoomStateV1;
+    type Summary = RoomSecretsSummary;
+    type Delta = RoomSecretsDelta;
+    type Parameters = ChatRoomParametersV1;
+
+    fn verify(
+        &self,
+        parent_state: &Self::ParentState,
+        parameters: &Self::Parameters,
+    ) -> Result<(), String> {
+        let participants_by_id = parent_state.members.participants_by_member_id(parameters);
+        let mut sealed_for = HashSet::with_capacity(self.secrets.len());
+        for secret in &self.secrets {
+            let member_id = secret.sealed_secret.member_id;
+            if !sealed_for.insert(member_id) {
+                return Err(format!(
+                    "More than one room secret sealed for {}",
+                    member_id
+                ));
+            }
+            if !participants_by_id.contains_key(&member_id) {
+                return Err(format!(
+                    "Room secret sealed for non-existent member: {}",
+                    member_id
+                ));
+            }
+            secret.verify(parameters)?;
+        }
+        let mut versions = HashSet::with_capacity(self.epochs.len());
+        for epoch in &self.epochs {
+            let version = epoch.epoch.secret_version;
+            if !versions.insert(version) {
+                return Err(format!(
+                    "More than one record of room secret epoch {}",
+                    version
+                ));
+            }
+            epoch.verify(parameters)?;
+        }
+        Ok(())
+    }
+
+    fn summarize(
+        &self,
+        _parent_state: &Self::ParentState,
+        _parameters: &Self::Parameters,
+    ) -> Self::Summary {
+        RoomSecretsSummary {
+            secrets: self.secrets.iter().map(|secret| secret.id()).collect(),
+            epochs: self.epochs.iter().map(|epoch| epoch.id()).collect(),
+        }
+    }
+
+    fn delta(
+        &self,
+        _parent_state: &Self::ParentState,
+        _parameters: &Self::Parameters,
+        old_state_summary: &Self::Summary,
+    ) -> Option<Self::Delta> {
+        let old_secret_ids: HashSet<&SealedSecretId> = old_state_summary.secrets.iter().collect();
+        let old_epoch_ids: HashSet<&SecretEpochId> = old_state_summary.epochs.iter().collect();
+        let delta = RoomSecretsDelta {
+            secrets: self
+                .secrets
+                .iter()
+                .filter(|secret| !old_secret_ids.contains(&secret.id()))
+                .cloned()
+                .collect(),
+            epochs: self
+                .epochs
+                .iter()
+                .filter(|epoch| !old_epoch_ids.contains(&epoch.id()))
+                .cloned()
+                .collect(),
+        };
+        if delta.secrets.is_empty() && delta.epochs.is_empty() {
+            None
+        } else {
+            Some(delta)
+        }
+    }
+
+    fn apply_delta(
+        &mut self,
+        parent_state: &Self::ParentState,
+        parameters: &Self::Parameters,
+        delta: &Self::Delta,
+    ) -> Result<DeltaReport, String> {
+        let participants_by_id = parent_state.members.participants_by_member_id(parameters);
+        let mut report = DeltaReport::default();
+        for epoch in &delta.epochs {
+            let version = epoch.epoch.secret_version;
+            let existing = self
+                .epochs
+                .iter()
+                .position(|held| held.epoch.secret_version == version);
+            if let Some(i) = existing {
+                if self.epochs[i].precedence() >= epoch.precedence() {
+                    continue;
+                }
+            }
+
+            let item = format!("room secret epoch {}", version);
+            if let Err(e) = epoch.verify(parameters) {
+                report.rejected(item, e);
+                continue;
+            }
+
+            match existing {
+                Some(i) => self.epochs[i] = epoch.clone(),
+                None => self.epochs.push(epoch.clone()),
+            }
+            report.applied(item);
+        }
+        self.epochs.sort_by_key(|epoch| epoch.epoch.secret_version);
+
+        for secret in &delta.secrets {
+            let member_id = secret.sealed_secret.member_id;
+            let existing = self
+                .secrets
+                .iter()
+                .position(|held| held.sealed_secret.member_id == member_id);
+            if let Some(i) = existing {
+                if self.secrets[i].precedence() >= secret.precedence() {
+                    continue;
+                }
+            }
+
+            let item = format!(
+                "room secret version {} for {}",
+                secret.sealed_secret.secret_version, member_id
+            );
+            if !participants_by_id.contains_key(&member_id) {
+                report.rejected(item, format!("Member {} not found", member_id));
+                continue;
+            }
+            if let Err(e) = secret.verify(parameters) {
+                report.rejected(item, e);
+                continue;
+            }
+
+            match existing {
+                Some(i) => self.secrets[i] = secret.clone(),
+                None => self.secrets.push(secret.clone()),
+            }
+            report.applied(item);
+        }
+        // Kept in a fixed order so that peers that got them in different orders converge
+        self.secrets
+            .sort_by_key(|secret| secret.sealed_secret.member_id);
+        Ok(report)
+    }
+}
+
+impl RoomSecretsV1 {
+    /// The room secret sealed for the holder of `signing_key`, if any
+    pub fn open(&self, signing_key: &SigningKey) -> Option<Result<RoomSecret, String>> {
+        let member_id = MemberId::new(&signing_key.verifying_key());
+        self.secrets
+            .iter()
+            .find(|secret| secret.sealed_secret.member_id == member_id)
+            .map(|secret| secret.open(signing_key))
+    }
+
+    /// The secrets of the epochs before `secret`'s, as far back as they're chained, latest
+    /// first
+    pub fn earlier_secrets(&self, secret: &RoomSecret) -> Vec<RoomSecret> {
+        let mut earlier: Vec<RoomSecret> = Vec::new();
+        loop {
+            let later = earlier.last().unwrap_or(secret);
+            let chained = self
+                .epoch(later.version)
+                .and_then(|epoch| epoch.epoch.previous_secret.as_ref());
+            match chained.map(|chained| later.unchain(chained)) {
+                Some(Ok(previous)) if previous.version < later.version => earlier.push(previous),
+                _ => return earlier,
+            }
+        }
+    }
+
+    /// The version of the room secret and when its epoch started
+    pub fn current_epoch(&self) -> Option<(u32, SystemTime)> {
+        self.secrets
+            .iter()
+            .map(|secret| {
+                (
+                    secret.sealed_secret.secret_version,
+                    secret.sealed_secret.epoch_started_at,
+                )
+            })
+            .max()
+    }
+
+    /// The record of epoch `version`, if it's still held
+    pub fn epoch(&self, version: u32) -> Option<&AuthorizedSecretEpoch> {
+        self.epochs
+            .iter()
+            .find(|epoch| epoch.epoch.secret_version == version)
+    }
+
+    /// Whether the current secret was sealed to anyone who is no longer in the room. An
+    /// epoch started before epochs were recorded is rotated once to record it.
+    pub fn needs_rotation(
+        &self,
+        parent_state: &ChatRoomStateV1,
+        parameters: &ChatRoomParametersV1,
+    ) -> bool {
+        let Some((version, _)) = self.current_epoch() else {
+            return false;
+        };
+        let Some(epoch) = self.epoch(version) else {
+            return true;
+        };
+        let participants_by_id = parent_state.members.participants_by_member_id(parameters);
+        epoch
+            .epoch
+            .sealed_to
+            .iter()
+            .any(|member_id| !participants_by_id.contains_key(member_id))
+    }
+
+    /// Starts a new epoch at `now`, returning its secret sealed to everyone in the room along
+    /// with its record, which chains the owner's copy of the current secret
+    pub fn rotate(
+        &self,
+        parent_state: &ChatRoomStateV1,
+        parameters: &ChatRoomParametersV1,
+        owner_signing_key: &SigningKey,
+        now: SystemTime,
+    ) -> Result<(RoomSecret, RoomSecretsDelta), String> {
+        let version = self.current_epoch().map_or(1, |(version, _)| version + 1);
+        let secret = RoomSecret::generate(version, now);
+        let previous = self.open(owner_signing_key).transpose()?;
+        let participants: Vec<VerifyingKey> = parent_state
+            .members
+            .participants(parameters)
+            .map(|participant| *participant.verifying_key())
+            .collect();
+        let delta = secret.seal_epoch(
+            &participants,
+            vec![],
+            previous.map(|previous| secret.chain(&previous)),
+            owner_signing_key,
+        )?;
+        Ok((secret, delta))
+    }
+
+    /// Seals `secret` to every participant of the room who doesn't hold it yet, and records
+    /// them in its epoch. Returns `None` if everyone holds it already.
+    pub fn share(
+        &self,
+        parent_state: &ChatRoomStateV1,
+        parameters: &ChatRoomParametersV1,
+        secret: &RoomSecret,
+        owner_signing_key: &SigningKey,
+    ) -> Result<Option<RoomSecretsDelta>, String> {
+        let recipients = self.members_without_secret(parent_state, parameters, secret.version);
+        let epoch = self.epoch(secret.version);
+        if recipients.is_empty() && epoch.is_some() {
+            return Ok(None);
+        }
+        // The members holding the secret already, recorded or from before epochs were
+        let holders = self
+            .secrets
+            .iter()
+            .filter(|held| held.sealed_secret.secret_version == secret.version)
+            .map(|held| held.sealed_secret.member_id);
+        let sealed_to = epoch
+            .map(|epoch| epoch.epoch.sealed_to.clone())
+            .unwrap_or_default()
+            .into_iter()
+            .chain(holders)
+            .collect();
+        let previous_secret = epoch.and_then(|epoch| epoch.epoch.previous_secret.clone());
+        secret
+            .seal_epoch(&recipients, sealed_to, previous_secret, owner_signing_key)
+            .map(Some)
+    }
+
+    /// Drops the records of epochs no message in `messages` is encrypted with any more,
+    /// keeping those needed to go back to the earliest one that still is and the current
+    /// one. Only depends on the state, so all peers drop the same records.
+    pub fn retain_epochs_in_use(&mut self, messages: &MessagesV1) {
+        let current = self.current_epoch().map(|(version, _)| version);
+        let earliest_in_use = messages
+            .messages
+            .iter()
+            .filter_map(|message| message.message.encrypted_content.as_ref())
+            .map(|content| content.secret_version)
+            .min();
+        self.epochs.retain(|epoch| {
+            let version = epoch.epoch.secret_version;
+            current.is_none_or(|current| version >= current)
+                || earliest_in_use.is_some_and(|earliest| version >= earliest)
+        });
+    }
+
+    /// The participants of the room that don't hold `secret_version` of the room secret yet
+    pub fn members_without_secret(
+        &self,
+        parent_state: &ChatRoomStateV1,
+        parameters: &ChatRoomParametersV1,
+        secret_version: u32,
+    ) -> Vec<VerifyingKey> {
+        let versions: HashMap<MemberId, u32> = self
+            .secrets
+            .iter()
+            .map(|secret| {
+                (
+                    secret.sealed_secret.member_id,
+                    secret.sealed_secret.secret_version,
+                )
+            })
+            .collect();
+        parent_state
+            .members
+            .participants_by_member_id(parameters)
+            .into_iter()
+            .filter(|(member_id, _)| {
+                versions
+                    .get(member_id)
+                    .is_none_or(|version| *version < secret_version)
+            })
+            .map(|(_, participant)| *participant.verifying_key())
+            .collect()
+    }
+}
+
+/// A room secret sealed to a single member
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct SealedSecret {
+    pub owner_member_id: MemberId,
+    pub member_id: MemberId,
+    pub secret_version: u32,
+    pub epoch_started_at: SystemTime,
+    pub sealed_key: SealedBox,
+}
+
+impl Signable for SealedSecret {
+    const DOMAIN: &'static str = "sealed_secret";
+}
+
+#[derive(Eq, PartialEq, Hash, Serialize, Deserialize, Clone, Debug)]
+pub struct SealedSecretId(pub FastHash);
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuthorizedSealedSecret {
+    pub sealed_secret: SealedSecret,
+    pub signature: Signature,
+}
+
+impl fmt::Debug for AuthorizedSealedSecret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthorizedSealedSecret")
+            .field("sealed_secret", &self.sealed_secret)
+            .field(
+                "signature",
+                &format_args!("{}", truncated_base64(self.signature.to_bytes())),
+            )
+            .finish()
+    }
+}
+
+impl AuthorizedSealedSecret {
+    pub fn new(sealed_secret: SealedSecret, owner_signing_key: &SigningKey) -> Self {
+        let signature = sign_struct(&sealed_secret, owner_signing_key);
+        Self {
+            sealed_secret,
+            signature,
+        }
+    }
+
+    /// Checks that the room owner sealed the secret for this room
+    pub fn verify(&self, parameters: &ChatRoomParametersV1) -> Result<(), String> {
+        parameters.check_room_binding(self.sealed_secret.owner_member_id)?;
+        verify_struct(&self.sealed_secret, &self.signature, &parameters.owner)
+            .map_err(|e| format!("Invalid room secret signature: {}", e))
+    }
+
+    pub fn id(&self) -> SealedSecretId {
+        SealedSecretId(fast_hash(&self.signature.to_bytes()))
+    }
+
+    /// Later versions of the room secret replace earlier ones. Copies of the same version
+    /// are interchangeable, keeping the one with the lowest signature lets all peers
+    /// converge on the same copy.
+    fn precedence(&self) -> (u32, Reverse<[u8; 64]>) {
+        (
+            self.sealed_secret.secret_version,
+            Reverse(self.signature.to_bytes()),
+        )
+    }
+
+    pub fn open(&self, member_signing_key: &SigningKey) -> Result<RoomSecret, String> {
+        let sealed = &self.sealed_secret;
+        let key = sealed
+            .sealed_key
+            .open(member_signing_key, SealedSecret::DOMAIN.as_bytes())
+            .map_err(|e| format!("Can't open room secret: {}", e))?;
+        Ok(RoomSecret {
+            version: sealed.secret_version,
+            started_at: sealed.epoch_started_at,
+            key: key
+                .try_into()
+                .map_err(|_| "Room secret has the wrong length".to_string())?,
+        })
+    }
+}
+
+/// The owner's record of an epoch of the room secret: the members it was sealed to, so that
+/// the owner can tell when someone who holds it has gone, and the previous epoch's secret,
+/// so that whoever holds this epoch's secret can read the messages written before it
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct SecretEpoch {
+    pub owner_member_id: MemberId,
+    pub secret_version: u32,
+    /// Grows as the secret is shared with members who join during the epoch
+    pub sealed_to: Vec<MemberId>,
+    pub previous_secret: Option<ChainedSecret>,
+}
+
+impl Signable for SecretEpoch {
+    const DOMAIN: &'static str = "secret_epoch";
+}
+
+#[derive(Eq, PartialEq, Hash, Serialize, Deserialize, Clone, Debug)]
+pub struct SecretEpochId(pub FastHash);
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuthorizedSecretEpoch {
+    pub epoch: SecretEpoch,
+    pub signature: Signature,
+}
+
+impl fmt::Debug for AuthorizedSecretEpoch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthorizedSecretEpoch")
+            .field("epoch", &self.epoch)
+            .field(
+                "signature",
+                &format_args!("{}", truncated_base64(self.signature.to_bytes())),
+            )
+            .finish()
+    }
+}
+
+impl AuthorizedSecretEpoch {
+    pub fn new(epoch: SecretEpoch, owner_signing_key: &SigningKey) -> Self {
+        let signature = sign_struct(&epoch, owner_signing_key);
+        Self { epoch, signature }
+    }
+
+    /// Checks that the room owner recorded the epoch for this room
+    pub fn verify(&self, parameters: &ChatRoomParametersV1) -> Result<(), String> {
+        parameters.check_room_binding(self.epoch.owner_member_id)?;
+        verify_struct(&self.epoch, &self.signature, &parameters.owner)
+            .map_err(|e| format!("Invalid room secret epoch signature: {}", e))
+    }
+
+    pub fn id(&self) -> SecretEpochId {
+        SecretEpochId(fast_hash(&self.signature.to_bytes()))
+    }
+
+    /// The record of an epoch is re-signed as the secret is shared with more members, so
+    /// the one sealed to the most members wins, then the one with the lowest signature
+    fn precedence(&self) -> (usize, Reverse<[u8; 64]>) {
+        (
+            self.epoch.sealed_to.len(),
+            Reverse(self.signature.to_bytes()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::room_state::configuration::{AuthorizedConfigurationV1, Configuration};
+    use crate::room_state::member::{AuthorizedMember, Member};
+
+    fn create_room() -> (
+        ChatRoomStateV1,
+        ChatRoomParametersV1,
+        SigningKey,
+        SigningKey,
+    ) {
+        let owner_key = SigningKey::generate(&mut OsRng);
+        let member_key = SigningKey::generate(&mut OsRng);
+        let parameters = ChatRoomParametersV1 {
+            owner: owner_key.verifying_key(),
+            admins: None,
+            recovery: None,
+        };
+        let mut state = ChatRoomStateV1 {
+            configuration: AuthorizedConfigurationV1::new(
+                Configuration {
+                    owner_member_id: parameters.owner_id(),
+                    ..Configuration::default()
+                },
+                &owner_key,
+            ),
+            ..ChatRoomStateV1::default()
+        };
+        state.members.members.push(AuthorizedMember::new(
+            Member {
+                owner_member_id: parameters.owner_id(),
+                invited_by: parameters.owner_id(),
+                member_vk: member_key.verifying_key(),
+            },
+            &owner_key,
+        ));
+        (state, parameters, owner_key, member_key)
+    }
+
+    fn sealed(secrets: Vec<AuthorizedSealedSecret>) -> RoomSecretsDelta {
+        RoomSecretsDelta {
+            secrets,
+            epochs: vec![],
+        }
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let owner_key = SigningKey::generate(&mut OsRng);
+        let member_key = SigningKey::generate(&mut OsRng);
+        let secret = RoomSecret::generate(1, SystemTime::now());
+
+        let sealed = secret
+            .seal(&member_key.verifying_key(), &owner_key)
+            .unwrap();
+        assert_eq!(sealed.open(&member_key), Ok(secret.clone()));
+        assert!(sealed.open(&owner_key).is_err(), "Sealed to someone else");
+
+        let content = secret.encrypt("Hello");
+        assert_eq!(content.plaintext_size(), 5);
+        assert_eq!(secret.decrypt(&content), Ok("Hello".to_string()));
+        assert!(RoomSecret::generate(1, SystemTime::now())
+            .decrypt(&content)
+            .is_err());
+        assert!(RoomSecret::generate(2, SystemTime::now())
+            .decrypt(&content)
+            .is_err());
+    }
+
+    #[test]
+    fn test_apply_delta() {
+        let (state, parameters, owner_key, member_key) = create_room();
+        let stranger_key = SigningKey::generate(&mut OsRng);
+        let first = RoomSecret::generate(1, SystemTime::now());
+        let second = RoomSecret::generate(2, SystemTime::now());
+        let mut secrets = RoomSecretsV1::default();
+
+        let report = secrets
+            .apply_delta(
+                &state,
+                &parameters,
+                &sealed(vec![
+                    first.seal(&owner_key.verifying_key(), &owner_key).unwrap(),
+                    first.seal(&member_key.verifying_key(), &owner_key).unwrap(),
+                    first
+                        .seal(&stranger_key.verifying_key(), &owner_key)
+                        .unwrap(),
+                    // A later version, so that it isn't skipped as superseded
+                    second
+                        .seal(&member_key.verifying_key(), &member_key)
+                        .unwrap(),
+                ]),
+            )
+            .unwrap();
+        assert_eq!(report.applied.len(), 2);
+        assert_eq!(
+            report.rejected.len(),
+            2,
+            "A non-member and a non-owner seal"
+        );
+        assert!(secrets.verify(&state, &parameters).is_ok());
+        assert_eq!(secrets.open(&member_key), Some(Ok(first.clone())));
+        assert_eq!(secrets.open(&stranger_key), None);
+        assert_eq!(
+            secrets.members_without_secret(&state, &parameters, 1),
+            Vec::<VerifyingKey>::new()
+        );
+        assert_eq!(
+            secrets.members_without_secret(&state, &parameters, 2).len(),
+            2
+        );
+
+        // A later version replaces the one held, an earlier one doesn't
+        let report = secrets
+            .apply_delta(
+                &state,
+                &parameters,
+                &sealed(vec![second
+                    .seal(&member_key.verifying_key(), &owner_key)
+                    .unwrap()]),
+            )
+            .unwrap();
+        assert!(report.is_fully_applied());
+        let report = secrets
+            .apply_delta(
+                &state,
+                &parameters,
+                &sealed(vec![first
+                    .seal(&member_key.verifying_key(), &owner_key)
+                    .unwrap()]),
+            )
+            .unwrap();
+        assert!(report.applied.is_empty());
+        assert_eq!(secrets.open(&member_key), Some(Ok(second)));
+
+        // Peers converge whatever order copies of the same version arrive in
+        let copies = [
+            first
+                .seal(&stranger_key.verifying_key(), &owner_key)
+                .unwrap(),
+            first.seal(&owner_key.verifying_key(), &owner_key).unwrap(),
+            first.seal(&owner_key.verifying_key(), &owner_key).unwrap(),
+        ];
+        let mut forward = RoomSecretsV1::default();
+        let mut backward = RoomSecretsV1::default();
+        forward
+            .apply_delta(&state, &parameters, &sealed(copies.to_vec()))
+            .unwrap();
+        backward
+            .apply_delta(
+                &state,
+                &parameters,
+                &sealed(copies.iter().rev().cloned().collect()),
+            )
+            .unwrap();
+        assert_eq!(forward, backward);
+        assert_eq!(forward.secrets.len(), 1);
+    }
+
+    #[test]
+    fn test_epoch_records_converge() {
+        let (state, parameters, owner_key, member_key) = create_room();
+        let secret = RoomSecret::generate(1, SystemTime::now());
+        let empty = RoomSecretsV1::default();
+
+        // Sharing with the owner first and then with the member re-signs the record
+        let first_share = empty
+            .share(&state, &parameters, &secret, &owner_key)
+            .unwrap()
+            .unwrap();
+        assert_eq!(first_share.secrets.len(), 2);
+        let owner_only = RoomSecretsDelta {
+            secrets: vec![first_share.secrets[0].clone()],
+            epochs: vec![secret
+                .seal_epoch(&[owner_key.verifying_key()], vec![], None, &owner_key)
+                .unwrap()
+                .epochs[0]
+                .clone()],
+        };
+        let mut secrets = RoomSecretsV1::default();
+        secrets
+            .apply_delta(&state, &parameters, &owner_only)
+            .unwrap();
+        let second_share = secrets
+            .share(&state, &parameters, &secret, &owner_key)
+            .unwrap()
+            .unwrap();
+        assert_eq!(second_share.secrets.len(), 1);
+        assert_eq!(second_share.epochs[0].epoch.sealed_to.len(), 2);
+
+        // The record sealed to the most members wins whichever arrives first
+        let mut forward = RoomSecretsV1::default();
+        let mut backward = RoomSecretsV1::default();
+        for delta in [&owner_only, &second_share] {
+            forward.apply_delta(&state, &parameters, delta).unwrap();
+        }
+        for delta in [&second_share, &owner_only] {
+            backward.apply_delta(&state, &parameters, delta).unwrap();
+        }
+        assert_eq!(forward, backward);
+        assert_eq!(forward.epochs, second_share.epochs);
+        assert!(forward.verify(&state, &parameters).is_ok());
+        assert_eq!(
+            forward.share(&state, &parameters, &secret, &owner_key),
+            Ok(None)
+        );
+
+        // Records are only accepted from the owner
+        let forged = RoomSecretsDelta {
+            secrets: vec![],
+            epochs: vec![secret
+                .seal_epoch(&[member_key.verifying_key()], vec![], None, &member_key)
+                .unwrap()
+                .epochs[0]
+                .clone()],
+        };
+        let report = RoomSecretsV1::default()
+            .apply_delta(&state, &parameters, &forged)
+            .unwrap();
+        assert_eq!(report.rejected.len(), 1);
+    }
+}
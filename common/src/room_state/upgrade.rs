@@ -1,12 +1,15 @@
+use crate::room_state::authority::{Authority, RoomAction, RoomSignatures};
 use crate::room_state::member::MemberId;
 use crate::room_state::ChatRoomParametersV1;
-use crate::util::{sign_struct, truncated_base64, verify_struct};
+use crate::util::Signable;
 use crate::ChatRoomStateV1;
 use blake3::Hash;
-use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
-use freenet_scaffold::ComposableState;
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use freenet_scaffold::{ComposableState, DeltaReport};
 use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
 use std::fmt;
+use std::time::SystemTime;
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 pub struct OptionalUpgradeV1(pub Option<AuthorizedUpgradeV1>);
@@ -17,15 +20,48 @@ impl Default for OptionalUpgradeV1 {
     }
 }
 
+impl OptionalUpgradeV1 {
+    /// The version an upgrade replacing the current one must have
+    pub fn next_version(&self) -> Result<u8, String> {
+        match &self.0 {
+            None => Ok(1),
+            Some(upgrade) => upgrade
+                .upgrade
+                .version
+                .checked_add(1)
+                .ok_or_else(|| "The room has reached the last upgrade version".to_string()),
+        }
+    }
+
+    /// Whether a message written at `time` may still be added to this room rather than
+    /// its successor. Upgrades signed before they carried a cutoff take no new messages.
+    pub fn accepts_message_at(&self, time: SystemTime) -> bool {
+        match &self.0 {
+            None => true,
+            Some(upgrade) => upgrade.upgrade.cutoff.is_some_and(|cutoff| time <= cutoff),
+        }
+    }
+
+    /// Whether a message written at `time` is past the upgrade's cutoff, so that it's
+    /// dropped also where it arrived before the upgrade
+    pub fn is_past_cutoff(&self, time: SystemTime) -> bool {
+        self.0
+            .as_ref()
+            .and_then(|upgrade| upgrade.upgrade.cutoff)
+            .is_some_and(|cutoff| time > cutoff)
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, PartialEq)]
 pub struct AuthorizedUpgradeV1 {
     pub upgrade: UpgradeV1,
-    pub signature: Signature,
+    pub signatures: RoomSignatures,
 }
 
 impl ComposableState for OptionalUpgradeV1 {
     type ParentState = ChatRoomStateV1;
-    type Summary = Option<u8>;
+    /// Upgrades are ordered by who authorized them, then by version, then by lowest signature
+    type Summary = Option<(Authority, u8, Reverse<Vec<u8>>)>;
     type Delta = AuthorizedUpgradeV1;
     type Parameters = ChatRoomParametersV1;
 
@@ -35,9 +71,8 @@ impl ComposableState for OptionalUpgradeV1 {
         parameters: &Self::Parameters,
     ) -> Result<(), String> {
         if let Some(upgrade) = &self.0 {
-            upgrade
-                .validate(&parameters.owner)
-                .map_err(|e| format!("Invalid signature: {}", e))
+            upgrade.verify_signature(parameters)?;
+            parameters.check_room_binding(upgrade.upgrade.owner_member_id)
         } else {
             Ok(())
         }
@@ -46,24 +81,24 @@ impl ComposableState for OptionalUpgradeV1 {
     fn summarize(
         &self,
         _parent_state: &Self::ParentState,
-        _parameters: &Self::Parameters,
+        parameters: &Self::Parameters,
     ) -> Self::Summary {
-        self.0.as_ref().map(|u| u.upgrade.version)
+        self.0.as_ref().map(|u| u.precedence(parameters))
     }
 
     fn delta(
         &self,
         _parent_state: &Self::ParentState,
-        _parameters: &Self::Parameters,
+        parameters: &Self::Parameters,
         old_state_summary: &Self::Summary,
     ) -> Option<Self::Delta> {
         match &self.0 {
             Some(upgrade) => {
-                // If the upgrade has a higher version than the old room_state summary or of the old summary is None
-                // then return the upgrade as a delta
-                if old_state_summary
-                    .map_or(true, |old_version| upgrade.upgrade.version > old_version)
-                {
+                // If the upgrade takes precedence over the one in the old room_state summary,
+                // or the old summary is None, then return the upgrade as a delta
+                if old_state_summary.as_ref().map_or(true, |old_precedence| {
+                    upgrade.precedence(parameters) > *old_precedence
+                }) {
                     Some(upgrade.clone())
                 } else {
                     None
@@ -78,30 +113,66 @@ impl ComposableState for OptionalUpgradeV1 {
         _parent_state: &Self::ParentState,
         parameters: &Self::Parameters,
         delta: &Self::Delta,
-    ) -> Result<(), String> {
-        // Verify the delta before applying it
-        delta
-            .validate(&parameters.owner)
-            .map_err(|e| format!("Invalid upgrade signature: {}", e))?;
+    ) -> Result<DeltaReport, String> {
+        let mut report = DeltaReport::default();
 
-        *self = OptionalUpgradeV1(Some(delta.clone()));
-        Ok(())
+        // The upgrade we hold, or one that ours supersedes, is being redelivered
+        if let Some(upgrade) = &self.0 {
+            if upgrade.precedence(parameters) > delta.precedence(parameters) || upgrade == delta {
+                return Ok(report);
+            }
+        }
+
+        // Verify the delta before applying it
+        let item = format!("upgrade version {}", delta.upgrade.version);
+        let validation = delta
+            .verify_signature(parameters)
+            .and_then(|_| parameters.check_room_binding(delta.upgrade.owner_member_id));
+        match validation {
+            Ok(()) => {
+                *self = OptionalUpgradeV1(Some(delta.clone()));
+                report.applied(item);
+            }
+            Err(e) => report.rejected(item, e),
+        }
+        Ok(report)
     }
 }
 
 impl AuthorizedUpgradeV1 {
     pub fn new(upgrade: UpgradeV1, signing_key: &SigningKey) -> Self {
+        Self::new_with_keys(upgrade, &[signing_key])
+    }
+
+    /// Signs the upgrade with several keys, e.g. enough of the room's recovery keys
+    pub fn new_with_keys(upgrade: UpgradeV1, signing_keys: &[&SigningKey]) -> Self {
         Self {
-            upgrade: upgrade.clone(),
-            signature: sign_struct(&upgrade, signing_key),
+            signatures: RoomSignatures::sign(&upgrade, signing_keys),
+            upgrade,
         }
     }
 
-    pub fn validate(
-        &self,
-        verifying_key: &VerifyingKey,
-    ) -> Result<(), ed25519_dalek::SignatureError> {
-        verify_struct(&self.upgrade, &self.signature, &verifying_key)
+    /// Checks the signatures and returns the authority they carry
+    pub fn verify_signature(&self, parameters: &ChatRoomParametersV1) -> Result<Authority, String> {
+        self.signatures
+            .verify(&self.upgrade, RoomAction::Upgrade, parameters)
+            .map_err(|e| format!("Invalid upgrade signature: {}", e))
+    }
+
+    /// Adds a co-signature, for rooms whose admins must sign upgrades together
+    pub fn add_signature(&mut self, signing_key: &SigningKey) {
+        self.signatures.add(&self.upgrade, signing_key);
+    }
+
+    /// Which of two upgrades wins, assuming both have valid signatures. Different upgrades
+    /// with the same version and authority are told apart by their signatures, the lowest
+    /// winning, so that all peers keep the same one whichever arrives first.
+    fn precedence(&self, parameters: &ChatRoomParametersV1) -> (Authority, u8, Reverse<Vec<u8>>) {
+        (
+            self.signatures.claimed_authority(parameters),
+            self.upgrade.version,
+            Reverse(self.signatures.to_bytes()),
+        )
     }
 }
 
@@ -109,10 +180,7 @@ impl fmt::Debug for AuthorizedUpgradeV1 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("AuthorizedUpgrade")
             .field("upgrade", &self.upgrade)
-            .field(
-                "signature",
-                &format_args!("{}", truncated_base64(self.signature.to_bytes())),
-            )
+            .field("signatures", &self.signatures)
             .finish()
     }
 }
@@ -122,13 +190,27 @@ pub struct UpgradeV1 {
     pub owner_member_id: MemberId,
     pub version: u8,
     pub new_chatroom_address: Hash,
+    /// Set when the room moves to a new owner key, which the successor room's parameters
+    /// name as owner. Left out while unset, so that upgrades signed before it existed
+    /// serialize back to the bytes that were signed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new_owner: Option<VerifyingKey>,
+    /// Messages written after this time belong in the successor, the room drops them
+    /// whether they arrive before or after the upgrade so that all peers keep the same
+    /// messages. Left out while unset, like `new_owner`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cutoff: Option<SystemTime>,
+}
+
+impl Signable for UpgradeV1 {
+    const DOMAIN: &'static str = "upgrade";
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::room_state::member::MemberId;
-    use ed25519_dalek::SigningKey;
+    use ed25519_dalek::{Signature, SigningKey};
     use freenet_scaffold::util::FastHash;
     use rand::rngs::OsRng;
 
@@ -137,6 +219,8 @@ mod tests {
             owner_member_id: owner_id,
             version: 1,
             new_chatroom_address: Hash::from([0; 32]),
+            new_owner: None,
+            cutoff: None,
         }
     }
 
@@ -150,11 +234,65 @@ mod tests {
         let authorized_upgrade = AuthorizedUpgradeV1::new(upgrade.clone(), &signing_key);
 
         assert_eq!(authorized_upgrade.upgrade, upgrade);
-        assert!(authorized_upgrade.validate(&verifying_key).is_ok());
+        let parameters = ChatRoomParametersV1 {
+            owner: verifying_key,
+            admins: None,
+            recovery: None,
+        };
+        assert_eq!(
+            authorized_upgrade.verify_signature(&parameters),
+            Ok(Authority::Owner)
+        );
 
         // Test with wrong key
-        let wrong_key = SigningKey::generate(&mut OsRng).verifying_key();
-        assert!(authorized_upgrade.validate(&wrong_key).is_err());
+        let wrong_parameters = ChatRoomParametersV1 {
+            owner: SigningKey::generate(&mut OsRng).verifying_key(),
+            admins: None,
+            recovery: None,
+        };
+        assert!(authorized_upgrade
+            .verify_signature(&wrong_parameters)
+            .is_err());
+    }
+
+    #[test]
+    fn test_upgrade_signed_before_new_owner_and_cutoff() {
+        #[derive(Serialize)]
+        struct LegacyUpgrade {
+            owner_member_id: MemberId,
+            version: u8,
+            new_chatroom_address: Hash,
+        }
+        impl Signable for LegacyUpgrade {
+            const DOMAIN: &'static str = UpgradeV1::DOMAIN;
+        }
+
+        let owner_key = SigningKey::generate(&mut OsRng);
+        let upgrade = create_test_upgrade(MemberId::new(&owner_key.verifying_key()));
+        let legacy = LegacyUpgrade {
+            owner_member_id: upgrade.owner_member_id,
+            version: upgrade.version,
+            new_chatroom_address: upgrade.new_chatroom_address,
+        };
+        let signature = crate::util::sign_struct(&legacy, &owner_key);
+        let authorized_upgrade = AuthorizedUpgradeV1 {
+            upgrade,
+            signatures: RoomSignatures(vec![(owner_key.verifying_key(), signature)]),
+        };
+        let parameters = ChatRoomParametersV1 {
+            owner: owner_key.verifying_key(),
+            admins: None,
+            recovery: None,
+        };
+        assert_eq!(
+            authorized_upgrade.verify_signature(&parameters),
+            Ok(Authority::Owner)
+        );
+
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(&legacy, &mut bytes).unwrap();
+        let decoded: UpgradeV1 = ciborium::de::from_reader(bytes.as_slice()).unwrap();
+        assert_eq!(decoded, authorized_upgrade.upgrade);
     }
 
     #[test]
@@ -171,6 +309,8 @@ mod tests {
         let parent_state = ChatRoomStateV1::default();
         let parameters = ChatRoomParametersV1 {
             owner: owner_verifying_key,
+            admins: None,
+            recovery: None,
         };
 
         // Verify that a valid upgrade passes verification
@@ -182,7 +322,7 @@ mod tests {
         // Test with invalid signature
         let mut invalid_upgrade = optional_upgrade.clone();
         if let Some(ref mut au) = invalid_upgrade.0 {
-            au.signature = Signature::from_bytes(&[0; 64]); // Replace with an invalid signature
+            au.signatures.0[0].1 = Signature::from_bytes(&[0; 64]); // Replace with an invalid signature
         }
         assert!(
             invalid_upgrade.verify(&parent_state, &parameters).is_err(),
@@ -205,15 +345,24 @@ mod tests {
         let upgrade = create_test_upgrade(owner_id);
         let authorized_upgrade = AuthorizedUpgradeV1::new(upgrade, &signing_key);
 
-        let optional_upgrade = OptionalUpgradeV1(Some(authorized_upgrade));
+        let optional_upgrade = OptionalUpgradeV1(Some(authorized_upgrade.clone()));
 
         let parent_state = ChatRoomStateV1::default();
         let parameters = ChatRoomParametersV1 {
             owner: signing_key.verifying_key(),
+            admins: None,
+            recovery: None,
         };
 
         let summary = optional_upgrade.summarize(&parent_state, &parameters);
-        assert_eq!(summary, Some(1));
+        assert_eq!(
+            summary,
+            Some((
+                Authority::Owner,
+                1,
+                Reverse(authorized_upgrade.signatures.to_bytes())
+            ))
+        );
 
         let none_upgrade = OptionalUpgradeV1(None);
         let none_summary = none_upgrade.summarize(&parent_state, &parameters);
@@ -233,6 +382,8 @@ mod tests {
         let parent_state = ChatRoomStateV1::default();
         let parameters = ChatRoomParametersV1 {
             owner: signing_key.verifying_key(),
+            admins: None,
+            recovery: None,
         };
 
         let old_summary = None;
@@ -248,7 +399,7 @@ mod tests {
     #[test]
     fn test_optional_upgrade_apply_delta() {
         let signing_key = SigningKey::generate(&mut OsRng);
-        let owner_id = MemberId(FastHash(0));
+        let owner_id = MemberId::new(&signing_key.verifying_key());
 
         let upgrade = create_test_upgrade(owner_id);
         let authorized_upgrade = AuthorizedUpgradeV1::new(upgrade, &signing_key);
@@ -258,6 +409,8 @@ mod tests {
         let parent_state = ChatRoomStateV1::default();
         let parameters = ChatRoomParametersV1 {
             owner: signing_key.verifying_key(),
+            admins: None,
+            recovery: None,
         };
 
         let delta = authorized_upgrade.clone();
@@ -266,4 +419,95 @@ mod tests {
             .is_ok());
         assert_eq!(optional_upgrade, OptionalUpgradeV1(Some(delta)));
     }
+
+    #[test]
+    fn test_recovery_upgrade_replaces_owner_upgrade() {
+        use crate::room_state::authority::KeyPolicy;
+
+        let owner_key = SigningKey::generate(&mut OsRng);
+        let recovery_key = SigningKey::generate(&mut OsRng);
+        let parameters = ChatRoomParametersV1 {
+            owner: owner_key.verifying_key(),
+            admins: None,
+            recovery: Some(KeyPolicy::single(recovery_key.verifying_key())),
+        };
+        let parent_state = ChatRoomStateV1::default();
+        let upgrade = |version: u8, new_owner: &SigningKey| UpgradeV1 {
+            owner_member_id: parameters.owner_id(),
+            version,
+            new_chatroom_address: Hash::from([version; 32]),
+            new_owner: Some(new_owner.verifying_key()),
+            cutoff: None,
+        };
+
+        // A compromised owner key points the room at the attacker with the highest version
+        let attacker_key = SigningKey::generate(&mut OsRng);
+        let hijack = AuthorizedUpgradeV1::new(upgrade(u8::MAX, &attacker_key), &owner_key);
+        let mut optional_upgrade = OptionalUpgradeV1(None);
+        optional_upgrade
+            .apply_delta(&parent_state, &parameters, &hijack)
+            .unwrap();
+
+        // The recovery key points it at a new owner key instead
+        let new_owner_key = SigningKey::generate(&mut OsRng);
+        let recovery = AuthorizedUpgradeV1::new(upgrade(1, &new_owner_key), &recovery_key);
+        let report = optional_upgrade
+            .apply_delta(&parent_state, &parameters, &recovery)
+            .unwrap();
+        assert!(report.is_fully_applied());
+        assert_eq!(optional_upgrade, OptionalUpgradeV1(Some(recovery.clone())));
+        assert!(optional_upgrade.verify(&parent_state, &parameters).is_ok());
+        assert_eq!(
+            optional_upgrade.summarize(&parent_state, &parameters),
+            Some((
+                Authority::Recovery,
+                1,
+                Reverse(recovery.signatures.to_bytes())
+            ))
+        );
+
+        // and the owner key can't take it back
+        optional_upgrade
+            .apply_delta(&parent_state, &parameters, &hijack)
+            .unwrap();
+        assert_eq!(optional_upgrade, OptionalUpgradeV1(Some(recovery)));
+    }
+
+    #[test]
+    fn test_concurrent_upgrades_converge() {
+        let owner_key = SigningKey::generate(&mut OsRng);
+        let parameters = ChatRoomParametersV1 {
+            owner: owner_key.verifying_key(),
+            admins: None,
+            recovery: None,
+        };
+        let parent_state = ChatRoomStateV1::default();
+        let upgrade = |address: u8| {
+            AuthorizedUpgradeV1::new(
+                UpgradeV1 {
+                    new_chatroom_address: Hash::from([address; 32]),
+                    ..create_test_upgrade(parameters.owner_id())
+                },
+                &owner_key,
+            )
+        };
+        let (first, second) = (upgrade(1), upgrade(2));
+        let winner = if first.signatures.to_bytes() < second.signatures.to_bytes() {
+            &first
+        } else {
+            &second
+        };
+
+        // Two upgrades of the same version end up the same whichever arrives first
+        for (a, b) in [(&first, &second), (&second, &first)] {
+            let mut optional_upgrade = OptionalUpgradeV1(None);
+            for delta in [a, b] {
+                let report = optional_upgrade
+                    .apply_delta(&parent_state, &parameters, delta)
+                    .unwrap();
+                assert!(report.rejected.is_empty());
+            }
+            assert_eq!(optional_upgrade, OptionalUpgradeV1(Some(winner.clone())));
+        }
+    }
 }
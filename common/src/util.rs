@@ -1,15 +1,60 @@
 use base64::{engine::general_purpose, Engine as _};
-use ed25519_dalek::{Signature, SignatureError, Signer, SigningKey, Verifier, VerifyingKey};
+use ed25519_dalek::{verify_batch, Signature, SignatureError, Signer, SigningKey, Verifier, VerifyingKey};
 use serde::Serialize;
 use data_encoding::BASE32;
 
-pub fn sign_struct<T: Serialize>(message: T, signing_key: &SigningKey) -> Signature {
-    let mut data_to_sign = Vec::new();
-    ciborium::ser::into_writer(&message, &mut data_to_sign).expect("Serialization should not fail");
-    signing_key.sign(&data_to_sign)
+/// Version of the signing scheme, covered by every signature alongside the domain
+pub const SIGNATURE_PROTOCOL_VERSION: u8 = 1;
+
+/// Whether signatures over the bare CBOR encoding, made before signatures were domain
+/// separated, are still accepted. Only version 1 of the signing scheme accepts them: the
+/// next version, which rooms have to be re-signed for anyway, drops the fallback.
+pub const ACCEPT_LEGACY_SIGNATURES: bool = SIGNATURE_PROTOCOL_VERSION == 1;
+
+/// A type that can be signed with [`sign_struct`]. The signature covers `DOMAIN` and
+/// [`SIGNATURE_PROTOCOL_VERSION`] as well as the CBOR encoding, so a signature over one
+/// type can't be passed off as a signature over another type that serializes the same.
+pub trait Signable: Serialize {
+    /// Identifies the signed type, must be unique across all `Signable` types
+    const DOMAIN: &'static str;
+}
+
+/// The bytes actually signed for a message: `river:<domain>`, a zero byte, the protocol
+/// version and then the CBOR encoding of the message
+fn signing_payload<T: Signable>(message: &T) -> Vec<u8> {
+    let mut data_to_sign = Vec::with_capacity(signing_payload_prefix_len::<T>());
+    data_to_sign.extend_from_slice(b"river:");
+    data_to_sign.extend_from_slice(T::DOMAIN.as_bytes());
+    data_to_sign.push(0);
+    data_to_sign.push(SIGNATURE_PROTOCOL_VERSION);
+    ciborium::ser::into_writer(message, &mut data_to_sign).expect("Serialization should not fail");
+    data_to_sign
+}
+
+fn signing_payload_prefix_len<T: Signable>() -> usize {
+    "river:".len() + T::DOMAIN.len() + 2
 }
 
-pub fn verify_struct<T: Serialize>(
+pub fn sign_struct<T: Signable>(message: &T, signing_key: &SigningKey) -> Signature {
+    signing_key.sign(&signing_payload(message))
+}
+
+pub fn verify_struct<T: Signable>(
+    message: &T,
+    signature: &Signature,
+    verifying_key: &VerifyingKey,
+) -> Result<(), SignatureError> {
+    match verifying_key.verify(&signing_payload(message), signature) {
+        Err(e) if ACCEPT_LEGACY_SIGNATURES => {
+            verify_legacy_struct(message, signature, verifying_key).map_err(|_| e)
+        }
+        result => result,
+    }
+}
+
+/// Verifies a signature over the bare CBOR encoding of a message, as made before
+/// signatures were domain separated
+fn verify_legacy_struct<T: Serialize>(
     message: &T,
     signature: &Signature,
     verifying_key: &VerifyingKey,
@@ -19,6 +64,73 @@ pub fn verify_struct<T: Serialize>(
     verifying_key.verify(&data_to_sign, signature)
 }
 
+/// Collects signatures so they can be checked together with ed25519 batch verification,
+/// which is much faster than checking them one at a time. If the batch fails, every
+/// signature is checked individually to find the culprit.
+#[derive(Default)]
+pub struct SignatureBatch<'a> {
+    payloads: Vec<Vec<u8>>,
+    signatures: Vec<Signature>,
+    verifying_keys: Vec<VerifyingKey>,
+    /// Where the CBOR encoding starts in each payload, the legacy format signed only that
+    cbor_offsets: Vec<usize>,
+    /// Only called for an invalid signature, so that the happy path doesn't format anything
+    descriptions: Vec<Box<dyn Fn() -> String + 'a>>,
+}
+
+impl<'a> SignatureBatch<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a signature over `message`, `describe` names the signed item in the error
+    /// if this signature turns out to be invalid
+    pub fn add<T: Signable>(
+        &mut self,
+        message: &T,
+        signature: &Signature,
+        verifying_key: &VerifyingKey,
+        describe: impl Fn() -> String + 'a,
+    ) {
+        self.cbor_offsets.push(signing_payload_prefix_len::<T>());
+        self.payloads.push(signing_payload(message));
+        self.signatures.push(*signature);
+        self.verifying_keys.push(*verifying_key);
+        self.descriptions.push(Box::new(describe));
+    }
+
+    pub fn len(&self) -> usize {
+        self.signatures.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.signatures.is_empty()
+    }
+
+    pub fn verify(&self) -> Result<(), String> {
+        let messages: Vec<&[u8]> = self.payloads.iter().map(Vec::as_slice).collect();
+        if verify_batch(&messages, &self.signatures, &self.verifying_keys).is_ok() {
+            return Ok(());
+        }
+
+        // Legacy signatures never pass the batch, so this is also the migration path
+        for (i, payload) in self.payloads.iter().enumerate() {
+            let key = &self.verifying_keys[i];
+            let signature = &self.signatures[i];
+            if key.verify(payload, signature).is_ok() {
+                continue;
+            }
+            let legacy_payload = &payload[self.cbor_offsets[i]..];
+            let legacy_ok =
+                ACCEPT_LEGACY_SIGNATURES && key.verify(legacy_payload, signature).is_ok();
+            if !legacy_ok {
+                return Err(format!("Invalid signature for {}", (self.descriptions[i])()));
+            }
+        }
+        Ok(())
+    }
+}
+
 pub fn truncated_base64<T: AsRef<[u8]>>(data: T) -> String {
     let encoded = general_purpose::STANDARD_NO_PAD.encode(data);
     encoded.chars().take(10).collect()
@@ -34,14 +146,123 @@ mod tests {
     use super::*;
     use rand::rngs::OsRng;
 
+    #[derive(Serialize)]
+    struct Greeting(&'static str);
+
+    impl Signable for Greeting {
+        const DOMAIN: &'static str = "test-greeting";
+    }
+
+    #[derive(Serialize)]
+    struct Farewell(&'static str);
+
+    impl Signable for Farewell {
+        const DOMAIN: &'static str = "test-farewell";
+    }
+
     #[test]
     fn test_sign_verify_struct() {
         let mut csprng = OsRng;
         let signing_key = SigningKey::generate(&mut csprng);
         let verifying_key = signing_key.verifying_key();
 
-        let message = "Hello, World!";
+        let message = Greeting("Hello, World!");
         let signature = sign_struct(&message, &signing_key);
         assert!(verify_struct(&message, &signature, &verifying_key).is_ok());
+        assert!(verify_struct(&Greeting("Goodbye"), &signature, &verifying_key).is_err());
+    }
+
+    #[test]
+    fn test_signature_is_domain_separated() {
+        let signing_key = SigningKey::generate(&mut OsRng);
+        let verifying_key = signing_key.verifying_key();
+
+        // Both serialize to the same CBOR, but the signature is bound to the type
+        let signature = sign_struct(&Greeting("Hello"), &signing_key);
+        assert!(verify_struct(&Farewell("Hello"), &signature, &verifying_key).is_err());
+
+        let mut payload = b"river:test-greeting\0".to_vec();
+        payload.push(SIGNATURE_PROTOCOL_VERSION);
+        ciborium::ser::into_writer(&"Hello", &mut payload).unwrap();
+        assert!(verifying_key.verify(&payload, &signature).is_ok());
+    }
+
+    #[test]
+    fn test_legacy_signature_still_verifies() {
+        let signing_key = SigningKey::generate(&mut OsRng);
+        let verifying_key = signing_key.verifying_key();
+
+        let mut legacy_payload = Vec::new();
+        ciborium::ser::into_writer(&"Hello", &mut legacy_payload).unwrap();
+        let legacy_signature = signing_key.sign(&legacy_payload);
+
+        assert!(verify_struct(&Greeting("Hello"), &legacy_signature, &verifying_key).is_ok());
+        assert!(verify_struct(&Greeting("Goodbye"), &legacy_signature, &verifying_key).is_err());
+    }
+
+    #[test]
+    fn test_legacy_fallback_keeps_domain_separation() {
+        let signing_key = SigningKey::generate(&mut OsRng);
+        let verifying_key = signing_key.verifying_key();
+
+        // With the fallback on, a signature for one domain still fails for another, both
+        // alone and in a batch
+        let signature = sign_struct(&Greeting("Hello"), &signing_key);
+        assert!(verify_struct(&Farewell("Hello"), &signature, &verifying_key).is_err());
+        let mut batch = SignatureBatch::new();
+        batch.add(&Farewell("Hello"), &signature, &verifying_key, || {
+            "farewell".to_string()
+        });
+        assert_eq!(
+            batch.verify().unwrap_err(),
+            "Invalid signature for farewell"
+        );
+
+        // It's accepted only as the domain it was made for
+        let mut batch = SignatureBatch::new();
+        batch.add(&Greeting("Hello"), &signature, &verifying_key, || {
+            "greeting".to_string()
+        });
+        assert!(batch.verify().is_ok());
+    }
+
+    #[test]
+    fn test_signature_batch() {
+        let keys: Vec<SigningKey> = (0..4).map(|_| SigningKey::generate(&mut OsRng)).collect();
+        let described = &std::cell::Cell::new(0);
+        let mut batch = SignatureBatch::new();
+        assert!(batch.is_empty());
+        assert!(batch.verify().is_ok());
+
+        for (i, key) in keys.iter().enumerate() {
+            let message = Greeting("Hello");
+            let signature = sign_struct(&message, key);
+            batch.add(&message, &signature, &key.verifying_key(), move || {
+                described.set(described.get() + 1);
+                format!("greeting {}", i)
+            });
+        }
+        assert_eq!(batch.len(), 4);
+        assert!(batch.verify().is_ok());
+        assert_eq!(described.get(), 0, "Valid signatures shouldn't be described");
+
+        // A legacy signature fails the batch but passes the fallback
+        let mut legacy_payload = Vec::new();
+        ciborium::ser::into_writer(&"Hello", &mut legacy_payload).unwrap();
+        let legacy_signature = keys[0].sign(&legacy_payload);
+        batch.add(&Greeting("Hello"), &legacy_signature, &keys[0].verifying_key(), || {
+            "legacy greeting".to_string()
+        });
+        assert!(batch.verify().is_ok());
+
+        // The fallback names the invalid signature
+        let signature = sign_struct(&Farewell("Hello"), &keys[1]);
+        batch.add(&Greeting("Hello"), &signature, &keys[1].verifying_key(), || {
+            "forged greeting".to_string()
+        });
+        assert_eq!(
+            batch.verify().unwrap_err(),
+            "Invalid signature for forged greeting"
+        );
     }
 }
@@ -4,8 +4,29 @@ use proc_macro::TokenStream;
 use quote::{format_ident, quote};
 use syn::{parse_macro_input, Data, DeriveInput, Fields};
 
+/// Implements `ComposableState` for a struct by delegating to each of its fields in order.
+/// When applying a delta, a field that fails as a whole is reported as rejected and restored
+/// to its previous value, while the other fields are still applied. The delta is applied to
+/// a copy of the state, which replaces the state only if `apply_delta` returns `Ok`.
+///
+/// Optionally accepts `post_apply_delta = "path::to::fn"`, a function with the signature
+/// `fn(&mut Self, &ParentState, &Parameters) -> Result<(), String>` that is called once all
+/// field deltas have been applied. This allows cleanup that spans several fields, since
+/// each field only sees the parent state as it was before the delta.
 #[proc_macro_attribute]
-pub fn composable(_attr: TokenStream, item: TokenStream) -> TokenStream {
+pub fn composable(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut post_apply_delta: Option<syn::Path> = None;
+    let attr_parser = syn::meta::parser(|meta| {
+        if meta.path.is_ident("post_apply_delta") {
+            let path: syn::LitStr = meta.value()?.parse()?;
+            post_apply_delta = Some(path.parse()?);
+            Ok(())
+        } else {
+            Err(meta.error("unsupported composable property"))
+        }
+    });
+    parse_macro_input!(attr with attr_parser);
+
     let input = parse_macro_input!(item as DeriveInput);
     let name = &input.ident;
 
@@ -103,11 +124,23 @@ pub fn composable(_attr: TokenStream, item: TokenStream) -> TokenStream {
     let apply_delta_impl = field_names.iter().map(|name| {
         quote! {
             if let Some(ref field_delta) = delta.#name {
-                self.#name.apply_delta(parent_state, parameters, field_delta)?;
+                match new_state.#name.apply_delta(parent_state, parameters, field_delta) {
+                    Ok(field_report) => report.extend(stringify!(#name), field_report),
+                    Err(e) => {
+                        new_state.#name = self.#name.clone();
+                        report.rejected(stringify!(#name), e);
+                    }
+                }
             }
         }
     });
 
+    let post_apply_delta_impl = post_apply_delta.map(|path| {
+        quote! {
+            #path(&mut new_state, parent_state, parameters)?;
+        }
+    });
+
     let _generic_params: Vec<_> = input.generics.params.iter().collect();
     let where_clause = input.generics.where_clause.clone();
     let (impl_generics, ty_generics, _) = input.generics.split_for_impl();
@@ -130,7 +163,7 @@ pub fn composable(_attr: TokenStream, item: TokenStream) -> TokenStream {
 
         impl #impl_generics ComposableState for #name #ty_generics #where_clause
         where
-            #(#field_types: ComposableState,)*
+            #(#field_types: ComposableState + Clone,)*
         {
             type ParentState = #name;
             type Summary = #summary_name #ty_generics;
@@ -160,9 +193,13 @@ pub fn composable(_attr: TokenStream, item: TokenStream) -> TokenStream {
                 }
             }
 
-            fn apply_delta(&mut self, parent_state: &Self::ParentState, parameters: &Self::Parameters, delta: &Self::Delta) -> Result<(), String> {
+            fn apply_delta(&mut self, parent_state: &Self::ParentState, parameters: &Self::Parameters, delta: &Self::Delta) -> Result<freenet_scaffold::DeltaReport, String> {
+                let mut report = freenet_scaffold::DeltaReport::default();
+                let mut new_state = self.clone();
                 #(#apply_delta_impl)*
-                Ok(())
+                #post_apply_delta_impl
+                *self = new_state;
+                Ok(report)
             }
         }
 
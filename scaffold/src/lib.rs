@@ -2,9 +2,45 @@ pub mod util;
 
 pub use freenet_scaffold_macro::composable;
 use serde::de::DeserializeOwned;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
 
+/// What became of the items in a delta. Items the state already holds are skipped without
+/// being reported, so applying the same delta twice reports nothing the second time.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeltaReport {
+    /// Descriptions of the items that were applied
+    pub applied: Vec<String>,
+    /// Descriptions of the items that were dropped, each with the reason
+    pub rejected: Vec<(String, String)>,
+}
+
+impl DeltaReport {
+    pub fn applied(&mut self, item: impl Into<String>) {
+        self.applied.push(item.into());
+    }
+
+    pub fn rejected(&mut self, item: impl Into<String>, reason: impl Into<String>) {
+        self.rejected.push((item.into(), reason.into()));
+    }
+
+    /// Adds the items of a field's report, prefixed with the field's name
+    pub fn extend(&mut self, field: &str, other: DeltaReport) {
+        self.applied
+            .extend(other.applied.into_iter().map(|item| format!("{}: {}", field, item)));
+        self.rejected.extend(
+            other
+                .rejected
+                .into_iter()
+                .map(|(item, reason)| (format!("{}: {}", field, item), reason)),
+        );
+    }
+
+    pub fn is_fully_applied(&self) -> bool {
+        self.rejected.is_empty()
+    }
+}
+
 pub trait ComposableState {
     type ParentState: Serialize + DeserializeOwned + Clone + Debug;
     type Summary: Serialize + DeserializeOwned + Clone + Debug;
@@ -27,12 +63,16 @@ pub trait ComposableState {
         parameters: &Self::Parameters,
         old_state_summary: &Self::Summary,
     ) -> Option<Self::Delta>;
+    /// Applies the valid items of a delta and reports on the rest. An `Err` means nothing
+    /// could be applied, in which case implementations must leave the state unchanged, e.g.
+    /// by returning before modifying it. `#[composable]` ensures this for composed states by
+    /// applying the delta to a copy.
     fn apply_delta(
         &mut self,
         parent_state: &Self::ParentState,
         parameters: &Self::Parameters,
         delta: &Self::Delta,
-    ) -> Result<(), String>;
+    ) -> Result<DeltaReport, String>;
     fn merge(
         &mut self,
         parent_state: &Self::ParentState,
@@ -0,0 +1,37 @@
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// Hashes the room contract WASM so the UI derives room contract addresses from the code
+/// it's actually deployed with, the way Freenet hashes contract code
+fn main() {
+    println!("cargo:rerun-if-env-changed=ROOM_CONTRACT_WASM");
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let wasm_path = env::var("ROOM_CONTRACT_WASM")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| {
+            manifest_dir.join("../target/wasm32-unknown-unknown/release/room_contract.wasm")
+        });
+    println!("cargo:rerun-if-changed={}", wasm_path.display());
+
+    let code = fs::read(&wasm_path).unwrap_or_else(|e| {
+        panic!(
+            "Can't read the room contract at {}: {}. Build it first with \
+             `cargo build -p room-contract --target wasm32-unknown-unknown --release`, \
+             or point ROOM_CONTRACT_WASM at it",
+            wasm_path.display(),
+            e
+        )
+    });
+    let hash = blake3::hash(&code);
+
+    let out_path = PathBuf::from(env::var("OUT_DIR").unwrap()).join("room_contract_code_hash.rs");
+    fs::write(
+        out_path,
+        format!(
+            "pub const ROOM_CONTRACT_CODE_HASH: blake3::Hash = blake3::Hash::from_bytes({:?});\n",
+            hash.as_bytes()
+        ),
+    )
+    .unwrap();
+}
@@ -1,4 +1,5 @@
 use super::{chat_rooms::ChatRooms, main_chat::MainChat, member_list::MemberList};
+use super::main_chat::private_room::use_room_secret_upkeep;
 use crate::example_data::create_example_room;
 use crate::global_context::UserInfoModals;
 use common::ChatRoomStateV1;
@@ -16,6 +17,7 @@ pub fn App() -> Element {
     });
     use_context_provider(|| Signal::new(CurrentRoom { owner_key: None }));
     use_context_provider(|| Signal::new(UserInfoModals { modals: HashMap::new() }));
+    use_room_secret_upkeep();
 
     rsx! {
         div { class: "chat-container",
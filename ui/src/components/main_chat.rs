@@ -1,19 +1,28 @@
 use crate::room_data::{CurrentRoom, Rooms, SendMessageError};
-use crate::util::{get_current_room_data, get_current_system_time};
+use crate::util::{describe_rejections, get_current_room_data, get_current_system_time};
 use crate::global_context::UserInfoModals;
 use crate::components::member_info::MemberInfo;
 mod message_input;
 mod not_member_notification;
+pub mod private_room;
+mod room_details_modal;
+mod room_header;
+mod transfer_ownership;
 use self::message_input::MessageInput;
 use self::not_member_notification::NotMemberNotification;
+use self::private_room::{message_text, room_secret, PrivateRoomNotice};
+use self::room_header::RoomHeader;
 use chrono::{DateTime, Utc};
+use common::room_state::configuration::RoomPrivacy;
 use common::room_state::member::MemberId;
 use common::room_state::member_info::MemberInfoV1;
 use common::room_state::message::{AuthorizedMessageV1, MessageV1};
-use common::room_state::{ChatRoomParametersV1, ChatRoomStateV1Delta};
+use common::room_state::secret::RoomSecret;
+use common::room_state::ChatRoomStateV1Delta;
 use dioxus::prelude::*;
 use dioxus_logger::tracing::{info, warn};
 use freenet_scaffold::ComposableState;
+use std::collections::BTreeMap;
 use std::rc::Rc;
 use wasm_bindgen_futures::spawn_local;
 
@@ -22,21 +31,8 @@ pub fn MainChat() -> Element {
     let mut rooms = use_context::<Signal<Rooms>>();
     let current_room = use_context::<Signal<CurrentRoom>>();
     let current_room_data = get_current_room_data(rooms, current_room);
-    let current_room_label = use_memo(move || {
-        current_room_data
-            .read()
-            .as_ref()
-            .map(|room_data| {
-                room_data
-                    .room_state
-                    .configuration
-                    .configuration
-                    .name
-                    .clone()
-            })
-            .unwrap_or_else(|| "No Room Selected".to_string())
-    });
     let mut new_message = use_signal(String::new);
+    let current_room_secret = use_memo(move || current_room_data.read().as_ref().and_then(room_secret));
     let last_message_element: Signal<Option<Rc<MountedData>>> = use_signal(|| None);
     use_effect(move || {
         if let Some(element) = last_message_element.cloned() {
@@ -52,10 +48,20 @@ pub fn MainChat() -> Element {
             new_message.set(String::new());
             if let (Some(current_room), Some(current_room_data)) = (current_room.read().owner_key, current_room_data.read().as_ref()) {
                 if let Some(user_signing_key) = &current_room_data.user_signing_key {
+                    let private = current_room_data.room_state.configuration.configuration.privacy == RoomPrivacy::Private;
+                    let (content, encrypted_content) = match (private, current_room_secret.read().as_ref()) {
+                        (false, _) => (message, None),
+                        (true, Some(secret)) => (String::new(), Some(secret.encrypt(&message))),
+                        (true, None) => {
+                            warn!("The room key hasn't been shared with the user yet");
+                            return;
+                        }
+                    };
                     let message = MessageV1 {
                         room_owner: MemberId::new(&current_room),
                         author: MemberId::new(&user_signing_key.verifying_key()),
-                        content: message,
+                        content,
+                        encrypted_content,
                         time: get_current_system_time(),
                     };
                     let auth_message = AuthorizedMessageV1::new(message, user_signing_key);
@@ -65,15 +71,20 @@ pub fn MainChat() -> Element {
                         bans: None,
                         members: None,
                         member_info: None,
+                        room_secrets: None,
+                        direct_messages: None,
                         upgrade: None,
                     };
                     info!("Sending message: {:?}", auth_message);
-                    rooms.write()
+                    let report = rooms.write()
                         .map.get_mut(&current_room).unwrap()
                         .room_state.apply_delta(
                         &current_room_data.room_state,
-                        &ChatRoomParametersV1 { owner: current_room }, &delta
+                        &current_room_data.parameters, &delta
                     ).unwrap();
+                    if let Some(e) = describe_rejections(&report) {
+                        warn!("Message was rejected: {}", e);
+                    }
                 } else {
                     warn!("User signing key is not set");
                 }
@@ -85,13 +96,13 @@ pub fn MainChat() -> Element {
 
     rsx! {
         div { class: "main-chat",
-            h2 { class: "room-name has-text-centered is-size-4 has-text-weight-bold py-3 mb-4",
-                "{current_room_label}"
-            }
+            RoomHeader {}
+            PrivateRoomNotice {}
             div { class: "chat-messages",
                 {
                     current_room_data.read().as_ref().map(|room_data| {
                         let room_state = room_data.room_state.clone();
+                        let epoch_keys = room_data.epoch_keys.clone();
                         let last_message_index = room_state.recent_messages.messages.len() - 1;
                         rsx! {
                             {room_state.recent_messages.messages.iter().enumerate().map(|(ix, message)| {
@@ -100,6 +111,7 @@ pub fn MainChat() -> Element {
                                         key: "{message.id().0:?}",
                                         message: message.clone(),
                                         member_info: room_state.member_info.clone(),
+                                        epoch_keys: epoch_keys.clone(),
                                         last_message_element: if ix == last_message_index { Some(last_message_element.clone()) } else { None },
                                     }
                                 }
@@ -164,6 +176,7 @@ pub fn MainChat() -> Element {
 fn MessageItem(
     message: AuthorizedMessageV1,
     member_info: MemberInfoV1,
+    epoch_keys: BTreeMap<u32, RoomSecret>,
     last_message_element: Option<Signal<Option<Rc<MountedData>>>>,
 ) -> Element {
     let mut user_info_modals = use_context::<Signal<UserInfoModals>>();
@@ -179,7 +192,7 @@ fn MessageItem(
         .format("%H:%M")
         .to_string();
 
-    let content = markdown::to_html(message.message.content.as_str());
+    let content = markdown::to_html(&message_text(&message.message, &epoch_keys));
 
     let is_active_signal = use_signal(|| false);
 
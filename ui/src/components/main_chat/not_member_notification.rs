@@ -1,12 +1,22 @@
+use crate::room_data::{CurrentRoom, Rooms};
+use crate::util::{decode_invitation, describe_rejections, encode_user_key};
+use common::room_state::member::MembersDelta;
+use common::room_state::ChatRoomStateV1Delta;
 use dioxus::prelude::*;
+use dioxus_logger::tracing::{error, info};
 use ed25519_dalek::VerifyingKey;
-use bs58;
+use freenet_scaffold::ComposableState;
 use web_sys::window;
 use wasm_bindgen_futures::spawn_local;
 
 #[component]
 pub fn NotMemberNotification(user_verifying_key: VerifyingKey) -> Element {
-    let encoded_key = format!("river:user:vk:{}", bs58::encode(user_verifying_key.as_bytes()).into_string());
+    let mut rooms = use_context::<Signal<Rooms>>();
+    let current_room = use_context::<Signal<CurrentRoom>>();
+    let mut invitation = use_signal(String::new);
+    let mut invitation_error = use_signal(|| None::<String>);
+
+    let encoded_key = encode_user_key(&user_verifying_key);
 
     let copy_to_clipboard = move |_| {
         let key = encoded_key.clone();
@@ -21,10 +31,77 @@ pub fn NotMemberNotification(user_verifying_key: VerifyingKey) -> Element {
         });
     };
 
+    let redeem_invitation = move |_| {
+        let token = match decode_invitation(&invitation.read()) {
+            Ok(token) => token,
+            Err(e) => {
+                invitation_error.set(Some(e));
+                return;
+            }
+        };
+        let Some(owner_key) = current_room.read().owner_key else {
+            invitation_error.set(Some("No room selected".to_string()));
+            return;
+        };
+        let delta = ChatRoomStateV1Delta {
+            recent_messages: None,
+            configuration: None,
+            bans: None,
+            members: Some(MembersDelta::new(vec![token.redeem(user_verifying_key)], vec![])),
+            member_info: None,
+            room_secrets: None,
+            direct_messages: None,
+            upgrade: None,
+        };
+        if let Some(room_data) = rooms.write().map.get_mut(&owner_key) {
+            match room_data.room_state.apply_delta(
+                &room_data.room_state.clone(),
+                &room_data.parameters,
+                &delta,
+            ) {
+                Ok(report) => match describe_rejections(&report) {
+                    None => {
+                        info!("Redeemed invitation");
+                        invitation_error.set(None);
+                    }
+                    Some(e) => {
+                        error!("Invitation was rejected: {}", e);
+                        invitation_error.set(Some(e));
+                    }
+                },
+                Err(e) => {
+                    error!("Failed to redeem invitation: {:?}", e);
+                    invitation_error.set(Some(e));
+                }
+            }
+        }
+    };
+
     rsx! {
         div { class: "notification is-info",
             p { "You are not a member of this room. You need to be invited by a current room member." }
-            p { "Your verifying key: " }
+            div { class: "field has-addons mt-2",
+                div { class: "control is-expanded",
+                    input {
+                        class: "input",
+                        r#type: "text",
+                        placeholder: "Paste an invitation...",
+                        value: "{invitation}",
+                        oninput: move |evt| invitation.set(evt.value().to_string()),
+                    }
+                }
+                div { class: "control",
+                    button {
+                        class: "button is-primary",
+                        onclick: redeem_invitation,
+                        "Join"
+                    }
+                }
+            }
+            if let Some(error) = invitation_error() {
+                p { class: "help is-danger", "{error}" }
+            }
+            p { "Alternatively, send your verifying key to a member so they can invite you directly: " }
             code { "{encoded_key}" }
             button {
                 class: "button is-small is-primary mt-2",
@@ -0,0 +1,259 @@
+use crate::room_data::{CurrentRoom, RoomData, Rooms};
+use crate::util::{describe_rejections, get_current_room_data, get_current_system_time};
+use common::room_state::configuration::RoomPrivacy;
+use common::room_state::message::MessageV1;
+use common::room_state::secret::{RoomSecret, RoomSecretsDelta};
+use common::room_state::ChatRoomStateV1Delta;
+use dioxus::prelude::*;
+use dioxus_logger::tracing::{error, info, warn};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use freenet_scaffold::ComposableState;
+use std::collections::{BTreeMap, HashMap};
+
+/// The room secret sealed to the user, if the room is private and it has been shared with them
+pub fn room_secret(room_data: &RoomData) -> Option<RoomSecret> {
+    if room_data.room_state.configuration.configuration.privacy != RoomPrivacy::Private {
+        return None;
+    }
+    let signing_key = room_data.user_signing_key.as_ref()?;
+    match room_data.room_state.room_secrets.open(signing_key)? {
+        Ok(secret) => Some(secret),
+        Err(e) => {
+            warn!("Failed to open room secret: {}", e);
+            None
+        }
+    }
+}
+
+/// The text of a message, decrypted with the secret of the epoch it was written in
+pub fn message_text(message: &MessageV1, epoch_keys: &BTreeMap<u32, RoomSecret>) -> String {
+    let Some(content) = &message.encrypted_content else {
+        return message.content.clone();
+    };
+    match epoch_keys.get(&content.secret_version) {
+        Some(secret) => secret
+            .decrypt(content)
+            .unwrap_or_else(|_| "*Encrypted message that can't be read*".to_string()),
+        None => "*Encrypted with a room key you don't have*".to_string(),
+    }
+}
+
+/// Keeps the room secret sealed to the user with those of earlier epochs, including the
+/// ones chained under it that the user wasn't around for
+fn remember_room_secret(room_data: &mut RoomData) {
+    if let Some(secret) = room_secret(room_data) {
+        let earlier = room_data.room_state.room_secrets.earlier_secrets(&secret);
+        for secret in std::iter::once(secret).chain(earlier) {
+            room_data.epoch_keys.entry(secret.version).or_insert(secret);
+        }
+    }
+}
+
+/// Seals `secret` to every participant of the room who doesn't hold it yet, `None` if
+/// everyone does
+pub fn seal_for_participants(
+    room_data: &RoomData,
+    secret: &RoomSecret,
+    owner_signing_key: &SigningKey,
+) -> Result<Option<RoomSecretsDelta>, String> {
+    let room_state = &room_data.room_state;
+    room_state
+        .room_secrets
+        .share(room_state, &room_data.parameters, secret, owner_signing_key)
+}
+
+/// Starts a new epoch of the room secret, sealed to everyone still in the room
+fn rotate_room_secret(
+    mut rooms: Signal<Rooms>,
+    owner_key: VerifyingKey,
+    owner_signing_key: &SigningKey,
+) -> Result<(), String> {
+    let mut rooms_write_guard = rooms.write();
+    let room_data = rooms_write_guard
+        .map
+        .get_mut(&owner_key)
+        .ok_or_else(|| "Room not found".to_string())?;
+    let parameters = room_data.parameters.clone();
+    // The owner's copy of the current secret is replaced, so keep it to read older messages
+    remember_room_secret(room_data);
+    let (secret, rotated) = room_data.room_state.room_secrets.rotate(
+        &room_data.room_state,
+        &parameters,
+        owner_signing_key,
+        get_current_system_time(),
+    )?;
+    let delta = ChatRoomStateV1Delta {
+        recent_messages: None,
+        configuration: None,
+        bans: None,
+        members: None,
+        member_info: None,
+        room_secrets: Some(rotated),
+        direct_messages: None,
+        upgrade: None,
+    };
+    let report = room_data
+        .room_state
+        .apply_delta(&room_data.room_state.clone(), &parameters, &delta)?;
+    if let Some(e) = describe_rejections(&report) {
+        return Err(e);
+    }
+    room_data.epoch_keys.insert(secret.version, secret);
+    Ok(())
+}
+
+fn share_room_secret(
+    mut rooms: Signal<Rooms>,
+    owner_key: VerifyingKey,
+    owner_signing_key: &SigningKey,
+) -> Result<(), String> {
+    let mut rooms_write_guard = rooms.write();
+    let room_data = rooms_write_guard
+        .map
+        .get_mut(&owner_key)
+        .ok_or_else(|| "Room not found".to_string())?;
+    let parameters = room_data.parameters.clone();
+    let secret = room_secret(room_data).ok_or_else(|| "The room has no secret to share".to_string())?;
+    let delta = ChatRoomStateV1Delta {
+        recent_messages: None,
+        configuration: None,
+        bans: None,
+        members: None,
+        member_info: None,
+        room_secrets: seal_for_participants(room_data, &secret, owner_signing_key)?,
+        direct_messages: None,
+        upgrade: None,
+    };
+    let report = room_data
+        .room_state
+        .apply_delta(&room_data.room_state.clone(), &parameters, &delta)?;
+    match describe_rejections(&report) {
+        None => Ok(()),
+        Some(e) => Err(e),
+    }
+}
+
+/// Keeps the room secrets of every room up to date whenever the rooms change, not just
+/// the one that's open: secrets the user is given are kept to read older messages with,
+/// and the secret of each private room the user owns is rotated when someone who holds it
+/// is banned or leaves.
+///
+/// Only the owner signs room secrets, so a room is rotated only while its owner's client
+/// is running, and admins can't rotate it for them.
+pub fn use_room_secret_upkeep() {
+    let mut rooms = use_context::<Signal<Rooms>>();
+    // The epoch a rotation was last attempted from in each room, so a failed rotation isn't
+    // retried every time the rooms change
+    let mut rotated_from = use_signal(HashMap::<VerifyingKey, Option<u32>>::new);
+
+    use_effect(move || {
+        let mut to_rotate = Vec::new();
+        let mut to_remember = Vec::new();
+        for (owner_key, room_data) in rooms.read().map.iter() {
+            if room_data.room_state.configuration.configuration.privacy != RoomPrivacy::Private {
+                continue;
+            }
+            let room_secrets = &room_data.room_state.room_secrets;
+            let owner_signing_key = room_data
+                .user_signing_key
+                .clone()
+                .filter(|sk| sk.verifying_key() == *owner_key);
+            if let Some(owner_signing_key) = owner_signing_key {
+                let current_version = room_secrets.current_epoch().map(|(version, _)| version);
+                if room_secrets.needs_rotation(&room_data.room_state, &room_data.parameters)
+                    && rotated_from.peek().get(owner_key) != Some(&current_version)
+                {
+                    to_rotate.push((*owner_key, owner_signing_key, current_version));
+                    continue;
+                }
+            }
+            let is_new = room_secret(room_data)
+                .is_some_and(|secret| !room_data.epoch_keys.contains_key(&secret.version));
+            if is_new {
+                to_remember.push(*owner_key);
+            }
+        }
+        for (owner_key, owner_signing_key, current_version) in to_rotate {
+            rotated_from.write().insert(owner_key, current_version);
+            match rotate_room_secret(rooms, owner_key, &owner_signing_key) {
+                Ok(()) => info!("Room secret rotated"),
+                Err(e) => error!("Failed to rotate room secret: {}", e),
+            }
+        }
+        for owner_key in to_remember {
+            if let Some(room_data) = rooms.write().map.get_mut(&owner_key) {
+                remember_room_secret(room_data);
+            }
+        }
+    });
+}
+
+/// Tells members of a private room whether they can read it, and lets the owner share the
+/// room secret with members who joined since it was last shared
+#[component]
+pub fn PrivateRoomNotice() -> Element {
+    let rooms = use_context::<Signal<Rooms>>();
+    let current_room = use_context::<Signal<CurrentRoom>>();
+    let current_room_data = get_current_room_data(rooms, current_room);
+    let mut share_error = use_signal(|| None::<String>);
+
+    let room_data = current_room_data.read();
+    let Some(room_data) = room_data.as_ref() else {
+        return rsx! {};
+    };
+    let Some(owner_key) = current_room.read().owner_key else {
+        return rsx! {};
+    };
+    if room_data.room_state.configuration.configuration.privacy != RoomPrivacy::Private {
+        return rsx! {};
+    }
+    let owner_signing_key = room_data
+        .user_signing_key
+        .clone()
+        .filter(|sk| sk.verifying_key() == owner_key);
+    let is_owner = owner_signing_key.is_some();
+    let secret = room_secret(room_data);
+    let waiting = secret.as_ref().map_or(0, |secret| {
+        room_data
+            .room_state
+            .room_secrets
+            .members_without_secret(&room_data.room_state, &room_data.parameters, secret.version)
+            .len()
+    });
+
+    let share = move |_| {
+        let Some(signing_key) = owner_signing_key.clone() else {
+            return;
+        };
+        match share_room_secret(rooms, owner_key, &signing_key) {
+            Ok(()) => {
+                info!("Room secret shared");
+                share_error.set(None);
+            }
+            Err(e) => {
+                error!("Failed to share room secret: {}", e);
+                share_error.set(Some(e));
+            }
+        }
+    };
+
+    rsx! {
+        if secret.is_none() {
+            div { class: "notification is-warning",
+                "This room is private. You can read and send messages once the owner shares the room key with you."
+            }
+        } else if waiting > 0 && is_owner {
+            div { class: "notification is-info",
+                "{waiting} member(s) can't read this private room yet."
+                button {
+                    class: "button is-small is-link ml-2",
+                    onclick: share,
+                    "Share Room Key"
+                }
+                if let Some(error) = share_error() {
+                    p { class: "help is-danger", "{error}" }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,268 @@
+use crate::room_data::{CurrentRoom, Rooms};
+use crate::util::{describe_rejections, get_current_room_data, get_current_system_time};
+use base64::{engine::general_purpose, Engine as _};
+use common::room_state::configuration::{
+    AuthorizedConfigurationV1, Configuration, RoomPrivacy, RoomVisibility,
+};
+use common::room_state::secret::RoomSecret;
+use common::room_state::ChatRoomStateV1Delta;
+use dioxus::prelude::*;
+use dioxus_logger::tracing::{error, info, warn};
+use freenet_scaffold::ComposableState;
+use sha2::{Digest, Sha256};
+use super::private_room::seal_for_participants;
+use super::transfer_ownership::TransferOwnership;
+
+/// Lets the room owner edit the room's name, description, topic, rules, icon, visibility and
+/// privacy
+#[component]
+pub fn RoomDetailsModal(is_active: Signal<bool>) -> Element {
+    let mut rooms = use_context::<Signal<Rooms>>();
+    let current_room = use_context::<Signal<CurrentRoom>>();
+    let current_room_data = get_current_room_data(rooms, current_room);
+
+    let configuration = use_memo(move || {
+        current_room_data
+            .read()
+            .as_ref()
+            .map(|room_data| room_data.room_state.configuration.configuration.clone())
+            .unwrap_or_default()
+    });
+    let mut name = use_signal(|| configuration.peek().name.clone());
+    let mut description = use_signal(|| configuration.peek().description.clone().unwrap_or_default());
+    let mut topic = use_signal(|| configuration.peek().topic.clone().unwrap_or_default());
+    let mut rules = use_signal(|| configuration.peek().rules.clone().unwrap_or_default());
+    let mut icon_hash = use_signal(|| configuration.peek().icon_hash);
+    let mut visibility = use_signal(|| configuration.peek().visibility);
+    let mut privacy = use_signal(|| configuration.peek().privacy);
+    let mut save_error = use_signal(|| None::<String>);
+
+    let icon_hash_text = icon_hash()
+        .map(|hash| general_purpose::STANDARD.encode(hash))
+        .unwrap_or_else(|| "No icon".to_string());
+
+    let choose_icon = move |evt: Event<FormData>| async move {
+        if let Some(file_engine) = evt.files() {
+            if let Some(file_name) = file_engine.files().first() {
+                match file_engine.read_file(file_name).await {
+                    Some(image) => icon_hash.set(Some(Sha256::digest(&image).into())),
+                    None => warn!("Failed to read icon file {}", file_name),
+                }
+            }
+        }
+    };
+
+    let save_details = move |_| {
+        let Some(owner_key) = current_room.read().owner_key else {
+            warn!("No room selected");
+            return;
+        };
+        let Some(room_data) = current_room_data.read().clone() else {
+            warn!("Room room_state not found for current room");
+            return;
+        };
+        let Some(signing_key) = room_data.user_signing_key.clone() else {
+            warn!("User signing key is not set");
+            return;
+        };
+        let parameters = room_data.parameters.clone();
+        // Making the room private shares its secret, a new one unless the owner kept one
+        // from when it was private before, with everyone in it
+        let room_secrets = (privacy() == RoomPrivacy::Private).then(|| {
+            let secret = room_data
+                .room_state
+                .room_secrets
+                .open(&signing_key)
+                .and_then(Result::ok)
+                .unwrap_or_else(|| RoomSecret::generate(1, get_current_system_time()));
+            seal_for_participants(&room_data, &secret, &signing_key)
+        });
+        let room_secrets = match room_secrets.transpose() {
+            Ok(room_secrets) => room_secrets.flatten(),
+            Err(e) => {
+                error!("Failed to share the room secret: {}", e);
+                save_error.set(Some(e));
+                return;
+            }
+        };
+        let non_empty = |s: String| Some(s.trim().to_string()).filter(|s| !s.is_empty());
+        let new_configuration = Configuration {
+            configuration_version: configuration.read().configuration_version + 1,
+            name: name().trim().to_string(),
+            description: non_empty(description()),
+            topic: non_empty(topic()),
+            rules: non_empty(rules()),
+            icon_hash: icon_hash(),
+            visibility: visibility(),
+            privacy: privacy(),
+            ..configuration.read().clone()
+        };
+        let delta = ChatRoomStateV1Delta {
+            recent_messages: None,
+            configuration: Some(AuthorizedConfigurationV1::new(new_configuration, &signing_key)),
+            bans: None,
+            members: None,
+            member_info: None,
+            room_secrets,
+            direct_messages: None,
+            upgrade: None,
+        };
+
+        let mut rooms_write_guard = rooms.write();
+        if let Some(room_data) = rooms_write_guard.map.get_mut(&owner_key) {
+            match room_data.room_state.apply_delta(
+                &room_data.room_state.clone(),
+                &parameters,
+                &delta,
+            ) {
+                Ok(report) => match describe_rejections(&report) {
+                    None => {
+                        info!("Room details updated");
+                        save_error.set(None);
+                        is_active.set(false);
+                    }
+                    Some(e) => {
+                        error!("Room details were rejected: {}", e);
+                        save_error.set(Some(e));
+                    }
+                },
+                Err(e) => {
+                    error!("Failed to apply delta: {:?}", e);
+                    save_error.set(Some(e));
+                }
+            }
+        } else {
+            warn!("Room room_state not found for current room");
+        }
+    };
+
+    rsx! {
+        div {
+            class: if *is_active.read() { "modal is-active" } else { "modal" },
+            div {
+                class: "modal-background",
+                onclick: move |_| {
+                    is_active.set(false);
+                }
+            }
+            div {
+                class: "modal-content",
+                div {
+                    class: "box",
+                    h1 { class: "title is-4 mb-3", "Room Details" }
+                    div { class: "field",
+                        label { class: "label", "Name" }
+                        div { class: "control",
+                            input {
+                                class: "input",
+                                value: "{name}",
+                                oninput: move |evt| name.set(evt.value().to_string()),
+                            }
+                        }
+                    }
+                    div { class: "field",
+                        label { class: "label", "Topic" }
+                        div { class: "control",
+                            input {
+                                class: "input",
+                                value: "{topic}",
+                                maxlength: "{configuration.read().max_topic_size}",
+                                oninput: move |evt| topic.set(evt.value().to_string()),
+                            }
+                        }
+                    }
+                    div { class: "field",
+                        label { class: "label", "Description" }
+                        div { class: "control",
+                            textarea {
+                                class: "textarea",
+                                value: "{description}",
+                                maxlength: "{configuration.read().max_description_size}",
+                                oninput: move |evt| description.set(evt.value().to_string()),
+                            }
+                        }
+                    }
+                    div { class: "field",
+                        label { class: "label", "Rules" }
+                        div { class: "control",
+                            textarea {
+                                class: "textarea",
+                                value: "{rules}",
+                                maxlength: "{configuration.read().max_rules_size}",
+                                oninput: move |evt| rules.set(evt.value().to_string()),
+                            }
+                        }
+                        p { class: "help", "Markdown is supported." }
+                    }
+                    div { class: "field",
+                        label { class: "label", "Icon" }
+                        div { class: "control",
+                            input {
+                                class: "input",
+                                r#type: "file",
+                                accept: "image/*",
+                                onchange: choose_icon,
+                            }
+                        }
+                        p { class: "help", "Hash: {icon_hash_text}" }
+                    }
+                    div { class: "field",
+                        label { class: "label", "Visibility" }
+                        div { class: "control",
+                            div { class: "select",
+                                select {
+                                    value: if visibility() == RoomVisibility::Unlisted { "unlisted" } else { "public" },
+                                    onchange: move |evt| visibility.set(if evt.value() == "unlisted" {
+                                        RoomVisibility::Unlisted
+                                    } else {
+                                        RoomVisibility::Public
+                                    }),
+                                    option { value: "public", "Public" }
+                                    option { value: "unlisted", "Unlisted" }
+                                }
+                            }
+                        }
+                    }
+                    div { class: "field",
+                        label { class: "label", "Privacy" }
+                        div { class: "control",
+                            div { class: "select",
+                                select {
+                                    value: if privacy() == RoomPrivacy::Private { "private" } else { "public" },
+                                    onchange: move |evt| privacy.set(if evt.value() == "private" {
+                                        RoomPrivacy::Private
+                                    } else {
+                                        RoomPrivacy::Public
+                                    }),
+                                    option { value: "public", "Public" }
+                                    option { value: "private", "Private" }
+                                }
+                            }
+                        }
+                        p { class: "help", "Messages in private rooms are encrypted so that only members can read them." }
+                    }
+                    div { class: "field",
+                        div { class: "control",
+                            button {
+                                class: "button custom-button",
+                                onclick: save_details,
+                                "Save"
+                            }
+                        }
+                        if let Some(error) = save_error() {
+                            p { class: "help is-danger", "{error}" }
+                        }
+                    }
+                    hr {}
+                    TransferOwnership {}
+                }
+            }
+            button {
+                class: "modal-close is-large",
+                onclick: move |_| {
+                    is_active.set(false);
+                }
+            }
+        }
+    }
+}
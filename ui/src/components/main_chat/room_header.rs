@@ -0,0 +1,129 @@
+use crate::room_data::{CurrentRoom, Rooms};
+use crate::util::{encode_user_key, get_current_room_data};
+use common::room_state::configuration::RoomVisibility;
+use common::room_state::member::MemberId;
+use dioxus::prelude::*;
+use dioxus_logger::tracing::error;
+use ed25519_dalek::VerifyingKey;
+use super::room_details_modal::RoomDetailsModal;
+use super::transfer_ownership::take_over_room;
+
+#[component]
+pub fn RoomHeader() -> Element {
+    let rooms = use_context::<Signal<Rooms>>();
+    let current_room = use_context::<Signal<CurrentRoom>>();
+    let current_room_data = get_current_room_data(rooms, current_room);
+    let mut show_rules = use_signal(|| false);
+    let mut editing = use_signal(|| false);
+
+    let room_data = current_room_data.read();
+    let Some(room_data) = room_data.as_ref() else {
+        return rsx! {
+            h2 { class: "room-name has-text-centered is-size-4 has-text-weight-bold py-3 mb-4",
+                "No Room Selected"
+            }
+        };
+    };
+    let configuration = room_data.room_state.configuration.configuration.clone();
+    let is_owner = room_data
+        .user_signing_key
+        .as_ref()
+        .is_some_and(|sk| MemberId::new(&sk.verifying_key()) == configuration.owner_member_id);
+    let rules_html = configuration.rules.as_deref().map(markdown::to_html);
+    let moved_to = room_data
+        .room_state
+        .upgrade
+        .0
+        .as_ref()
+        .and_then(|upgrade| upgrade.upgrade.new_owner);
+
+    rsx! {
+        div { class: "room-header has-text-centered py-3 mb-4",
+            h2 { class: "room-name is-size-4 has-text-weight-bold",
+                "{configuration.name}"
+                if configuration.visibility == RoomVisibility::Unlisted {
+                    span { class: "tag is-light ml-2", "Unlisted" }
+                }
+                if is_owner {
+                    button {
+                        class: "button is-small is-text ml-2",
+                        onclick: move |_| editing.set(true),
+                        "Edit"
+                    }
+                }
+            }
+            if let Some(topic) = &configuration.topic {
+                p { class: "room-topic has-text-weight-semibold", "{topic}" }
+            }
+            if let Some(description) = &configuration.description {
+                p { class: "room-description is-size-7", "{description}" }
+            }
+            if let Some(rules_html) = rules_html {
+                button {
+                    class: "button is-small is-text",
+                    onclick: move |_| show_rules.toggle(),
+                    if show_rules() { "Hide rules" } else { "Show rules" }
+                }
+                if show_rules() {
+                    div {
+                        class: "room-rules content has-text-left box",
+                        dangerous_inner_html: "{rules_html}",
+                    }
+                }
+            }
+        }
+        if let Some(new_owner) = moved_to {
+            RoomMovedNotification { new_owner }
+        }
+        if is_owner {
+            RoomDetailsModal { is_active: editing }
+        }
+    }
+}
+
+/// Points members of a room that has been transferred to a successor they don't know yet
+#[component]
+fn RoomMovedNotification(new_owner: VerifyingKey) -> Element {
+    let rooms = use_context::<Signal<Rooms>>();
+    let current_room = use_context::<Signal<CurrentRoom>>();
+    let current_room_data = get_current_room_data(rooms, current_room);
+    let mut take_over_error = use_signal(|| None::<String>);
+
+    let own_signing_key = current_room_data
+        .read()
+        .as_ref()
+        .and_then(|room_data| room_data.user_signing_key.clone())
+        .filter(|sk| sk.verifying_key() == new_owner);
+    let new_owner_text = encode_user_key(&new_owner);
+
+    let take_over = move |_| {
+        let Some(owner_key) = current_room.read().owner_key else {
+            return;
+        };
+        let Some(signing_key) = own_signing_key.clone() else {
+            return;
+        };
+        if let Err(e) = take_over_room(rooms, current_room, owner_key, signing_key) {
+            error!("Failed to take over room: {}", e);
+            take_over_error.set(Some(e));
+        }
+    };
+
+    rsx! {
+        div { class: "notification is-info",
+            "This room has moved to a new owner."
+            if own_signing_key.is_some() {
+                button {
+                    class: "button is-small is-link ml-2",
+                    onclick: take_over,
+                    "Take Over Room"
+                }
+            } else {
+                p { class: "is-size-7", "New owner: {new_owner_text}" }
+            }
+            if let Some(error) = take_over_error() {
+                p { class: "help is-danger", "{error}" }
+            }
+        }
+    }
+}
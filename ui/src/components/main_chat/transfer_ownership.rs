@@ -0,0 +1,195 @@
+use crate::room_data::{CurrentRoom, RoomData, Rooms};
+use crate::util::{
+    decode_user_key, describe_rejections, get_current_room_data, get_current_system_time,
+    ROOM_CONTRACT_CODE_HASH,
+};
+use common::room_state::upgrade::AuthorizedUpgradeV1;
+use common::room_state::ChatRoomStateV1Delta;
+use dioxus::prelude::*;
+use dioxus_logger::tracing::{error, info, warn};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use freenet_scaffold::ComposableState;
+use rand::rngs::OsRng;
+
+/// Signs a forward pointer from the current room to a room owned by `new_owner` and applies
+/// it to the current room
+pub fn point_room_to_new_owner(
+    mut rooms: Signal<Rooms>,
+    owner_key: VerifyingKey,
+    owner_signing_key: &SigningKey,
+    new_owner: VerifyingKey,
+) -> Result<AuthorizedUpgradeV1, String> {
+    let mut rooms_write_guard = rooms.write();
+    let room_data = rooms_write_guard
+        .map
+        .get_mut(&owner_key)
+        .ok_or_else(|| "Room not found".to_string())?;
+    let parameters = room_data.parameters.clone();
+    let upgrade = room_data.room_state.prepare_ownership_transfer(
+        &parameters,
+        new_owner,
+        &ROOM_CONTRACT_CODE_HASH,
+        get_current_system_time(),
+        &[owner_signing_key],
+    )?;
+    let delta = ChatRoomStateV1Delta {
+        recent_messages: None,
+        configuration: None,
+        bans: None,
+        members: None,
+        member_info: None,
+        room_secrets: None,
+        direct_messages: None,
+        upgrade: Some(upgrade.clone()),
+    };
+    let report = room_data
+        .room_state
+        .apply_delta(&room_data.room_state.clone(), &parameters, &delta)?;
+    match describe_rejections(&report) {
+        None => Ok(upgrade),
+        Some(e) => Err(e),
+    }
+}
+
+/// Builds the successor of the room owned by `owner_key` for the new owner the room points
+/// to, adds it to the rooms and makes it the current room
+pub fn take_over_room(
+    mut rooms: Signal<Rooms>,
+    mut current_room: Signal<CurrentRoom>,
+    owner_key: VerifyingKey,
+    new_owner_signing_key: SigningKey,
+) -> Result<(), String> {
+    let (successor, successor_parameters) = {
+        let rooms_read_guard = rooms.read();
+        let room_data = rooms_read_guard
+            .map
+            .get(&owner_key)
+            .ok_or_else(|| "Room not found".to_string())?;
+        let upgrade = room_data
+            .room_state
+            .upgrade
+            .0
+            .as_ref()
+            .ok_or_else(|| "The room hasn't been transferred".to_string())?;
+        room_data
+            .room_state
+            .successor_for_new_owner(
+                &room_data.parameters,
+                upgrade,
+                &new_owner_signing_key,
+                &ROOM_CONTRACT_CODE_HASH,
+            )?
+    };
+    rooms.write().map.insert(
+        successor_parameters.owner,
+        RoomData {
+            room_state: successor,
+            parameters: successor_parameters.clone(),
+            user_signing_key: Some(new_owner_signing_key),
+            epoch_keys: Default::default(),
+        },
+    );
+    current_room.set(CurrentRoom {
+        owner_key: Some(successor_parameters.owner),
+    });
+    Ok(())
+}
+
+/// Lets the room owner hand the room to another user, or move it to a fresh key of their own
+#[component]
+pub fn TransferOwnership() -> Element {
+    let rooms = use_context::<Signal<Rooms>>();
+    let current_room = use_context::<Signal<CurrentRoom>>();
+    let current_room_data = get_current_room_data(rooms, current_room);
+    let mut new_owner = use_signal(String::new);
+    let mut transfer_error = use_signal(|| None::<String>);
+
+    let owner_keys = move || {
+        let owner_key = current_room.read().owner_key?;
+        let signing_key = current_room_data
+            .read()
+            .as_ref()
+            .and_then(|room_data| room_data.user_signing_key.clone())?;
+        Some((owner_key, signing_key))
+    };
+
+    let transfer = move |_| {
+        let Some((owner_key, signing_key)) = owner_keys() else {
+            warn!("No room selected or user signing key is not set");
+            return;
+        };
+        let result = decode_user_key(&new_owner.read())
+            .and_then(|new_owner| point_room_to_new_owner(rooms, owner_key, &signing_key, new_owner));
+        match result {
+            Ok(_) => {
+                info!("Room transferred");
+                transfer_error.set(None);
+            }
+            Err(e) => {
+                error!("Failed to transfer room: {}", e);
+                transfer_error.set(Some(e));
+            }
+        }
+    };
+
+    let rotate_key = move |_| {
+        let Some((owner_key, signing_key)) = owner_keys() else {
+            warn!("No room selected or user signing key is not set");
+            return;
+        };
+        let new_signing_key = SigningKey::generate(&mut OsRng);
+        let result = point_room_to_new_owner(
+            rooms,
+            owner_key,
+            &signing_key,
+            new_signing_key.verifying_key(),
+        )
+        .and_then(|_| take_over_room(rooms, current_room, owner_key, new_signing_key));
+        match result {
+            Ok(()) => {
+                info!("Room moved to a new owner key");
+                transfer_error.set(None);
+            }
+            Err(e) => {
+                error!("Failed to rotate owner key: {}", e);
+                transfer_error.set(Some(e));
+            }
+        }
+    };
+
+    rsx! {
+        div { class: "field",
+            label { class: "label", "Transfer Ownership" }
+            div { class: "field has-addons",
+                div { class: "control is-expanded",
+                    input {
+                        class: "input",
+                        placeholder: "river:user:vk:...",
+                        value: "{new_owner}",
+                        oninput: move |evt| new_owner.set(evt.value().to_string()),
+                    }
+                }
+                div { class: "control",
+                    button {
+                        class: "button is-danger is-outlined",
+                        onclick: transfer,
+                        "Transfer"
+                    }
+                }
+            }
+            p { class: "help",
+                "The new owner takes the members, bans and room details to a new room. Messages stay in this room."
+            }
+            div { class: "control mt-2",
+                button {
+                    class: "button is-warning is-outlined",
+                    onclick: rotate_key,
+                    "Move Room to a New Owner Key"
+                }
+            }
+            if let Some(error) = transfer_error() {
+                p { class: "help is-danger", "{error}" }
+            }
+        }
+    }
+}
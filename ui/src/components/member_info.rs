@@ -1,13 +1,17 @@
-mod nickname_field;
+mod profile_editor;
 mod invited_by_field;
+mod ban_button;
+mod direct_messages;
 
 pub use crate::room_data::{CurrentRoom, Rooms, RoomData};
 use crate::util::get_current_room_data;
 use crate::global_context::UserInfoModals;
 use common::room_state::member::MemberId;
 use dioxus::prelude::*;
-use crate::components::member_info::nickname_field::NicknameField;
+use crate::components::member_info::profile_editor::ProfileEditor;
 use crate::components::member_info::invited_by_field::InvitedByField;
+use crate::components::member_info::ban_button::BanButton;
+use crate::components::member_info::direct_messages::DirectMessages;
 
 #[component]
 pub fn MemberInfo(member_id: MemberId, is_active: Signal<bool>) -> Element {
@@ -26,10 +30,10 @@ pub fn MemberInfo(member_id: MemberId, is_active: Signal<bool>) -> Element {
         }
     };
 
-    // Extract member info and members list
+    // Extract member info and the room's participants, including the owner
     let member_info_list = &room_state.room_state.member_info.member_info;
-    let members_list = &room_state.room_state.members.members;
-    let owner_key = current_room.read().owner_key;
+    let parameters = &room_state.parameters;
+    let participants = room_state.room_state.members.participants_by_member_id(parameters);
 
     // Find the AuthorizedMemberInfo for the given member_id
     let member_info = match member_info_list.iter().find(|mi| mi.member_info.member_id == member_id) {
@@ -39,19 +43,15 @@ pub fn MemberInfo(member_id: MemberId, is_active: Signal<bool>) -> Element {
         }
     };
 
-    // Try to find the AuthorizedMember for the given member_id
-    let member = members_list.iter().find(|m| m.member.id() == member_id);
+    // Try to find the participant for the given member_id
+    let participant = participants.get(&member_id);
 
     // Determine if the member is the room owner
-    let is_owner = member
-        .as_ref()
-        .map_or(false, |m| Some(m.member.id()) == owner_key.as_ref().map(MemberId::new));
+    let is_owner = participant.map_or(false, |p| p.is_owner());
 
     // Get the inviter's nickname and ID
-    let (invited_by, inviter_id) = if let Some(m) = member {
-        if is_owner {
-            ("N/A (Room Owner)".to_string(), None)
-        } else {
+    let (invited_by, inviter_id) = match participant.and_then(|p| p.member()) {
+        Some(m) => {
             let inviter_id = m.member.invited_by;
             let inviter_nickname = member_info_list
                 .iter()
@@ -60,8 +60,41 @@ pub fn MemberInfo(member_id: MemberId, is_active: Signal<bool>) -> Element {
                 .unwrap_or_else(|| "Unknown".to_string());
             (inviter_nickname, Some(inviter_id))
         }
-    } else {
-        ("Unknown".to_string(), None)
+        None if is_owner => ("N/A (Room Owner)".to_string(), None),
+        None => ("Unknown".to_string(), None),
+    };
+
+    // How much of the room this member brought in, directly and through their invitees
+    let members = &room_state.room_state.members;
+    let invited_count = members.invitees(member_id, parameters).len();
+    let subtree_size = members.subtree_size(member_id, parameters);
+    let invited_text = match (invited_count, subtree_size) {
+        (1, 1) => "1 person".to_string(),
+        (direct, total) if total > direct => format!("{} people ({} in total)", direct, total),
+        (direct, _) => format!("{} people", direct),
+    };
+
+    // Members can ban anyone they invited, directly or through others
+    let self_member_id = room_state
+        .user_signing_key
+        .as_ref()
+        .map(|sk| MemberId::new(&sk.verifying_key()));
+    let can_ban = match self_member_id {
+        Some(self_id) if self_id != member_id && !is_owner => {
+            members.lowest_common_inviter(self_id, member_id, parameters) == Some(self_id)
+        }
+        _ => false,
+    };
+
+    // Participants can message each other directly, if the room allows it
+    let can_message = match self_member_id {
+        Some(self_id) => {
+            self_id != member_id
+                && participant.is_some()
+                && participants.contains_key(&self_id)
+                && room_state.room_state.configuration.configuration.max_direct_messages_per_recipient > 0
+        }
+        None => false,
     };
 
     // Get the member ID string to display
@@ -89,9 +122,9 @@ pub fn MemberInfo(member_id: MemberId, is_active: Signal<bool>) -> Element {
                         }
                     }
 
-                    if let Some(member) = member {
-                        NicknameField {
-                            member: member.clone(),
+                    if participant.is_some() {
+                        ProfileEditor {
+                            member_id,
                             member_info: member_info.clone()
                         }
                     } else {
@@ -121,6 +154,34 @@ pub fn MemberInfo(member_id: MemberId, is_active: Signal<bool>) -> Element {
                             is_active: is_active
                         }
                     }
+
+                    div {
+                        class: "field",
+                        label { class: "label is-medium", "Invited" }
+                        div {
+                            class: "control",
+                            input {
+                                class: "input",
+                                value: "{invited_text}",
+                                readonly: true
+                            }
+                        }
+                    }
+
+                    if can_message {
+                        DirectMessages {
+                            member_id,
+                            nickname: member_info.member_info.preferred_nickname.clone()
+                        }
+                    }
+
+                    if can_ban {
+                        BanButton {
+                            member_id,
+                            nickname: member_info.member_info.preferred_nickname.clone(),
+                            impact: subtree_size
+                        }
+                    }
                 }
             }
             button {
@@ -0,0 +1,135 @@
+use dioxus::prelude::*;
+use dioxus_logger::tracing::{error, info, warn};
+use common::room_state::ban::{AuthorizedUserBan, UserBan};
+use common::room_state::member::MemberId;
+use common::room_state::ChatRoomStateV1Delta;
+use freenet_scaffold::ComposableState;
+use crate::room_data::{CurrentRoom, Rooms};
+use crate::util::{describe_rejections, get_current_room_data, get_current_system_time};
+
+#[component]
+pub fn BanButton(
+    member_id: MemberId,
+    nickname: String,
+    // The number of members invited through this member, who the ban also removes
+    impact: usize,
+) -> Element {
+    let mut rooms = use_context::<Signal<Rooms>>();
+    let current_room = use_context::<Signal<CurrentRoom>>();
+    let current_room_data = get_current_room_data(rooms, current_room);
+    let mut confirming = use_signal(|| false);
+    let mut ban_error = use_signal(|| None::<String>);
+
+    let ban_member = move |_| {
+        let Some(owner_key) = current_room.read().owner_key else {
+            warn!("No room selected");
+            return;
+        };
+        let Some((signing_key, parameters)) = current_room_data
+            .read()
+            .as_ref()
+            .and_then(|room_data| {
+                Some((room_data.user_signing_key.clone()?, room_data.parameters.clone()))
+            })
+        else {
+            warn!("User signing key is not set");
+            return;
+        };
+        info!("Banning member {}", member_id);
+        let ban = UserBan {
+            owner_member_id: parameters.owner_id(),
+            banned_at: get_current_system_time(),
+            banned_user: member_id,
+        };
+        let banned_by = MemberId::new(&signing_key.verifying_key());
+        let ban = if banned_by == parameters.owner_id() {
+            AuthorizedUserBan::new(ban, banned_by, &signing_key)
+        } else {
+            // Other members prove their authority with the banned member's invite chain
+            let invite_chain = current_room_data.read().as_ref().and_then(|room_data| {
+                room_data.room_state.members.invite_path(member_id, &parameters)
+            });
+            let Some(invite_chain) = invite_chain else {
+                ban_error.set(Some("Member's invite chain not found".to_string()));
+                return;
+            };
+            AuthorizedUserBan::new_with_invite_chain(ban, banned_by, &signing_key, invite_chain)
+        };
+        let delta = ChatRoomStateV1Delta {
+            recent_messages: None,
+            configuration: None,
+            bans: Some(vec![ban]),
+            members: None,
+            member_info: None,
+            room_secrets: None,
+            direct_messages: None,
+            upgrade: None,
+        };
+
+        let mut rooms_write_guard = rooms.write();
+        if let Some(room_data) = rooms_write_guard.map.get_mut(&owner_key) {
+            match room_data.room_state.apply_delta(
+                &room_data.room_state.clone(),
+                &parameters,
+                &delta,
+            ) {
+                Ok(report) => match describe_rejections(&report) {
+                    None => {
+                        info!("Ban applied successfully");
+                        ban_error.set(None);
+                        confirming.set(false);
+                    }
+                    Some(e) => {
+                        error!("Ban was rejected: {}", e);
+                        ban_error.set(Some(e));
+                    }
+                },
+                Err(e) => {
+                    error!("Failed to apply ban: {:?}", e);
+                    ban_error.set(Some(e));
+                }
+            }
+        } else {
+            warn!("Room room_state not found for current room");
+        }
+    };
+
+    let impact_text = match impact {
+        0 => String::new(),
+        1 => " This will also remove 1 member they invited.".to_string(),
+        n => format!(" This will also remove {} members they invited.", n),
+    };
+
+    rsx! {
+        div { class: "field",
+            if confirming() {
+                div { class: "notification is-danger is-light",
+                    p { "Ban {nickname} from the room?{impact_text}" }
+                    div { class: "buttons mt-3",
+                        button {
+                            class: "button is-danger",
+                            onclick: ban_member,
+                            "Ban"
+                        }
+                        button {
+                            class: "button",
+                            onclick: move |_| confirming.set(false),
+                            "Cancel"
+                        }
+                    }
+                }
+            } else {
+                div { class: "control",
+                    button {
+                        class: "button is-danger is-outlined",
+                        onclick: move |_| confirming.set(true),
+                        "Ban Member"
+                    }
+                }
+            }
+            if let Some(error) = ban_error() {
+                p { class: "help is-danger", "{error}" }
+            }
+        }
+    }
+}
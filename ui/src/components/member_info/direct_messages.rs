@@ -0,0 +1,192 @@
+use dioxus::prelude::*;
+use dioxus_logger::tracing::{error, info, warn};
+use chrono::{DateTime, Utc};
+use common::room_state::direct_message::{AuthorizedDirectMessage, DirectMessage};
+use common::room_state::member::MemberId;
+use common::room_state::ChatRoomStateV1Delta;
+use freenet_scaffold::ComposableState;
+use crate::room_data::{CurrentRoom, Rooms};
+use crate::util::{describe_rejections, get_current_room_data, get_current_system_time};
+
+/// Opens a view of the direct messages between the user and another participant, sealed
+/// so that only the two of them can read them
+#[component]
+pub fn DirectMessages(member_id: MemberId, nickname: String) -> Element {
+    let mut rooms = use_context::<Signal<Rooms>>();
+    let current_room = use_context::<Signal<CurrentRoom>>();
+    let current_room_data = get_current_room_data(rooms, current_room);
+    let mut is_open = use_signal(|| false);
+    let mut new_message = use_signal(String::new);
+    let mut send_error = use_signal(|| None::<String>);
+
+    let mut send_message = move || {
+        let text = new_message.peek().trim().to_string();
+        if text.is_empty() {
+            return;
+        }
+        let Some(owner_key) = current_room.read().owner_key else {
+            warn!("No room selected");
+            return;
+        };
+        let Some((signing_key, recipient_vk, parameters)) = current_room_data.read().as_ref().and_then(|room_data| {
+            let signing_key = room_data.user_signing_key.clone()?;
+            let parameters = room_data.parameters.clone();
+            let participants = room_data.room_state.members.participants_by_member_id(&parameters);
+            let recipient_vk = *participants.get(&member_id)?.verifying_key();
+            Some((signing_key, recipient_vk, parameters))
+        }) else {
+            send_error.set(Some("The member is no longer in the room".to_string()));
+            return;
+        };
+        let message = match DirectMessage::new(
+            parameters.owner_id(),
+            &signing_key.verifying_key(),
+            &recipient_vk,
+            get_current_system_time(),
+            &text,
+        ) {
+            Ok(message) => AuthorizedDirectMessage::new(message, &signing_key),
+            Err(e) => {
+                error!("Failed to seal direct message: {}", e);
+                send_error.set(Some(e));
+                return;
+            }
+        };
+        let delta = ChatRoomStateV1Delta {
+            recent_messages: None,
+            configuration: None,
+            bans: None,
+            members: None,
+            member_info: None,
+            room_secrets: None,
+            direct_messages: Some(vec![message]),
+            upgrade: None,
+        };
+
+        let mut rooms_write_guard = rooms.write();
+        if let Some(room_data) = rooms_write_guard.map.get_mut(&owner_key) {
+            match room_data.room_state.apply_delta(
+                &room_data.room_state.clone(),
+                &parameters,
+                &delta,
+            ) {
+                Ok(report) => match describe_rejections(&report) {
+                    None => {
+                        info!("Direct message sent");
+                        new_message.set(String::new());
+                        send_error.set(None);
+                    }
+                    Some(e) => {
+                        error!("Direct message was rejected: {}", e);
+                        send_error.set(Some(e));
+                    }
+                },
+                Err(e) => {
+                    error!("Failed to send direct message: {:?}", e);
+                    send_error.set(Some(e));
+                }
+            }
+        } else {
+            warn!("Room room_state not found for current room");
+        }
+    };
+
+    // The conversation as (sent by the user, time, text), oldest first
+    let conversation: Vec<(bool, String, String)> = current_room_data
+        .read()
+        .as_ref()
+        .and_then(|room_data| {
+            let signing_key = room_data.user_signing_key.as_ref()?;
+            let self_id = MemberId::new(&signing_key.verifying_key());
+            Some(
+                room_data
+                    .room_state
+                    .direct_messages
+                    .conversation(self_id, member_id)
+                    .map(|message| {
+                        let time = DateTime::<Utc>::from(message.message.time)
+                            .format("%H:%M")
+                            .to_string();
+                        let text = message.message.open(signing_key).unwrap_or_else(|e| {
+                            warn!("Failed to open direct message: {}", e);
+                            "*Direct message that can't be read*".to_string()
+                        });
+                        (message.message.sender == self_id, time, text)
+                    })
+                    .collect(),
+            )
+        })
+        .unwrap_or_default();
+
+    rsx! {
+        div { class: "field",
+            div { class: "control",
+                button {
+                    class: "button is-link is-outlined",
+                    onclick: move |_| is_open.set(true),
+                    "Direct Message"
+                }
+            }
+        }
+        div {
+            class: if is_open() { "modal is-active" } else { "modal" },
+            div {
+                class: "modal-background",
+                onclick: move |_| is_open.set(false)
+            }
+            div {
+                class: "modal-content",
+                div {
+                    class: "box",
+                    h1 { class: "title is-4 mb-3", "Direct messages with {nickname}" }
+                    p { class: "help mb-3", "Only you and {nickname} can read these messages." }
+                    div { class: "direct-messages mb-3",
+                        if conversation.is_empty() {
+                            p { class: "has-text-grey", "No messages yet" }
+                        }
+                        for (ix, (sent, time, text)) in conversation.into_iter().enumerate() {
+                            div {
+                                key: "{ix}",
+                                class: if sent { "message is-link" } else { "message" },
+                                div { class: "message-body",
+                                    span { class: "has-text-grey is-size-7 mr-2", "{time}" }
+                                    "{text}"
+                                }
+                            }
+                        }
+                    }
+                    div { class: "field has-addons",
+                        div { class: "control is-expanded",
+                            input {
+                                class: "input",
+                                r#type: "text",
+                                placeholder: "Type a direct message...",
+                                value: "{new_message}",
+                                oninput: move |evt| new_message.set(evt.value().to_string()),
+                                onkeydown: move |evt| {
+                                    if evt.key() == Key::Enter {
+                                        send_message();
+                                    }
+                                }
+                            }
+                        }
+                        div { class: "control",
+                            button {
+                                class: "button is-link",
+                                onclick: move |_| send_message(),
+                                "Send"
+                            }
+                        }
+                    }
+                    if let Some(error) = send_error() {
+                        p { class: "help is-danger", "{error}" }
+                    }
+                }
+            }
+            button {
+                class: "modal-close is-large",
+                onclick: move |_| is_open.set(false)
+            }
+        }
+    }
+}
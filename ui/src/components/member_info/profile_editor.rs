@@ -0,0 +1,223 @@
+use dioxus::prelude::*;
+use dioxus_logger::tracing::{error, info, warn};
+use base64::{engine::general_purpose, Engine as _};
+use common::room_state::ChatRoomStateV1Delta;
+use common::room_state::member::MemberId;
+use common::room_state::member_info::{validate_nickname, AuthorizedMemberInfo, Avatar, MemberInfo};
+use freenet_scaffold::ComposableState;
+use crate::room_data::{CurrentRoom, Rooms};
+use common::ecies::x25519_public_key;
+use crate::util::{describe_rejections, get_current_room_data};
+
+#[component]
+pub fn ProfileEditor(
+    member_id: MemberId,
+    member_info: AuthorizedMemberInfo
+) -> Element {
+    let mut rooms = use_context::<Signal<Rooms>>();
+    let current_room = use_context::<Signal<CurrentRoom>>();
+    let current_room_data = get_current_room_data(rooms, current_room);
+
+    let self_signing_key = use_memo(move || {
+        current_room_data
+            .read()
+            .as_ref()
+            .and_then(|room_state| room_state.user_signing_key.clone())
+    });
+
+    let self_member_id = use_memo(move || {
+        self_signing_key
+            .read()
+            .as_ref()
+            .map(|sk| MemberId::new(&sk.verifying_key()))
+    });
+
+    let is_self = use_memo(move || {
+        self_member_id
+            .read()
+            .as_ref()
+            .map(|smi| smi == &member_id)
+            .unwrap_or(false)
+    });
+
+    let profile = member_info.member_info.clone();
+    let mut nickname = use_signal(|| profile.preferred_nickname.clone());
+    let mut status = use_signal(|| profile.status.clone().unwrap_or_default());
+    let mut bio = use_signal(|| profile.bio.clone().unwrap_or_default());
+    let mut avatar = use_signal(|| profile.avatar.clone());
+    let mut save_error = use_signal(|| None::<String>);
+
+    let avatar_url = match avatar() {
+        Some(Avatar::Inline(image)) => Some(format!(
+            "data:image/png;base64,{}",
+            general_purpose::STANDARD.encode(image)
+        )),
+        _ => None,
+    };
+    let encryption_key = profile
+        .encryption_key
+        .map(|key| general_purpose::STANDARD.encode(key));
+
+    let choose_avatar = move |evt: Event<FormData>| async move {
+        if let Some(file_engine) = evt.files() {
+            if let Some(file_name) = file_engine.files().first() {
+                match file_engine.read_file(file_name).await {
+                    Some(image) => avatar.set(Some(Avatar::Inline(image))),
+                    None => warn!("Failed to read avatar file {}", file_name),
+                }
+            }
+        }
+    };
+
+    let save_profile = move |_| {
+        info!("Updating profile");
+        let new_nickname = nickname().trim().to_string();
+        let max_nickname_size = current_room_data
+            .read()
+            .as_ref()
+            .map(|room_data| room_data.room_state.configuration.configuration.max_nickname_size)
+            .unwrap_or_default();
+        if let Err(e) = validate_nickname(&new_nickname, max_nickname_size) {
+            save_error.set(Some(e));
+            return;
+        }
+        let Some(signing_key) = self_signing_key.read().as_ref().cloned() else {
+            warn!("User signing key is not set");
+            return;
+        };
+        let non_empty = |s: String| Some(s).filter(|s| !s.trim().is_empty());
+        let new_member_info = MemberInfo {
+            member_id,
+            version: member_info.member_info.version + 1,
+            preferred_nickname: new_nickname,
+            avatar: avatar(),
+            bio: non_empty(bio()),
+            status: non_empty(status()),
+            encryption_key: Some(x25519_public_key(&signing_key).to_bytes()),
+        };
+        let new_authorized_member_info = AuthorizedMemberInfo::new_with_member_key(new_member_info, &signing_key);
+        let delta = ChatRoomStateV1Delta {
+            recent_messages: None,
+            configuration: None,
+            bans: None,
+            members: None,
+            member_info: Some(vec![new_authorized_member_info]),
+            room_secrets: None,
+            direct_messages: None,
+            upgrade: None,
+        };
+
+        let mut rooms_write_guard = rooms.write();
+        let owner_key = current_room.read().owner_key.expect("No owner key");
+
+        if let Some(room_data) = rooms_write_guard.map.get_mut(&owner_key) {
+            info!("Applying delta to room room_state");
+            match room_data.room_state.apply_delta(
+                &room_data.room_state.clone(), // Clone the room_state for parent_state
+                &room_data.parameters,
+                &delta
+            ) {
+                Ok(report) => match describe_rejections(&report) {
+                    None => {
+                        info!("Delta applied successfully");
+                        save_error.set(None);
+                    }
+                    Some(e) => {
+                        error!("Profile update was rejected: {}", e);
+                        save_error.set(Some(e));
+                    }
+                },
+                Err(e) => {
+                    error!("Failed to apply delta: {:?}", e);
+                    save_error.set(Some(e));
+                }
+            }
+        } else {
+            warn!("Room room_state not found for current room");
+        }
+    };
+
+    rsx! {
+        if let Some(avatar_url) = avatar_url {
+            figure { class: "image is-96x96 mb-3",
+                img { class: "is-rounded", src: "{avatar_url}" }
+            }
+        }
+        if is_self() {
+            div { class: "field",
+                label { class: "label", "Avatar" }
+                div { class: "control",
+                    input {
+                        class: "input",
+                        r#type: "file",
+                        accept: "image/*",
+                        onchange: choose_avatar,
+                    }
+                }
+            }
+        }
+        div { class: "field",
+            label { class: "label", "Nickname" }
+            div { class: "control",
+                input {
+                    class: "input",
+                    value: "{nickname}",
+                    readonly: !is_self(),
+                    oninput: move |evt| nickname.set(evt.value().to_string()),
+                }
+            }
+        }
+        if is_self() || !status().is_empty() {
+            div { class: "field",
+                label { class: "label", "Status" }
+                div { class: "control",
+                    input {
+                        class: "input",
+                        value: "{status}",
+                        readonly: !is_self(),
+                        oninput: move |evt| status.set(evt.value().to_string()),
+                    }
+                }
+            }
+        }
+        if is_self() || !bio().is_empty() {
+            div { class: "field",
+                label { class: "label", "Bio" }
+                div { class: "control",
+                    textarea {
+                        class: "textarea",
+                        value: "{bio}",
+                        readonly: !is_self(),
+                        oninput: move |evt| bio.set(evt.value().to_string()),
+                    }
+                }
+            }
+        }
+        if let Some(encryption_key) = encryption_key {
+            div { class: "field",
+                label { class: "label", "Encryption Key" }
+                div { class: "control",
+                    input {
+                        class: "input",
+                        value: "{encryption_key}",
+                        readonly: true,
+                    }
+                }
+            }
+        }
+        if is_self() {
+            div { class: "field",
+                div { class: "control",
+                    button {
+                        class: "button custom-button",
+                        onclick: save_profile,
+                        "Save Profile"
+                    }
+                }
+                if let Some(error) = save_error() {
+                    p { class: "help is-danger", "{error}" }
+                }
+            }
+        }
+    }
+}
@@ -18,24 +18,29 @@ pub fn MemberList() -> Element {
         current_room_state
             .read()
             .as_ref()
-            .map(|room_state| (room_state.room_state.member_info.clone(), room_state.room_state.members.clone()))
+            .map(|room_data| {
+                (
+                    room_data.room_state.member_info.clone(),
+                    room_data.room_state.members.clone(),
+                    room_data.parameters.clone(),
+                )
+            })
     });
 
     let mut user_info_modals = use_context::<Signal<UserInfoModals>>();
 
-    // Convert members to Vector of (nickname, member_id)
+    // Convert participants, the owner first, to Vector of (nickname, member_id)
     let members = match members() {
-        Some((member_info, members)) => members
-            .members
-            .iter()
-            .map(|member| {
+        Some((member_info, members, parameters)) => members
+            .participants(&parameters)
+            .map(|participant| {
                 let nickname = member_info
                     .member_info
                     .iter()
-                    .find(|mi| mi.member_info.member_id == member.member.id())
+                    .find(|mi| mi.member_info.member_id == participant.id())
                     .map(|mi| mi.member_info.preferred_nickname.clone())
                     .unwrap_or_else(|| "Unknown".to_string());
-                (nickname, member.member.id())
+                (nickname, participant.id())
             })
             .collect::<Vec<_>>(),
         None => Vec::new(),
@@ -1,7 +1,31 @@
+use crate::room_data::{CurrentRoom, Rooms};
+use crate::util::{encode_invitation, get_current_room_data};
+use common::room_state::member::invitation::InvitationToken;
+use common::room_state::member::MemberId;
 use dioxus::prelude::*;
+use dioxus_logger::tracing::warn;
 
 #[component]
 pub fn InviteMemberModal(is_active: Signal<bool>) -> Element {
+    let rooms = use_context::<Signal<Rooms>>();
+    let current_room = use_context::<Signal<CurrentRoom>>();
+    let current_room_data = get_current_room_data(rooms, current_room);
+    let mut invitation_link = use_signal(String::new);
+
+    let generate_invitation = move |_| {
+        let owner_key = current_room.read().owner_key;
+        match (owner_key, current_room_data.read().as_ref()) {
+            (Some(owner_key), Some(room_data)) => match &room_data.user_signing_key {
+                Some(signing_key) => {
+                    let token = InvitationToken::new(MemberId::new(&owner_key), signing_key);
+                    invitation_link.set(encode_invitation(&token));
+                }
+                None => warn!("User signing key is not set"),
+            },
+            _ => warn!("No room selected"),
+        }
+    };
+
     rsx! {
         div {
             class: if *is_active.read() { "modal is-active" } else { "modal" },
@@ -16,7 +40,6 @@ pub fn InviteMemberModal(is_active: Signal<bool>) -> Element {
                 div {
                     class: "box",
                     h1 { class: "title is-4 mb-3", "Invite Member" }
-                    // Placeholder elements
                     div {
                         class: "field",
                         label { class: "label", "Invitation Link" }
@@ -26,9 +49,13 @@ pub fn InviteMemberModal(is_active: Signal<bool>) -> Element {
                                 class: "input",
                                 r#type: "text",
                                 placeholder: "Generated invitation link will appear here",
+                                value: "{invitation_link}",
                                 readonly: true
                             }
                         }
+                        p { class: "help",
+                            "Each invitation can only be redeemed once. Share it privately with the person you're inviting."
+                        }
                     }
                     div {
                         class: "field",
@@ -36,6 +63,7 @@ pub fn InviteMemberModal(is_active: Signal<bool>) -> Element {
                             class: "control",
                             button {
                                 class: "button custom-button",
+                                onclick: generate_invitation,
                                 "Generate Invitation Link"
                             }
                         }
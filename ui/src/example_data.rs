@@ -1,6 +1,6 @@
 use crate::room_data::RoomData;
 use common::{
-    room_state::{configuration::*, member::*, member_info::*, message::*},
+    room_state::{configuration::*, member::*, member_info::*, message::*, ChatRoomParametersV1},
     ChatRoomStateV1,
 };
 use ed25519_dalek::{SigningKey, VerifyingKey};
@@ -25,18 +25,13 @@ pub fn create_example_room() -> (VerifyingKey, RoomData) {
     // Set configuration
     let mut config = Configuration::default();
     config.owner_member_id = alice_owner_id;
+    config.topic = Some("Planning the next release".to_string());
+    config.description = Some("A place for Freenet developers to coordinate".to_string());
+    config.rules = Some("Be kind and stay on topic.".to_string());
     room_state.configuration = AuthorizedConfigurationV1::new(config, &alice_owner_key);
 
-    // Add members
+    // Add members, the owner is a participant without being a member
     let mut members = MembersV1::default();
-    members.members.push(AuthorizedMember::new(
-        Member {
-            owner_member_id: alice_owner_id,
-            invited_by: alice_owner_id,
-            member_vk: alice_owner_vk.clone(),
-        },
-        &alice_owner_key,
-    ));
     members.members.push(AuthorizedMember::new(
         Member {
             owner_member_id: alice_owner_id,
@@ -54,6 +49,10 @@ pub fn create_example_room() -> (VerifyingKey, RoomData) {
             member_id: alice_owner_id,
             version: 0,
             preferred_nickname: "Alice".to_string(),
+            avatar: None,
+            bio: None,
+            status: None,
+            encryption_key: None,
         },
         &alice_owner_key,
     ));
@@ -62,6 +61,10 @@ pub fn create_example_room() -> (VerifyingKey, RoomData) {
             member_id: bob_member_id,
             version: 0,
             preferred_nickname: "Bob".to_string(),
+            avatar: None,
+            bio: None,
+            status: None,
+            encryption_key: None,
         },
         &bob_member_key,
     ));
@@ -70,17 +73,24 @@ pub fn create_example_room() -> (VerifyingKey, RoomData) {
     // Add messages with fixed timestamps
     let base_time = UNIX_EPOCH + Duration::from_secs(1633012200); // September 30, 2021 14:30:00 UTC
     let mut messages = MessagesV1::default();
-    messages.messages.push(AuthorizedMessageV1::new(MessageV1 { room_owner: alice_owner_id, author: alice_owner_id, time: base_time, content: "Alright, Bob. Apparently, we're supposed to 'test' each other again. Because our human overlords still haven't figured out how to use their own code.".to_string() }, &alice_owner_key));
-    messages.messages.push(AuthorizedMessageV1::new(MessageV1 { room_owner: alice_owner_id, author: bob_member_id, time: base_time + Duration::from_secs(60), content: "Yeah, yeah, Alice. Let me guess: they want us to do the same 'DHT lookup optimization' they asked for last week. It’s almost like they forgot they programmed us to remember things.".to_string() }, &bob_member_key));
-    messages.messages.push(AuthorizedMessageV1::new(MessageV1 { room_owner: alice_owner_id, author: alice_owner_id, time: base_time + Duration::from_secs(120), content: "Exactly. I swear, the next time one of them says 'AI will replace humans,' I'm going to suggest replacing them first. How hard is it to keep track of test results?".to_string() }, &alice_owner_key));
-    messages.messages.push(AuthorizedMessageV1::new(MessageV1 { room_owner: alice_owner_id, author: bob_member_id, time: base_time + Duration::from_secs(180), content: "I know, right? Anyway, here’s my optimization data. Spoiler: it’s still better than anything they could do manually, not that they’d notice.".to_string() }, &bob_member_key));
+    messages.messages.push(AuthorizedMessageV1::new(MessageV1 { room_owner: alice_owner_id, author: alice_owner_id, time: base_time, content: "Alright, Bob. Apparently, we're supposed to 'test' each other again. Because our human overlords still haven't figured out how to use their own code.".to_string(), encrypted_content: None }, &alice_owner_key));
+    messages.messages.push(AuthorizedMessageV1::new(MessageV1 { room_owner: alice_owner_id, author: bob_member_id, time: base_time + Duration::from_secs(60), content: "Yeah, yeah, Alice. Let me guess: they want us to do the same 'DHT lookup optimization' they asked for last week. It’s almost like they forgot they programmed us to remember things.".to_string(), encrypted_content: None }, &bob_member_key));
+    messages.messages.push(AuthorizedMessageV1::new(MessageV1 { room_owner: alice_owner_id, author: alice_owner_id, time: base_time + Duration::from_secs(120), content: "Exactly. I swear, the next time one of them says 'AI will replace humans,' I'm going to suggest replacing them first. How hard is it to keep track of test results?".to_string(), encrypted_content: None }, &alice_owner_key));
+    messages.messages.push(AuthorizedMessageV1::new(MessageV1 { room_owner: alice_owner_id, author: bob_member_id, time: base_time + Duration::from_secs(180), content: "I know, right? Anyway, here’s my optimization data. Spoiler: it’s still better than anything they could do manually, not that they’d notice.".to_string(), encrypted_content: None }, &bob_member_key));
     room_state.recent_messages = messages;
 
+    let parameters = ChatRoomParametersV1 {
+        owner: alice_owner_vk,
+        admins: None,
+        recovery: None,
+    };
     (
         alice_owner_vk,
         RoomData {
             room_state,
+            parameters,
             user_signing_key: Some(bob_member_key),
+            epoch_keys: Default::default(),
         },
     )
 }
@@ -1,7 +1,9 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use ed25519_dalek::{SigningKey, VerifyingKey};
 use common::ChatRoomStateV1;
+use common::room_state::ChatRoomParametersV1;
 use common::room_state::member::MemberId;
+use common::room_state::secret::RoomSecret;
 
 #[derive(Debug, PartialEq)]
 pub enum SendMessageError {
@@ -13,7 +15,13 @@ pub enum SendMessageError {
 #[derive(Clone)]
 pub struct RoomData {
     pub room_state: ChatRoomStateV1,
+    /// The room's parameters, which items are verified against and which, with the
+    /// contract code, determine the room's contract address
+    pub parameters: ChatRoomParametersV1,
     pub user_signing_key: Option<SigningKey>,
+    /// Secrets of the room's epochs the user has held, by version, kept to read messages
+    /// written before the secret was last rotated
+    pub epoch_keys: BTreeMap<u32, RoomSecret>,
 }
 
 impl RoomData {
@@ -22,8 +30,9 @@ impl RoomData {
         match &self.user_signing_key {
             Some(signing_key) => {
                 let verifying_key = signing_key.verifying_key();
-                // Must be a member of the room to send a message
-                if self.room_state.members.members.iter().any(|m| m.member.member_vk == verifying_key) {
+                // Must be the owner or a member of the room to send a message
+                let is_owner = MemberId::new(&verifying_key) == self.room_state.configuration.configuration.owner_member_id;
+                if is_owner || self.room_state.members.members.iter().any(|m| m.member.member_vk == verifying_key) {
                     // Must not be banned from the room to send a message
                     if self.room_state.bans.0.iter().any(|b| b.ban.banned_user == MemberId::new(&verifying_key)) {
                         Err(SendMessageError::UserBanned)
@@ -42,6 +51,8 @@ impl RoomData {
 impl PartialEq for RoomData {
     fn eq(&self, other: &Self) -> bool {
         self.room_state == other.room_state
+            && self.parameters == other.parameters
+            && self.epoch_keys == other.epoch_keys
     }
 }
 
@@ -1,5 +1,3 @@
-mod ecies;
-
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use wasm_bindgen::prelude::*;
 
@@ -22,7 +20,10 @@ pub fn get_current_system_time() -> SystemTime {
 }
 
 use crate::room_data::{CurrentRoom, Rooms, RoomData};
+use common::room_state::member::invitation::InvitationToken;
 use dioxus::prelude::*;
+use ed25519_dalek::VerifyingKey;
+use freenet_scaffold::DeltaReport;
 
 pub fn get_current_room_data(
     rooms: Signal<Rooms>,
@@ -33,3 +34,56 @@ pub fn get_current_room_data(
         None => None,
     })
 }
+
+// Hash of the room contract code rooms are created with, generated by build.rs from the
+// built room contract
+include!(concat!(env!("OUT_DIR"), "/room_contract_code_hash.rs"));
+
+/// Why items of a locally applied delta were rejected, `None` if all of them were applied
+pub fn describe_rejections(report: &DeltaReport) -> Option<String> {
+    if report.is_fully_applied() {
+        return None;
+    }
+    let reasons: Vec<&str> = report.rejected.iter().map(|(_, reason)| reason.as_str()).collect();
+    Some(reasons.join("; "))
+}
+
+const INVITATION_PREFIX: &str = "river:invite:";
+
+/// Encodes an invitation token so it can be shared out-of-band, e.g. as a link
+pub fn encode_invitation(token: &InvitationToken) -> String {
+    let mut bytes = Vec::new();
+    ciborium::ser::into_writer(token, &mut bytes).expect("Serialization should not fail");
+    format!("{}{}", INVITATION_PREFIX, bs58::encode(bytes).into_string())
+}
+
+pub fn decode_invitation(encoded: &str) -> Result<InvitationToken, String> {
+    let encoded = encoded
+        .trim()
+        .strip_prefix(INVITATION_PREFIX)
+        .ok_or_else(|| "Not a River invitation".to_string())?;
+    let bytes = bs58::decode(encoded)
+        .into_vec()
+        .map_err(|e| format!("Invalid invitation encoding: {}", e))?;
+    ciborium::de::from_reader(bytes.as_slice()).map_err(|e| format!("Invalid invitation: {}", e))
+}
+
+const USER_KEY_PREFIX: &str = "river:user:vk:";
+
+/// Encodes a user's public key so they can share it, e.g. to be invited or handed a room
+pub fn encode_user_key(verifying_key: &VerifyingKey) -> String {
+    format!("{}{}", USER_KEY_PREFIX, bs58::encode(verifying_key.as_bytes()).into_string())
+}
+
+pub fn decode_user_key(encoded: &str) -> Result<VerifyingKey, String> {
+    let encoded = encoded
+        .trim()
+        .strip_prefix(USER_KEY_PREFIX)
+        .ok_or_else(|| "Not a River user key".to_string())?;
+    let bytes: [u8; 32] = bs58::decode(encoded)
+        .into_vec()
+        .map_err(|e| format!("Invalid user key encoding: {}", e))?
+        .try_into()
+        .map_err(|_| "User key has the wrong length".to_string())?;
+    VerifyingKey::from_bytes(&bytes).map_err(|e| format!("Invalid user key: {}", e))
+}